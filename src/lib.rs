@@ -20,9 +20,6 @@
 //!   ([assert_approx_eq](https://docs.rs/assert_approx_eq/) just for testing).
 //! * Internal Jacobian calculations.
 //!
-//! # Disadvantages
-//! * Sided, analytical or user provided derivates are not implemented.
-//!
 //! # Usage Example
 //! A user should implement trait [`Problem`](crate::Problem) for its struct:
 //! ```
@@ -91,7 +88,40 @@
 //! ```
 //! `init` will afterwards contain the refined parameters of the fitting
 //! function. If the user function fails to calculate residuals, it should
-//! return [`Error::Eval`](crate::Error::Eval)
+//! return [`Error::Eval`](crate::Error::Eval) or its own error wrapped in
+//! [`Error::User`](crate::Error::User), which is passed through by
+//! [`fit`](crate::fit).
+//!
+//! # Thread safety
+//! A fit keeps all of its state local to the call and only borrows the
+//! user [`Problem`](crate::Problem), so independent fits can run in parallel,
+//! e.g. via [`std::thread::scope`]. Sharing one problem between threads
+//! requires it to be `Sync`. All public types are `Send` and `Sync`, as long
+//! as the user error type is, and the adapters like
+//! [`Masked`](crate::Masked) as long as the wrapped problem, closures and
+//! progress bar are.
+//!
+//! # Reproducibility
+//! The routines only use plain IEEE 754 arithmetic and `sqrt` in a fixed
+//! order, without fused multiply-add or reassociated sums, so a fit gives
+//! bit-identical results in debug and release builds. Results can still
+//! differ across platforms and toolchains if the user
+//! [`Problem`](crate::Problem) uses
+//! transcendental functions like `exp`, whose last bit depends on the
+//! platform math library. The same holds for the fit itself with
+//! [`ParamConfig::log_scale`](crate::ParamConfig::log_scale),
+//! which transforms by `exp` and `ln`, and with
+//! [`Config::auto_step`](crate::Config::auto_step), which probes
+//! powers of ten from `powi`.
+//!
+//! # Linear algebra
+//! The QR factorization and the triangular solves of the trust region are
+//! the MINPACK routines ported to Rust. There is no backend for `nalgebra` or
+//! LAPACK, which would add dependencies and give up the bit-identical
+//! results above. For many free parameters,
+//! [`fit_streaming`](crate::fit_streaming) and
+//! [`fit_sparse`](crate::fit_sparse) only keep the normal equations instead
+//! of the full Jacobian.
 //!
 //! # Note
 //! This is a fork of
@@ -101,56 +131,229 @@
 //! state. All structs have basic traits derived, [Error](crate::Error) type
 //! implements [Error](std::error::Error)
 
-/// MPFIT return result
-pub type Result<T> = ::std::result::Result<T, Error>;
+/// MPFIT return result, `E` is the error type of the user [`Problem`]
+pub type Result<T, E = ::std::convert::Infallible> = ::std::result::Result<T, Error<E>>;
 
 /// (f64::MIN_POSITIVE * 1.5).sqrt() * 10
 const MP_RDWARF: f64 = 1.826_912_928_959_669_9e-153;
 /// f64::MAX.sqrt() * 0.1
 const MP_RGIANT: f64 = 1.340_780_779_993_508_3e153;
+/// Smallest sine of the angle between a Jacobian column and the span of the
+/// preceding ones for it to count towards [`Status::rank`], well above the
+/// precision of finite difference derivatives
+const RANK_TOL: f64 = 1e-6;
 
-/// Trait to be implemented by user.
-pub trait Problem<const N: usize> {
+/// Trait to be implemented by user. `E` is the type of errors the user code
+/// can return via [`Error::User`].
+pub trait Problem<const N: usize, E = ::std::convert::Infallible> {
     /// Main evaluation procedure which is called from [`fit`]. Size of
     /// `deviates` is equal to the value returned by [`Self::number_of_points`].
     /// User should compute the residuals using parameters from `params` and any
     /// user data that are required, and fill the `deviates` slice. The
     /// residuals are defined as `(y[i] - f(x[i]))/y_error[i]`.
-    fn eval(&self, params: &[f64; N], deviates: &mut [f64]) -> Result<()>;
+    fn eval(&self, params: &[f64; N], deviates: &mut [f64]) -> Result<(), E>;
 
     /// Number of the data points in the user private data.
     fn number_of_points(&self) -> usize;
+
+    /// Number of the data points whose residuals are always set to zero, e.g.
+    /// by [`Masked`]. They do not contribute to the fit and are not counted in
+    /// [`Status::n_func`]. Zero by default.
+    fn number_of_masked_points(&self) -> usize {
+        0
+    }
+
+    /// Whether the residual of the data point with index `point` is always
+    /// set to zero, consistent with [`Self::number_of_masked_points`]. Used by
+    /// [`bootstrap`] to leave the masked points out. `false` by default.
+    fn is_masked(&self, point: usize) -> bool {
+        let _ = point;
+        false
+    }
+
+    /// Evaluates the residuals `offset..offset + deviates.len()` only, used for
+    /// chunked evaluation if [`Config::chunk_size`] is set. The default
+    /// implementation evaluates all residuals via [`Self::eval`] into a
+    /// temporary vector, so it should be overridden to actually profit from
+    /// chunking.
+    fn eval_chunk(&self, params: &[f64; N], offset: usize, deviates: &mut [f64]) -> Result<(), E> {
+        let mut all = zeros(self.number_of_points())?;
+        self.eval(params, &mut all)?;
+        deviates.copy_from_slice(&all[offset..offset + deviates.len()]);
+        Ok(())
+    }
+
+    /// Evaluates the residuals at complex parameters, used for the derivatives
+    /// of parameters with [`Side::Complex`]. The implementation has to be the
+    /// analytic continuation of [`Self::eval`], i.e. use the same formulas
+    /// with [`Complex`] arithmetic. Returns [`Error::Input`] by default.
+    fn eval_complex(&self, params: &[Complex; N], deviates: &mut [Complex]) -> Result<(), E> {
+        let _ = (params, deviates);
+        Err(Error::Input)
+    }
+
+    /// Evaluates the model values of the data points, used to normalize the
+    /// residuals for [`Config::relative`]. Returns [`Error::Input`] by default.
+    fn model(&self, params: &[f64; N], values: &mut [f64]) -> Result<(), E> {
+        let _ = (params, values);
+        Err(Error::Input)
+    }
+
+    /// Computes the analytical derivatives of the residuals for the
+    /// parameters with [`Side::User`], the finite differences of the others
+    /// are still computed numerically. `jacobian` is the nfunc x npar
+    /// column-major array of the derivatives of the residuals with respect to
+    /// all parameters, only the columns of the [`Side::User`] parameters are
+    /// used. Returns [`Error::Input`] by default.
+    fn jacobian(&self, params: &[f64; N], jacobian: &mut [f64]) -> Result<(), E> {
+        let _ = (params, jacobian);
+        Err(Error::Input)
+    }
+
+    /// Evaluates the residuals together with their Jacobian in one pass, for
+    /// models where both share expensive intermediate results. `jacobian` is
+    /// the nfunc x npar column-major array of the derivatives of the residuals
+    /// with respect to all parameters. Returns `false` if not implemented,
+    /// which is the default, and the derivatives are computed numerically. If
+    /// implemented, it replaces the numerical derivatives of all parameters.
+    fn eval_with_jacobian(
+        &self,
+        params: &[f64; N],
+        deviates: &mut [f64],
+        jacobian: &mut [f64],
+    ) -> Result<bool, E> {
+        let _ = (params, deviates, jacobian);
+        Ok(false)
+    }
+
+    /// Evaluates the residuals of several parameter sets at once, e.g. as a
+    /// single batched kernel on a GPU. `deviates` holds the residual vectors
+    /// of all `params` one after another. If implemented, the forward
+    /// differences of the Jacobian are computed in one batch per iteration.
+    /// Returns `false` if not implemented, which is the default.
+    fn eval_batch(&self, params: &[[f64; N]], deviates: &mut [f64]) -> Result<bool, E> {
+        let _ = (params, deviates);
+        Ok(false)
+    }
+
+    /// Range of the residuals which depend on the parameter with index
+    /// `param`, used by [`fit_sparse`] to evaluate the finite differences of
+    /// `param` on these residuals only (via [`Self::eval_chunk`]) and to skip
+    /// the products of non-overlapping Jacobian columns. `None`, the default,
+    /// means all residuals.
+    fn support(&self, param: usize) -> Option<::std::ops::Range<usize>> {
+        let _ = param;
+        None
+    }
+
+    /// Called by [`fit`] at the start of every iteration, after the Jacobian
+    /// at the current parameters is computed. Does nothing by default.
+    fn iteration(&self, iteration: &Iteration<N>) {
+        let _ = iteration;
+    }
+}
+
+/// State of the fit passed to [`Problem::iteration`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Iteration<const N: usize> {
+    /// Number of the iteration, starting at 1
+    pub n_iter: usize,
+    /// Number of function evaluations so far
+    pub n_fev: usize,
+    /// Current chi^2
+    pub chi2: f64,
+    /// Current parameters npar-vector
+    pub params: [f64; N],
+    /// Current trust region radius (of the scaled parameters). A radius that
+    /// keeps shrinking without recovering indicates trouble, e.g. before a
+    /// premature [`Success::Xtol`] stop.
+    pub delta: f64,
+    /// Current Levenberg-Marquardt parameter, see [`Status::final_par`]
+    pub par: f64,
+    /// Approximate parameter covariance matrix npar x npar array from the
+    /// current Jacobian. Only computed if requested by
+    /// [`Config::iteration_covar`].
+    pub covar: Option<Box<[f64]>>,
 }
 
 /// Main function to refine the parameters.
+///
+/// If all parameters are fixed, no iterations are done and the returned
+/// [`Status`] only reports chi^2 at the given parameters.
 /// # Arguments
 /// * `problem` - A user defind struct implementing the [`Problem`] trait
-/// * `params` - A mutable array with starting fit parameters
+/// * `params` - A mutable array with starting fit parameters, which is
+///   overwritten with the fitted parameters (fixed parameters are left
+///   untouched, as are all on errors)
 /// * `params_config` - An array with parameter configurations
 /// * `config` - [`Config`] to configure the fit
-pub fn fit<const N: usize, P>(
+pub fn fit<const N: usize, E, P>(
     problem: &P,
     params: &mut [f64; N],
     params_config: [ParamConfig; N],
     config: Config,
-) -> Result<Status<N>>
+) -> Result<Status<N>, E>
 where
-    P: Problem<N>,
+    P: Problem<N, E>,
 {
-    let mut fit = Fit::new(problem, params, config)?;
+    restore_on_error(params, |params| {
+        fit_impl(problem, params, params_config, config)
+    })
+}
+
+/// Runs `f` on `params` and restores their start values if it fails.
+fn restore_on_error<const N: usize, T, E>(
+    params: &mut [f64; N],
+    f: impl FnOnce(&mut [f64; N]) -> Result<T, E>,
+) -> Result<T, E> {
+    let start = *params;
+    let result = f(params);
+    if result.is_err() {
+        // Parameters with log_scale are stored as logarithms during the fit
+        *params = start;
+    }
+    result
+}
+
+fn fit_impl<const N: usize, E, P>(
+    problem: &P,
+    params: &mut [f64; N],
+    params_config: [ParamConfig; N],
+    config: Config,
+) -> Result<Status<N>, E>
+where
+    P: Problem<N, E>,
+{
+    let mut fit = Fit::new(problem, params, &params_config, config)?;
     fit.check_config()?;
     fit.parse_params(params_config)?;
     fit.init_lm()?;
+    if fit.nfree == 0 {
+        // Nothing to refine, only evaluate chi^2 at the given parameters
+        fit.info = Success::MaxIter;
+        return fit.terminate(&params_config);
+    }
     loop {
         fit.fill_xnew();
+        fit.reweight()?;
+        if fit.exceeds_max_fev(fit.jacobian_cost()) {
+            fit.info = Success::MaxIter;
+            return fit.terminate(&params_config);
+        }
+        let (start, eval) = (Tick::now(), fit.f.eval_time.get());
         fit.fdjac2()?;
+        fit.timing.jacobian += start.elapsed().saturating_sub(fit.f.eval_time.get() - eval);
+        fit.check_columns()?;
         fit.check_limits();
+        let start = Tick::now();
         fit.qrfac();
+        fit.timing.linear_algebra += start.elapsed();
         fit.scale();
         fit.transpose();
         if !fit.check_is_finite() {
             return Err(Error::Nan);
         }
+        fit.report_iteration()?;
         let gnorm = fit.gnorm();
         if gnorm <= config.gtol {
             fit.info = Success::Dir;
@@ -158,13 +361,15 @@ where
         if fit.info != Success::NotDone {
             return fit.terminate(&params_config);
         }
-        if config.max_iter == 0 {
+        if config.max_iter == 0 || fit.exceeds_max_fev(1) {
             fit.info = Success::MaxIter;
             return fit.terminate(&params_config);
         }
         fit.rescale();
         loop {
+            let start = Tick::now();
             fit.lmpar();
+            fit.timing.linear_algebra += start.elapsed();
             let res = fit.iterate(gnorm)?;
             match res {
                 MPDone::Exit => return fit.terminate(&params_config),
@@ -175,1662 +380,9362 @@ where
     }
 }
 
-/// MPFIT error status
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Error {
-    /// General input parameter error
-    Input,
-    /// User function produced non-finite values
-    Nan,
-    /// No user data points were supplied
-    Empty,
-    /// No free parameters
-    NoFree,
-    /// Initial values inconsistent with constraints
-    InitBounds,
-    /// Initial constraints inconsistent
-    Bounds,
-    /// Not enough degrees of freedom
-    DoF,
-    /// Error during evaluation by user
-    Eval,
+/// Same as [`fit`], but for models which are linear in the free parameters,
+/// e.g. polynomials or sums of fixed basis functions. The least squares
+/// solution is obtained from a single Jacobian evaluation with one QR solve
+/// instead of iterating, and the covariance is exact. For a nonlinear model
+/// the result is one Gauss-Newton step from `params`.
+///
+/// Since they make the problem nonlinear, limits and
+/// [`ParamConfig::log_scale`] of free parameters, a [`Config::loss`] other
+/// than [`Loss::Squared`] and [`Config::relative`] are rejected with
+/// [`Error::Input`]. The status reports one iteration and
+/// [`Success::Both`].
+pub fn fit_linear<const N: usize, E, P>(
+    problem: &P,
+    params: &mut [f64; N],
+    params_config: [ParamConfig; N],
+    config: Config,
+) -> Result<Status<N>, E>
+where
+    P: Problem<N, E>,
+{
+    restore_on_error(params, |params| {
+        fit_linear_impl(problem, params, params_config, config)
+    })
 }
 
-impl ::std::fmt::Display for Error {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Error::Input => "general input parameter error",
-                Error::Nan => "user function produced non-finite values",
-                Error::Empty => "no user data points were supplied",
-                Error::NoFree => "no free parameters",
-                Error::InitBounds => "initial values inconsistent with constraints",
-                Error::Bounds => "initial constraints inconsistent",
-                Error::DoF => "not enough degrees of freedom",
-                Error::Eval => "error during user evaluation",
-            }
-        )
+fn fit_linear_impl<const N: usize, E, P>(
+    problem: &P,
+    params: &mut [f64; N],
+    params_config: [ParamConfig; N],
+    config: Config,
+) -> Result<Status<N>, E>
+where
+    P: Problem<N, E>,
+{
+    if config.loss != Loss::Squared
+        || config.relative.is_some()
+        || params_config
+            .iter()
+            .any(|p| !p.fixed && (p.log_scale || p.limits != Limits::None))
+    {
+        return Err(Error::Input);
+    }
+    let mut fit = Fit::new(problem, params, &params_config, config)?;
+    fit.check_config()?;
+    fit.parse_params(params_config)?;
+    fit.init_lm()?;
+    if fit.nfree == 0 {
+        fit.info = Success::MaxIter;
+        return fit.terminate(&params_config);
+    }
+    fit.fill_xnew();
+    let (start, eval) = (Tick::now(), fit.f.eval_time.get());
+    fit.fdjac2()?;
+    fit.timing.jacobian += start.elapsed().saturating_sub(fit.f.eval_time.get() - eval);
+    fit.check_columns()?;
+    let start = Tick::now();
+    fit.qrfac();
+    fit.timing.linear_algebra += start.elapsed();
+    fit.transpose();
+    if !fit.check_is_finite() {
+        return Err(Error::Nan);
     }
+    fit.report_iteration()?;
+    // Without a trust region the Levenberg-Marquardt step is the Gauss-Newton
+    // step, which solves a linear model exactly
+    fit.delta = f64::INFINITY;
+    let start = Tick::now();
+    fit.lmpar();
+    fit.timing.linear_algebra += start.elapsed();
+    for j in 0..fit.nfree {
+        fit.x[j] -= fit.wa1[j];
+        fit.xnew[fit.ifree[j]] = fit.x[j];
+    }
+    fit.f.eval(&fit.xnew, &mut fit.fvec)?;
+    fit.nfev += 1;
+    fit.fnorm = fit.fvec.enorm_with(config.compensated_sum);
+    fit.info = Success::Both;
+    fit.terminate(&params_config)
 }
-impl ::std::error::Error for Error {}
 
-/// Potential success status
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Success {
-    /// Not finished iterations
-    NotDone,
-    /// Convergence in chi-square value
-    Chi,
-    /// Convergence in parameter value
-    Par,
-    /// Convergence in both chi-square and parameter
-    Both,
-    /// Convergence in orthogonality
-    Dir,
-    /// Maximum number of iterations reached
-    MaxIter,
-    /// ftol is too small; no further improvement
-    Ftol,
-    /// xtol is too small; no further improvement
-    Xtol,
-    /// gtol is too small; no further improvement
-    Gtol,
+/// Same as [`fit`], but starts from a copy of `params` and leaves them
+/// untouched. The fitted parameters are available in [`Status::params`].
+/// Since [`ParamConfig`] and [`Config`] are `Copy`, this allows to rerun the
+/// same fit on fresh data, e.g. for bootstrapping.
+pub fn fit_from<const N: usize, E, P>(
+    problem: &P,
+    params: &[f64; N],
+    params_config: [ParamConfig; N],
+    config: Config,
+) -> Result<Status<N>, E>
+where
+    P: Problem<N, E>,
+{
+    let mut params = *params;
+    fit(problem, &mut params, params_config, config)
 }
 
-impl ::std::fmt::Display for Success {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Success::NotDone => "unknown error",
-                Success::Chi => "convergence in chi-square value",
-                Success::Par => "convergence in parameter value",
-                Success::Both => "convergence in chi-square and parameter values",
-                Success::Dir => "convergence in orthogonality",
-                Success::MaxIter => "maximum number of iterations reached",
-                Success::Ftol => "ftol is too small; no further improvement",
-                Success::Xtol => "xtol is too small; no further improvement",
-                Success::Gtol => "gtol is too small; no further improvement",
-            }
-        )
+/// Fits the model curve `y = f(params, x)` to the data `(x, y)` with optional
+/// uncertainties `sigma`, building the residuals internally (see [`Curve`]).
+/// Otherwise the same as [`fit`], mismatching lengths of the data slices are
+/// rejected with [`Error::Input`].
+pub fn curve_fit<const N: usize, F>(
+    f: F,
+    x: &[f64],
+    y: &[f64],
+    sigma: Option<&[f64]>,
+    params: &mut [f64; N],
+    params_config: [ParamConfig; N],
+    config: Config,
+) -> Result<Status<N>>
+where
+    F: Fn(&[f64; N], f64) -> f64,
+{
+    let curve = Curve::new(f, x, y, sigma);
+    if !curve.check() {
+        return Err(Error::Input);
     }
+    fit(&curve, params, params_config, config)
 }
 
-/// Status structure, for fit when it completes
-#[derive(Debug, Clone, PartialEq)]
-pub struct Status<const N: usize> {
-    /// Success enum
-    pub success: Success,
-    /// Final chi^2
-    pub best_norm: f64,
-    /// Starting value of chi^2
-    pub orig_norm: f64,
-    /// Number of iterations
-    pub n_iter: usize,
-    /// Number of function evaluations
-    pub n_fev: usize,
-    /// Number of free parameters
-    pub n_free: usize,
-    /// Number of pegged parameters
-    pub n_pegged: usize,
-    /// Number of residuals (= num. of data points)
-    pub n_func: usize,
-    /// Final residuals nfunc-vector
-    pub resid: Box<[f64]>,
-    /// Final parameter uncertainties (1-sigma) npar-vector
-    pub xerror: [f64; N],
-    /// Final parameter covariance matrix npar x npar array
-    pub covar: Box<[f64]>,
+/// Same as [`fit`], but restarts from a perturbed start point if the fit does
+/// not converge cleanly, i.e. stops with [`Success::MaxIter`],
+/// [`Success::MaxRejects`] or a warning (see [`Success::is_warning`]).
+///
+/// Every retry shifts the free parameters of the original start point by a
+/// uniformly distributed offset of up to `jitter` times their magnitude (at
+/// least 1, so parameters at zero get perturbed as well), clamped to the
+/// limits. The offsets come from a fixed seed, so the retries are
+/// reproducible. The fit with the lowest chi^2 is written back to `params`
+/// and returned. Retries that fail, e.g. because the model cannot be
+/// evaluated at the perturbed start point, are skipped, only an error of the
+/// first fit is returned.
+/// # Arguments
+/// * `problem` - A user defind struct implementing the [`Problem`] trait
+/// * `params` - A mutable array with starting fit parameters, which is
+///   overwritten with the best fitted parameters
+/// * `params_config` - An array with parameter configurations
+/// * `config` - [`Config`] to configure the fits
+/// * `attempts` - Maximum number of retries after the first fit
+/// * `jitter` - Relative size of the perturbation
+pub fn fit_with_retry<const N: usize, E, P>(
+    problem: &P,
+    params: &mut [f64; N],
+    params_config: [ParamConfig; N],
+    config: Config,
+    attempts: usize,
+    jitter: f64,
+) -> Result<Status<N>, E>
+where
+    P: Problem<N, E>,
+{
+    let clean = |status: &Status<N>| {
+        !matches!(status.success, Success::MaxIter | Success::MaxRejects)
+            && !status.success.is_warning()
+    };
+    let start = *params;
+    let mut best = fit_from(problem, &start, params_config, config)?;
+    let mut rng = SplitMix64::new(0x5EED);
+    for _ in 0..attempts {
+        if clean(&best) {
+            break;
+        }
+        let mut init = start;
+        for (x, p) in init.iter_mut().zip(&params_config) {
+            if !p.fixed {
+                let u = 2. * rng.next_f64() - 1.;
+                *x = p.limits.clamp(*x + jitter * u * x.abs().max(1.));
+            }
+        }
+        if let Ok(status) = fit_from(problem, &init, params_config, config) {
+            if status.best_norm < best.best_norm || (clean(&status) && !clean(&best)) {
+                best = status;
+            }
+        }
+    }
+    for (x, (p, &fitted)) in params
+        .iter_mut()
+        .zip(params_config.iter().zip(&best.params))
+    {
+        if !p.fixed {
+            *x = fitted;
+        }
+    }
+    Ok(best)
 }
 
-impl<const N: usize> ::std::fmt::Display for Status<N> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Status")
-            .field("success", &self.success)
-            .field("best_norm", &self.best_norm)
-            .field("orig_norm", &self.orig_norm)
-            .field("n_iter", &self.n_iter)
-            .field("n_fev", &self.n_fev)
-            .field("n_par", &N)
-            .field("n_free", &self.n_free)
-            .field("n_pegged", &self.n_pegged)
-            .field("n_func", &self.n_func)
-            .field("xerror", &self.xerror)
-            .finish_non_exhaustive()
+/// Runs [`fit`] from `k` start points sampled uniformly with a seeded
+/// generator, to search for the global optimum over a parameter box.
+///
+/// Free parameters with [`Limits::Both`] are sampled within their limits,
+/// all other parameters start at their value in `params`. The same `seed`
+/// gives the same start points. The parameters of the fit with the lowest
+/// chi^2 are written back to `params`. Fits that fail are kept in
+/// [`MultiStart::results`], only if all of them fail the first error is
+/// returned. `k` has to be at least 1.
+pub fn multistart<const N: usize, E, P>(
+    problem: &P,
+    params: &mut [f64; N],
+    params_config: [ParamConfig; N],
+    config: Config,
+    k: usize,
+    seed: u64,
+) -> Result<MultiStart<N, E>, E>
+where
+    P: Problem<N, E>,
+{
+    if k == 0 {
+        return Err(Error::Input);
+    }
+    let mut rng = SplitMix64::new(seed);
+    let mut starts = Vec::with_capacity(k);
+    let mut results = Vec::with_capacity(k);
+    for _ in 0..k {
+        let mut init = *params;
+        for (x, p) in init.iter_mut().zip(&params_config) {
+            if let (false, Limits::Both(lower, upper)) = (p.fixed, p.limits) {
+                *x = p.limits.clamp(lower + (upper - lower) * rng.next_f64());
+            }
+        }
+        starts.push(init);
+        results.push(fit_from(problem, &init, params_config, config));
+    }
+    let best = results
+        .iter()
+        .enumerate()
+        .filter_map(|(i, r)| r.as_ref().ok().map(|s| (i, s.best_norm)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(i, _)| i);
+    let Some(best) = best else {
+        // All fits failed
+        return Err(results
+            .into_iter()
+            .find_map(Result::err)
+            .unwrap_or(Error::Input));
+    };
+    if let Ok(status) = &results[best] {
+        *params = status.params;
     }
+    Ok(MultiStart {
+        best,
+        starts,
+        results,
+    })
 }
 
-/// MPFIT configuration structure
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct Config {
-    /// Relative chi-square convergence criterion (Default: 1e-10)
-    pub ftol: f64,
-    /// Relative parameter convergence criterion  (Default: 1e-10)
-    pub xtol: f64,
-    /// Orthogonality convergence criterion        (Default: 1e-10)
-    pub gtol: f64,
-    /// Finite derivative step size                (Default: f64::EPSILON)
-    pub epsfcn: f64,
-    /// Initial step bound                         (Default: 100.0)
-    pub step_factor: f64,
-    /// Range tolerance for covariance calculation (Default: 1e-14)
-    pub covtol: f64,
-    /// Maximum number of iterations (Default: 200).  If maxiter == 0,
-    /// then basic error checking is done, and parameter
-    /// errors/covariances are estimated based on input
-    /// parameter values, but no fitting iterations are done.
-    pub max_iter: usize,
-    /// Maximum number of function evaluations, or 0 for no limit
-    /// (Default: 0 (no limit))
-    pub max_fev: usize,
-    /// Scale variables by user values?
-    /// true = yes, user scale values in diag;
-    /// false = no, variables scaled internally (Default: false)
-    pub do_user_scale: bool,
-    /// Disable check for infinite quantities from user?
-    /// true = perform check;
-    /// false = do not perform check (Default: false)
-    pub finite_check: bool,
+/// Results of [`multistart`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultiStart<const N: usize, E = ::std::convert::Infallible> {
+    /// Index of the fit with the lowest chi^2
+    pub best: usize,
+    /// Start points of all fits
+    pub starts: Vec<[f64; N]>,
+    /// Results of all fits, in the order of `starts`
+    pub results: Vec<Result<Status<N>, E>>,
 }
 
-impl ::std::default::Default for Config {
-    fn default() -> Self {
-        Config {
-            ftol: 1e-10,
-            xtol: 1e-10,
-            gtol: 1e-10,
-            epsfcn: f64::EPSILON,
-            step_factor: 100.0,
-            covtol: 1e-14,
-            max_iter: 200,
-            max_fev: 0,
-            do_user_scale: false,
-            finite_check: false,
-        }
+impl<const N: usize, E> MultiStart<N, E> {
+    /// Status of the fit with the lowest chi^2, `None` if the `best` index does
+    /// not point to a successful fit, e.g. after changing the fields.
+    pub fn best(&self) -> Option<&Status<N>> {
+        self.results.get(self.best)?.as_ref().ok()
     }
 }
-/// Parameter constraint structure
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct ParamConfig {
-    /// A boolean value, whether the parameter is to be held fixed or not. Fixed
-    /// parameters are not varied by MPFIT, but are passed on to ```MPFitter```
-    /// for evaluation.
-    pub fixed: bool,
-    /// bounds constraints of the parameter
-    pub limits: Limits,
-    /// The *relative* step size to be used in calculating the numerical
-    /// derivatives.  This number is the fractional size of the step, compared
-    /// to the parameter value.
-    pub rel_step: f64,
-    // TODO: Derivative side
+
+/// Residual bootstrap of the parameter errors, which does not rely on the
+/// linearized covariance of [`Status::covar`].
+///
+/// Each of the `n_samples` synthetic data sets replaces the residuals at
+/// `best_fit` by ones drawn with replacement from them (scaled by
+/// `sqrt(n_func / dof)` to undo the shrinking by the fit), which amounts to
+/// adding them to the best-fit model. Every data set is refitted with [`fit`]
+/// from the parameters of `best_fit`. The residuals are drawn from a seeded
+/// generator, so the same `seed` gives the same result. As the errors follow
+/// the actual scatter of the data, they correspond to [`Status::xerror`]
+/// scaled by the square root of [`Status::reduced_chi2`]. Masked points (see
+/// [`Problem::is_masked`]) are left out.
+/// `n_samples` has to be at least 2, the first failing refit is returned as
+/// error.
+pub fn bootstrap<const N: usize, E, P>(
+    problem: &P,
+    best_fit: &Status<N>,
+    params_config: [ParamConfig; N],
+    config: Config,
+    n_samples: usize,
+    seed: u64,
+) -> Result<Bootstrap<N>, E>
+where
+    P: Problem<N, E>,
+{
+    if n_samples < 2 {
+        return Err(Error::Input);
+    }
+    let m = problem.number_of_points();
+    if m == 0 {
+        return Err(Error::Empty);
+    }
+    let mut resid = zeros(m)?;
+    problem.eval(&best_fit.params, &mut resid)?;
+    let scale = match best_fit.dof() {
+        Some(dof) if dof > 0 => (best_fit.n_func as f64 / dof as f64).sqrt(),
+        _ => 1.,
+    };
+    // Masked points keep their zero residual and are not drawn
+    let kept: Vec<usize> = (0..m).filter(|&i| !problem.is_masked(i)).collect();
+    if kept.is_empty() {
+        return Err(Error::Empty);
+    }
+    let mut rng = SplitMix64::new(seed);
+    let mut resampled = Resampled {
+        problem,
+        shift: zeros(m)?,
+    };
+    let mut samples = Vec::with_capacity(n_samples);
+    for _ in 0..n_samples {
+        for &i in &kept {
+            let j = ((rng.next_f64() * kept.len() as f64) as usize).min(kept.len() - 1);
+            resampled.shift[i] = scale * resid[kept[j]] - resid[i];
+        }
+        let status = fit_from(&resampled, &best_fit.params, params_config, config)?;
+        samples.push(status.params);
+    }
+    let n = n_samples as f64;
+    let mut std = [0.; N];
+    for (i, s) in std.iter_mut().enumerate() {
+        let mean = samples.iter().map(|p| p[i]).sum::<f64>() / n;
+        let var = samples.iter().map(|p| (p[i] - mean).powi(2)).sum::<f64>() / (n - 1.);
+        *s = var.sqrt();
+    }
+    Ok(Bootstrap { samples, std })
 }
 
-impl ::std::default::Default for ParamConfig {
-    fn default() -> Self {
-        ParamConfig {
-            fixed: false,
-            limits: Limits::None,
-            rel_step: 0.0,
+/// Results of [`bootstrap`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bootstrap<const N: usize> {
+    /// Fitted parameters of all synthetic data sets
+    pub samples: Vec<[f64; N]>,
+    /// Standard deviations of the parameters over [`Self::samples`]
+    pub std: [f64; N],
+}
+
+impl<const N: usize> Bootstrap<N> {
+    /// The `p`-th percentile (`0..=100`) of every parameter over
+    /// [`Self::samples`], linearly interpolated between the closest ranks.
+    /// `None` if there are no samples.
+    pub fn percentile(&self, p: f64) -> Option<[f64; N]> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let p = p.clamp(0., 100.) / 100.;
+        let mut percentile = [0.; N];
+        let mut values = vec![0.; self.samples.len()];
+        for (i, q) in percentile.iter_mut().enumerate() {
+            for (v, s) in values.iter_mut().zip(&self.samples) {
+                *v = s[i];
+            }
+            values.sort_by(f64::total_cmp);
+            let rank = p * (values.len() - 1) as f64;
+            let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+            *q = values[lo] + (rank - lo as f64) * (values[hi] - values[lo]);
         }
+        Some(percentile)
     }
 }
 
-/// Specifies the bounds constrains for a fitting parameter
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum Limits {
-    /// The parameter is not limited on either side.
-    None,
-    /// The parameter is limited at the lower side.
-    Lower(f64),
-    /// The parameter is limited at the upper side.
-    Upper(f64),
-    /// The parameter is limits at the both sides.
-    Both(f64, f64),
+/// Problem with the residuals shifted by `shift`, the synthetic data sets of
+/// [`bootstrap`]. The Jacobian is unchanged.
+struct Resampled<'a, P> {
+    problem: &'a P,
+    shift: Vec<f64>,
 }
 
-/// Internal structure to hold calculated values.
-struct Fit<'a, const N: usize, T: Problem<N>> {
-    m: usize,
-    nfree: usize,
-    ifree: Vec<usize>,
-    fvec: Vec<f64>,
-    nfev: usize,
-    xnew: [f64; N],
-    x: Vec<f64>,
-    xall: &'a mut [f64; N],
-    qtf: Vec<f64>,
-    fjac: Vec<f64>,
-    step: Vec<f64>,
-    dstep: Vec<f64>,
-    qllim: Vec<bool>,
-    qulim: Vec<bool>,
-    llim: Vec<f64>,
-    ulim: Vec<f64>,
-    qanylim: bool,
-    f: &'a T,
-    wa1: [f64; N],
-    wa2: Vec<f64>,
-    wa3: [f64; N],
-    wa4: Vec<f64>,
-    ipvt: [usize; N],
-    diag: [f64; N],
-    fnorm: f64,
-    fnorm1: f64,
-    xnorm: f64,
-    delta: f64,
-    info: Success,
-    orig_norm: f64,
-    par: f64,
-    iter: usize,
-    cfg: Config,
+impl<const N: usize, E, P: Problem<N, E>> Problem<N, E> for Resampled<'_, P> {
+    fn eval(&self, params: &[f64; N], deviates: &mut [f64]) -> Result<(), E> {
+        self.problem.eval(params, deviates)?;
+        for (d, s) in deviates.iter_mut().zip(&self.shift) {
+            *d += s;
+        }
+        Ok(())
+    }
+
+    fn number_of_points(&self) -> usize {
+        self.problem.number_of_points()
+    }
+
+    fn number_of_masked_points(&self) -> usize {
+        self.problem.number_of_masked_points()
+    }
+
+    fn is_masked(&self, point: usize) -> bool {
+        self.problem.is_masked(point)
+    }
+
+    fn eval_chunk(&self, params: &[f64; N], offset: usize, deviates: &mut [f64]) -> Result<(), E> {
+        self.problem.eval_chunk(params, offset, deviates)?;
+        for (d, s) in deviates.iter_mut().zip(&self.shift[offset..]) {
+            *d += s;
+        }
+        Ok(())
+    }
+
+    fn model(&self, params: &[f64; N], values: &mut [f64]) -> Result<(), E> {
+        self.problem.model(params, values)
+    }
+
+    fn eval_complex(&self, params: &[Complex; N], deviates: &mut [Complex]) -> Result<(), E> {
+        self.problem.eval_complex(params, deviates)?;
+        for (d, s) in deviates.iter_mut().zip(&self.shift) {
+            d.re += s;
+        }
+        Ok(())
+    }
+
+    fn jacobian(&self, params: &[f64; N], jacobian: &mut [f64]) -> Result<(), E> {
+        self.problem.jacobian(params, jacobian)
+    }
+
+    fn eval_with_jacobian(
+        &self,
+        params: &[f64; N],
+        deviates: &mut [f64],
+        jacobian: &mut [f64],
+    ) -> Result<bool, E> {
+        if !self
+            .problem
+            .eval_with_jacobian(params, deviates, jacobian)?
+        {
+            return Ok(false);
+        }
+        for (d, s) in deviates.iter_mut().zip(&self.shift) {
+            *d += s;
+        }
+        Ok(true)
+    }
+
+    fn eval_batch(&self, params: &[[f64; N]], deviates: &mut [f64]) -> Result<bool, E> {
+        if !self.problem.eval_batch(params, deviates)? {
+            return Ok(false);
+        }
+        for set in deviates.chunks_exact_mut(self.shift.len()) {
+            for (d, s) in set.iter_mut().zip(&self.shift) {
+                *d += s;
+            }
+        }
+        Ok(true)
+    }
+
+    fn support(&self, param: usize) -> Option<::std::ops::Range<usize>> {
+        self.problem.support(param)
+    }
 }
 
-impl<'a, const N: usize, P: Problem<N>> Fit<'a, N, P> {
-    fn new(f: &'a P, xall: &'a mut [f64; N], cfg: Config) -> Result<Fit<'a, N, P>> {
-        let m = f.number_of_points();
-        if m == 0 {
-            Err(Error::Empty)
-        } else {
-            Ok(Fit {
-                m,
-                nfree: 0,
-                ifree: vec![],
-                fvec: vec![0.; m],
-                nfev: 1,
-                xnew: [0.; N],
-                x: vec![],
-                xall,
-                qtf: vec![],
-                fjac: vec![],
-                step: vec![],
-                dstep: vec![],
-                qllim: vec![],
-                qulim: vec![],
-                llim: vec![],
-                ulim: vec![],
-                qanylim: false,
-                f,
-                wa1: [0.; N],
-                wa2: vec![0.; m],
-                wa3: [0.; N],
-                wa4: vec![0.; m],
-                ipvt: [0; N],
-                diag: [0.; N],
-                fnorm: -1.0,
-                fnorm1: -1.0,
-                xnorm: -1.0,
-                delta: 0.0,
-                info: Success::NotDone,
-                orig_norm: 0.0,
-                par: 0.0,
-                iter: 1,
-                cfg,
-            })
+/// Same as [`fit`], but for data sets that do not fit into memory. The
+/// residuals are streamed in chunks of [`Config::chunk_size`] (which has to be
+/// set) via [`Problem::eval_chunk`], and instead of the full Jacobian only the
+/// normal equations `J^T J` and `J^T r` of the free parameters are
+/// accumulated. The memory is thus bounded by the chunk size times the number
+/// of free parameters.
+///
+/// The normal equations are solved by a plain Levenberg-Marquardt iteration,
+/// which is less robust than the QR based trust region of [`fit`] for badly
+/// conditioned problems. Only `ftol`, `xtol`, `max_iter`, `epsfcn`,
+/// `limit_margin` and `chunk_size` of `config` are used, as well as `fixed`,
+/// `limits`, `rel_step` and `step_scale` of `params_config`. The finite
+/// differences of all free parameters are evaluated chunk by chunk, so one
+/// Jacobian costs `n_free + 1` evaluations of all residuals. [`Status::resid`]
+/// is empty and [`Status::final_par`] holds the final damping factor.
+pub fn fit_streaming<const N: usize, E, P>(
+    problem: &P,
+    params: &mut [f64; N],
+    params_config: [ParamConfig; N],
+    config: Config,
+) -> Result<Status<N>, E>
+where
+    P: Problem<N, E>,
+{
+    let m = problem.number_of_points();
+    if m == 0 {
+        return Err(Error::Empty);
+    }
+    if config.chunk_size == 0 {
+        return Err(Error::Input);
+    }
+    let (ifree, nfree) = normal_free(
+        m,
+        problem.number_of_masked_points(),
+        params,
+        &params_config,
+        config,
+    )?;
+    let ifree = &ifree[..nfree];
+    let mut stream = Stream {
+        problem,
+        ifree,
+        params_config: &params_config,
+        eps: config.epsfcn.max(f64::EPSILON).sqrt(),
+        margin: config.limit_margin,
+        chunk: config.chunk_size.min(m),
+        base: zeros(config.chunk_size.min(m))?,
+        probes: zeros(config.chunk_size.min(m) * nfree)?,
+        eq: Equations::new(nfree)?,
+    };
+    let n_func = m.saturating_sub(problem.number_of_masked_points());
+    solve_normal(&mut stream, params, &params_config, ifree, config, n_func)
+}
+
+/// Same as [`fit`], but for sparse Jacobians, where most parameters only
+/// affect a few residuals, e.g. piecewise models. The residuals each parameter
+/// depends on are declared by [`Problem::support`]. Only these are evaluated
+/// for its finite differences (via [`Problem::eval_chunk`], which should be
+/// overridden) and stored, instead of the dense nfunc x npar Jacobian, so the
+/// memory is bounded by the total size of the supports.
+///
+/// The normal equations are assembled from the overlapping parts of the
+/// Jacobian columns and solved as in [`fit_streaming`], with the same
+/// restrictions on the used configuration (except that `chunk_size` is not
+/// used). A partial evaluation for the finite differences counts as one
+/// function evaluation.
+pub fn fit_sparse<const N: usize, E, P>(
+    problem: &P,
+    params: &mut [f64; N],
+    params_config: [ParamConfig; N],
+    config: Config,
+) -> Result<Status<N>, E>
+where
+    P: Problem<N, E>,
+{
+    let m = problem.number_of_points();
+    let (ifree, nfree) = normal_free(
+        m,
+        problem.number_of_masked_points(),
+        params,
+        &params_config,
+        config,
+    )?;
+    let ifree = &ifree[..nfree];
+    let mut support = Vec::with_capacity(nfree);
+    let mut offsets = vec![0];
+    for &i in ifree {
+        let range = problem.support(i).unwrap_or(0..m);
+        if range.start > range.end || range.end > m {
+            return Err(Error::Input);
         }
+        offsets.push(offsets[offsets.len() - 1] + range.len());
+        support.push(range);
     }
+    let mut sparse = Sparse {
+        problem,
+        ifree,
+        params_config: &params_config,
+        eps: config.epsfcn.max(f64::EPSILON).sqrt(),
+        margin: config.limit_margin,
+        columns: zeros(offsets[nfree])?,
+        support,
+        offsets,
+        resid: zeros(m)?,
+        work: zeros(m)?,
+        eq: Equations::new(nfree)?,
+    };
+    let n_func = m.saturating_sub(problem.number_of_masked_points());
+    let mut status = solve_normal(&mut sparse, params, &params_config, ifree, config, n_func)?;
+    status.resid = sparse.resid.into_boxed_slice();
+    Ok(status)
+}
 
-    /// subroutine fdjac2
-    ///
-    /// this subroutine computes a forward-difference approximation to the m by
-    /// n jacobian matrix associated with a specified problem of m functions in
-    /// n variables.
-    ///
-    /// the subroutine statement is
-    ///
-    /// subroutine fdjac2(fcn,m,n,x,fvec,fjac,ldfjac,iflag,epsfcn,wa)
-    ///
-    /// where
-    ///
-    /// fcn is the name of the user-supplied subroutine which calculates the
-    /// functions. fcn must be declared in an external statement in the user
-    /// calling program, and should be written as follows.
-    ///
-    /// subroutine fcn(m,n,x,fvec,iflag) integer m,n,iflag double precision
-    /// x(n),fvec(m)
-    /// ----------
-    /// calculate the functions at x and return this vector in fvec.
-    /// ----------
-    /// return end
-    ///
-    /// the value of iflag should not be changed by fcn unless the user wants to
-    /// terminate execution of fdjac2. in this case set iflag to a negative
-    /// integer.
-    ///
-    /// m is a positive integer input variable set to the number of functions.
-    ///
-    /// n is a positive integer input variable set to the number of variables. n
-    /// must not exceed m.
-    ///
-    /// x is an input array of length n.
-    ///
-    /// fvec is an input array of length m which must contain the functions
-    /// evaluated at x.
-    ///
-    /// fjac is an output m by n array which contains the approximation to the
-    /// jacobian matrix evaluated at x.
-    ///
-    /// ldfjac is a positive integer input variable not less than m which
-    /// specifies the leading dimension of the array fjac.
-    ///
-    /// iflag is an integer variable which can be used to terminate the
-    /// execution of fdjac2. see description of fcn.
-    ///
-    /// epsfcn is an input variable used in determining a suitable step length
-    /// for the forward-difference approximation. this approximation assumes
-    /// that the relative errors in the functions are of the order of epsfcn. if
-    /// epsfcn is less than the machine precision, it is assumed that the
-    /// relative errors in the functions are of the order of the machine
-    /// precision.
-    ///
-    /// wa is a work array of length m.
-    fn fdjac2(&mut self) -> Result<()> {
-        // Calculate the Jacobian matrix
-        let eps = self.cfg.epsfcn.max(f64::EPSILON).sqrt();
-        // TODO: probably sides and analytical derivatives should be implemented at some point
-        self.fjac.fill(0.);
-        let mut ij = 0;
-        /* Any parameters requiring numerical derivatives */
-        for j in 0..self.nfree {
-            let free_p = self.ifree[j];
-            let temp = self.xnew[free_p];
-            let mut h = eps * temp.abs();
-            if free_p < self.step.len() && self.step[free_p] > 0. {
-                h = self.step[free_p];
+/// Same as [`fit_streaming`], but without any heap allocation, for small
+/// models whose number of data points `M` is known at compile time, e.g. to
+/// fit inside a control loop. The residuals, the Jacobian and the normal
+/// equations are stack arrays, and the result is a [`FixedStatus`] instead of
+/// a [`Status`] with its heap allocated slices.
+///
+/// [`Problem::number_of_points`] has to return `M`. The normal equations are
+/// solved as in [`fit_streaming`], with the same restrictions on the used
+/// configuration (except that `chunk_size` is not used). The finite
+/// differences of one Jacobian cost `n_free` evaluations via
+/// [`Problem::eval`], which itself must not allocate for the whole fit to be
+/// allocation free.
+pub fn fit_fixed<const N: usize, const M: usize, E, P>(
+    problem: &P,
+    params: &mut [f64; N],
+    params_config: [ParamConfig; N],
+    config: Config,
+) -> Result<FixedStatus<N>, E>
+where
+    P: Problem<N, E>,
+{
+    if problem.number_of_points() != M {
+        return Err(Error::Input);
+    }
+    let masked = problem.number_of_masked_points();
+    let (ifree, nfree) = normal_free(M, masked, params, &params_config, config)?;
+    let mut fixed = Fixed {
+        problem,
+        ifree: &ifree[..nfree],
+        params_config: &params_config,
+        eps: config.epsfcn.max(f64::EPSILON).sqrt(),
+        margin: config.limit_margin,
+        resid: [0.; M],
+        jac: [[0.; M]; N],
+        jtj: [[0.; N]; N],
+        jtr: [0.; N],
+        n_fev: 0,
+    };
+    let n2 = nfree * nfree;
+    let mut a = [[0.; N]; N];
+    let mut inv = [[0.; N]; N];
+    let (a, inv) = (
+        &mut a.as_flattened_mut()[..n2],
+        &mut inv.as_flattened_mut()[..n2],
+    );
+    let mut x = *params;
+    let mut chi2 = fixed.normal(&x)?;
+    let orig_norm = chi2;
+    let mut lambda = 1e-3;
+    let mut n_iter = 0;
+    let mut success = Success::NotDone;
+    while success == Success::NotDone {
+        if nfree == 0 || n_iter >= config.max_iter {
+            success = Success::MaxIter;
+            break;
+        }
+        n_iter += 1;
+        let jtj = &fixed.jtj.as_flattened()[..n2];
+        a.copy_from_slice(jtj);
+        for j in 0..nfree {
+            a[j * nfree + j] += lambda * jtj[j * nfree + j].max(MP_RDWARF);
+        }
+        let mut trial = x;
+        let solved = invert_with(a, inv, nfree);
+        if solved {
+            for (j, &i) in fixed.ifree.iter().enumerate() {
+                let step: f64 = (0..nfree).map(|k| a[j * nfree + k] * fixed.jtr[k]).sum();
+                trial[i] = params_config[i].limits.clamp(x[i] - step);
             }
-            if free_p < self.dstep.len() && self.dstep[free_p] > 0. {
-                h = (self.dstep[free_p] * temp).abs();
+        }
+        let trial_chi2 = if solved {
+            fixed.chi2(&trial)?
+        } else {
+            f64::INFINITY
+        };
+        if trial_chi2 > chi2 {
+            lambda *= 10.;
+            if lambda > 1e16 {
+                success = Success::Ftol;
             }
-            if h == 0. {
-                h = eps;
+            continue;
+        }
+        let chi = chi2 - trial_chi2 <= config.ftol * chi2;
+        let par = (fixed.ifree.iter())
+            .all(|&i| (trial[i] - x[i]).abs() <= config.xtol * (x[i].abs() + config.xtol));
+        success = match (chi, par) {
+            (true, true) => Success::Both,
+            (true, false) => Success::Chi,
+            (false, true) => Success::Par,
+            (false, false) => Success::NotDone,
+        };
+        x = trial;
+        chi2 = fixed.normal(&x)?;
+        lambda = (lambda / 10.).max(1e-12);
+    }
+    /* Covariance of the free parameters from the normal equations */
+    let mut covar = [[0.; N]; N];
+    a.copy_from_slice(&fixed.jtj.as_flattened()[..n2]);
+    if invert_with(a, inv, nfree) {
+        for (j, &i) in fixed.ifree.iter().enumerate() {
+            for (k, &l) in fixed.ifree.iter().enumerate() {
+                covar[i][l] = a[j * nfree + k];
             }
-            if j < self.qulim.len()
-                && self.qulim[j]
-                && j < self.ulim.len()
-                && temp > self.ulim[j] - h
-            {
-                h = -h;
-            }
-            self.xnew[free_p] = temp + h;
-            self.f.eval(&self.xnew, &mut self.wa4)?;
-            self.nfev += 1;
-            self.xnew[free_p] = temp;
-            for (wa4, fvec) in self.wa4.iter().zip(&self.fvec) {
-                self.fjac[ij] = (wa4 - fvec) / h;
-                ij += 1;
+        }
+    }
+    let negative_variance = clamp_variances(covar.as_flattened_mut(), N);
+    let mut xerror = [0.; N];
+    for (i, e) in xerror.iter_mut().enumerate() {
+        *e = covar[i][i].sqrt();
+    }
+    let pegged = pegged(&x, &params_config);
+    *params = x;
+    Ok(FixedStatus {
+        success,
+        best_norm: chi2,
+        orig_norm,
+        n_iter,
+        n_fev: fixed.n_fev,
+        n_free: nfree,
+        n_pegged: pegged.iter().filter(|&&p| p).count(),
+        pegged,
+        n_func: M.saturating_sub(masked),
+        params: x,
+        xerror,
+        covar,
+        negative_variance,
+        final_par: lambda,
+    })
+}
+
+/// Results of [`fit_fixed`], the fields have the same meaning as those of
+/// [`Status`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FixedStatus<const N: usize> {
+    /// See [`Status::success`]
+    pub success: Success,
+    /// See [`Status::best_norm`]
+    pub best_norm: f64,
+    /// See [`Status::orig_norm`]
+    pub orig_norm: f64,
+    /// See [`Status::n_iter`]
+    pub n_iter: usize,
+    /// See [`Status::n_fev`]
+    pub n_fev: usize,
+    /// See [`Status::n_free`]
+    pub n_free: usize,
+    /// See [`Status::n_pegged`]
+    pub n_pegged: usize,
+    /// See [`Status::pegged`]
+    pub pegged: [bool; N],
+    /// See [`Status::n_func`]
+    pub n_func: usize,
+    /// See [`Status::params`]
+    pub params: [f64; N],
+    /// See [`Status::xerror`]
+    pub xerror: [f64; N],
+    /// See [`Status::covar`], as rows of the npar x npar matrix
+    pub covar: [[f64; N]; N],
+    /// See [`Status::negative_variance`]
+    pub negative_variance: bool,
+    /// See [`Status::final_par`]
+    pub final_par: f64,
+}
+
+/// Evaluation state of [`fit_fixed`], the normal equations have the stride
+/// `nfree`.
+struct Fixed<'a, const N: usize, const M: usize, P> {
+    problem: &'a P,
+    ifree: &'a [usize],
+    params_config: &'a [ParamConfig; N],
+    eps: f64,
+    margin: f64,
+    /// Residuals at the point of the normal equations
+    resid: [f64; M],
+    /// Jacobian columns of the free parameters
+    jac: [[f64; M]; N],
+    jtj: [[f64; N]; N],
+    jtr: [f64; N],
+    n_fev: usize,
+}
+
+impl<const N: usize, const M: usize, P> Fixed<'_, N, M, P> {
+    /// chi^2 at `x`
+    fn chi2<E>(&mut self, x: &[f64; N]) -> Result<f64, E>
+    where
+        P: Problem<N, E>,
+    {
+        let mut resid = [0.; M];
+        self.problem.eval(x, &mut resid)?;
+        self.n_fev += 1;
+        Ok(resid.iter().map(|r| r * r).sum())
+    }
+
+    /// Accumulates the normal equations at `x` by forward differences,
+    /// returns chi^2 at `x`.
+    fn normal<E>(&mut self, x: &[f64; N]) -> Result<f64, E>
+    where
+        P: Problem<N, E>,
+    {
+        let nfree = self.ifree.len();
+        self.problem.eval(x, &mut self.resid)?;
+        for (j, &i) in self.ifree.iter().enumerate() {
+            let h = normal_step(&self.params_config[i], x[i], self.eps, self.margin);
+            let mut probe = *x;
+            probe[i] += h;
+            let column = &mut self.jac[j];
+            self.problem.eval(&probe, column)?;
+            for (d, r) in column.iter_mut().zip(&self.resid) {
+                *d = (*d - r) / h;
             }
         }
-        Ok(())
+        self.n_fev += 1 + nfree;
+        let jtj = self.jtj.as_flattened_mut();
+        for j in 0..nfree {
+            let dj = &self.jac[j];
+            self.jtr[j] = dj.iter().zip(&self.resid).map(|(d, r)| d * r).sum();
+            for k in 0..=j {
+                let sum = dj.iter().zip(&self.jac[k]).map(|(a, b)| a * b).sum();
+                jtj[j * nfree + k] = sum;
+                jtj[k * nfree + j] = sum;
+            }
+        }
+        Ok(self.resid.iter().map(|r| r * r).sum())
     }
+}
 
-    /// subroutine qrfac
-    ///
-    /// this subroutine uses householder transformations with column pivoting
-    /// (optional) to compute a qr factorization of the m by n matrix a. that
-    /// is, qrfac determines an orthogonal matrix q, a permutation matrix p, and
-    /// an upper trapezoidal matrix r with diagonal elements of nonincreasing
-    /// magnitude, such that a*p = q*r. the householder transformation for
-    /// column k, k = 1,2,...,min(m,n), is of the form
-    ///
-    /// i - (1/u(k))*u*u
-    ///
-    /// where u has zeros in the first k-1 positions. the form of this
-    /// transformation and the method of pivoting first appeared in the
-    /// corresponding linpack subroutine.
-    ///
-    /// the subroutine statement is
-    ///
-    /// subroutine qrfac(m,n,a,lda,pivot,ipvt,lipvt,rdiag,acnorm,wa)
-    ///
-    /// where
-    ///
-    /// m is a positive integer input variable set to the number of rows of a.
-    ///
-    /// n is a positive integer input variable set to the number of columns of
-    /// a.
-    ///
-    /// a is an m by n array. on input a contains the matrix for which the qr
-    /// factorization is to be computed. on output the strict upper trapezoidal
-    /// part of a contains the strict upper trapezoidal part of r, and the lower
-    /// trapezoidal part of a contains a factored form of q (the non-trivial
-    /// elements of the u vectors described above).
-    ///
-    /// lda is a positive integer input variable not less than m which specifies
-    /// the leading dimension of the array a.
-    ///
-    /// pivot is a logical input variable. if pivot is set true, then column
-    /// pivoting is enforced. if pivot is set false, then no column pivoting is
-    /// done.
-    ///
-    /// ipvt is an integer output array of length lipvt. ipvt defines the
-    /// permutation matrix p such that a*p = q*r. column j of p is column
-    /// ipvt(j) of the identity matrix. if pivot is false, ipvt is not
-    /// referenced.
-    ///
-    /// lipvt is a positive integer input variable. if pivot is false, then
-    /// lipvt may be as small as 1. if pivot is true, then lipvt must be at
-    /// least n.
-    ///
-    /// rdiag is an output array of length n which contains the diagonal
-    /// elements of r.
-    ///
-    /// acnorm is an output array of length n which contains the norms of the
-    /// corresponding columns of the input matrix a. if this information is not
-    /// needed, then acnorm can coincide with rdiag.
-    ///
-    /// wa is a work array of length n. if pivot is false, then wa can coincide
-    /// with rdiag.
-    fn qrfac(&mut self) {
-        // Compute the QR factorization of the jacobian
-        // compute the initial column norms and initialize several arrays.
-        for (j, ij) in (0..self.nfree).zip((0..self.m * self.nfree).step_by(self.m)) {
-            self.wa2[j] = self.fjac[ij..ij + self.m].enorm();
-            self.wa1[j] = self.wa2[j];
-            self.wa3[j] = self.wa1[j];
-            self.ipvt[j] = j;
+/// Checks the common inputs of [`fit_streaming`], [`fit_sparse`] and
+/// [`fit_fixed`] for `m` data points of which `masked` are masked, returns the
+/// indices and the number of the free parameters.
+fn normal_free<const N: usize, E>(
+    m: usize,
+    masked: usize,
+    params: &[f64; N],
+    params_config: &[ParamConfig; N],
+    config: Config,
+) -> Result<([usize; N], usize), E> {
+    if m == 0 {
+        return Err(Error::Empty);
+    }
+    if config.ftol <= 0.
+        || config.xtol <= 0.
+        || config.limit_margin < 0.
+        || config.limit_margin.is_nan()
+    {
+        return Err(Error::Input);
+    }
+    for (&x, p) in params.iter().zip(params_config) {
+        let ordered = match p.limits {
+            Limits::Both(lower, upper) => lower < upper,
+            _ => true,
+        };
+        if p.fixed && !p.limits.contains(x) || !p.fixed && !ordered {
+            return Err(Error::Bounds);
         }
-        // reduce a to r with householder transformations.
-        for j in 0..self.m.min(self.nfree) {
-            // bring the column of largest norm into the pivot position.
-            let mut kmax = j;
-            for k in j..self.nfree {
-                if self.wa1[k] > self.wa1[kmax] {
-                    kmax = k;
-                }
-            }
-            if kmax != j {
-                let mut ij = self.m * j;
-                let mut jj = self.m * kmax;
-                for _ in 0..self.m {
-                    self.fjac.swap(jj, ij);
-                    ij += 1;
-                    jj += 1;
-                }
-                self.wa1[kmax] = self.wa1[j];
-                self.wa3[kmax] = self.wa3[j];
-                self.ipvt.swap(j, kmax);
-            }
-            let jj = j + self.m * j;
-            let jjj = self.m - j + jj;
-            let mut ajnorm = self.fjac[jj..jjj].enorm();
-            if ajnorm == 0. {
-                self.wa1[j] = -ajnorm;
-                continue;
-            }
-            if self.fjac[jj] < 0. {
-                ajnorm = -ajnorm;
+    }
+    if !is_feasible(params, params_config) {
+        return Err(Error::InitBounds);
+    }
+    let mut ifree = [0; N];
+    let mut nfree = 0;
+    for (i, p) in params_config.iter().enumerate() {
+        if !p.fixed {
+            ifree[nfree] = i;
+            nfree += 1;
+        }
+    }
+    if m.saturating_sub(masked) < nfree {
+        return Err(Error::DoF);
+    }
+    Ok((ifree, nfree))
+}
+
+/// Levenberg-Marquardt iteration on the normal equations assembled by
+/// `normal` for the free parameters `ifree`, shared by [`fit_streaming`] and
+/// [`fit_sparse`]. `n_func` is the number of unmasked data points.
+/// [`fit_fixed`] repeats the iteration on stack arrays, changes have to be
+/// made in both.
+fn solve_normal<const N: usize, E, S: Normal<N, E>>(
+    stream: &mut S,
+    params: &mut [f64; N],
+    params_config: &[ParamConfig; N],
+    ifree: &[usize],
+    config: Config,
+    n_func: usize,
+) -> Result<Status<N>, E> {
+    let nfree = ifree.len();
+    let mut x = *params;
+    let mut chi2 = stream.normal(&x)?;
+    let orig_norm = chi2;
+    let mut lambda = 1e-3;
+    let mut n_iter = 0;
+    let mut success = Success::NotDone;
+    let mut a = zeros(nfree * nfree)?;
+    while success == Success::NotDone {
+        if nfree == 0 || n_iter >= config.max_iter {
+            success = Success::MaxIter;
+            break;
+        }
+        n_iter += 1;
+        let eq = stream.equations();
+        a.copy_from_slice(&eq.jtj);
+        for j in 0..nfree {
+            a[j * nfree + j] += lambda * eq.jtj[j * nfree + j].max(MP_RDWARF);
+        }
+        let mut trial = x;
+        let start = Tick::now();
+        let solved = invert(&mut a, nfree);
+        eq.timing.linear_algebra += start.elapsed();
+        if solved {
+            for (j, &i) in ifree.iter().enumerate() {
+                let step: f64 = (0..nfree).map(|k| a[j * nfree + k] * eq.jtr[k]).sum();
+                trial[i] = params_config[i].limits.clamp(x[i] - step);
             }
-            for fjac in self.fjac[jj..jjj].iter_mut() {
-                *fjac /= ajnorm;
+        }
+        let trial_chi2 = if solved {
+            stream.chi2(&trial)?
+        } else {
+            f64::INFINITY
+        };
+        if trial_chi2 > chi2 {
+            lambda *= 10.;
+            if lambda > 1e16 {
+                success = Success::Ftol;
             }
-            self.fjac[jj] += 1.;
-            // apply the transformation to the remaining columns
-            // and update the norms.
-            let jp1 = j + 1;
-            if jp1 < self.nfree {
-                for k in jp1..self.nfree {
-                    let mut sum = 0.;
-                    let mut ij = j + self.m * k;
-                    let mut jj = j + self.m * j;
-                    for _ in j..self.m {
-                        sum += self.fjac[jj] * self.fjac[ij];
-                        ij += 1;
-                        jj += 1;
-                    }
-                    let temp = sum / self.fjac[j + self.m * j];
-                    ij = j + self.m * k;
-                    jj = j + self.m * j;
-                    for _ in j..self.m {
-                        self.fjac[ij] -= temp * self.fjac[jj];
-                        ij += 1;
-                        jj += 1;
-                    }
-                    if self.wa1[k] != 0. {
-                        let temp = self.fjac[j + self.m * k] / self.wa1[k];
-                        let temp = (1. - temp.powi(2)).max(0.);
-                        self.wa1[k] *= temp.sqrt();
-                        let temp = self.wa1[k] / self.wa3[k];
-                        if 0.05 * temp * temp < f64::EPSILON {
-                            let start = jp1 + self.m * k;
-                            self.wa1[k] = self.fjac[start..start + self.m - j - 1].enorm();
-                            self.wa3[k] = self.wa1[k];
-                        }
-                    }
-                }
+            continue;
+        }
+        let chi = chi2 - trial_chi2 <= config.ftol * chi2;
+        let par = ifree
+            .iter()
+            .all(|&i| (trial[i] - x[i]).abs() <= config.xtol * (x[i].abs() + config.xtol));
+        success = match (chi, par) {
+            (true, true) => Success::Both,
+            (true, false) => Success::Chi,
+            (false, true) => Success::Par,
+            (false, false) => Success::NotDone,
+        };
+        x = trial;
+        chi2 = stream.normal(&x)?;
+        lambda = (lambda / 10.).max(1e-12);
+    }
+    /* Covariance of the free parameters from the normal equations */
+    let mut covar = zeros(N * N)?;
+    let eq = stream.equations();
+    let mut insensitive = [false; N];
+    for (j, &i) in ifree.iter().enumerate() {
+        insensitive[i] = eq.jtj[j * nfree + j] == 0.;
+    }
+    a.copy_from_slice(&eq.jtj);
+    let start = Tick::now();
+    let inverted = invert(&mut a, nfree);
+    eq.timing.linear_algebra += start.elapsed();
+    if inverted {
+        for (j, &i) in ifree.iter().enumerate() {
+            for (k, &l) in ifree.iter().enumerate() {
+                covar[i * N + l] = a[j * nfree + k];
             }
-            self.wa1[j] = -ajnorm;
         }
     }
+    let zero_covariance = nfree > 0 && covar.iter().all(|&c| c == 0.);
+    let negative_variance = clamp_variances(&mut covar, N);
+    let mut xerror = [0.; N];
+    for (i, e) in xerror.iter_mut().enumerate() {
+        *e = covar[i * N + i].sqrt();
+    }
+    let pegged = pegged(&x, params_config);
+    *params = x;
+    Ok(Status {
+        success,
+        best_norm: chi2,
+        orig_norm,
+        n_iter,
+        n_fev: eq.n_fev,
+        n_free: nfree,
+        n_pegged: pegged.iter().filter(|&&p| p).count(),
+        pegged,
+        insensitive,
+        n_func,
+        zero_dof: n_func == nfree,
+        rank: gram_rank(&eq.jtj, nfree),
+        resid: Box::new([]),
+        params: x,
+        xerror,
+        covar: covar.into_boxed_slice(),
+        negative_variance,
+        zero_covariance,
+        final_par: lambda,
+        jacobian: None,
+        timing: eq.timing,
+    })
+}
 
-    fn parse_params(&mut self, params: [ParamConfig; N]) -> Result<()> {
-        for (i, p) in params.iter().enumerate() {
-            if p.fixed {
-                match p.limits {
-                    Limits::Lower(lower) if lower > self.xall[i] => return Err(Error::Bounds),
-                    Limits::Upper(upper) if upper < self.xall[i] => return Err(Error::Bounds),
-                    Limits::Both(lower, upper)
-                        if lower > self.xall[i] || upper < self.xall[i] || lower > upper =>
-                    {
-                        return Err(Error::Bounds)
-                    }
-                    _ => {}
-                }
-            } else {
-                let (limited_low, limit_low, limited_up, limit_up) = match p.limits {
-                    Limits::None => (false, f64::NEG_INFINITY, false, f64::INFINITY),
-                    Limits::Lower(lower) => (true, lower, false, f64::INFINITY),
-                    Limits::Upper(upper) => (false, f64::NEG_INFINITY, true, upper),
-                    Limits::Both(lower, upper) if lower <= upper => (true, lower, true, upper),
-                    _ => return Err(Error::Bounds),
-                };
+/// Chunk-wise evaluation state of [`fit_streaming`]
+struct Stream<'a, const N: usize, P> {
+    problem: &'a P,
+    ifree: &'a [usize],
+    params_config: &'a [ParamConfig; N],
+    eps: f64,
+    margin: f64,
+    chunk: usize,
+    /// Residuals of the current chunk
+    base: Vec<f64>,
+    /// Residuals of the current chunk for every probe, chunk x nfree
+    probes: Vec<f64>,
+    eq: Equations,
+}
 
-                self.nfree += 1;
-                self.ifree.push(i);
-                self.qllim.push(limited_low);
-                self.qulim.push(limited_up);
-                self.llim.push(limit_low);
-                self.ulim.push(limit_up);
-                if limited_low || limited_up {
-                    self.qanylim = true;
-                }
+/// Evaluation state of [`fit_sparse`]
+struct Sparse<'a, const N: usize, P> {
+    problem: &'a P,
+    ifree: &'a [usize],
+    params_config: &'a [ParamConfig; N],
+    eps: f64,
+    margin: f64,
+    /// Residuals depending on every free parameter
+    support: Vec<::std::ops::Range<usize>>,
+    /// Start of the column of every free parameter in `columns`
+    offsets: Vec<usize>,
+    /// Supported parts of the Jacobian columns one after another
+    columns: Vec<f64>,
+    /// Residuals at the point of the normal equations
+    resid: Vec<f64>,
+    /// Residuals of the trial points
+    work: Vec<f64>,
+    eq: Equations,
+}
+
+impl<const N: usize, E, P: Problem<N, E>> Normal<N, E> for Sparse<'_, N, P> {
+    fn chi2(&mut self, x: &[f64; N]) -> Result<f64, E> {
+        let start = Tick::now();
+        self.problem.eval(x, &mut self.work)?;
+        self.eq.timing.eval += start.elapsed();
+        self.eq.n_fev += 1;
+        Ok(self.work.iter().map(|r| r * r).sum())
+    }
+
+    fn normal(&mut self, x: &[f64; N]) -> Result<f64, E> {
+        let (start, eval) = (Tick::now(), self.eq.timing.eval);
+        let tick = Tick::now();
+        self.problem.eval(x, &mut self.resid)?;
+        self.eq.timing.eval += tick.elapsed();
+        for (j, &i) in self.ifree.iter().enumerate() {
+            let range = self.support[j].clone();
+            let h = normal_step(&self.params_config[i], x[i], self.eps, self.margin);
+            let mut probe = *x;
+            probe[i] += h;
+            let column = &mut self.columns[self.offsets[j]..self.offsets[j + 1]];
+            let tick = Tick::now();
+            self.problem.eval_chunk(&probe, range.start, column)?;
+            self.eq.timing.eval += tick.elapsed();
+            for (d, r) in column.iter_mut().zip(&self.resid[range]) {
+                *d = (*d - r) / h;
             }
-            self.step.push(0.0);
-            self.dstep.push(p.rel_step);
         }
-        if self.nfree == 0 {
-            return Err(Error::NoFree);
+        let nfree = self.ifree.len();
+        let (columns, offsets) = (&self.columns, &self.offsets);
+        let column = |j: usize| &columns[offsets[j]..offsets[j + 1]];
+        self.eq.jtj.fill(0.);
+        for j in 0..nfree {
+            let (sj, dj) = (&self.support[j], column(j));
+            self.eq.jtr[j] = dj
+                .iter()
+                .zip(&self.resid[sj.clone()])
+                .map(|(d, r)| d * r)
+                .sum();
+            for k in 0..=j {
+                let sk = &self.support[k];
+                let (lo, hi) = (sj.start.max(sk.start), sj.end.min(sk.end));
+                if lo >= hi {
+                    continue;
+                }
+                let dk = &column(k)[lo - sk.start..hi - sk.start];
+                let sum = dj[lo - sj.start..hi - sj.start]
+                    .iter()
+                    .zip(dk)
+                    .map(|(a, b)| a * b)
+                    .sum::<f64>();
+                self.eq.jtj[j * nfree + k] = sum;
+                self.eq.jtj[k * nfree + j] = sum;
+            }
         }
-        if self.m < self.nfree {
-            return Err(Error::DoF);
+        self.eq.n_fev += 1 + nfree;
+        self.eq.timing.jacobian += start.elapsed().saturating_sub(self.eq.timing.eval - eval);
+        Ok(self.resid.iter().map(|r| r * r).sum())
+    }
+
+    fn equations(&mut self) -> &mut Equations {
+        &mut self.eq
+    }
+}
+
+/// Normal equations `J^T J` (nfree x nfree) and `J^T r` of the free
+/// parameters with the evaluation statistics
+struct Equations {
+    jtj: Vec<f64>,
+    jtr: Vec<f64>,
+    n_fev: usize,
+    timing: Timing,
+}
+
+impl Equations {
+    fn new<E>(nfree: usize) -> Result<Self, E> {
+        Ok(Equations {
+            jtj: zeros(nfree * nfree)?,
+            jtr: zeros(nfree)?,
+            n_fev: 0,
+            timing: Timing::default(),
+        })
+    }
+}
+
+/// Assembly of the normal equations for [`solve_normal`]
+trait Normal<const N: usize, E> {
+    /// chi^2 at `x`
+    fn chi2(&mut self, x: &[f64; N]) -> Result<f64, E>;
+
+    /// Accumulates the normal equations at `x` by forward differences,
+    /// returns chi^2 at `x`.
+    fn normal(&mut self, x: &[f64; N]) -> Result<f64, E>;
+
+    fn equations(&mut self) -> &mut Equations;
+}
+
+/// Forward difference step of the free parameter `x` with the configuration
+/// `p` for the normal equations, `eps` is the default relative step and
+/// `margin` the [`Config::limit_margin`].
+fn normal_step(p: &ParamConfig, x: f64, eps: f64, margin: f64) -> f64 {
+    let mut h = if p.step_scale > 0. {
+        p.step_scale
+    } else if p.rel_step > 0. {
+        (p.rel_step * x).abs()
+    } else {
+        eps * x.abs()
+    };
+    if h == 0. {
+        h = eps;
+    }
+    let (lower, upper) = match p.limits {
+        Limits::None => (f64::NEG_INFINITY, f64::INFINITY),
+        Limits::Lower(lower) => (lower, f64::INFINITY),
+        Limits::Upper(upper) => (f64::NEG_INFINITY, upper),
+        Limits::Both(lower, upper) => (lower, upper),
+    };
+    limit_step(x, h, lower, upper, margin)
+}
+
+/// Turns the positive step `h` at `x` into one whose probe `x + h` is inside
+/// `lower..=upper` shrunk by `margin * h` (at most to the midpoint), exactly
+/// also after round-off. The step goes backwards if the forward probe would
+/// leave the limits, and to the wider side, shortened, if both would.
+fn limit_step(x: f64, h: f64, lower: f64, upper: f64, margin: f64) -> f64 {
+    let shrink = (margin * h).min(0.5 * (upper - lower));
+    let (lower, upper) = (lower + shrink, upper - shrink);
+    let mut h = h;
+    if x > upper - h {
+        h = -h;
+    }
+    if (lower..=upper).contains(&(x + h)) {
+        return h;
+    }
+    if x + h < lower {
+        h = if upper - x >= x - lower {
+            upper - x
+        } else {
+            lower - x
+        };
+    }
+    // Round-off of the probe is at most a few ulps
+    let mut probe = (x + h).max(lower).min(upper);
+    for _ in 0..8 {
+        let h = probe - x;
+        if x + h > upper {
+            probe = probe.next_down();
+        } else if x + h < lower {
+            probe = probe.next_up();
+        } else {
+            return h;
         }
-        Ok(())
     }
+    probe - x
+}
 
-    // Initialize Levenberg-Marquardt parameter and iteration counter
-    fn init_lm(&mut self) -> Result<()> {
-        self.f.eval(self.xall, &mut self.fvec)?;
-        self.nfev += 1;
-        self.fnorm = self.fvec.enorm();
-        self.orig_norm = self.fnorm * self.fnorm;
-        self.xnew.copy_from_slice(self.xall);
-        self.x = Vec::with_capacity(self.nfree);
-        for i in 0..self.nfree {
-            self.x.push(self.xall[self.ifree[i]]);
+impl<const N: usize, E, P: Problem<N, E>> Normal<N, E> for Stream<'_, N, P> {
+    fn chi2(&mut self, x: &[f64; N]) -> Result<f64, E> {
+        let m = self.problem.number_of_points();
+        let mut chi2 = 0.;
+        for offset in (0..m).step_by(self.chunk) {
+            let base = &mut self.base[..self.chunk.min(m - offset)];
+            let start = Tick::now();
+            self.problem.eval_chunk(x, offset, base)?;
+            self.eq.timing.eval += start.elapsed();
+            chi2 += base.iter().map(|r| r * r).sum::<f64>();
         }
-        self.qtf = vec![0.; self.nfree];
-        self.fjac = vec![0.; self.m * self.nfree];
-        Ok(())
+        self.eq.n_fev += 1;
+        Ok(chi2)
     }
 
-    fn check_limits(&mut self) {
-        if !self.qanylim {
-            return;
+    fn normal(&mut self, x: &[f64; N]) -> Result<f64, E> {
+        let (start, eval) = (Tick::now(), self.eq.timing.eval);
+        let m = self.problem.number_of_points();
+        let nfree = self.ifree.len();
+        let mut h = [0.; N];
+        let mut probes = [*x; N];
+        for (j, &i) in self.ifree.iter().enumerate() {
+            h[j] = normal_step(&self.params_config[i], x[i], self.eps, self.margin);
+            probes[j][i] += h[j];
         }
-        for j in 0..self.nfree {
-            let lpegged = j < self.qllim.len() && self.x[j] == self.llim[j];
-            let upegged = j < self.qulim.len() && self.x[j] == self.ulim[j];
-            let mut sum = 0.;
-            // If the parameter is pegged at a limit, compute the gradient direction
-            let ij = j * self.m;
-            if lpegged || upegged {
-                for i in 0..self.m {
-                    sum += self.fvec[i] * self.fjac[ij + i];
-                }
-            }
-            // If pegged at lower limit and gradient is toward negative then
-            // reset gradient to zero
-            if lpegged && sum > 0. {
-                for i in 0..self.m {
-                    self.fjac[ij + i] = 0.;
+        self.eq.jtj.fill(0.);
+        self.eq.jtr.fill(0.);
+        let mut chi2 = 0.;
+        for offset in (0..m).step_by(self.chunk) {
+            let len = self.chunk.min(m - offset);
+            let base = &mut self.base[..len];
+            let tick = Tick::now();
+            self.problem.eval_chunk(x, offset, base)?;
+            self.eq.timing.eval += tick.elapsed();
+            chi2 += base.iter().map(|r| r * r).sum::<f64>();
+            for j in 0..nfree {
+                let probe = &mut self.probes[j * self.chunk..j * self.chunk + len];
+                let tick = Tick::now();
+                self.problem.eval_chunk(&probes[j], offset, probe)?;
+                self.eq.timing.eval += tick.elapsed();
+                for (d, r) in probe.iter_mut().zip(base.iter()) {
+                    *d = (*d - r) / h[j];
                 }
             }
-            // If pegged at upper limit and gradient is toward positive then
-            // reset gradient to zero
-            if upegged && sum < 0. {
-                for i in 0..self.m {
-                    self.fjac[ij + i] = 0.;
+            for j in 0..nfree {
+                let dj = &self.probes[j * self.chunk..j * self.chunk + len];
+                self.eq.jtr[j] += dj.iter().zip(base.iter()).map(|(d, r)| d * r).sum::<f64>();
+                for k in 0..=j {
+                    let dk = &self.probes[k * self.chunk..k * self.chunk + len];
+                    let sum = dj.iter().zip(dk).map(|(a, b)| a * b).sum::<f64>();
+                    self.eq.jtj[j * nfree + k] += sum;
+                    if k != j {
+                        self.eq.jtj[k * nfree + j] += sum;
+                    }
                 }
             }
         }
+        self.eq.n_fev += 1 + nfree;
+        self.eq.timing.jacobian += start.elapsed().saturating_sub(self.eq.timing.eval - eval);
+        Ok(chi2)
     }
 
-    /// On the first iteration and if user_scale is requested, scale according
-    /// to the norms of the columns of the initial jacobian,
-    /// calculate the norm of the scaled x, and initialize the step bound delta.
-    fn scale(&mut self) {
-        if self.iter != 1 {
-            return;
-        }
-        if !self.cfg.do_user_scale {
-            for j in 0..self.nfree {
-                self.diag[self.ifree[j]] = if self.wa2[j] == 0. { 1. } else { self.wa2[j] };
-            }
-        }
-        for j in 0..self.nfree {
-            self.wa3[j] = self.diag[self.ifree[j]] * self.x[j];
-        }
-        self.xnorm = self.wa3.enorm();
-        self.delta = self.cfg.step_factor * self.xnorm;
-        if self.delta == 0. {
-            self.delta = self.cfg.step_factor;
-        }
+    fn equations(&mut self) -> &mut Equations {
+        &mut self.eq
     }
+}
 
-    fn fill_xnew(&mut self) {
-        for i in 0..self.nfree {
-            self.xnew[self.ifree[i]] = self.x[i];
-        }
+/// Evaluates chi^2 of `problem` at `params` without fitting, i.e. the
+/// squared overflow-safe Euclidean norm of the residuals.
+pub fn chi_square<const N: usize, E, P>(problem: &P, params: &[f64; N]) -> Result<f64, E>
+where
+    P: Problem<N, E>,
+{
+    let m = problem.number_of_points();
+    if m == 0 {
+        return Err(Error::Empty);
     }
+    let mut deviates = zeros(m)?;
+    problem.eval(params, &mut deviates)?;
+    let norm = deviates.enorm();
+    Ok(norm * norm)
+}
 
-    /// form (q transpose)*fvec and store the first n components in qtf.
-    fn transpose(&mut self) {
-        self.wa4.copy_from_slice(&self.fvec);
-        let mut jj = 0;
-        for j in 0..self.nfree {
-            let temp = self.fjac[jj];
-            if temp != 0. {
-                let mut sum = 0.0;
-                let mut ij = jj;
-                for i in j..self.m {
-                    sum += self.fjac[ij] * self.wa4[i];
-                    ij += 1;
-                }
-                let temp = -sum / temp;
-                ij = jj;
-                for i in j..self.m {
-                    self.wa4[i] += self.fjac[ij] * temp;
-                    ij += 1;
-                }
-            }
-            self.fjac[jj] = self.wa1[j];
-            jj += self.m + 1;
-            self.qtf[j] = self.wa4[j];
+/// Runs the checks [`fit`] performs before the first evaluation of
+/// `problem`, i.e. of `config`, the number of points, the limits and the
+/// degrees of freedom, without evaluating any residuals. Returns the error
+/// [`fit`] would return for the invalid input.
+pub fn validate<const N: usize, E, P>(
+    problem: &P,
+    params: &[f64; N],
+    params_config: [ParamConfig; N],
+    config: Config,
+) -> Result<(), E>
+where
+    P: Problem<N, E>,
+{
+    let mut params = *params;
+    let mut fit = Fit::new(problem, &mut params, &params_config, config)?;
+    fit.check_config()?;
+    fit.parse_params(params_config)
+}
+
+/// Jacobian of the residuals at `params` from the same finite differences
+/// [`fit`] uses, e.g. for a sensitivity analysis after a fit. The result is
+/// the nfunc x npar column-major array like [`Status::jacobian`], with zero
+/// columns for fixed parameters. The steps and sides of `params_config` and
+/// `epsfcn` of `config` are respected.
+pub fn sensitivity<const N: usize, E, P>(
+    problem: &P,
+    params: &[f64; N],
+    params_config: [ParamConfig; N],
+    config: Config,
+) -> Result<Box<[f64]>, E>
+where
+    P: Problem<N, E>,
+{
+    let mut params = *params;
+    let config = Config {
+        return_jacobian: true,
+        freeze_jacobian_after: None,
+        ..config
+    };
+    let mut fit = Fit::new(problem, &mut params, &params_config, config)?;
+    fit.check_config()?;
+    fit.parse_params(params_config)?;
+    fit.init_lm()?;
+    fit.fill_xnew();
+    fit.reweight()?;
+    fit.fdjac2()?;
+    Ok(fit.jacobian.take().unwrap_or_default().into_boxed_slice())
+}
+
+/// Profile likelihood confidence intervals.
+///
+/// Every free parameter is fixed at offsets from its best-fit value while the
+/// remaining parameters are refined, until chi^2 rises by `delta_chi2` above
+/// its minimum (1.0 corresponds to 1-sigma). Unlike the symmetric
+/// [`Status::xerror`] the resulting `(lower, upper)` bounds can be asymmetric,
+/// and they are cut at the parameter limits. Fixed parameters yield
+/// `(value, value)`.
+/// # Arguments
+/// * `problem` - A user defind struct implementing the [`Problem`] trait
+/// * `params` - An array with the best-fit parameters
+/// * `params_config` - An array with parameter configurations
+/// * `config` - [`Config`] to configure the fits
+/// * `delta_chi2` - Increase of chi^2 defining the interval
+pub fn profile<const N: usize, E, P>(
+    problem: &P,
+    params: &[f64; N],
+    params_config: [ParamConfig; N],
+    config: Config,
+    delta_chi2: f64,
+) -> Result<[(f64, f64); N], E>
+where
+    P: Problem<N, E>,
+{
+    if delta_chi2 <= 0. {
+        return Err(Error::Input);
+    }
+    let mut best = *params;
+    let status = fit(problem, &mut best, params_config, config)?;
+    let target = status.best_norm + delta_chi2;
+    let mut bounds = [(0., 0.); N];
+    for i in 0..N {
+        let value = best[i];
+        if params_config[i].fixed {
+            bounds[i] = (value, value);
+            continue;
+        }
+        let mut pars = params_config;
+        pars[i].fixed = true;
+        let chi2 = |v: f64| -> Result<f64, E> {
+            let mut p = best;
+            p[i] = v;
+            fit(problem, &mut p, pars, config).map(|status| status.best_norm)
+        };
+        let mut scale = status.xerror[i];
+        if scale == 0. {
+            scale = if value == 0. { 1. } else { 0.1 * value.abs() };
         }
+        let (lower, upper) = match params_config[i].limits {
+            Limits::None => (f64::NEG_INFINITY, f64::INFINITY),
+            Limits::Lower(lower) => (lower, f64::INFINITY),
+            Limits::Upper(upper) => (f64::NEG_INFINITY, upper),
+            Limits::Both(lower, upper) => (lower, upper),
+        };
+        let lo = profile_bound(&chi2, value, -scale, lower, target)?;
+        let hi = profile_bound(&chi2, value, scale, upper, target)?;
+        bounds[i] = (lo, hi);
     }
+    Ok(bounds)
+}
 
-    /// Check for overflow. This should be a cheap test here since FJAC has been
-    /// reduced to a (small) square matrix, and the test is O(N^2).
-    fn check_is_finite(&self) -> bool {
-        if !self.cfg.finite_check {
-            for val in &self.fjac {
-                if !val.is_finite() {
-                    return false;
-                }
-            }
+/// Walks from `value` in direction of `step` until chi^2 exceeds `target` or
+/// `limit` is reached and bisects the crossing point.
+fn profile_bound<E, F>(chi2: &F, value: f64, step: f64, limit: f64, target: f64) -> Result<f64, E>
+where
+    F: Fn(f64) -> Result<f64, E>,
+{
+    let clamp = |v: f64| {
+        if step < 0. {
+            v.max(limit)
+        } else {
+            v.min(limit)
+        }
+    };
+    let mut inner = value;
+    let mut outer = clamp(value + step);
+    let mut width = step;
+    loop {
+        if chi2(outer)? >= target {
+            break;
+        }
+        if outer == limit {
+            return Ok(limit);
+        }
+        inner = outer;
+        width *= 2.;
+        outer = clamp(outer + width);
+        if !outer.is_finite() {
+            return Ok(outer);
         }
-        true
     }
-
-    /// compute the norm of the scaled gradient.
-    fn gnorm(&self) -> f64 {
-        let mut gnorm: f64 = 0.;
-        if self.fnorm != 0. {
-            let mut jj = 0;
-            for j in 0..self.nfree {
-                let l = self.ipvt[j];
-                if self.wa2[l] != 0. {
-                    let mut sum = 0.;
-                    let mut ij = jj;
-                    for i in 0..=j {
-                        sum += self.fjac[ij] * (self.qtf[i] / self.fnorm);
-                        ij += 1;
-                    }
-                    gnorm = gnorm.max((sum / self.wa2[l]).abs());
-                }
-                jj += self.m;
-            }
+    for _ in 0..100 {
+        let mid = 0.5 * (inner + outer);
+        if mid == inner || mid == outer {
+            break;
+        }
+        if chi2(mid)? >= target {
+            outer = mid;
+        } else {
+            inner = mid;
         }
-        gnorm
     }
+    Ok(0.5 * (inner + outer))
+}
 
-    fn terminate(mut self, params: &[ParamConfig; N]) -> Result<Status<N>> {
-        for i in 0..self.nfree {
-            self.xall[self.ifree[i]] = self.x[i];
-        }
-        /* Compute number of pegged parameters */
-        let n_pegged = {
-            let mut n_pegged = 0;
-            for (i, p) in params.iter().enumerate() {
-                match p.limits {
-                    Limits::Lower(lower) if lower == self.xall[i] => n_pegged += 1,
-                    Limits::Upper(upper) if upper == self.xall[i] => n_pegged += 1,
-                    Limits::Both(lower, upper)
-                        if lower == self.xall[i] || upper == self.xall[i] =>
-                    {
-                        n_pegged += 1;
-                    }
-                    _ => {}
-                }
-            }
-            n_pegged
-        };
-        /* Compute and return the covariance matrix and/or parameter errors */
-        self = self.covar();
-        let mut covar = vec![0.; N * N];
-        for j in 0..self.nfree {
-            let k = self.ifree[j] * N;
-            let l = j * self.m;
-            for i in 0..self.nfree {
-                covar[k + self.ifree[i]] = self.fjac[l + i]
-            }
+/// Test helper returning the [`Status`] of a cleanly converged fit. Panics
+/// with a descriptive message if the fit failed, reached
+/// [`Success::MaxIter`] or [`Success::MaxRejects`] or stopped with a warning.
+#[cfg(any(test, feature = "test-util"))]
+#[track_caller]
+pub fn expect_success<const N: usize, E>(result: Result<Status<N>, E>) -> Status<N>
+where
+    E: ::std::fmt::Display,
+{
+    match result {
+        Err(err) => panic!("fit failed: {err}"),
+        Ok(status) if status.success == Success::NotDone => {
+            panic!("fit did not finish: {status}")
         }
-        let mut xerror = [0.; N];
-        for j in 0..self.nfree {
-            let cc = self.fjac[j * self.m + j];
-            if cc > 0. {
-                xerror[self.ifree[j]] = cc.sqrt();
-            }
+        Ok(status)
+            if matches!(status.success, Success::MaxIter | Success::MaxRejects)
+                || status.success.is_warning() =>
+        {
+            panic!("fit did not converge ({}): {status}", status.success)
         }
-        let best_norm = self.fnorm.max(self.fnorm1);
-        Ok(Status {
-            success: self.info,
-            best_norm: best_norm * best_norm,
-            orig_norm: self.orig_norm,
-            n_iter: self.iter,
-            n_fev: self.nfev,
-            n_free: self.nfree,
-            n_pegged,
-            n_func: self.m,
-            resid: self.fvec.into_boxed_slice(),
-            xerror,
-            covar: covar.into_boxed_slice(),
-        })
+        Ok(status) => status,
     }
+}
 
-    /// subroutine covar
-    ///
-    /// given an m by n matrix a, the problem is to determine the covariance
-    /// matrix corresponding to a, defined as
-    ///
-    /// inverse(a *a) .
-    ///
-    /// this subroutine completes the solution of the problem if it is provided
-    /// with the necessary information from the qr factorization, with column
-    /// pivoting, of a. that is, if a*p = q*r, where p is a permutation matrix,
-    /// q has orthogonal columns, and r is an upper triangular matrix with
-    /// diagonal elements of nonincreasing magnitude, then covar expects the
-    /// full upper triangle of r and the permutation matrix p. the covariance
-    /// matrix is then computed as
-    ///
-    /// p*inverse(r *r)*p  .
-    ///
-    /// if a is nearly rank deficient, it may be desirable to compute the
-    /// covariance matrix corresponding to the linearly independent columns of
-    /// a. to define the numerical rank of a, covar uses the tolerance tol. if l
-    /// is the largest integer such that
-    ///
-    /// abs(r(l,l)) .gt. tol*abs(r(1,1)) ,
-    ///
-    /// then covar computes the covariance matrix corresponding to the first l
-    /// columns of r. for k greater than l, column and row ipvt(k) of the
-    /// covariance matrix are set to zero.
-    ///
-    /// the subroutine statement is
-    ///
-    /// subroutine covar(n,r,ldr,ipvt,tol,wa)
-    ///
-    /// where
-    ///
-    /// n is a positive integer input variable set to the order of r.
-    ///
-    /// r is an n by n array. on input the full upper triangle must contain the
-    /// full upper triangle of the matrix r. on output r contains the square
-    /// symmetric covariance matrix.
-    ///
-    /// ldr is a positive integer input variable not less than n which specifies
-    /// the leading dimension of the array r.
-    ///
-    /// ipvt is an integer input array of length n which defines the permutation
-    /// matrix p such that a*p = q*r. column j of p is column ipvt(j) of the
-    /// identity matrix.
-    ///
-    /// tol is a nonnegative input variable used to define the numerical rank of
-    /// a in the manner described above.
-    ///
-    /// wa is a work array of length n.
-    fn covar(mut self) -> Self {
-        /*
-         * form the inverse of r in the full upper triangle of r.
-         */
-        let tolr = self.cfg.covtol * self.fjac[0].abs();
-        let mut l: isize = -1;
-        for k in 0..self.nfree {
-            let k0 = k * self.m;
-            let kk = k0 + k;
-            if self.fjac[kk].abs() <= tolr {
-                break;
-            }
-            self.fjac[kk] = 1.0 / self.fjac[kk];
-            for j in 0..k {
-                let kj = k0 + j;
-                let temp = self.fjac[kk] * self.fjac[kj];
-                self.fjac[kj] = 0.;
-                let j0 = j * self.m;
-                for i in 0..=j {
-                    self.fjac[k0 + i] += -temp * self.fjac[j0 + i];
-                }
-            }
-            l = k as isize;
+/// SplitMix64 pseudo random number generator for reproducible perturbations
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        SplitMix64(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed in `[0, 1)`
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Minimal complex number for [`Problem::eval_complex`]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Complex {
+    /// Real part
+    pub re: f64,
+    /// Imaginary part
+    pub im: f64,
+}
+
+impl Complex {
+    /// Complex number `re + i im`
+    pub const fn new(re: f64, im: f64) -> Self {
+        Complex { re, im }
+    }
+
+    /// Exponential function
+    pub fn exp(self) -> Self {
+        let r = self.re.exp();
+        Complex::new(r * self.im.cos(), r * self.im.sin())
+    }
+
+    /// Principal branch of the natural logarithm
+    pub fn ln(self) -> Self {
+        Complex::new(self.re.hypot(self.im).ln(), self.im.atan2(self.re))
+    }
+
+    /// Principal branch of the square root
+    pub fn sqrt(self) -> Self {
+        let r = self.re.hypot(self.im);
+        let re = (0.5 * (r + self.re)).sqrt();
+        let im = (0.5 * (r - self.re)).sqrt();
+        Complex::new(re, if self.im < 0. { -im } else { im })
+    }
+
+    /// Sine
+    pub fn sin(self) -> Self {
+        Complex::new(
+            self.re.sin() * self.im.cosh(),
+            self.re.cos() * self.im.sinh(),
+        )
+    }
+
+    /// Cosine
+    pub fn cos(self) -> Self {
+        Complex::new(
+            self.re.cos() * self.im.cosh(),
+            -self.re.sin() * self.im.sinh(),
+        )
+    }
+
+    /// Integer power
+    pub fn powi(self, n: i32) -> Self {
+        let mut result = Complex::new(1., 0.);
+        for _ in 0..n.unsigned_abs() {
+            result = result * self;
         }
-        /*
-         * Form the full upper triangle of the inverse of (r transpose)*r
-         * in the full upper triangle of r
-         */
-        if l >= 0 {
-            let l = l as usize;
-            for k in 0..=l {
-                let k0 = k * self.m;
-                for j in 0..k {
-                    let temp = self.fjac[k0 + j];
-                    let j0 = j * self.m;
-                    for i in 0..=j {
-                        self.fjac[j0 + i] += temp * self.fjac[k0 + i];
-                    }
-                }
-                let temp = self.fjac[k0 + k];
-                for i in 0..=k {
-                    self.fjac[k0 + i] *= temp;
-                }
-            }
+        if n < 0 {
+            Complex::new(1., 0.) / result
+        } else {
+            result
         }
-        /*
-         * For the full lower triangle of the covariance matrix
-         * in the strict lower triangle or and in wa
-         */
-        for j in 0..self.nfree {
-            let jj = self.ipvt[j];
-            let sing = j as isize > l;
-            let j0 = j * self.m;
-            let jj0 = jj * self.m;
-            for i in 0..=j {
-                let ji = j0 + i;
-                if sing {
-                    self.fjac[ji] = 0.;
-                }
-                let ii = self.ipvt[i];
-                if ii > jj {
-                    self.fjac[jj0 + ii] = self.fjac[ji];
-                }
-                if ii < jj {
-                    self.fjac[ii * self.m + jj] = self.fjac[ji];
-                }
+    }
+}
+
+impl From<f64> for Complex {
+    fn from(re: f64) -> Self {
+        Complex::new(re, 0.)
+    }
+}
+
+impl ::std::ops::Neg for Complex {
+    type Output = Complex;
+    fn neg(self) -> Complex {
+        Complex::new(-self.re, -self.im)
+    }
+}
+
+impl ::std::ops::Add for Complex {
+    type Output = Complex;
+    fn add(self, rhs: Complex) -> Complex {
+        Complex::new(self.re + rhs.re, self.im + rhs.im)
+    }
+}
+
+impl ::std::ops::Sub for Complex {
+    type Output = Complex;
+    fn sub(self, rhs: Complex) -> Complex {
+        Complex::new(self.re - rhs.re, self.im - rhs.im)
+    }
+}
+
+impl ::std::ops::Mul for Complex {
+    type Output = Complex;
+    fn mul(self, rhs: Complex) -> Complex {
+        Complex::new(
+            self.re * rhs.re - self.im * rhs.im,
+            self.re * rhs.im + self.im * rhs.re,
+        )
+    }
+}
+
+impl ::std::ops::Div for Complex {
+    type Output = Complex;
+    fn div(self, rhs: Complex) -> Complex {
+        let d = rhs.re * rhs.re + rhs.im * rhs.im;
+        Complex::new(
+            (self.re * rhs.re + self.im * rhs.im) / d,
+            (self.im * rhs.re - self.re * rhs.im) / d,
+        )
+    }
+}
+
+macro_rules! complex_real_ops {
+    ($($trait:ident, $fn:ident;)*) => {$(
+        impl ::std::ops::$trait<f64> for Complex {
+            type Output = Complex;
+            fn $fn(self, rhs: f64) -> Complex {
+                ::std::ops::$trait::$fn(self, Complex::from(rhs))
             }
-            self.wa2[jj] = self.fjac[j0 + j];
         }
-        /*
-         * Symmetrize the covariance matrix in r
-         */
-        for j in 0..self.nfree {
-            let j0 = j * self.m;
-            for i in 0..j {
-                self.fjac[j0 + i] = self.fjac[i * self.m + j];
+
+        impl ::std::ops::$trait<Complex> for f64 {
+            type Output = Complex;
+            fn $fn(self, rhs: Complex) -> Complex {
+                ::std::ops::$trait::$fn(Complex::from(self), rhs)
             }
-            self.fjac[j0 + j] = self.wa2[j];
         }
-        self
-    }
+    )*};
+}
 
-    fn rescale(&mut self) {
-        if self.cfg.do_user_scale {
-            return;
-        }
-        for j in 0..self.nfree {
-            let i = self.ifree[j];
-            self.diag[i] = self.diag[i].max(self.wa2[j]);
-        }
+complex_real_ops! {
+    Add, add;
+    Sub, sub;
+    Mul, mul;
+    Div, div;
+}
+
+impl ::std::iter::Sum for Complex {
+    fn sum<I: Iterator<Item = Complex>>(iter: I) -> Complex {
+        iter.fold(Complex::default(), |a, b| a + b)
     }
+}
 
-    /// subroutine lmpar
-    ///
-    /// given an m by nfree matrix a, an nfree by nfree nonsingular diagonal
-    /// matrix d, an m-vector b, and a positive number delta, the problem is to
-    /// determine a value for the parameter par such that if wa1 solves the
-    /// system
-    ///
-    /// a*wa1 = b ,   sqrt(par)*d*wa1 = 0 ,
-    ///
-    /// in the least squares sense, and dxnorm is the euclidean norm of d*wa1,
-    /// then either par is zero and
-    ///
-    /// (dxnorm-delta) <= 0.1*delta ,
-    ///
-    /// or par is positive and
-    ///
-    /// abs(dxnorm-delta) <= 0.1*delta .
-    ///
-    /// this subroutine completes the solution of the problem if it is provided
-    /// with the necessary information from the qr factorization, with column
-    /// pivoting, of a. that is, if a*p = q*fjack, where p is a permutation
-    /// matrix, q has orthogonal columns, and fjack is an upper triangular
-    /// matrix with diagonal elements of nonincreasing magnitude, then lmpar
-    /// expects the full upper triangle of fjack, the permutation matrix p, and
-    /// the first nfree components of (q transpose)*b. on output lmpar also
-    /// provides an upper triangular matrix s such that
-    ///
-    ///  t   t           t p *(a *a + par*d*d)*p = s *s .
-    ///
-    /// s is employed within lmpar and may be of separate interest.
-    ///
-    /// only a few iterations are generally needed for convergence of the
-    /// algorithm. if, however, the limit of 10 iterations is reached, then the
-    /// output par will contain the best value obtained so far.
-    ///
-    /// the subroutine statement is
-    ///
-    /// subroutine lmpar(nfree,fjack,m,ipvt,diag,qtf,delta,par,wa1,wa2, wa3,wa4)
-    ///
-    /// where
-    ///
-    /// nfree is a positive integer input variable set to the order of fjack.
-    ///
-    /// fjack is an nfree by nfree array. on input the full upper triangle must
-    /// contain the full upper triangle of the matrix fjack. on output the full
-    /// upper triangle is unaltered, and the strict lower triangle contains the
-    /// strict upper triangle (transposed) of the upper triangular matrix s.
-    ///
-    /// m is a positive integer input variable not less than nfree which
-    /// specifies the leading dimension of the array fjack.
-    ///
-    /// ipvt is an integer input array of length nfree which defines the
-    /// permutation matrix p such that a*p = q*fjack. column j of p is column
-    /// ipvt(j) of the identity matrix.
-    ///
-    /// diag is an input array of length nfree which must contain the diagonal
-    /// elements of the matrix d.
-    ///
-    /// qtf is an input array of length nfree which must contain the first nfree
-    /// elements of the vector (q transpose)*b.
-    ///
-    /// delta is a positive input variable which specifies an upper bound on the
-    /// euclidean norm of d*wa1.
-    ///
-    /// par is a nonnegative variable. on input par contains an initial estimate
-    /// of the levenberg-marquardt parameter. on output par contains the final
-    /// estimate.
-    ///
-    /// wa1 is an output array of length nfree which contains the least squares
-    /// solution of the system a*wa1 = b, sqrt(par)*d*wa1 = 0, for the output
-    /// par.
-    ///
-    /// wa2 is an output array of length nfree which contains the diagonal
-    /// elements of the upper triangular matrix s.
+/// MPFIT error status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error<E = ::std::convert::Infallible> {
+    /// General input parameter error
+    Input,
+    /// User function produced non-finite values
+    Nan,
+    /// No user data points were supplied, i.e. [`Problem::number_of_points`]
+    /// returned 0
+    Empty,
+    /// Initial values inconsistent with constraints
+    InitBounds,
+    /// Initial constraints inconsistent
+    Bounds,
+    /// Not enough degrees of freedom
+    DoF,
+    /// Error during evaluation by user
+    Eval,
+    /// Working arrays could not be allocated
+    Memory,
+    /// The residuals do not depend on the free parameter with this index,
+    /// see [`ZeroColumn::Error`]
+    Insensitive(usize),
+    /// Error returned by the user code
+    User(E),
+}
+
+impl<E: ::std::fmt::Display> ::std::fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Error::Input => "general input parameter error",
+                Error::Nan => "user function produced non-finite values",
+                Error::Empty => "no user data points were supplied (zero points reported)",
+                Error::InitBounds => "initial values inconsistent with constraints",
+                Error::Bounds => "initial constraints inconsistent",
+                Error::DoF => "not enough degrees of freedom",
+                Error::Eval => "error during user evaluation",
+                Error::Memory => "not enough memory for the working arrays",
+                Error::Insensitive(i) => {
+                    return write!(f, "residuals do not depend on parameter {i}");
+                }
+                Error::User(err) => return write!(f, "user error: {err}"),
+            }
+        )
+    }
+}
+impl<E> Error<E> {
+    /// The non-positive error code of CMPFIT. User errors map to the code of
+    /// [`Error::Eval`], insensitive parameters to the code of [`Error::DoF`].
+    pub fn to_info(&self) -> i32 {
+        match self {
+            Error::Input => 0,
+            Error::Nan => -16,
+            Error::Eval | Error::User(_) => -17,
+            Error::Empty => -18,
+            Error::Memory => -20,
+            Error::InitBounds => -21,
+            Error::Bounds => -22,
+            Error::DoF | Error::Insensitive(_) => -24,
+        }
+    }
+}
+
+impl<E> ::std::error::Error for Error<E>
+where
+    E: ::std::error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn ::std::error::Error + 'static)> {
+        match self {
+            Error::User(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Potential success status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Success {
+    /// Not finished iterations
+    NotDone,
+    /// Convergence in chi-square value
+    Chi,
+    /// Convergence in parameter value
+    Par,
+    /// Convergence in both chi-square and parameter
+    Both,
+    /// Convergence in orthogonality
+    Dir,
+    /// Maximum number of iterations reached
+    MaxIter,
+    /// ftol is too small; no further improvement. This is a warning, see
+    /// [`Success::is_warning`].
+    Ftol,
+    /// xtol is too small; no further improvement. This is a warning, see
+    /// [`Success::is_warning`].
+    Xtol,
+    /// gtol is too small; no further improvement. This is a warning, see
+    /// [`Success::is_warning`].
+    Gtol,
+    /// Maximum number of consecutive rejected steps reached, see
+    /// [`Config::max_consecutive_rejects`]
+    MaxRejects,
+}
+
+impl Success {
+    /// Whether the fit stopped because one of the tolerances is too small to
+    /// make further progress ([`Success::Ftol`], [`Success::Xtol`] or
+    /// [`Success::Gtol`]), rather than by meeting a convergence criterion.
+    /// The parameters are usually still fine, but the requested precision was
+    /// not confirmed.
+    pub fn is_warning(&self) -> bool {
+        matches!(self, Success::Ftol | Success::Xtol | Success::Gtol)
+    }
+
+    /// Whether one of the convergence criteria was met, i.e. the fit did not
+    /// stop early on [`Success::MaxIter`], [`Success::MaxRejects`] or a
+    /// warning.
+    pub fn is_converged(&self) -> bool {
+        matches!(
+            self,
+            Success::Chi | Success::Par | Success::Both | Success::Dir
+        )
+    }
+
+    /// The positive `info` code of CMPFIT/MINPACK. [`Success::MaxRejects`]
+    /// has no equivalent and maps to the code of [`Success::MaxIter`],
+    /// [`Success::NotDone`] to 0.
+    pub fn to_info(&self) -> i32 {
+        match self {
+            Success::NotDone => 0,
+            Success::Chi => 1,
+            Success::Par => 2,
+            Success::Both => 3,
+            Success::Dir => 4,
+            Success::MaxIter | Success::MaxRejects => 5,
+            Success::Ftol => 6,
+            Success::Xtol => 7,
+            Success::Gtol => 8,
+        }
+    }
+}
+
+impl ::std::fmt::Display for Success {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Success::NotDone => "unknown error",
+                Success::Chi => "convergence in chi-square value",
+                Success::Par => "convergence in parameter value",
+                Success::Both => "convergence in chi-square and parameter values",
+                Success::Dir => "convergence in orthogonality",
+                Success::MaxIter => "maximum number of iterations reached",
+                Success::Ftol => "ftol is too small; no further improvement",
+                Success::Xtol => "xtol is too small; no further improvement",
+                Success::Gtol => "gtol is too small; no further improvement",
+                Success::MaxRejects => "maximum number of consecutive rejected steps reached",
+            }
+        )
+    }
+}
+
+/// Status structure, for fit when it completes
+#[derive(Debug, Clone)]
+pub struct Status<const N: usize> {
+    /// Success enum
+    pub success: Success,
+    /// Final chi^2 at [`Self::params`], the lowest of all accepted steps
+    pub best_norm: f64,
+    /// Starting value of chi^2
+    pub orig_norm: f64,
+    /// Number of iterations
+    pub n_iter: usize,
+    /// Number of function evaluations, i.e. evaluations of all residuals
+    /// including the initial one and the finite difference probes
+    pub n_fev: usize,
+    /// Number of free parameters
+    pub n_free: usize,
+    /// Number of pegged parameters
+    pub n_pegged: usize,
+    /// Which parameters are pegged at one of their limits npar-vector
+    pub pegged: [bool; N],
+    /// Which free parameters had an all zero Jacobian column in any iteration
+    /// npar-vector, see [`Config::zero_column`]
+    pub insensitive: [bool; N],
+    /// Number of residuals (= num. of data points), without the masked ones
+    /// (see [`Problem::number_of_masked_points`])
+    pub n_func: usize,
+    /// Whether the system is exactly determined, i.e. there are as many data
+    /// points as free parameters. chi^2 then carries no information about the
+    /// goodness of fit and [`Self::reduced_chi2`] is `None`.
+    pub zero_dof: bool,
+    /// Numerical rank of the Jacobian of the free parameters. Columns which
+    /// are (nearly) linear combinations of others do not count, so it is less
+    /// than [`Self::n_free`] if some parameters are not determined by the
+    /// data individually, their uncertainties are then not meaningful.
+    pub rank: usize,
+    /// Final residuals nfunc-vector
+    pub resid: Box<[f64]>,
+    /// Fitted parameters npar-vector
+    pub params: [f64; N],
+    /// Final parameter uncertainties (1-sigma) npar-vector
+    pub xerror: [f64; N],
+    /// Final parameter covariance matrix npar x npar array
+    pub covar: Box<[f64]>,
+    /// Whether negative (or NaN) variances on the diagonal of [`Self::covar`]
+    /// from round-off in a (nearly) rank deficient Jacobian were clamped to
+    /// zero. The corresponding [`Self::xerror`] are zero and not meaningful.
+    pub negative_variance: bool,
+    /// Whether [`Config::covtol`] rejected every column of the Jacobian (or
+    /// the normal equations of [`fit_streaming`] are singular), e.g. because
+    /// the residuals depend on none of the free parameters. [`Self::covar`]
+    /// and [`Self::xerror`] are then all zero, which means undetermined
+    /// rather than tiny uncertainties.
+    pub zero_covariance: bool,
+    /// Final Levenberg-Marquardt parameter. Values close to zero indicate
+    /// Gauss-Newton like steps, large values gradient descent like steps.
+    pub final_par: f64,
+    /// Jacobian of the residuals from the last iteration as nfunc x npar
+    /// column-major array (zero columns for fixed parameters). Only returned
+    /// if requested by [`Config::return_jacobian`].
+    pub jacobian: Option<Box<[f64]>>,
+    /// Where the time of the fit went, only measured with the `timing`
+    /// feature
+    pub timing: Timing,
+}
+
+// Equal fits have equal results, the measured timing is not part of that
+impl<const N: usize> PartialEq for Status<N> {
+    fn eq(&self, other: &Self) -> bool {
+        let Status {
+            success,
+            best_norm,
+            orig_norm,
+            n_iter,
+            n_fev,
+            n_free,
+            n_pegged,
+            pegged,
+            insensitive,
+            n_func,
+            zero_dof,
+            rank,
+            resid,
+            params,
+            xerror,
+            covar,
+            negative_variance,
+            zero_covariance,
+            final_par,
+            jacobian,
+            timing: _,
+        } = self;
+        *success == other.success
+            && *best_norm == other.best_norm
+            && *orig_norm == other.orig_norm
+            && *n_iter == other.n_iter
+            && *n_fev == other.n_fev
+            && *n_free == other.n_free
+            && *n_pegged == other.n_pegged
+            && *pegged == other.pegged
+            && *insensitive == other.insensitive
+            && *n_func == other.n_func
+            && *zero_dof == other.zero_dof
+            && *rank == other.rank
+            && *resid == other.resid
+            && *params == other.params
+            && *xerror == other.xerror
+            && *covar == other.covar
+            && *negative_variance == other.negative_variance
+            && *zero_covariance == other.zero_covariance
+            && *final_par == other.final_par
+            && *jacobian == other.jacobian
+    }
+}
+
+/// Breakdown of the wall-clock time of a fit, to tell whether the model or
+/// the fitting itself is worth optimizing. Only measured with the `timing`
+/// feature, all zero otherwise.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timing {
+    /// Time spent in the evaluation methods of the user [`Problem`]
+    pub eval: ::std::time::Duration,
+    /// Time spent assembling the Jacobian besides the evaluations, i.e. the
+    /// finite differences
+    pub jacobian: ::std::time::Duration,
+    /// Time spent in the linear algebra: QR factorization, the
+    /// Levenberg-Marquardt parameter and the covariance matrix
+    pub linear_algebra: ::std::time::Duration,
+}
+
+/// Start of a measurement for [`Timing`], which is only taken with the
+/// `timing` feature.
+#[derive(Clone, Copy)]
+struct Tick(#[cfg(any(test, feature = "timing"))] ::std::time::Instant);
+
+impl Tick {
+    #[cfg(any(test, feature = "timing"))]
+    fn now() -> Self {
+        Tick(::std::time::Instant::now())
+    }
+
+    #[cfg(not(any(test, feature = "timing")))]
+    fn now() -> Self {
+        Tick()
+    }
+
+    #[cfg(any(test, feature = "timing"))]
+    fn elapsed(self) -> ::std::time::Duration {
+        self.0.elapsed()
+    }
+
+    #[cfg(not(any(test, feature = "timing")))]
+    fn elapsed(self) -> ::std::time::Duration {
+        ::std::time::Duration::ZERO
+    }
+}
+
+impl<const N: usize> Status<N> {
+    /// Probability of a chi^2 at least as large as [`Self::best_norm`] for the
+    /// degrees of freedom of the fit. Values close to zero indicate that the
+    /// model is rejected by the data. `None` if there are no degrees of
+    /// freedom left.
+    pub fn chi2_pvalue(&self) -> Option<f64> {
+        let dof = self.dof().filter(|&dof| dof > 0)?;
+        Some(gamma_q(0.5 * dof as f64, 0.5 * self.best_norm))
+    }
+
+    /// Degrees of freedom, the number of data points minus the number of free
+    /// parameters. `None` if there are more free parameters than points,
+    /// which is only possible if the fit was regularized.
+    pub fn dof(&self) -> Option<usize> {
+        self.n_func.checked_sub(self.n_free)
+    }
+
+    /// Akaike information criterion `2k + n ln(chi^2/n)` for model selection,
+    /// with `k` the number of free parameters and `n` the number of data
+    /// points. It assumes Gaussian errors with a common (unknown) scale, lower
+    /// values indicate the better model for the same data.
+    pub fn aic(&self) -> f64 {
+        let n = self.n_func as f64;
+        2. * self.n_free as f64 + n * (self.best_norm / n).ln()
+    }
+
+    /// Bayesian information criterion `k ln(n) + n ln(chi^2/n)`, with the
+    /// same assumptions as [`Self::aic`] but a stronger penalty for
+    /// additional parameters.
+    pub fn bic(&self) -> f64 {
+        let n = self.n_func as f64;
+        self.n_free as f64 * n.ln() + n * (self.best_norm / n).ln()
+    }
+
+    /// chi^2 per degree of freedom, `None` without degrees of freedom (see
+    /// [`Self::zero_dof`]).
+    pub fn reduced_chi2(&self) -> Option<f64> {
+        let dof = self.dof().filter(|&dof| dof > 0)?;
+        Some(self.best_norm / dof as f64)
+    }
+
+    /// Quick heuristic whether the fit result can be trusted without looking
+    /// at the details: `false` if no convergence criterion was met (see
+    /// [`Success::is_converged`], this includes [`Success::MaxIter`] and the
+    /// tolerance warnings), if any parameter is pegged at a limit or if the
+    /// Jacobian is rank deficient, i.e. some free parameters are not
+    /// determined by the data (see [`Self::rank`],
+    /// [`Self::negative_variance`] and [`Self::zero_covariance`]).
+    pub fn is_reliable(&self) -> bool {
+        self.success.is_converged()
+            && self.n_pegged == 0
+            && self.rank == self.n_free
+            && !self.negative_variance
+            && !self.zero_covariance
+    }
+
+    /// Flat summary of the fit for downstream consumers, with the parameters
+    /// labelled by `names` where given.
+    pub fn report(&self, names: Option<&[&str]>) -> FitReport {
+        let dof = self.dof().unwrap_or(0);
+        let params = (0..N)
+            .map(|i| ParamReport {
+                name: names.and_then(|names| names.get(i)).map(|&n| n.to_owned()),
+                value: self.params[i],
+                error: self.xerror[i],
+                pegged: self.pegged[i],
+            })
+            .collect();
+        FitReport {
+            success: self.success,
+            warning: self.success.is_warning(),
+            params,
+            best_norm: self.best_norm,
+            reduced_chi2: self.reduced_chi2(),
+            dof,
+            n_iter: self.n_iter,
+            n_fev: self.n_fev,
+        }
+    }
+
+    /// Model value and its 1-sigma uncertainty `sqrt(g^T C g)` propagated from
+    /// the parameter covariance [`Self::covar`], e.g. for confidence bands.
+    /// `model` returns the model value and its gradient with respect to the
+    /// parameters at the given (fitted) parameters.
+    pub fn predict_with_errors<F>(&self, model: F) -> (f64, f64)
+    where
+        F: FnOnce(&[f64; N]) -> (f64, [f64; N]),
+    {
+        let (value, grad) = model(&self.params);
+        let mut var = 0.;
+        for i in 0..N {
+            for j in 0..N {
+                var += grad[i] * self.covar[i * N + j] * grad[j];
+            }
+        }
+        (value, var.max(0.).sqrt())
+    }
+}
+
+/// Combines the covariance matrices of two independent fits sharing some
+/// parameters into the K x K covariance of the combined parameter vector.
+///
+/// `first_map` and `second_map` give the index in the combined parameter
+/// vector for every parameter of the respective fit. The information matrices
+/// (inverse covariances) of the free parameters of both fits are summed and
+/// inverted. `None` if an index is out of range or the combined information
+/// matrix is singular, e.g. because a combined parameter is free in neither
+/// fit.
+pub fn combine_covariance<const K: usize, const N: usize, const M: usize>(
+    first: &Status<N>,
+    first_map: [usize; N],
+    second: &Status<M>,
+    second_map: [usize; M],
+) -> Option<Box<[f64]>> {
+    let mut info = vec![0.; K * K];
+    add_information(&mut info, K, &first.covar, N, &first_map)?;
+    add_information(&mut info, K, &second.covar, M, &second_map)?;
+    invert(&mut info, K).then(|| info.into_boxed_slice())
+}
+
+/// Information matrix `J^T J` accumulated over batches of data, e.g. for
+/// sequential fits where the data arrive in batches. The covariance of all
+/// batches follows without refitting the combined data, as long as the
+/// Jacobians of the batches are evaluated at (nearly) the same parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Information<const N: usize> {
+    matrix: Vec<f64>,
+}
+
+impl<const N: usize> Information<N> {
+    /// Empty information matrix without any data
+    pub fn new() -> Self {
+        Information {
+            matrix: vec![0.; N * N],
+        }
+    }
+
+    /// Adds the contribution `J^T J` of the residuals of a batch, with the
+    /// Jacobian `jacobian` as nfunc x npar column-major array like
+    /// [`Status::jacobian`] (see [`Config::return_jacobian`]).
     ///
-    /// wa3 and wa4 are work arrays of length nfree.
-    fn lmpar(&mut self) {
-        /*
-         *     compute and store in wa1 the gauss-newton direction. if the
-         *     jacobian is rank-deficient, obtain a least squares solution.
-         */
-        let mut nsing = self.nfree;
-        let mut jj = 0;
-        for j in 0..self.nfree {
-            self.wa3[j] = self.qtf[j];
-            if self.fjac[jj] == 0. && nsing == self.nfree {
-                nsing = j;
+    /// # Panics
+    /// If the length of `jacobian` is not a multiple of `N`.
+    pub fn add_jacobian(&mut self, jacobian: &[f64]) -> &mut Self {
+        assert_eq!(jacobian.len() % N, 0, "jacobian has to have N columns");
+        let n = jacobian.len() / N;
+        for i in 0..N {
+            let ci = &jacobian[i * n..(i + 1) * n];
+            for j in 0..=i {
+                let cj = &jacobian[j * n..(j + 1) * n];
+                let sum: f64 = ci.iter().zip(cj).map(|(a, b)| a * b).sum();
+                self.matrix[i * N + j] += sum;
+                if i != j {
+                    self.matrix[j * N + i] += sum;
+                }
+            }
+        }
+        self
+    }
+
+    /// The N x N information matrix
+    pub fn matrix(&self) -> &[f64] {
+        &self.matrix
+    }
+
+    /// N x N covariance matrix, the inverse of the information matrix for
+    /// the parameters with information, zero for parameters without any
+    /// (e.g. fixed ones). `None` if the information matrix is singular.
+    pub fn covariance(&self) -> Option<Box<[f64]>> {
+        let free: Vec<usize> = (0..N).filter(|&i| self.matrix[i * N + i] != 0.).collect();
+        let nf = free.len();
+        let mut sub = vec![0.; nf * nf];
+        for (a, &i) in free.iter().enumerate() {
+            for (b, &j) in free.iter().enumerate() {
+                sub[a * nf + b] = self.matrix[i * N + j];
+            }
+        }
+        if !invert(&mut sub, nf) {
+            return None;
+        }
+        let mut covar = vec![0.; N * N];
+        for (a, &i) in free.iter().enumerate() {
+            for (b, &j) in free.iter().enumerate() {
+                covar[i * N + j] = sub[a * nf + b];
+            }
+        }
+        Some(covar.into_boxed_slice())
+    }
+}
+
+impl<const N: usize> ::std::default::Default for Information<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Adds the inverse of the covariance of the free parameters (non-zero
+/// diagonal) in the n x n `covar` to the k x k `info` at the indices `map`.
+fn add_information(
+    info: &mut [f64],
+    k: usize,
+    covar: &[f64],
+    n: usize,
+    map: &[usize],
+) -> Option<()> {
+    if map.iter().any(|&i| i >= k) {
+        return None;
+    }
+    let free: Vec<usize> = (0..n).filter(|&i| covar[i * n + i] != 0.).collect();
+    let nf = free.len();
+    let mut sub = vec![0.; nf * nf];
+    for (a, &i) in free.iter().enumerate() {
+        for (b, &j) in free.iter().enumerate() {
+            sub[a * nf + b] = covar[i * n + j];
+        }
+    }
+    if !invert(&mut sub, nf) {
+        return None;
+    }
+    for (a, &i) in free.iter().enumerate() {
+        for (b, &j) in free.iter().enumerate() {
+            info[map[i] * k + map[j]] += sub[a * nf + b];
+        }
+    }
+    Some(())
+}
+
+/// Inverts the n x n matrix `a` in place by Gauss-Jordan elimination with
+/// partial pivoting, `false` if it is singular.
+fn invert(a: &mut [f64], n: usize) -> bool {
+    invert_with(a, &mut vec![0.; n * n], n)
+}
+
+/// Same as [`invert`], with the n x n workspace `inv`.
+fn invert_with(a: &mut [f64], inv: &mut [f64], n: usize) -> bool {
+    inv.fill(0.);
+    for i in 0..n {
+        inv[i * n + i] = 1.;
+    }
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&i, &j| a[i * n + col].abs().total_cmp(&a[j * n + col].abs()))
+            .unwrap_or(col);
+        if a[pivot * n + col] == 0. {
+            return false;
+        }
+        for j in 0..n {
+            a.swap(col * n + j, pivot * n + j);
+            inv.swap(col * n + j, pivot * n + j);
+        }
+        let d = a[col * n + col];
+        for j in 0..n {
+            a[col * n + j] /= d;
+            inv[col * n + j] /= d;
+        }
+        for i in (0..n).filter(|&i| i != col) {
+            let factor = a[i * n + col];
+            for j in 0..n {
+                a[i * n + j] -= factor * a[col * n + j];
+                inv[i * n + j] -= factor * inv[col * n + j];
+            }
+        }
+    }
+    a.copy_from_slice(inv);
+    true
+}
+
+/// Numerical rank (see [`Status::rank`]) of `J` from its n x n gram matrix
+/// `a = J^T J`, by a Cholesky decomposition with diagonal pivoting of its
+/// correlation form.
+fn gram_rank(a: &[f64], n: usize) -> usize {
+    let mut c = a.to_vec();
+    for i in 0..n {
+        for j in 0..n {
+            let d = (a[i * n + i] * a[j * n + j]).sqrt();
+            c[i * n + j] = if d > 0. { a[i * n + j] / d } else { 0. };
+        }
+    }
+    let mut rest: Vec<usize> = (0..n).collect();
+    for k in 0..n {
+        // the remaining diagonal is the squared sine of the column angles
+        let (p, &pivot) = rest
+            .iter()
+            .enumerate()
+            .max_by(|(_, &i), (_, &j)| c[i * n + i].total_cmp(&c[j * n + j]))
+            .expect("rank is at most n");
+        if c[pivot * n + pivot] <= RANK_TOL * RANK_TOL {
+            return k;
+        }
+        rest.swap_remove(p);
+        for &i in &rest {
+            let factor = c[i * n + pivot] / c[pivot * n + pivot];
+            for &j in &rest {
+                c[i * n + j] -= factor * c[pivot * n + j];
+            }
+        }
+    }
+    n
+}
+
+/// Free parameters sitting exactly on one of their limits. Fixed parameters
+/// are never pegged, they do not take part in the fit.
+fn pegged<const N: usize>(params: &[f64; N], params_config: &[ParamConfig; N]) -> [bool; N] {
+    let mut pegged = [false; N];
+    for (i, p) in params_config.iter().enumerate().filter(|(_, p)| !p.fixed) {
+        pegged[i] = match p.limits {
+            Limits::Lower(lower) => lower == params[i],
+            Limits::Upper(upper) => upper == params[i],
+            Limits::Both(lower, upper) => lower == params[i] || upper == params[i],
+            Limits::None => false,
+        };
+    }
+    pegged
+}
+
+/// Limits of the logarithm of a [`ParamConfig::log_scale`] parameter,
+/// `None` if no positive value is within `limits`.
+fn log_limits(limits: Limits) -> Option<Limits> {
+    let upper = |upper: f64| (upper > 0.).then(|| upper.ln());
+    Some(match limits {
+        Limits::None => Limits::None,
+        Limits::Lower(lower) if lower > 0. => Limits::Lower(lower.ln()),
+        Limits::Lower(_) => Limits::None,
+        Limits::Upper(u) => Limits::Upper(upper(u)?),
+        Limits::Both(lower, u) if lower > 0. => Limits::Both(lower.ln(), upper(u)?),
+        Limits::Both(_, u) => Limits::Upper(upper(u)?),
+    })
+}
+
+/// Machine-readable summary of a fit, see [`Status::report`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct FitReport {
+    /// Success enum
+    pub success: Success,
+    /// Whether `success` is a warning, see [`Success::is_warning`]
+    pub warning: bool,
+    /// Per-parameter results
+    pub params: Vec<ParamReport>,
+    /// Final chi^2
+    pub best_norm: f64,
+    /// Final chi^2 per degree of freedom, `None` without degrees of freedom
+    pub reduced_chi2: Option<f64>,
+    /// Degrees of freedom, number of residuals minus free parameters
+    pub dof: usize,
+    /// Number of iterations
+    pub n_iter: usize,
+    /// Number of function evaluations
+    pub n_fev: usize,
+}
+
+/// Result of a single parameter in a [`FitReport`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamReport {
+    /// Name of the parameter, if given
+    pub name: Option<String>,
+    /// Fitted value
+    pub value: f64,
+    /// 1-sigma uncertainty
+    pub error: f64,
+    /// Whether the parameter is pegged at one of its limits
+    pub pegged: bool,
+}
+
+/// Propagates the n x n covariance `covar` of the internal parameters to the
+/// user parameters, with `chain` the derivatives of the latter.
+fn scale_covar(covar: &mut [f64], chain: &[f64]) {
+    let n = chain.len();
+    for i in 0..n {
+        for j in 0..n {
+            covar[i * n + j] *= chain[i] * chain[j];
+        }
+    }
+}
+
+/// Clamps negative or NaN variances on the diagonal of the n x n covariance
+/// matrix `covar` to zero, returns whether any were clamped.
+fn clamp_variances(covar: &mut [f64], n: usize) -> bool {
+    let mut clamped = false;
+    for i in 0..n {
+        let cc = &mut covar[i * n + i];
+        if *cc < 0. || cc.is_nan() {
+            *cc = 0.;
+            clamped = true;
+        }
+    }
+    clamped
+}
+
+impl<const N: usize> ::std::fmt::Display for Status<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Status")
+            .field("success", &self.success)
+            .field("best_norm", &self.best_norm)
+            .field("orig_norm", &self.orig_norm)
+            .field("n_iter", &self.n_iter)
+            .field("n_fev", &self.n_fev)
+            .field("n_par", &N)
+            .field("n_free", &self.n_free)
+            .field("rank", &self.rank)
+            .field("n_pegged", &self.n_pegged)
+            .field("n_func", &self.n_func)
+            .field("params", &self.params)
+            .field("xerror", &self.xerror)
+            .field("negative_variance", &self.negative_variance)
+            .field("zero_covariance", &self.zero_covariance)
+            .field("final_par", &self.final_par)
+            .finish_non_exhaustive()
+    }
+}
+
+/// MPFIT configuration structure
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    /// Relative chi-square convergence criterion (Default: 1e-10)
+    pub ftol: f64,
+    /// Relative parameter convergence criterion  (Default: 1e-10)
+    pub xtol: f64,
+    /// Orthogonality convergence criterion        (Default: 1e-10)
+    pub gtol: f64,
+    /// Finite derivative step size                (Default: f64::EPSILON)
+    pub epsfcn: f64,
+    /// Initial step bound                         (Default: 100.0)
+    pub step_factor: f64,
+    /// Range tolerance for covariance calculation (Default: 1e-14)
+    pub covtol: f64,
+    /// Maximum number of iterations (Default: 200).  If maxiter == 0,
+    /// then basic error checking is done, and parameter
+    /// errors/covariances are estimated based on input
+    /// parameter values, but no fitting iterations are done.
+    pub max_iter: usize,
+    /// Maximum number of function evaluations, or 0 for no limit. The fit
+    /// stops with [`Success::MaxIter`] before a Jacobian or trial step would
+    /// exceed it, only the initial evaluation and the step tuning of
+    /// [`Self::auto_step`] are always done. (Default: 0 (no limit))
+    pub max_fev: usize,
+    /// Scale variables by user values?
+    /// true = yes, user scale values in diag;
+    /// false = no, variables scaled internally (Default: false)
+    pub do_user_scale: bool,
+    /// Disable check for infinite quantities from user?
+    /// true = perform check;
+    /// false = do not perform check (Default: false)
+    pub finite_check: bool,
+    /// Weight lambda of the Tikhonov regularization, which adds
+    /// `lambda * (p - prior)^2` to chi^2 for every parameter with a
+    /// [`ParamConfig::prior`] (Default: 0.0)
+    pub regularization: f64,
+    /// Use compensated (Kahan-Babuska) summation for the norms of
+    /// residual-sized vectors. Slightly slower, but more accurate for very
+    /// large numbers of data points (Default: false)
+    pub compensated_sum: bool,
+    /// Return the final Jacobian in [`Status::jacobian`] (Default: false)
+    pub return_jacobian: bool,
+    /// Only stop on convergence in both chi-square and parameter values or in
+    /// orthogonality, convergence in only one of chi-square or parameter
+    /// values is not enough (Default: false)
+    pub require_all_criteria: bool,
+    /// Evaluate the residuals in chunks of this size via
+    /// [`Problem::eval_chunk`], or all at once via [`Problem::eval`] if 0
+    /// (Default: 0)
+    pub chunk_size: usize,
+    /// Compute the current covariance matrix for every
+    /// [`Problem::iteration`] call in [`Iteration::covar`], which costs an
+    /// extra matrix inversion per iteration (Default: false)
+    pub iteration_covar: bool,
+    /// Absolute parameter convergence criterion, the fit also converges in
+    /// parameter value if the last step of every free parameter is at most
+    /// this large, or 0 to disable. Unlike the relative `xtol` this also
+    /// works for parameters close to zero (Default: 0.0)
+    pub xtol_abs: f64,
+    /// Reuse the Jacobian of this iteration, counted from 0 at the start
+    /// point, for all following iterations instead of recomputing it, which
+    /// saves the function evaluations of the finite differences for nearly
+    /// linear models. `Some(0)` only computes the Jacobian at the start point,
+    /// `Some(1)` also at the parameters after the first step. The parameter errors are then based on
+    /// the frozen Jacobian as well (Default: None)
+    pub freeze_jacobian_after: Option<usize>,
+    /// Loss function of the residuals (Default: Loss::Squared)
+    pub loss: Loss,
+    /// Minimum ratio of the actual to the predicted reduction of chi^2 for a
+    /// step to be accepted, in `[0, 1)`. Larger values reject steps that only
+    /// barely improve chi^2, e.g. for noisy models (Default: 1e-4)
+    pub min_ratio: f64,
+    /// Use column pivoting in the QR factorization of the Jacobian, which is
+    /// numerically more stable. Without pivoting the factorization keeps the
+    /// natural order of the free parameters (Default: true)
+    pub pivot: bool,
+    /// Stop with [`Success::MaxRejects`] after this many rejected steps in a
+    /// row, or 0 for no limit (Default: 0)
+    pub max_consecutive_rejects: usize,
+    /// Calibrate the finite difference step of every free parameter without
+    /// `rel_step` or `step_scale` once at the start point. Steps from `1e-1`
+    /// to `1e-12` (relative to the parameter, at least absolute) are probed
+    /// and the one with the smallest Richardson estimate of the truncation
+    /// plus round-off error is kept, at the cost of 12 extra evaluations per
+    /// parameter (Default: false)
+    pub auto_step: bool,
+    /// Normalize the data residuals by the magnitude of the model values of
+    /// [`Problem::model`], floored at the given positive value, to fit
+    /// relative instead of absolute errors, e.g. for multiplicative noise.
+    /// The normalization is updated once per iteration and held fixed for the
+    /// Jacobian, which avoids the bias towards larger model values of
+    /// minimizing the relative residuals directly (Default: None)
+    pub relative: Option<f64>,
+    /// Handling of free parameters with an all zero Jacobian column
+    /// (Default: ZeroColumn::Warn)
+    pub zero_column: ZeroColumn,
+    /// Margin by which the finite difference probes stay inside the limits
+    /// of a parameter, as a fraction of the step, for models which are not
+    /// defined exactly at a limit. The probes never leave the limits, and go
+    /// backwards if a forward step would (Default: 0.0)
+    pub limit_margin: f64,
+}
+
+impl ::std::default::Default for Config {
+    fn default() -> Self {
+        Config {
+            ftol: 1e-10,
+            xtol: 1e-10,
+            gtol: 1e-10,
+            epsfcn: f64::EPSILON,
+            step_factor: 100.0,
+            covtol: 1e-14,
+            max_iter: 200,
+            max_fev: 0,
+            do_user_scale: false,
+            finite_check: false,
+            regularization: 0.0,
+            compensated_sum: false,
+            return_jacobian: false,
+            require_all_criteria: false,
+            chunk_size: 0,
+            iteration_covar: false,
+            xtol_abs: 0.0,
+            freeze_jacobian_after: None,
+            loss: Loss::Squared,
+            min_ratio: 1e-4,
+            pivot: true,
+            max_consecutive_rejects: 0,
+            auto_step: false,
+            relative: None,
+            zero_column: ZeroColumn::Warn,
+            limit_margin: 0.0,
+        }
+    }
+}
+
+impl Config {
+    /// Builder starting from the [`Default`] configuration, so only the
+    /// deviating settings have to be given.
+    ///
+    /// ```
+    /// let config = rmpfit::Config::builder().ftol(1e-12).max_iter(50).build();
+    /// assert_eq!(config.max_iter, 50);
+    /// assert_eq!(config.xtol, rmpfit::Config::default().xtol);
+    /// ```
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder(Config::default())
+    }
+}
+
+/// Builder for [`Config`], see [`Config::builder`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConfigBuilder(Config);
+
+macro_rules! config_setters {
+    ($($field:ident: $ty:ty,)*) => {$(
+        #[doc = concat!("Sets [`Config::", stringify!($field), "`]")]
+        pub fn $field(mut self, $field: $ty) -> Self {
+            self.0.$field = $field;
+            self
+        }
+    )*};
+}
+
+impl ConfigBuilder {
+    config_setters! {
+        ftol: f64,
+        xtol: f64,
+        gtol: f64,
+        epsfcn: f64,
+        step_factor: f64,
+        covtol: f64,
+        max_iter: usize,
+        max_fev: usize,
+        do_user_scale: bool,
+        finite_check: bool,
+        regularization: f64,
+        compensated_sum: bool,
+        return_jacobian: bool,
+        require_all_criteria: bool,
+        chunk_size: usize,
+        iteration_covar: bool,
+        xtol_abs: f64,
+        freeze_jacobian_after: Option<usize>,
+        loss: Loss,
+        min_ratio: f64,
+        pivot: bool,
+        max_consecutive_rejects: usize,
+        auto_step: bool,
+        relative: Option<f64>,
+        zero_column: ZeroColumn,
+        limit_margin: f64,
+    }
+
+    /// The configured [`Config`]
+    pub fn build(self) -> Config {
+        self.0
+    }
+}
+
+/// Loss function minimized by the fit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Loss {
+    /// Least squares, minimizes the sum of the squared residuals.
+    Squared,
+    /// Least absolute deviations for heavy-tailed noise, minimizes the sum of
+    /// the absolute residuals by iteratively reweighted least squares. Every
+    /// iteration the residuals are weighted by `1/|r|` of the current
+    /// residuals, with `|r|` limited to at least 1e-6. [`Status::best_norm`]
+    /// then is the sum of the absolute residuals, while [`Status::resid`]
+    /// holds the unweighted residuals.
+    Absolute,
+}
+
+/// Handling of free parameters the residuals do not depend on, i.e. with a
+/// Jacobian column of all zeros
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZeroColumn {
+    /// Continue the fit, the parameter keeps its value and has a zero error.
+    /// The parameter is flagged in [`Status::insensitive`].
+    Warn,
+    /// Stop the fit with [`Error::Insensitive`]
+    Error,
+    /// Fix the parameter at its current value for the remainder of the fit.
+    /// The parameter is flagged in [`Status::insensitive`] and no longer
+    /// counted in [`Status::n_free`].
+    Fix,
+}
+
+/// Parameter constraint structure
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParamConfig {
+    /// A boolean value, whether the parameter is to be held fixed or not. Fixed
+    /// parameters are not varied by MPFIT, but are passed on to ```MPFitter```
+    /// for evaluation.
+    pub fixed: bool,
+    /// bounds constraints of the parameter
+    pub limits: Limits,
+    /// The *relative* step size to be used in calculating the numerical
+    /// derivatives.  This number is the fractional size of the step, compared
+    /// to the parameter value. Ignored if `step_scale` is positive.
+    pub rel_step: f64,
+    /// Prior value the parameter is pulled towards by the Tikhonov
+    /// regularization, see [`Config::regularization`].
+    pub prior: Option<f64>,
+    /// The *absolute* step size to be used in calculating the numerical
+    /// derivatives, e.g. the uncertainty from a previous fit. Takes
+    /// precedence over `rel_step` if positive.
+    pub step_scale: f64,
+    /// How the derivatives of the parameter are computed
+    pub side: Side,
+    /// Maximum absolute change of the parameter in a single step, in addition
+    /// to the trust region of all parameters, or 0 for no limit. Only the
+    /// step of this parameter is shortened, the others are unaffected.
+    pub max_step: f64,
+    /// Optimize the logarithm of the (strictly positive) parameter, which is
+    /// better conditioned than a lower limit at zero for parameters spanning
+    /// orders of magnitude. The start value has to be positive. Step sizes,
+    /// `max_step` and [`Config::xtol_abs`] then apply to the logarithm, while
+    /// the fitted value, its error and covariance are transformed back.
+    pub log_scale: bool,
+}
+
+impl ::std::default::Default for ParamConfig {
+    fn default() -> Self {
+        ParamConfig {
+            fixed: false,
+            limits: Limits::None,
+            rel_step: 0.0,
+            prior: None,
+            step_scale: 0.0,
+            side: Side::Auto,
+            max_step: 0.0,
+            log_scale: false,
+        }
+    }
+}
+
+impl ParamConfig {
+    /// Parameter held fixed at its start value
+    pub fn fixed() -> Self {
+        ParamConfig {
+            fixed: true,
+            ..Default::default()
+        }
+    }
+
+    /// Free parameter limited to `low..=high`
+    pub fn bounded(low: f64, high: f64) -> Self {
+        ParamConfig {
+            limits: Limits::Both(low, high),
+            ..Default::default()
+        }
+    }
+
+    /// Free parameter limited to at least `low`, e.g. a positive amplitude
+    ///
+    /// ```
+    /// use rmpfit::{Limits, ParamConfig};
+    ///
+    /// let amplitude = ParamConfig::lower_bound(0.0);
+    /// assert_eq!(amplitude.limits, Limits::Lower(0.0));
+    /// assert!(!amplitude.fixed);
+    /// ```
+    pub fn lower_bound(low: f64) -> Self {
+        ParamConfig {
+            limits: Limits::Lower(low),
+            ..Default::default()
+        }
+    }
+
+    /// Free parameter limited to at most `high`
+    pub fn upper_bound(high: f64) -> Self {
+        ParamConfig {
+            limits: Limits::Upper(high),
+            ..Default::default()
+        }
+    }
+
+    /// Free parameter with the absolute finite difference step `h`, see
+    /// [`Self::step_scale`]
+    pub fn with_step(h: f64) -> Self {
+        ParamConfig {
+            step_scale: h,
+            ..Default::default()
+        }
+    }
+}
+
+/// Parameter values together with their configurations, e.g. to toggle
+/// which parameters are free between fits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Parameters<const N: usize> {
+    /// Parameter values
+    pub values: [f64; N],
+    /// Parameter configurations
+    pub config: [ParamConfig; N],
+}
+
+impl<const N: usize> Parameters<N> {
+    /// Free parameters with default configuration
+    pub fn new(values: [f64; N]) -> Self {
+        Parameters {
+            values,
+            config: [ParamConfig::default(); N],
+        }
+    }
+
+    /// Holds parameter `i` fixed. Panics if `i` is out of range.
+    pub fn fix(&mut self, i: usize) -> &mut Self {
+        self.config[i].fixed = true;
+        self
+    }
+
+    /// Lets parameter `i` vary. Panics if `i` is out of range.
+    pub fn free(&mut self, i: usize) -> &mut Self {
+        self.config[i].fixed = false;
+        self
+    }
+
+    /// Limits parameter `i` to `lower..=upper`. Panics if `i` is out of
+    /// range.
+    pub fn set_bounds(&mut self, i: usize, lower: f64, upper: f64) -> &mut Self {
+        self.config[i].limits = Limits::Both(lower, upper);
+        self
+    }
+
+    /// Value of parameter `i`, `None` if `i` is out of range.
+    pub fn value(&self, i: usize) -> Option<f64> {
+        self.values.get(i).copied()
+    }
+
+    /// Configuration of parameter `i`, `None` if `i` is out of range.
+    pub fn param_config(&self, i: usize) -> Option<&ParamConfig> {
+        self.config.get(i)
+    }
+
+    /// Fits `problem` starting from the current values, which are overwritten
+    /// with the fitted values, see [`fit`].
+    pub fn solve<E, P>(&mut self, problem: &P, config: Config) -> Result<Status<N>, E>
+    where
+        P: Problem<N, E>,
+    {
+        fit(problem, &mut self.values, self.config, config)
+    }
+}
+
+/// Computation of the numerical derivatives of a parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Side {
+    /// One-sided forward differences, backward differences close to an upper
+    /// limit.
+    Auto,
+    /// One-sided forward differences `(f(x + h) - f(x))/h`, e.g. for models
+    /// which are not defined below the parameter value. Close to an upper
+    /// limit the step is shortened to stay within it instead of going
+    /// backwards as with [`Side::Auto`], only a parameter sitting on its upper
+    /// limit gets backward differences.
+    Right,
+    /// One-sided backward differences `(f(x) - f(x - h))/h`, e.g. for models
+    /// which are not defined above the parameter value. Forward ones only if
+    /// the backward probe would leave the limits.
+    Left,
+    /// Analytical derivatives from [`Problem::jacobian`], which is called once
+    /// per Jacobian for all such parameters and does not count as function
+    /// evaluation.
+    User,
+    /// Complex-step differentiation `Im(f(x + ih))/h` via
+    /// [`Problem::eval_complex`], which is accurate to machine precision since
+    /// there is no subtractive cancellation.
+    Complex,
+    /// Two-sided central differences `(f(x + h) - f(x - h))/2h`, which are
+    /// more accurate for curved models at the cost of two evaluations. Falls
+    /// back to [`Side::Auto`] if one of the probes would leave the limits.
+    Both,
+}
+
+/// Specifies the bounds constrains for a fitting parameter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limits {
+    /// The parameter is not limited on either side.
+    None,
+    /// The parameter is limited at the lower side.
+    Lower(f64),
+    /// The parameter is limited at the upper side.
+    Upper(f64),
+    /// The parameter is limits at the both sides. The lower limit has to lie
+    /// below the upper one unless the parameter is fixed.
+    Both(f64, f64),
+}
+
+impl Limits {
+    /// Whether `value` lies within the limits (inclusive).
+    pub fn contains(&self, value: f64) -> bool {
+        match *self {
+            Limits::None => true,
+            Limits::Lower(lower) => lower <= value,
+            Limits::Upper(upper) => value <= upper,
+            Limits::Both(lower, upper) => lower <= value && value <= upper,
+        }
+    }
+
+    /// Moves `value` to the closest limit if it lies outside.
+    pub fn clamp(&self, value: f64) -> f64 {
+        match *self {
+            Limits::None => value,
+            Limits::Lower(lower) => value.max(lower),
+            Limits::Upper(upper) => value.min(upper),
+            Limits::Both(lower, upper) => value.max(lower).min(upper),
+        }
+    }
+}
+
+/// Whether all `params` lie within the limits of their `params_config`.
+pub fn is_feasible<const N: usize>(params: &[f64; N], params_config: &[ParamConfig; N]) -> bool {
+    params
+        .iter()
+        .zip(params_config)
+        .all(|(&x, p)| p.limits.contains(x))
+}
+
+/// Moves all `params` outside of the limits of their `params_config` to the
+/// closest limit.
+pub fn clamp_to_bounds<const N: usize>(params: &mut [f64; N], params_config: &[ParamConfig; N]) {
+    for (x, p) in params.iter_mut().zip(params_config) {
+        *x = p.limits.clamp(*x);
+    }
+}
+
+/// Adapter for data with correlated errors (generalized least squares).
+///
+/// The wrapped problem has to return the unweighted residuals `r = y - f(x)`,
+/// which are transformed to `L^T r`, where `L` is the lower triangular
+/// Cholesky factor of the data weight matrix `W = L L^T` (the inverse of the
+/// data covariance matrix). chi^2 then becomes `r^T W r`.
+#[derive(Debug, Clone, Copy)]
+pub struct Correlated<'a, P> {
+    problem: &'a P,
+    cholesky: &'a [f64],
+}
+
+impl<'a, P> Correlated<'a, P> {
+    /// `cholesky` is the row-major m x m lower triangular factor `L`, entries
+    /// above the diagonal are ignored.
+    pub fn new(problem: &'a P, cholesky: &'a [f64]) -> Self {
+        Correlated { problem, cholesky }
+    }
+
+    /// Transforms `r` to `L^T r` in place.
+    fn transform<T>(&self, r: &mut [T])
+    where
+        T: Copy + ::std::ops::Mul<f64, Output = T> + ::std::iter::Sum,
+    {
+        let m = r.len();
+        // (L^T r)_i only depends on r_k with k >= i, so it can be done in place
+        for i in 0..m {
+            let column = self.cholesky[i * m + i..].iter().step_by(m);
+            r[i] = column.zip(&r[i..]).map(|(l, r)| *r * *l).sum();
+        }
+    }
+}
+
+impl<const N: usize, E, P: Problem<N, E>> Problem<N, E> for Correlated<'_, P> {
+    fn eval(&self, params: &[f64; N], deviates: &mut [f64]) -> Result<(), E> {
+        let m = deviates.len();
+        if self.cholesky.len() != m * m {
+            return Err(Error::Input);
+        }
+        self.problem.eval(params, deviates)?;
+        self.transform(deviates);
+        Ok(())
+    }
+
+    fn number_of_points(&self) -> usize {
+        self.problem.number_of_points()
+    }
+
+    fn number_of_masked_points(&self) -> usize {
+        self.problem.number_of_masked_points()
+    }
+
+    fn is_masked(&self, point: usize) -> bool {
+        self.problem.is_masked(point)
+    }
+
+    fn eval_complex(&self, params: &[Complex; N], deviates: &mut [Complex]) -> Result<(), E> {
+        let m = deviates.len();
+        if self.cholesky.len() != m * m {
+            return Err(Error::Input);
+        }
+        self.problem.eval_complex(params, deviates)?;
+        self.transform(deviates);
+        Ok(())
+    }
+
+    fn jacobian(&self, params: &[f64; N], jacobian: &mut [f64]) -> Result<(), E> {
+        let m = self.problem.number_of_points();
+        if self.cholesky.len() != m * m {
+            return Err(Error::Input);
+        }
+        self.problem.jacobian(params, jacobian)?;
+        for column in jacobian.chunks_exact_mut(m) {
+            self.transform(column);
+        }
+        Ok(())
+    }
+
+    fn eval_with_jacobian(
+        &self,
+        params: &[f64; N],
+        deviates: &mut [f64],
+        jacobian: &mut [f64],
+    ) -> Result<bool, E> {
+        let m = deviates.len();
+        if self.cholesky.len() != m * m {
+            return Err(Error::Input);
+        }
+        if !self
+            .problem
+            .eval_with_jacobian(params, deviates, jacobian)?
+        {
+            return Ok(false);
+        }
+        self.transform(deviates);
+        for column in jacobian.chunks_exact_mut(m) {
+            self.transform(column);
+        }
+        Ok(true)
+    }
+
+    fn eval_batch(&self, params: &[[f64; N]], deviates: &mut [f64]) -> Result<bool, E> {
+        let m = self.problem.number_of_points();
+        if self.cholesky.len() != m * m {
+            return Err(Error::Input);
+        }
+        if !self.problem.eval_batch(params, deviates)? {
+            return Ok(false);
+        }
+        for set in deviates.chunks_exact_mut(m) {
+            self.transform(set);
+        }
+        Ok(true)
+    }
+
+    fn iteration(&self, iteration: &Iteration<N>) {
+        self.problem.iteration(iteration)
+    }
+}
+
+/// Adapter fitting a problem with `N` parameters in a reduced set of `M` free
+/// parameters, for deterministic constraints beyond fixed parameters, like
+/// `x[2] = x[0] * x[1]`.
+///
+/// `map` computes the full parameters of the wrapped problem from the reduced
+/// ones before each evaluation, finite differences are taken in the reduced
+/// space. [`Problem::iteration`] is not forwarded, as the reported parameters
+/// and covariance are the reduced ones; the full parameters of the result are
+/// given by [`Self::full`].
+#[derive(Debug, Clone, Copy)]
+pub struct Reparameterized<'a, P, F, const N: usize> {
+    problem: &'a P,
+    map: F,
+}
+
+impl<'a, P, F, const N: usize> Reparameterized<'a, P, F, N> {
+    /// Wraps `problem` with the mapping `map` from the reduced to the full
+    /// parameters.
+    pub fn new<const M: usize>(problem: &'a P, map: F) -> Self
+    where
+        F: Fn(&[f64; M]) -> [f64; N],
+    {
+        Reparameterized { problem, map }
+    }
+
+    /// Full parameters of the wrapped problem for the reduced `params`.
+    pub fn full<const M: usize>(&self, params: &[f64; M]) -> [f64; N]
+    where
+        F: Fn(&[f64; M]) -> [f64; N],
+    {
+        (self.map)(params)
+    }
+}
+
+impl<const M: usize, const N: usize, E, P, F> Problem<M, E> for Reparameterized<'_, P, F, N>
+where
+    P: Problem<N, E>,
+    F: Fn(&[f64; M]) -> [f64; N],
+{
+    fn eval(&self, params: &[f64; M], deviates: &mut [f64]) -> Result<(), E> {
+        self.problem.eval(&(self.map)(params), deviates)
+    }
+
+    fn number_of_points(&self) -> usize {
+        self.problem.number_of_points()
+    }
+
+    fn number_of_masked_points(&self) -> usize {
+        self.problem.number_of_masked_points()
+    }
+
+    fn is_masked(&self, point: usize) -> bool {
+        self.problem.is_masked(point)
+    }
+
+    fn eval_chunk(&self, params: &[f64; M], offset: usize, deviates: &mut [f64]) -> Result<(), E> {
+        self.problem
+            .eval_chunk(&(self.map)(params), offset, deviates)
+    }
+
+    fn model(&self, params: &[f64; M], values: &mut [f64]) -> Result<(), E> {
+        self.problem.model(&(self.map)(params), values)
+    }
+
+    fn eval_batch(&self, params: &[[f64; M]], deviates: &mut [f64]) -> Result<bool, E> {
+        let full = params.iter().map(&self.map).collect::<Vec<_>>();
+        self.problem.eval_batch(&full, deviates)
+    }
+}
+
+/// Adapter for data with independent errors.
+///
+/// The wrapped problem has to return the unweighted residuals `r = y - f(x)`,
+/// which are multiplied by per-point factors `1 / sigma`, so chi^2 becomes
+/// `sum(w r^2)` with `w = 1 / sigma^2`. The factors are computed once on
+/// construction and applied in place, so repeated evaluations (and repeated
+/// fits on the same data) do not allocate.
+#[derive(Debug, Clone)]
+pub struct Weighted<'a, P> {
+    problem: &'a P,
+    scale: ::std::borrow::Cow<'a, [f64]>,
+}
+
+impl<'a, P> Weighted<'a, P> {
+    /// Uses the residual factors `scale` (`1 / sigma`) as they are, without
+    /// copying them.
+    pub fn new(problem: &'a P, scale: &'a [f64]) -> Self {
+        Weighted {
+            problem,
+            scale: ::std::borrow::Cow::Borrowed(scale),
+        }
+    }
+
+    /// Weights the residuals by the data uncertainties `sigma`.
+    pub fn from_sigma(problem: &'a P, sigma: &[f64]) -> Self {
+        Weighted {
+            problem,
+            scale: sigma.iter().map(|s| 1. / s).collect(),
+        }
+    }
+
+    /// Weights the residuals by the statistical weights `weights`
+    /// (`1 / sigma^2`).
+    pub fn from_weights(problem: &'a P, weights: &[f64]) -> Self {
+        Weighted {
+            problem,
+            scale: weights.iter().map(|w| w.sqrt()).collect(),
+        }
+    }
+
+    /// The factors the residuals are multiplied with.
+    pub fn scale(&self) -> &[f64] {
+        &self.scale
+    }
+
+    /// Multiplies `r` by the factors starting at `offset` in place.
+    fn apply<T>(&self, offset: usize, r: &mut [T])
+    where
+        T: Copy + ::std::ops::Mul<f64, Output = T>,
+    {
+        for (r, s) in r.iter_mut().zip(&self.scale[offset..]) {
+            *r = *r * *s;
+        }
+    }
+}
+
+impl<const N: usize, E, P: Problem<N, E>> Problem<N, E> for Weighted<'_, P> {
+    fn eval(&self, params: &[f64; N], deviates: &mut [f64]) -> Result<(), E> {
+        if self.scale.len() != deviates.len() {
+            return Err(Error::Input);
+        }
+        self.problem.eval(params, deviates)?;
+        self.apply(0, deviates);
+        Ok(())
+    }
+
+    fn number_of_points(&self) -> usize {
+        self.problem.number_of_points()
+    }
+
+    fn number_of_masked_points(&self) -> usize {
+        self.problem.number_of_masked_points()
+    }
+
+    fn is_masked(&self, point: usize) -> bool {
+        self.problem.is_masked(point)
+    }
+
+    fn eval_chunk(&self, params: &[f64; N], offset: usize, deviates: &mut [f64]) -> Result<(), E> {
+        if self.scale.len() < offset + deviates.len() {
+            return Err(Error::Input);
+        }
+        self.problem.eval_chunk(params, offset, deviates)?;
+        self.apply(offset, deviates);
+        Ok(())
+    }
+
+    fn model(&self, params: &[f64; N], values: &mut [f64]) -> Result<(), E> {
+        self.problem.model(params, values)
+    }
+
+    fn eval_complex(&self, params: &[Complex; N], deviates: &mut [Complex]) -> Result<(), E> {
+        if self.scale.len() != deviates.len() {
+            return Err(Error::Input);
+        }
+        self.problem.eval_complex(params, deviates)?;
+        self.apply(0, deviates);
+        Ok(())
+    }
+
+    fn jacobian(&self, params: &[f64; N], jacobian: &mut [f64]) -> Result<(), E> {
+        let m = self.problem.number_of_points();
+        if self.scale.len() != m {
+            return Err(Error::Input);
+        }
+        self.problem.jacobian(params, jacobian)?;
+        for column in jacobian.chunks_exact_mut(m) {
+            self.apply(0, column);
+        }
+        Ok(())
+    }
+
+    fn eval_with_jacobian(
+        &self,
+        params: &[f64; N],
+        deviates: &mut [f64],
+        jacobian: &mut [f64],
+    ) -> Result<bool, E> {
+        if self.scale.len() != deviates.len() {
+            return Err(Error::Input);
+        }
+        if !self
+            .problem
+            .eval_with_jacobian(params, deviates, jacobian)?
+        {
+            return Ok(false);
+        }
+        self.apply(0, deviates);
+        for column in jacobian.chunks_exact_mut(deviates.len()) {
+            self.apply(0, column);
+        }
+        Ok(true)
+    }
+
+    fn support(&self, param: usize) -> Option<::std::ops::Range<usize>> {
+        self.problem.support(param)
+    }
+
+    fn eval_batch(&self, params: &[[f64; N]], deviates: &mut [f64]) -> Result<bool, E> {
+        let m = self.problem.number_of_points();
+        if self.scale.len() != m {
+            return Err(Error::Input);
+        }
+        if !self.problem.eval_batch(params, deviates)? {
+            return Ok(false);
+        }
+        for set in deviates.chunks_exact_mut(m) {
+            self.apply(0, set);
+        }
+        Ok(true)
+    }
+
+    fn iteration(&self, iteration: &Iteration<N>) {
+        self.problem.iteration(iteration)
+    }
+}
+
+/// Adapter excluding data points from the fit without removing them from the
+/// data, e.g. flagged samples.
+///
+/// The residuals of the points with `mask[i] == true` (and their rows of the
+/// Jacobian) are set to exactly zero after each evaluation, so they do not
+/// contribute to chi^2 or the derivatives. They are not counted in
+/// [`Status::n_func`], so the degrees of freedom are those of the unmasked
+/// points.
+#[derive(Debug, Clone, Copy)]
+pub struct Masked<'a, P> {
+    problem: &'a P,
+    mask: &'a [bool],
+}
+
+impl<'a, P> Masked<'a, P> {
+    /// `mask` has one entry per data point, `true` excludes the point. With a
+    /// mask of another length every evaluation fails with [`Error::Input`].
+    pub fn new(problem: &'a P, mask: &'a [bool]) -> Self {
+        Masked { problem, mask }
+    }
+
+    /// Zeroes the masked entries of `r` starting at `offset`.
+    fn apply<T: Default>(&self, offset: usize, r: &mut [T]) {
+        for (r, _) in r.iter_mut().zip(&self.mask[offset..]).filter(|(_, &m)| m) {
+            *r = T::default();
+        }
+    }
+}
+
+impl<const N: usize, E, P: Problem<N, E>> Problem<N, E> for Masked<'_, P> {
+    fn eval(&self, params: &[f64; N], deviates: &mut [f64]) -> Result<(), E> {
+        if self.mask.len() != deviates.len() {
+            return Err(Error::Input);
+        }
+        self.problem.eval(params, deviates)?;
+        self.apply(0, deviates);
+        Ok(())
+    }
+
+    fn number_of_points(&self) -> usize {
+        self.problem.number_of_points()
+    }
+
+    fn number_of_masked_points(&self) -> usize {
+        // A mask of the wrong length is rejected by the evaluations
+        if self.mask.len() != self.problem.number_of_points() {
+            return 0;
+        }
+        self.mask.iter().filter(|&&m| m).count()
+    }
+
+    fn is_masked(&self, point: usize) -> bool {
+        self.mask.get(point) == Some(&true)
+    }
+
+    fn eval_chunk(&self, params: &[f64; N], offset: usize, deviates: &mut [f64]) -> Result<(), E> {
+        if self.mask.len() != self.problem.number_of_points() {
+            return Err(Error::Input);
+        }
+        self.problem.eval_chunk(params, offset, deviates)?;
+        self.apply(offset, deviates);
+        Ok(())
+    }
+
+    fn model(&self, params: &[f64; N], values: &mut [f64]) -> Result<(), E> {
+        self.problem.model(params, values)
+    }
+
+    fn eval_complex(&self, params: &[Complex; N], deviates: &mut [Complex]) -> Result<(), E> {
+        if self.mask.len() != deviates.len() {
+            return Err(Error::Input);
+        }
+        self.problem.eval_complex(params, deviates)?;
+        self.apply(0, deviates);
+        Ok(())
+    }
+
+    fn jacobian(&self, params: &[f64; N], jacobian: &mut [f64]) -> Result<(), E> {
+        let m = self.problem.number_of_points();
+        if self.mask.len() != m {
+            return Err(Error::Input);
+        }
+        self.problem.jacobian(params, jacobian)?;
+        for column in jacobian.chunks_exact_mut(m) {
+            self.apply(0, column);
+        }
+        Ok(())
+    }
+
+    fn eval_with_jacobian(
+        &self,
+        params: &[f64; N],
+        deviates: &mut [f64],
+        jacobian: &mut [f64],
+    ) -> Result<bool, E> {
+        if self.mask.len() != deviates.len() {
+            return Err(Error::Input);
+        }
+        if !self
+            .problem
+            .eval_with_jacobian(params, deviates, jacobian)?
+        {
+            return Ok(false);
+        }
+        self.apply(0, deviates);
+        for column in jacobian.chunks_exact_mut(deviates.len()) {
+            self.apply(0, column);
+        }
+        Ok(true)
+    }
+
+    fn support(&self, param: usize) -> Option<::std::ops::Range<usize>> {
+        self.problem.support(param)
+    }
+
+    fn eval_batch(&self, params: &[[f64; N]], deviates: &mut [f64]) -> Result<bool, E> {
+        let m = self.problem.number_of_points();
+        if self.mask.len() != m {
+            return Err(Error::Input);
+        }
+        if !self.problem.eval_batch(params, deviates)? {
+            return Ok(false);
+        }
+        for set in deviates.chunks_exact_mut(m) {
+            self.apply(0, set);
+        }
+        Ok(true)
+    }
+
+    fn iteration(&self, iteration: &Iteration<N>) {
+        self.problem.iteration(iteration)
+    }
+}
+
+/// Receiver of the progress of a fit reported by [`Progress`], e.g. a
+/// progress bar of a command line tool. The methods are those of
+/// `indicatif::ProgressBar`, so implementing the trait for a wrapper of it
+/// only forwards them:
+///
+/// ```ignore
+/// struct Bar(indicatif::ProgressBar);
+///
+/// impl rmpfit::ProgressBar for Bar {
+///     fn set_length(&self, len: u64) {
+///         self.0.set_length(len)
+///     }
+///     // ...
+/// }
+/// ```
+pub trait ProgressBar {
+    /// Sets the total number of iterations, [`Config::max_iter`]. Not called
+    /// without iterations, the bar is then used as a spinner.
+    fn set_length(&self, len: u64);
+
+    /// Moves the bar to the iteration `pos`.
+    fn set_position(&self, pos: u64);
+
+    /// Advances the spinner, called per iteration instead of
+    /// [`Self::set_position`] if there is no length.
+    fn tick(&self);
+
+    /// Shows the current chi^2.
+    fn set_message(&self, msg: String);
+}
+
+/// Adapter that reports the progress of a fit to a [`ProgressBar`] via
+/// [`Problem::iteration`]. Every iteration sets the position of the bar to
+/// [`Iteration::n_iter`] and the message to the current chi^2. With
+/// [`Config::max_iter`] 0 the length is unknown and the bar ticks as a spinner
+/// instead.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress<'a, P, B> {
+    problem: &'a P,
+    bar: &'a B,
+    spinner: bool,
+}
+
+impl<'a, P, B: ProgressBar> Progress<'a, P, B> {
+    /// Sets the length of `bar` to `config.max_iter`, which has to be the
+    /// configuration of the fit.
+    pub fn new(problem: &'a P, bar: &'a B, config: &Config) -> Self {
+        let spinner = config.max_iter == 0;
+        if !spinner {
+            bar.set_length(config.max_iter as u64);
+        }
+        Progress {
+            problem,
+            bar,
+            spinner,
+        }
+    }
+}
+
+impl<const N: usize, E, P, B> Problem<N, E> for Progress<'_, P, B>
+where
+    P: Problem<N, E>,
+    B: ProgressBar,
+{
+    fn eval(&self, params: &[f64; N], deviates: &mut [f64]) -> Result<(), E> {
+        self.problem.eval(params, deviates)
+    }
+
+    fn number_of_points(&self) -> usize {
+        self.problem.number_of_points()
+    }
+
+    fn number_of_masked_points(&self) -> usize {
+        self.problem.number_of_masked_points()
+    }
+
+    fn is_masked(&self, point: usize) -> bool {
+        self.problem.is_masked(point)
+    }
+
+    fn eval_chunk(&self, params: &[f64; N], offset: usize, deviates: &mut [f64]) -> Result<(), E> {
+        self.problem.eval_chunk(params, offset, deviates)
+    }
+
+    fn eval_complex(&self, params: &[Complex; N], deviates: &mut [Complex]) -> Result<(), E> {
+        self.problem.eval_complex(params, deviates)
+    }
+
+    fn model(&self, params: &[f64; N], values: &mut [f64]) -> Result<(), E> {
+        self.problem.model(params, values)
+    }
+
+    fn jacobian(&self, params: &[f64; N], jacobian: &mut [f64]) -> Result<(), E> {
+        self.problem.jacobian(params, jacobian)
+    }
+
+    fn eval_with_jacobian(
+        &self,
+        params: &[f64; N],
+        deviates: &mut [f64],
+        jacobian: &mut [f64],
+    ) -> Result<bool, E> {
+        self.problem.eval_with_jacobian(params, deviates, jacobian)
+    }
+
+    fn eval_batch(&self, params: &[[f64; N]], deviates: &mut [f64]) -> Result<bool, E> {
+        self.problem.eval_batch(params, deviates)
+    }
+
+    fn support(&self, param: usize) -> Option<::std::ops::Range<usize>> {
+        self.problem.support(param)
+    }
+
+    fn iteration(&self, iteration: &Iteration<N>) {
+        if self.spinner {
+            self.bar.tick();
+        } else {
+            self.bar.set_position(iteration.n_iter as u64);
+        }
+        self.bar
+            .set_message(format!("chi^2 = {:.6e}", iteration.chi2));
+        self.problem.iteration(iteration)
+    }
+}
+
+/// Adapter for fitting a model curve `y = f(params, x)` to scattered data
+/// `(x, y)` with optional uncertainties `sigma`, so the residual loop does not
+/// have to be written by hand. See [`curve_fit`] for the common case.
+#[derive(Debug, Clone, Copy)]
+pub struct Curve<'a, F> {
+    f: F,
+    x: &'a [f64],
+    y: &'a [f64],
+    sigma: Option<&'a [f64]>,
+}
+
+impl<'a, F> Curve<'a, F> {
+    /// Residuals `(y - f(params, x)) / sigma`, or `y - f(params, x)` without
+    /// `sigma`. All data slices have to be of the same length.
+    pub fn new(f: F, x: &'a [f64], y: &'a [f64], sigma: Option<&'a [f64]>) -> Self {
+        Curve { f, x, y, sigma }
+    }
+
+    /// Whether the lengths of the data slices match.
+    fn check(&self) -> bool {
+        let m = self.x.len();
+        self.y.len() == m && self.sigma.is_none_or(|s| s.len() == m)
+    }
+}
+
+impl<const N: usize, F> Problem<N> for Curve<'_, F>
+where
+    F: Fn(&[f64; N], f64) -> f64,
+{
+    fn eval(&self, params: &[f64; N], deviates: &mut [f64]) -> Result<()> {
+        self.eval_chunk(params, 0, deviates)
+    }
+
+    fn number_of_points(&self) -> usize {
+        self.x.len()
+    }
+
+    fn eval_chunk(&self, params: &[f64; N], offset: usize, deviates: &mut [f64]) -> Result<()> {
+        if !self.check() || self.x.len() < offset + deviates.len() {
+            return Err(Error::Input);
+        }
+        for (i, d) in (offset..).zip(deviates.iter_mut()) {
+            *d = self.y[i] - (self.f)(params, self.x[i]);
+            if let Some(sigma) = self.sigma {
+                *d /= sigma[i];
+            }
+        }
+        Ok(())
+    }
+
+    fn model(&self, params: &[f64; N], values: &mut [f64]) -> Result<()> {
+        if values.len() != self.x.len() {
+            return Err(Error::Input);
+        }
+        for (v, &x) in values.iter_mut().zip(self.x) {
+            *v = (self.f)(params, x);
+        }
+        Ok(())
+    }
+}
+
+/// subroutine covar
+///
+/// given an m by n matrix a, the problem is to determine the covariance
+/// matrix corresponding to a, defined as
+///
+/// inverse(a *a) .
+///
+/// this subroutine completes the solution of the problem if it is provided
+/// with the necessary information from the qr factorization, with column
+/// pivoting, of a. that is, if a*p = q*r, where p is a permutation matrix,
+/// q has orthogonal columns, and r is an upper triangular matrix with
+/// diagonal elements of nonincreasing magnitude, then covar expects the
+/// full upper triangle of r and the permutation matrix p. the covariance
+/// matrix is then computed as
+///
+/// p*inverse(r *r)*p  .
+///
+/// if a is nearly rank deficient, it may be desirable to compute the
+/// covariance matrix corresponding to the linearly independent columns of
+/// a. to define the numerical rank of a, covar uses the tolerance tol. if l
+/// is the largest integer such that
+///
+/// abs(r(l,l)) .gt. tol*abs(r(1,1)) ,
+///
+/// then covar computes the covariance matrix corresponding to the first l
+/// columns of r. for k greater than l, column and row ipvt(k) of the
+/// covariance matrix are set to zero.
+///
+/// the subroutine statement is
+///
+/// subroutine covar(n,r,ldr,ipvt,tol,wa)
+///
+/// where
+///
+/// n is a positive integer input variable set to the order of r.
+///
+/// r is an n by n array. on input the full upper triangle must contain the
+/// full upper triangle of the matrix r. on output r contains the square
+/// symmetric covariance matrix.
+///
+/// ldr is a positive integer input variable not less than n which specifies
+/// the leading dimension of the array r.
+///
+/// ipvt is an integer input array of length n which defines the permutation
+/// matrix p such that a*p = q*r. column j of p is column ipvt(j) of the
+/// identity matrix.
+///
+/// tol is a nonnegative input variable used to define the numerical rank of
+/// a in the manner described above.
+///
+/// wa is a work array of length n.
+#[allow(clippy::needless_range_loop)]
+fn covar(n: usize, r: &mut [f64], ldr: usize, ipvt: &[usize], tol: f64, wa: &mut [f64]) {
+    if n == 0 {
+        return;
+    }
+    /*
+     * form the inverse of r in the full upper triangle of r.
+     */
+    let tolr = tol * r[0].abs();
+    let mut l: isize = -1;
+    for k in 0..n {
+        let k0 = k * ldr;
+        let kk = k0 + k;
+        if r[kk].abs() <= tolr {
+            break;
+        }
+        r[kk] = 1.0 / r[kk];
+        for j in 0..k {
+            let kj = k0 + j;
+            let temp = r[kk] * r[kj];
+            r[kj] = 0.;
+            let j0 = j * ldr;
+            for i in 0..=j {
+                r[k0 + i] += -temp * r[j0 + i];
+            }
+        }
+        l = k as isize;
+    }
+    /*
+     * Form the full upper triangle of the inverse of (r transpose)*r
+     * in the full upper triangle of r
+     */
+    if l >= 0 {
+        let l = l as usize;
+        for k in 0..=l {
+            let k0 = k * ldr;
+            for j in 0..k {
+                let temp = r[k0 + j];
+                let j0 = j * ldr;
+                for i in 0..=j {
+                    r[j0 + i] += temp * r[k0 + i];
+                }
+            }
+            let temp = r[k0 + k];
+            for i in 0..=k {
+                r[k0 + i] *= temp;
+            }
+        }
+    }
+    /*
+     * For the full lower triangle of the covariance matrix
+     * in the strict lower triangle or and in wa
+     */
+    for j in 0..n {
+        let jj = ipvt[j];
+        let sing = j as isize > l;
+        let j0 = j * ldr;
+        let jj0 = jj * ldr;
+        for i in 0..=j {
+            let ji = j0 + i;
+            if sing {
+                r[ji] = 0.;
+            }
+            let ii = ipvt[i];
+            if ii > jj {
+                r[jj0 + ii] = r[ji];
+            }
+            if ii < jj {
+                r[ii * ldr + jj] = r[ji];
+            }
+        }
+        wa[jj] = r[j0 + j];
+    }
+    /*
+     * Symmetrize the covariance matrix in r
+     */
+    for j in 0..n {
+        let j0 = j * ldr;
+        for i in 0..j {
+            r[j0 + i] = r[i * ldr + j];
+        }
+        r[j0 + j] = wa[j];
+    }
+}
+
+/// User problem extended by the regularization pseudo-residuals, which are
+/// appended after the `n_data` user residuals.
+struct Model<'a, const N: usize, T> {
+    problem: &'a T,
+    n_data: usize,
+    priors: [Option<f64>; N],
+    weight: f64,
+    chunk_size: usize,
+    /// Square roots of the IRLS weights of the data residuals, empty for
+    /// [`Loss::Squared`] without [`Config::relative`]
+    irls: Vec<f64>,
+    loss: Loss,
+    relative: Option<f64>,
+    /// Model values for [`Config::relative`], empty if not used
+    values: Vec<f64>,
+    /// Free parameters with [`ParamConfig::log_scale`], passed as logarithms
+    log: [bool; N],
+    /// Time spent in the user evaluations, see [`Timing::eval`]
+    eval_time: ::std::cell::Cell<::std::time::Duration>,
+}
+
+impl<'a, const N: usize, T> Model<'a, N, T> {
+    fn new<E>(problem: &'a T, params: &[ParamConfig; N], cfg: Config) -> Result<Self, E>
+    where
+        T: Problem<N, E>,
+    {
+        let mut priors = [None; N];
+        if cfg.regularization > 0. {
+            for (prior, p) in priors.iter_mut().zip(params) {
+                *prior = p.prior;
+            }
+        }
+        let n_data = problem.number_of_points();
+        let mut irls = vec![];
+        if cfg.loss == Loss::Absolute || cfg.relative.is_some() {
+            irls = filled(n_data, 1.)?;
+        }
+        let mut values = vec![];
+        if cfg.relative.is_some() {
+            values = zeros(n_data)?;
+        }
+        Ok(Model {
+            problem,
+            n_data,
+            priors,
+            weight: cfg.regularization.max(0.).sqrt(),
+            chunk_size: cfg.chunk_size,
+            irls,
+            loss: cfg.loss,
+            relative: cfg.relative,
+            values,
+            log: params.map(|p| p.log_scale && !p.fixed),
+            eval_time: Default::default(),
+        })
+    }
+
+    /// Total number of residuals
+    fn len(&self) -> usize {
+        self.n_data + self.priors.iter().flatten().count()
+    }
+
+    /// User parameters of the internal ones, i.e. undoes the logarithm of
+    /// parameters with [`ParamConfig::log_scale`].
+    fn external<X: Copy>(&self, params: &[X; N], exp: impl Fn(X) -> X) -> [X; N] {
+        let mut external = *params;
+        for (x, _) in external.iter_mut().zip(&self.log).filter(|(_, &log)| log) {
+            *x = exp(*x);
+        }
+        external
+    }
+
+    /// Derivatives of the user parameters with respect to the internal ones
+    fn chain(&self, params: &[f64; N]) -> [f64; N] {
+        let mut chain = [1.; N];
+        for i in (0..N).filter(|&i| self.log[i]) {
+            chain[i] = params[i].exp();
+        }
+        chain
+    }
+
+    fn eval<E>(&self, params: &[f64; N], deviates: &mut [f64]) -> Result<(), E>
+    where
+        T: Problem<N, E>,
+    {
+        let params = &self.external(params, f64::exp);
+        let (data, reg) = deviates.split_at_mut(self.n_data);
+        let start = Tick::now();
+        if self.chunk_size == 0 {
+            self.problem.eval(params, data)?;
+        } else {
+            for (i, chunk) in data.chunks_mut(self.chunk_size).enumerate() {
+                self.problem
+                    .eval_chunk(params, i * self.chunk_size, chunk)?;
+            }
+        }
+        self.add_eval_time(start);
+        for (d, w) in data.iter_mut().zip(&self.irls) {
+            *d *= w;
+        }
+        let diffs =
+            (self.priors.iter().zip(params)).filter_map(|(prior, p)| prior.map(|prior| p - prior));
+        for (d, diff) in reg.iter_mut().zip(diffs) {
+            *d = self.weight * diff;
+        }
+        Ok(())
+    }
+
+    fn eval_complex<E>(&self, params: &[Complex; N], deviates: &mut [Complex]) -> Result<(), E>
+    where
+        T: Problem<N, E>,
+    {
+        let params = &self.external(params, Complex::exp);
+        let (data, reg) = deviates.split_at_mut(self.n_data);
+        let start = Tick::now();
+        self.problem.eval_complex(params, data)?;
+        self.add_eval_time(start);
+        for (d, w) in data.iter_mut().zip(&self.irls) {
+            *d = *d * *w;
+        }
+        let diffs =
+            (self.priors.iter().zip(params)).filter_map(|(prior, p)| prior.map(|prior| *p - prior));
+        for (d, diff) in reg.iter_mut().zip(diffs) {
+            *d = self.weight * diff;
+        }
+        Ok(())
+    }
+
+    /// Adds the time since `start` to [`Self::eval_time`].
+    fn add_eval_time(&self, start: Tick) {
+        self.eval_time.set(self.eval_time.get() + start.elapsed());
+    }
+
+    /// Derives the IRLS weights from the weighted residuals in `deviates` at
+    /// `params`, which are updated to the new weights.
+    fn reweight<E>(&mut self, params: &[f64; N], deviates: &mut [f64]) -> Result<(), E>
+    where
+        T: Problem<N, E>,
+    {
+        if self.relative.is_some() {
+            let params = self.external(params, f64::exp);
+            let start = Tick::now();
+            self.problem.model(&params, &mut self.values)?;
+            self.add_eval_time(start);
+        }
+        for (i, (d, w)) in deviates.iter_mut().zip(&mut self.irls).enumerate() {
+            let r = *d / *w;
+            *w = 1.;
+            if let Some(floor) = self.relative {
+                *w /= self.values[i].abs().max(floor);
+            }
+            if self.loss == Loss::Absolute {
+                *w /= (r * *w).abs().max(1e-6).sqrt();
+            }
+            *d = r * *w;
+        }
+        Ok(())
+    }
+}
+
+/// Internal structure to hold calculated values. Everything sized by the
+/// number of parameters lives on the stack, only the residual sized arrays
+/// (`fvec`, `fjac`, `wa2`, `wa4`) are heap allocated.
+struct Fit<'a, const N: usize, E, T: Problem<N, E>> {
+    m: usize,
+    nfree: usize,
+    ifree: [usize; N],
+    fvec: Vec<f64>,
+    nfev: usize,
+    xnew: [f64; N],
+    x: [f64; N],
+    xall: &'a mut [f64; N],
+    qtf: [f64; N],
+    fjac: Vec<f64>,
+    step: [f64; N],
+    dstep: [f64; N],
+    step_scale: [f64; N],
+    max_step: [f64; N],
+    /// Time spent outside of the user evaluations, see [`Timing`]
+    timing: Timing,
+    side: [Side; N],
+    qllim: [bool; N],
+    qulim: [bool; N],
+    llim: [f64; N],
+    ulim: [f64; N],
+    qanylim: bool,
+    f: Model<'a, N, T>,
+    wa1: [f64; N],
+    wa2: Vec<f64>,
+    wa3: [f64; N],
+    wa4: Vec<f64>,
+    ipvt: [usize; N],
+    diag: [f64; N],
+    fnorm: f64,
+    fnorm1: f64,
+    xnorm: f64,
+    delta: f64,
+    info: Success,
+    orig_norm: f64,
+    par: f64,
+    iter: usize,
+    rejects: usize,
+    jacobian: Option<Vec<f64>>,
+    frozen: Option<Vec<f64>>,
+    /// Complex residuals for [`Side::Complex`], empty if not used
+    cvec: Vec<Complex>,
+    /// Whether [`Problem::eval_with_jacobian`] may be implemented
+    fused: bool,
+    /// User Jacobian of [`Problem::eval_with_jacobian`], nfunc x npar
+    ujac: Vec<f64>,
+    /// Whether [`Problem::eval_batch`] may be implemented
+    batched: bool,
+    insensitive: [bool; N],
+    /// Perturbed parameter sets and residuals of [`Problem::eval_batch`]
+    sets: Vec<[f64; N]>,
+    batch: Vec<f64>,
+    cfg: Config,
+    _error: ::std::marker::PhantomData<E>,
+}
+
+impl<'a, const N: usize, E, P: Problem<N, E>> Fit<'a, N, E, P> {
+    fn new(
+        f: &'a P,
+        xall: &'a mut [f64; N],
+        params: &[ParamConfig; N],
+        cfg: Config,
+    ) -> Result<Fit<'a, N, E, P>, E> {
+        let f = Model::new(f, params, cfg)?;
+        let m = f.len();
+        if f.n_data == 0 {
+            Err(Error::Empty)
+        } else {
+            Ok(Fit {
+                m,
+                nfree: 0,
+                ifree: [0; N],
+                fvec: zeros(m)?,
+                nfev: 0,
+                xnew: [0.; N],
+                x: [0.; N],
+                xall,
+                qtf: [0.; N],
+                fjac: vec![],
+                step: [0.; N],
+                dstep: [0.; N],
+                step_scale: [0.; N],
+                max_step: [0.; N],
+                timing: Timing::default(),
+                side: [Side::Auto; N],
+                qllim: [false; N],
+                qulim: [false; N],
+                llim: [0.; N],
+                ulim: [0.; N],
+                qanylim: false,
+                f,
+                wa1: [0.; N],
+                wa2: zeros(m)?,
+                wa3: [0.; N],
+                wa4: zeros(m)?,
+                ipvt: [0; N],
+                diag: [0.; N],
+                fnorm: -1.0,
+                fnorm1: -1.0,
+                xnorm: -1.0,
+                delta: 0.0,
+                info: Success::NotDone,
+                orig_norm: 0.0,
+                par: 0.0,
+                iter: 1,
+                rejects: 0,
+                jacobian: None,
+                frozen: None,
+                cvec: vec![],
+                fused: true,
+                ujac: vec![],
+                batched: true,
+                insensitive: [false; N],
+                sets: vec![],
+                batch: vec![],
+                cfg,
+                _error: ::std::marker::PhantomData,
+            })
+        }
+    }
+
+    /// Copies the analytical Jacobian of [`Problem::eval_with_jacobian`] into
+    /// the zeroed fjac, `false` if the problem does not implement it.
+    fn fused_jacobian(&mut self) -> Result<bool, E> {
+        if !self.fused {
+            return Ok(false);
+        }
+        let n = self.f.n_data;
+        if self.ujac.is_empty() {
+            self.ujac = zeros(n.checked_mul(N).ok_or(Error::Memory)?)?;
+        }
+        let data = &mut self.wa4[..n];
+        let params = self.f.external(&self.xnew, f64::exp);
+        let start = Tick::now();
+        let fused = self
+            .f
+            .problem
+            .eval_with_jacobian(&params, data, &mut self.ujac)?;
+        self.f.add_eval_time(start);
+        if !fused {
+            self.fused = false;
+            return Ok(false);
+        }
+        // fvec already holds the (weighted) residuals at xnew, so the fused
+        // ones are not needed
+        self.nfev += 1;
+        for j in 0..self.nfree {
+            self.user_column(j);
+        }
+        Ok(true)
+    }
+
+    /// Number of the residuals without the masked points, see
+    /// [`Problem::number_of_masked_points`]
+    fn n_unmasked(&self) -> usize {
+        self.m
+            .saturating_sub(self.f.problem.number_of_masked_points())
+    }
+
+    /// Whether `n` more function evaluations would exceed [`Config::max_fev`].
+    fn exceeds_max_fev(&self, n: usize) -> bool {
+        self.cfg.max_fev > 0 && self.nfev + n > self.cfg.max_fev
+    }
+
+    /// Upper bound of the function evaluations of the next Jacobian
+    fn jacobian_cost(&self) -> usize {
+        if let (Some(after), Some(_)) = (self.cfg.freeze_jacobian_after, &self.frozen) {
+            if self.iter > after + 1 {
+                return 0;
+            }
+        }
+        let numeric: usize = self.ifree[..self.nfree]
+            .iter()
+            .map(|&i| match self.side[i] {
+                Side::User => 0,
+                Side::Both => 2,
+                _ => 1,
+            })
+            .sum();
+        if self.fused {
+            // Unknown yet whether the problem implements it
+            numeric.max(1)
+        } else {
+            numeric
+        }
+    }
+
+    /// Evaluates [`Problem::jacobian`] into `ujac` if any free parameter uses
+    /// [`Side::User`].
+    fn user_jacobian(&mut self) -> Result<(), E> {
+        if !self.ifree[..self.nfree]
+            .iter()
+            .any(|&i| self.side[i] == Side::User)
+        {
+            return Ok(());
+        }
+        let n = self.f.n_data;
+        if self.ujac.is_empty() {
+            self.ujac = zeros(n.checked_mul(N).ok_or(Error::Memory)?)?;
+        }
+        let params = self.f.external(&self.xnew, f64::exp);
+        let start = Tick::now();
+        self.f.problem.jacobian(&params, &mut self.ujac)?;
+        self.f.add_eval_time(start);
+        Ok(())
+    }
+
+    /// Copies the column of the free parameter `j` from the user Jacobian
+    /// `ujac` to `fjac`.
+    fn user_column(&mut self, j: usize) {
+        let n = self.f.n_data;
+        let free_p = self.ifree[j];
+        let col = free_p * n;
+        let ij = j * self.m;
+        let chain = self.f.chain(&self.xnew)[free_p];
+        for i in 0..n {
+            let w = self.f.irls.get(i).copied().unwrap_or(1.);
+            self.fjac[ij + i] = self.ujac[col + i] * w * chain;
+        }
+        self.regularization_column(j);
+    }
+
+    /// Derivatives of the regularization pseudo-residuals with respect to the
+    /// free parameter `j`.
+    fn regularization_column(&mut self, j: usize) {
+        let ij = j * self.m;
+        let mut k = self.f.n_data;
+        let chain = self.f.chain(&self.xnew);
+        for (p, prior) in self.f.priors.iter().enumerate() {
+            if prior.is_some() {
+                if p == self.ifree[j] {
+                    self.fjac[ij + k] = self.f.weight * chain[p];
+                }
+                k += 1;
+            }
+        }
+    }
+
+    /// Limits of the free parameter `j`, infinite if not limited
+    fn free_limits(&self, j: usize) -> (f64, f64) {
+        let lower = if self.qllim[j] {
+            self.llim[j]
+        } else {
+            f64::NEG_INFINITY
+        };
+        let upper = if self.qulim[j] {
+            self.ulim[j]
+        } else {
+            f64::INFINITY
+        };
+        (lower, upper)
+    }
+
+    /// Probes `x - h` and `x + h` of the central differences of the free
+    /// parameter `j` for the positive step `h`, `None` if one of them is not
+    /// inside the limits shrunk by [`Config::limit_margin`].
+    fn central_probes(&self, j: usize, h: f64) -> Option<(f64, f64)> {
+        let temp = self.xnew[self.ifree[j]];
+        let (lower, upper) = self.free_limits(j);
+        let shrink = self.cfg.limit_margin * h;
+        let (lo, hi) = (temp - h, temp + h);
+        (lower + shrink <= lo && hi <= upper - shrink && lo < hi).then_some((lo, hi))
+    }
+
+    /// Signed forward difference step of the free parameter `j`
+    fn fd_step(&self, j: usize, eps: f64) -> f64 {
+        let free_p = self.ifree[j];
+        let temp = self.xnew[free_p];
+        let mut h = eps * temp.abs();
+        if self.step[free_p] > 0. {
+            h = self.step[free_p];
+        }
+        if self.dstep[free_p] > 0. {
+            h = (self.dstep[free_p] * temp).abs();
+        }
+        if self.step_scale[free_p] > 0. {
+            h = self.step_scale[free_p];
+        }
+        if h == 0. {
+            h = eps;
+        }
+        self.limited_step(j, h)
+    }
+
+    /// Turns the positive step `h` of the free parameter `j` into the signed
+    /// one of its [`Side`] whose probe is inside the limits, see
+    /// [`limit_step`].
+    fn limited_step(&self, j: usize, h: f64) -> f64 {
+        let free_p = self.ifree[j];
+        let temp = self.xnew[free_p];
+        let (lower, upper) = self.free_limits(j);
+        let margin = self.cfg.limit_margin;
+        match self.side[free_p] {
+            // Backward steps are the forward steps of the mirrored parameter
+            Side::Left => -limit_step(-temp, h, -upper, -lower, margin),
+            Side::Right => {
+                let room = upper - (margin * h).min(0.5 * (upper - lower)) - temp;
+                let h = if room > 0. { h.min(room) } else { h };
+                limit_step(temp, h, lower, upper, margin)
+            }
+            _ => limit_step(temp, h, lower, upper, margin),
+        }
+    }
+
+    /// Forward difference Jacobian from a single [`Problem::eval_batch`] of
+    /// all perturbed parameter sets, `false` if the problem does not
+    /// implement it or a parameter uses [`Side::Complex`], [`Side::Both`] or
+    /// [`Side::User`].
+    #[allow(clippy::needless_range_loop)]
+    fn batched_jacobian(&mut self, eps: f64) -> Result<bool, E> {
+        if !self.batched
+            || self.ifree[..self.nfree]
+                .iter()
+                .any(|&i| matches!(self.side[i], Side::Complex | Side::Both | Side::User))
+        {
+            return Ok(false);
+        }
+        let n = self.f.n_data;
+        if self.batch.is_empty() {
+            self.batch = zeros(n.checked_mul(self.nfree).ok_or(Error::Memory)?)?;
+            self.sets = filled(self.nfree, self.xnew)?;
+        }
+        let mut h = [0.; N];
+        for j in 0..self.nfree {
+            h[j] = self.fd_step(j, eps);
+            let mut set = self.xnew;
+            set[self.ifree[j]] += h[j];
+            self.sets[j] = self.f.external(&set, f64::exp);
+        }
+        let sets = &self.sets[..self.nfree];
+        let start = Tick::now();
+        let batched = self
+            .f
+            .problem
+            .eval_batch(sets, &mut self.batch[..n * self.nfree])?;
+        self.f.add_eval_time(start);
+        if !batched {
+            self.batched = false;
+            return Ok(false);
+        }
+        self.nfev += self.nfree;
+        for j in 0..self.nfree {
+            let ij = j * self.m;
+            for i in 0..n {
+                let w = self.f.irls.get(i).copied().unwrap_or(1.);
+                self.fjac[ij + i] = (self.batch[j * n + i] * w - self.fvec[i]) / h[j];
+            }
+            self.regularization_column(j);
+        }
+        Ok(true)
+    }
+
+    /// subroutine fdjac2
+    ///
+    /// this subroutine computes a forward-difference approximation to the m by
+    /// n jacobian matrix associated with a specified problem of m functions in
+    /// n variables.
+    ///
+    /// the subroutine statement is
+    ///
+    /// subroutine fdjac2(fcn,m,n,x,fvec,fjac,ldfjac,iflag,epsfcn,wa)
+    ///
+    /// where
+    ///
+    /// fcn is the name of the user-supplied subroutine which calculates the
+    /// functions. fcn must be declared in an external statement in the user
+    /// calling program, and should be written as follows.
+    ///
+    /// subroutine fcn(m,n,x,fvec,iflag) integer m,n,iflag double precision
+    /// x(n),fvec(m)
+    /// ----------
+    /// calculate the functions at x and return this vector in fvec.
+    /// ----------
+    /// return end
+    ///
+    /// the value of iflag should not be changed by fcn unless the user wants to
+    /// terminate execution of fdjac2. in this case set iflag to a negative
+    /// integer.
+    ///
+    /// m is a positive integer input variable set to the number of functions.
+    ///
+    /// n is a positive integer input variable set to the number of variables. n
+    /// must not exceed m.
+    ///
+    /// x is an input array of length n.
+    ///
+    /// fvec is an input array of length m which must contain the functions
+    /// evaluated at x.
+    ///
+    /// fjac is an output m by n array which contains the approximation to the
+    /// jacobian matrix evaluated at x.
+    ///
+    /// ldfjac is a positive integer input variable not less than m which
+    /// specifies the leading dimension of the array fjac.
+    ///
+    /// iflag is an integer variable which can be used to terminate the
+    /// execution of fdjac2. see description of fcn.
+    ///
+    /// epsfcn is an input variable used in determining a suitable step length
+    /// for the forward-difference approximation. this approximation assumes
+    /// that the relative errors in the functions are of the order of epsfcn. if
+    /// epsfcn is less than the machine precision, it is assumed that the
+    /// relative errors in the functions are of the order of the machine
+    /// precision.
+    ///
+    /// wa is a work array of length m.
+    fn fdjac2(&mut self) -> Result<(), E> {
+        // iter counts from 1, freeze_jacobian_after from 0 at the start point
+        if let (Some(after), Some(frozen)) = (self.cfg.freeze_jacobian_after, &self.frozen) {
+            if self.iter > after + 1 {
+                self.fjac.copy_from_slice(frozen);
+                return Ok(());
+            }
+        }
+        // Calculate the Jacobian matrix
+        let eps = self.cfg.epsfcn.max(f64::EPSILON).sqrt();
+        self.fjac.fill(0.);
+        let mut ij = 0;
+        if !self.fused_jacobian()? && !self.batched_jacobian(eps)? {
+            self.user_jacobian()?;
+            /* Any parameters requiring numerical derivatives */
+            for j in 0..self.nfree {
+                let free_p = self.ifree[j];
+                let temp = self.xnew[free_p];
+                if self.side[free_p] == Side::User {
+                    self.user_column(j);
+                    ij += self.m;
+                    continue;
+                }
+                if self.side[free_p] == Side::Complex {
+                    let h = f64::EPSILON * temp.abs().max(1.);
+                    let mut xc = self.xnew.map(|x| Complex::new(x, 0.));
+                    xc[free_p].im = h;
+                    self.f.eval_complex(&xc, &mut self.cvec)?;
+                    self.nfev += 1;
+                    for d in &self.cvec {
+                        self.fjac[ij] = d.im / h;
+                        ij += 1;
+                    }
+                    continue;
+                }
+                let h = self.fd_step(j, eps);
+                let central = match self.side[free_p] {
+                    Side::Both => self.central_probes(j, h.abs()),
+                    _ => None,
+                };
+                if let Some((lo, hi)) = central {
+                    // wa2 only holds the column norms after qrfac
+                    self.xnew[free_p] = hi;
+                    let res = self.f.eval(&self.xnew, &mut self.wa4);
+                    self.xnew[free_p] = lo;
+                    let res = res.and_then(|_| self.f.eval(&self.xnew, &mut self.wa2));
+                    self.xnew[free_p] = temp;
+                    res?;
+                    self.nfev += 2;
+                    for (wa4, wa2) in self.wa4.iter().zip(&self.wa2) {
+                        self.fjac[ij] = (wa4 - wa2) / (hi - lo);
+                        ij += 1;
+                    }
+                    continue;
+                }
+                self.xnew[free_p] = temp + h;
+                let res = self.f.eval(&self.xnew, &mut self.wa4);
+                // restore the parameter before checking the evaluation
+                self.xnew[free_p] = temp;
+                res?;
+                self.nfev += 1;
+                for (wa4, fvec) in self.wa4.iter().zip(&self.fvec) {
+                    self.fjac[ij] = (wa4 - fvec) / h;
+                    ij += 1;
+                }
+            }
+        }
+        if let Some(jacobian) = &mut self.jacobian {
+            let n = self.f.n_data;
+            let chain = self.f.chain(&self.xnew);
+            for j in 0..self.nfree {
+                let col = self.ifree[j] * n;
+                let ij = j * self.m;
+                jacobian[col..col + n].copy_from_slice(&self.fjac[ij..ij + n]);
+                if self.f.log[self.ifree[j]] {
+                    // Derivatives with respect to the user parameter
+                    for d in &mut jacobian[col..col + n] {
+                        *d /= chain[self.ifree[j]];
+                    }
+                }
+            }
+        }
+        if let Some(after) = self.cfg.freeze_jacobian_after {
+            if self.iter > after {
+                let mut frozen = zeros(self.fjac.len())?;
+                frozen.copy_from_slice(&self.fjac);
+                self.frozen = Some(frozen);
+            }
+        }
+        Ok(())
+    }
+
+    /// subroutine qrfac
+    ///
+    /// this subroutine uses householder transformations with column pivoting
+    /// (optional) to compute a qr factorization of the m by n matrix a. that
+    /// is, qrfac determines an orthogonal matrix q, a permutation matrix p, and
+    /// an upper trapezoidal matrix r with diagonal elements of nonincreasing
+    /// magnitude, such that a*p = q*r. the householder transformation for
+    /// column k, k = 1,2,...,min(m,n), is of the form
+    ///
+    /// i - (1/u(k))*u*u
+    ///
+    /// where u has zeros in the first k-1 positions. the form of this
+    /// transformation and the method of pivoting first appeared in the
+    /// corresponding linpack subroutine.
+    ///
+    /// the subroutine statement is
+    ///
+    /// subroutine qrfac(m,n,a,lda,pivot,ipvt,lipvt,rdiag,acnorm,wa)
+    ///
+    /// where
+    ///
+    /// m is a positive integer input variable set to the number of rows of a.
+    ///
+    /// n is a positive integer input variable set to the number of columns of
+    /// a.
+    ///
+    /// a is an m by n array. on input a contains the matrix for which the qr
+    /// factorization is to be computed. on output the strict upper trapezoidal
+    /// part of a contains the strict upper trapezoidal part of r, and the lower
+    /// trapezoidal part of a contains a factored form of q (the non-trivial
+    /// elements of the u vectors described above).
+    ///
+    /// lda is a positive integer input variable not less than m which specifies
+    /// the leading dimension of the array a.
+    ///
+    /// pivot is a logical input variable. if pivot is set true, then column
+    /// pivoting is enforced. if pivot is set false, then no column pivoting is
+    /// done.
+    ///
+    /// ipvt is an integer output array of length lipvt. ipvt defines the
+    /// permutation matrix p such that a*p = q*r. column j of p is column
+    /// ipvt(j) of the identity matrix. if pivot is false, ipvt is not
+    /// referenced.
+    ///
+    /// lipvt is a positive integer input variable. if pivot is false, then
+    /// lipvt may be as small as 1. if pivot is true, then lipvt must be at
+    /// least n.
+    ///
+    /// rdiag is an output array of length n which contains the diagonal
+    /// elements of r.
+    ///
+    /// acnorm is an output array of length n which contains the norms of the
+    /// corresponding columns of the input matrix a. if this information is not
+    /// needed, then acnorm can coincide with rdiag.
+    ///
+    /// wa is a work array of length n. if pivot is false, then wa can coincide
+    /// with rdiag.
+    #[allow(clippy::explicit_counter_loop)]
+    fn qrfac(&mut self) {
+        // Compute the QR factorization of the jacobian
+        // compute the initial column norms and initialize several arrays.
+        for (j, ij) in (0..self.nfree).zip((0..self.m * self.nfree).step_by(self.m)) {
+            self.wa2[j] = self.fjac[ij..ij + self.m].enorm_with(self.cfg.compensated_sum);
+            self.wa1[j] = self.wa2[j];
+            self.wa3[j] = self.wa1[j];
+            self.ipvt[j] = j;
+        }
+        // reduce a to r with householder transformations.
+        for j in 0..self.m.min(self.nfree) {
+            // bring the column of largest norm into the pivot position.
+            let mut kmax = j;
+            if self.cfg.pivot {
+                for k in j..self.nfree {
+                    if self.wa1[k] > self.wa1[kmax] {
+                        kmax = k;
+                    }
+                }
+            }
+            if kmax != j {
+                let mut ij = self.m * j;
+                let mut jj = self.m * kmax;
+                for _ in 0..self.m {
+                    self.fjac.swap(jj, ij);
+                    ij += 1;
+                    jj += 1;
+                }
+                self.wa1[kmax] = self.wa1[j];
+                self.wa3[kmax] = self.wa3[j];
+                self.ipvt.swap(j, kmax);
+            }
+            let jj = j + self.m * j;
+            let jjj = self.m - j + jj;
+            let mut ajnorm = self.fjac[jj..jjj].enorm_with(self.cfg.compensated_sum);
+            if ajnorm == 0. {
+                self.wa1[j] = -ajnorm;
+                continue;
+            }
+            if self.fjac[jj] < 0. {
+                ajnorm = -ajnorm;
+            }
+            for fjac in self.fjac[jj..jjj].iter_mut() {
+                *fjac /= ajnorm;
+            }
+            self.fjac[jj] += 1.;
+            // apply the transformation to the remaining columns
+            // and update the norms.
+            let jp1 = j + 1;
+            if jp1 < self.nfree {
+                for k in jp1..self.nfree {
+                    let mut sum = 0.;
+                    let mut ij = j + self.m * k;
+                    let mut jj = j + self.m * j;
+                    for _ in j..self.m {
+                        sum += self.fjac[jj] * self.fjac[ij];
+                        ij += 1;
+                        jj += 1;
+                    }
+                    let temp = sum / self.fjac[j + self.m * j];
+                    ij = j + self.m * k;
+                    jj = j + self.m * j;
+                    for _ in j..self.m {
+                        self.fjac[ij] -= temp * self.fjac[jj];
+                        ij += 1;
+                        jj += 1;
+                    }
+                    if self.wa1[k] != 0. {
+                        let temp = self.fjac[j + self.m * k] / self.wa1[k];
+                        let temp = (1. - temp.powi(2)).max(0.);
+                        self.wa1[k] *= temp.sqrt();
+                        let temp = self.wa1[k] / self.wa3[k];
+                        if 0.05 * temp * temp < f64::EPSILON {
+                            let start = jp1 + self.m * k;
+                            self.wa1[k] = self.fjac[start..start + self.m - j - 1]
+                                .enorm_with(self.cfg.compensated_sum);
+                            self.wa3[k] = self.wa1[k];
+                        }
+                    }
+                }
+            }
+            self.wa1[j] = -ajnorm;
+        }
+    }
+
+    fn parse_params(&mut self, params: [ParamConfig; N]) -> Result<(), E> {
+        for (i, p) in params.iter().enumerate() {
+            if p.fixed {
+                if !p.limits.contains(self.xall[i]) {
+                    return Err(Error::Bounds);
+                }
+            } else {
+                let mut limits = p.limits;
+                if p.log_scale {
+                    if self.xall[i] <= 0. || self.xall[i].is_nan() {
+                        return Err(Error::InitBounds);
+                    }
+                    self.xall[i] = self.xall[i].ln();
+                    limits = log_limits(limits).ok_or(Error::Bounds)?;
+                }
+                let (limited_low, limit_low, limited_up, limit_up) = match limits {
+                    Limits::None => (false, f64::NEG_INFINITY, false, f64::INFINITY),
+                    Limits::Lower(lower) => (true, lower, false, f64::INFINITY),
+                    Limits::Upper(upper) => (false, f64::NEG_INFINITY, true, upper),
+                    Limits::Both(lower, upper) if lower < upper => (true, lower, true, upper),
+                    _ => return Err(Error::Bounds),
+                };
+                if !(limit_low..=limit_up).contains(&self.xall[i]) {
+                    return Err(Error::InitBounds);
+                }
+
+                let j = self.nfree;
+                self.nfree += 1;
+                self.ifree[j] = i;
+                self.qllim[j] = limited_low;
+                self.qulim[j] = limited_up;
+                self.llim[j] = limit_low;
+                self.ulim[j] = limit_up;
+                if limited_low || limited_up {
+                    self.qanylim = true;
+                }
+            }
+            self.step[i] = 0.0;
+            self.dstep[i] = p.rel_step;
+            self.step_scale[i] = p.step_scale;
+            self.max_step[i] = p.max_step;
+            self.side[i] = p.side;
+        }
+        if self.n_unmasked() < self.nfree {
+            return Err(Error::DoF);
+        }
+        Ok(())
+    }
+
+    // Initialize Levenberg-Marquardt parameter and iteration counter
+    fn init_lm(&mut self) -> Result<(), E> {
+        self.f.eval(self.xall, &mut self.fvec)?;
+        self.nfev += 1;
+        self.fnorm = self.fvec.enorm_with(self.cfg.compensated_sum);
+        self.xnew.copy_from_slice(self.xall);
+        self.reweight()?;
+        self.orig_norm = self.fnorm * self.fnorm;
+        for i in 0..self.nfree {
+            self.x[i] = self.xall[self.ifree[i]];
+        }
+        self.fjac = zeros(self.m.checked_mul(self.nfree).ok_or(Error::Memory)?)?;
+        if self.cfg.return_jacobian {
+            self.jacobian = Some(zeros(self.f.n_data.checked_mul(N).ok_or(Error::Memory)?)?);
+        }
+        if self.ifree[..self.nfree]
+            .iter()
+            .any(|&i| self.side[i] == Side::Complex)
+        {
+            self.cvec = filled(self.m, Complex::default())?;
+        }
+        if self.cfg.auto_step {
+            self.tune_steps()?;
+        }
+        Ok(())
+    }
+
+    /// Chooses the absolute finite difference steps for [`Config::auto_step`].
+    /// The difference of the forward differences of two consecutive steps of
+    /// the decade grid estimates the error of the larger one.
+    fn tune_steps(&mut self) -> Result<(), E> {
+        let mut prev = zeros(self.m)?;
+        let mut curr = zeros(self.m)?;
+        for j in 0..self.nfree {
+            let free_p = self.ifree[j];
+            if self.dstep[free_p] > 0.
+                || self.step_scale[free_p] > 0.
+                || matches!(self.side[free_p], Side::Complex | Side::User)
+            {
+                continue;
+            }
+            let temp = self.xnew[free_p];
+            let mut best = (f64::INFINITY, 0.);
+            let mut prev_h = 0.;
+            for k in 1..=12 {
+                let h = self.limited_step(j, temp.abs().max(1.) * 10f64.powi(-k));
+                self.xnew[free_p] = temp + h;
+                let res = self.f.eval(&self.xnew, &mut self.wa4);
+                self.xnew[free_p] = temp;
+                res?;
+                self.nfev += 1;
+                for ((c, wa4), fvec) in curr.iter_mut().zip(&self.wa4).zip(&self.fvec) {
+                    *c = (wa4 - fvec) / h;
+                }
+                if k > 1 {
+                    for ((wa4, p), c) in self.wa4.iter_mut().zip(&prev).zip(&curr) {
+                        *wa4 = p - c;
+                    }
+                    let err = self.wa4.enorm();
+                    if err < best.0 {
+                        best = (err, prev_h);
+                    }
+                }
+                ::std::mem::swap(&mut prev, &mut curr);
+                prev_h = h;
+            }
+            self.step[free_p] = best.1.abs();
+        }
+        Ok(())
+    }
+
+    /// Updates the IRLS weights for the current residuals and recomputes
+    /// their norm.
+    fn reweight(&mut self) -> Result<(), E> {
+        if self.f.irls.is_empty() {
+            return Ok(());
+        }
+        self.f.reweight(&self.xnew, &mut self.fvec)?;
+        self.fnorm = self.fvec.enorm_with(self.cfg.compensated_sum);
+        Ok(())
+    }
+
+    /// Handles free parameters with an all zero Jacobian column according to
+    /// [`Config::zero_column`].
+    fn check_columns(&mut self) -> Result<(), E> {
+        let mut j = 0;
+        while j < self.nfree {
+            let ij = j * self.m;
+            if self.fjac[ij..ij + self.m].iter().any(|&d| d != 0.) {
+                j += 1;
+                continue;
+            }
+            let free_p = self.ifree[j];
+            self.insensitive[free_p] = true;
+            match self.cfg.zero_column {
+                ZeroColumn::Warn => j += 1,
+                ZeroColumn::Error => return Err(Error::Insensitive(free_p)),
+                ZeroColumn::Fix => self.remove_free(j),
+            }
+        }
+        Ok(())
+    }
+
+    /// Fixes the free parameter `j` at its current value.
+    fn remove_free(&mut self, j: usize) {
+        let (m, n) = (self.m, self.nfree);
+        self.xall[self.ifree[j]] = self.x[j];
+        self.fjac.copy_within((j + 1) * m..n * m, j * m);
+        if let Some(frozen) = &mut self.frozen {
+            frozen.copy_within((j + 1) * m..n * m, j * m);
+        }
+        self.ifree.copy_within(j + 1..n, j);
+        self.x.copy_within(j + 1..n, j);
+        self.qllim.copy_within(j + 1..n, j);
+        self.qulim.copy_within(j + 1..n, j);
+        self.llim.copy_within(j + 1..n, j);
+        self.ulim.copy_within(j + 1..n, j);
+        self.nfree -= 1;
+    }
+
+    fn check_limits(&mut self) {
+        if !self.qanylim {
+            return;
+        }
+        for j in 0..self.nfree {
+            let lpegged = self.qllim[j] && self.x[j] == self.llim[j];
+            let upegged = self.qulim[j] && self.x[j] == self.ulim[j];
+            let mut sum = 0.;
+            // If the parameter is pegged at a limit, compute the gradient direction
+            let ij = j * self.m;
+            if lpegged || upegged {
+                for i in 0..self.m {
+                    sum += self.fvec[i] * self.fjac[ij + i];
+                }
+            }
+            // If pegged at lower limit and gradient is toward negative then
+            // reset gradient to zero
+            if lpegged && sum > 0. {
+                for i in 0..self.m {
+                    self.fjac[ij + i] = 0.;
+                }
+            }
+            // If pegged at upper limit and gradient is toward positive then
+            // reset gradient to zero
+            if upegged && sum < 0. {
+                for i in 0..self.m {
+                    self.fjac[ij + i] = 0.;
+                }
+            }
+        }
+    }
+
+    /// On the first iteration and if user_scale is requested, scale according
+    /// to the norms of the columns of the initial jacobian,
+    /// calculate the norm of the scaled x, and initialize the step bound delta.
+    fn scale(&mut self) {
+        if self.iter != 1 {
+            return;
+        }
+        if !self.cfg.do_user_scale {
+            for j in 0..self.nfree {
+                self.diag[self.ifree[j]] = if self.wa2[j] == 0. { 1. } else { self.wa2[j] };
+            }
+        }
+        for j in 0..self.nfree {
+            self.wa3[j] = self.diag[self.ifree[j]] * self.x[j];
+        }
+        self.xnorm = self.wa3.enorm();
+        self.delta = self.cfg.step_factor * self.xnorm;
+        if self.delta == 0. {
+            self.delta = self.cfg.step_factor;
+        }
+    }
+
+    fn fill_xnew(&mut self) {
+        for i in 0..self.nfree {
+            self.xnew[self.ifree[i]] = self.x[i];
+        }
+    }
+
+    /// form (q transpose)*fvec and store the first n components in qtf.
+    fn transpose(&mut self) {
+        self.wa4.copy_from_slice(&self.fvec);
+        let mut jj = 0;
+        for j in 0..self.nfree {
+            let temp = self.fjac[jj];
+            if temp != 0. {
+                let mut sum = 0.0;
+                let mut ij = jj;
+                for i in j..self.m {
+                    sum += self.fjac[ij] * self.wa4[i];
+                    ij += 1;
+                }
+                let temp = -sum / temp;
+                ij = jj;
+                for i in j..self.m {
+                    self.wa4[i] += self.fjac[ij] * temp;
+                    ij += 1;
+                }
+            }
+            self.fjac[jj] = self.wa1[j];
+            jj += self.m + 1;
+            self.qtf[j] = self.wa4[j];
+        }
+    }
+
+    /// Check for overflow. This should be a cheap test here since FJAC has been
+    /// reduced to a (small) square matrix, and the test is O(N^2).
+    fn check_is_finite(&self) -> bool {
+        if !self.cfg.finite_check {
+            for val in &self.fjac {
+                if !val.is_finite() {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// compute the norm of the scaled gradient. Every component is divided by
+    /// the norm of the corresponding Jacobian column (acnorm, kept in `wa2` by
+    /// [`Self::qrfac`]) to make the test scale invariant. Columns of parameters pegged
+    /// at a limit with the gradient pointing out of the feasible region have
+    /// been zeroed by [`Self::check_limits`], so this is the projected gradient.
+    fn gnorm(&self) -> f64 {
+        let mut gnorm: f64 = 0.;
+        if self.fnorm != 0. {
+            let mut jj = 0;
+            for j in 0..self.nfree {
+                let l = self.ipvt[j];
+                if self.wa2[l] != 0. {
+                    let mut sum = 0.;
+                    for (i, fjac) in self.fjac[jj..=jj + j].iter().enumerate() {
+                        sum += fjac * (self.qtf[i] / self.fnorm);
+                    }
+                    gnorm = gnorm.max((sum / self.wa2[l]).abs());
+                }
+                jj += self.m;
+            }
+        }
+        gnorm
+    }
+
+    fn terminate(mut self, params: &[ParamConfig; N]) -> Result<Status<N>, E> {
+        for i in 0..self.nfree {
+            self.xall[self.ifree[i]] = self.x[i];
+        }
+        let chain = self.f.chain(self.xall);
+        for (i, p) in params.iter().enumerate().filter(|&(i, _)| self.f.log[i]) {
+            let x = self.xall[i];
+            let mut value = x.exp();
+            // Keep parameters on their limits exactly there
+            if let Limits::Lower(bound) | Limits::Upper(bound) = p.limits {
+                if x == bound.ln() {
+                    value = bound;
+                }
+            }
+            if let Limits::Both(lower, upper) = p.limits {
+                if x == lower.ln() {
+                    value = lower;
+                } else if x == upper.ln() {
+                    value = upper;
+                }
+            }
+            self.xall[i] = p.limits.clamp(value);
+        }
+        /* Compute number of pegged parameters */
+        let pegged = pegged(self.xall, params);
+        let n_pegged = pegged.iter().filter(|&&p| p).count();
+        let rank = (0..self.nfree)
+            .filter(|&j| {
+                let column = &self.fjac[j * self.m..][..=j];
+                column[j].abs() > RANK_TOL * column.enorm()
+            })
+            .count();
+        /* Compute and return the covariance matrix and/or parameter errors */
+        let start = Tick::now();
+        self = self.covar();
+        self.timing.linear_algebra += start.elapsed();
+        let mut covar = self.scatter_covar(&self.fjac, self.m);
+        scale_covar(&mut covar, &chain);
+        let zero_covariance = self.nfree > 0 && covar.iter().all(|&c| c == 0.);
+        let negative_variance = clamp_variances(&mut covar, N);
+        let mut xerror = [0.; N];
+        for (i, e) in xerror.iter_mut().enumerate() {
+            *e = covar[i * N + i].sqrt();
+        }
+        // fnorm1 may belong to a rejected trial, x and fvec to the best point
+        let best_norm = self.fnorm;
+        let n_func = self
+            .f
+            .n_data
+            .saturating_sub(self.f.problem.number_of_masked_points());
+        self.fvec.truncate(self.f.n_data);
+        for (d, w) in self.fvec.iter_mut().zip(&self.f.irls) {
+            *d /= w;
+        }
+        Ok(Status {
+            success: self.info,
+            best_norm: best_norm * best_norm,
+            orig_norm: self.orig_norm,
+            n_iter: self.iter,
+            n_fev: self.nfev,
+            n_free: self.nfree,
+            n_pegged,
+            pegged,
+            insensitive: self.insensitive,
+            n_func,
+            zero_dof: n_func == self.nfree,
+            rank,
+            resid: self.fvec.into_boxed_slice(),
+            params: *self.xall,
+            xerror,
+            covar: covar.into_boxed_slice(),
+            negative_variance,
+            zero_covariance,
+            final_par: self.par,
+            jacobian: self.jacobian.map(Vec::into_boxed_slice),
+            timing: Timing {
+                eval: self.f.eval_time.get(),
+                ..self.timing
+            },
+        })
+    }
+
+    /// Expands the free parameter covariance in `r` to the full npar x npar matrix.
+    fn scatter_covar(&self, r: &[f64], ldr: usize) -> Vec<f64> {
+        let mut covar = vec![0.; N * N];
+        for j in 0..self.nfree {
+            let k = self.ifree[j] * N;
+            let l = j * ldr;
+            for i in 0..self.nfree {
+                covar[k + self.ifree[i]] = r[l + i]
+            }
+        }
+        covar
+    }
+
+    /// Reports the current iteration to [`Problem::iteration`].
+    fn report_iteration(&self) -> Result<(), E> {
+        let covar = if self.cfg.iteration_covar {
+            // Only the upper nfree x nfree triangle of fjac holds R
+            let mut r = zeros(self.nfree * self.nfree)?;
+            for j in 0..self.nfree {
+                r[j * self.nfree..][..=j].copy_from_slice(&self.fjac[j * self.m..][..=j]);
+            }
+            let mut wa = [0.; N];
+            covar(
+                self.nfree,
+                &mut r,
+                self.nfree,
+                &self.ipvt,
+                self.cfg.covtol,
+                &mut wa,
+            );
+            let mut covar = self.scatter_covar(&r, self.nfree);
+            scale_covar(&mut covar, &self.f.chain(&self.xnew));
+            Some(covar.into_boxed_slice())
+        } else {
+            None
+        };
+        self.f.problem.iteration(&Iteration {
+            n_iter: self.iter,
+            n_fev: self.nfev,
+            chi2: self.fnorm * self.fnorm,
+            params: self.f.external(&self.xnew, f64::exp),
+            delta: self.delta,
+            par: self.par,
+            covar,
+        });
+        Ok(())
+    }
+
+    fn covar(mut self) -> Self {
+        covar(
+            self.nfree,
+            &mut self.fjac,
+            self.m,
+            &self.ipvt,
+            self.cfg.covtol,
+            &mut self.wa2,
+        );
+        self
+    }
+
+    fn rescale(&mut self) {
+        if self.cfg.do_user_scale {
+            return;
+        }
+        for j in 0..self.nfree {
+            let i = self.ifree[j];
+            self.diag[i] = self.diag[i].max(self.wa2[j]);
+        }
+    }
+
+    /// subroutine lmpar
+    ///
+    /// given an m by nfree matrix a, an nfree by nfree nonsingular diagonal
+    /// matrix d, an m-vector b, and a positive number delta, the problem is to
+    /// determine a value for the parameter par such that if wa1 solves the
+    /// system
+    ///
+    /// a*wa1 = b ,   sqrt(par)*d*wa1 = 0 ,
+    ///
+    /// in the least squares sense, and dxnorm is the euclidean norm of d*wa1,
+    /// then either par is zero and
+    ///
+    /// (dxnorm-delta) <= 0.1*delta ,
+    ///
+    /// or par is positive and
+    ///
+    /// abs(dxnorm-delta) <= 0.1*delta .
+    ///
+    /// this subroutine completes the solution of the problem if it is provided
+    /// with the necessary information from the qr factorization, with column
+    /// pivoting, of a. that is, if a*p = q*fjack, where p is a permutation
+    /// matrix, q has orthogonal columns, and fjack is an upper triangular
+    /// matrix with diagonal elements of nonincreasing magnitude, then lmpar
+    /// expects the full upper triangle of fjack, the permutation matrix p, and
+    /// the first nfree components of (q transpose)*b. on output lmpar also
+    /// provides an upper triangular matrix s such that
+    ///
+    ///  t   t           t p *(a *a + par*d*d)*p = s *s .
+    ///
+    /// s is employed within lmpar and may be of separate interest.
+    ///
+    /// only a few iterations are generally needed for convergence of the
+    /// algorithm. if, however, the limit of 10 iterations is reached, then the
+    /// output par will contain the best value obtained so far.
+    ///
+    /// the subroutine statement is
+    ///
+    /// subroutine lmpar(nfree,fjack,m,ipvt,diag,qtf,delta,par,wa1,wa2, wa3,wa4)
+    ///
+    /// where
+    ///
+    /// nfree is a positive integer input variable set to the order of fjack.
+    ///
+    /// fjack is an nfree by nfree array. on input the full upper triangle must
+    /// contain the full upper triangle of the matrix fjack. on output the full
+    /// upper triangle is unaltered, and the strict lower triangle contains the
+    /// strict upper triangle (transposed) of the upper triangular matrix s.
+    ///
+    /// m is a positive integer input variable not less than nfree which
+    /// specifies the leading dimension of the array fjack.
+    ///
+    /// ipvt is an integer input array of length nfree which defines the
+    /// permutation matrix p such that a*p = q*fjack. column j of p is column
+    /// ipvt(j) of the identity matrix.
+    ///
+    /// diag is an input array of length nfree which must contain the diagonal
+    /// elements of the matrix d.
+    ///
+    /// qtf is an input array of length nfree which must contain the first nfree
+    /// elements of the vector (q transpose)*b.
+    ///
+    /// delta is a positive input variable which specifies an upper bound on the
+    /// euclidean norm of d*wa1.
+    ///
+    /// par is a nonnegative variable. on input par contains an initial estimate
+    /// of the levenberg-marquardt parameter. on output par contains the final
+    /// estimate.
+    ///
+    /// wa1 is an output array of length nfree which contains the least squares
+    /// solution of the system a*wa1 = b, sqrt(par)*d*wa1 = 0, for the output
+    /// par.
+    ///
+    /// wa2 is an output array of length nfree which contains the diagonal
+    /// elements of the upper triangular matrix s.
+    ///
+    /// wa3 and wa4 are work arrays of length nfree.
+    #[allow(clippy::explicit_counter_loop)]
+    fn lmpar(&mut self) {
+        /*
+         *     compute and store in wa1 the gauss-newton direction. if the
+         *     jacobian is rank-deficient, obtain a least squares solution.
+         */
+        let mut nsing = self.nfree;
+        let mut jj = 0;
+        for j in 0..self.nfree {
+            self.wa3[j] = self.qtf[j];
+            if self.fjac[jj] == 0. && nsing == self.nfree {
+                nsing = j;
+            }
+            if nsing < self.nfree {
+                self.wa3[j] = 0.;
+            }
+            jj += self.m + 1;
+        }
+        if nsing >= 1 {
+            for k in 0..nsing {
+                let j = nsing - k - 1;
+                let mut ij = self.m * j;
+                self.wa3[j] /= self.fjac[j + ij];
+                let temp = self.wa3[j];
+                if j > 0 {
+                    for i in 0..j {
+                        self.wa3[i] -= self.fjac[ij] * temp;
+                        ij += 1;
+                    }
+                }
+            }
+        }
+        for j in 0..self.nfree {
+            self.wa1[self.ipvt[j]] = self.wa3[j];
+        }
+        /*
+         *     initialize the iteration counter.
+         *     evaluate the function at the origin, and test
+         *     for acceptance of the gauss-newton direction.
+         */
+        for j in 0..self.nfree {
+            self.wa4[j] = self.diag[self.ifree[j]] * self.wa1[j];
+        }
+        let mut dxnorm = self.wa4[0..self.nfree].enorm();
+        let mut fp = dxnorm - self.delta;
+        if fp <= 0.1 * self.delta {
+            self.par = 0.;
+            return;
+        }
+        /*
+         *     if the jacobian is not rank deficient, the newton
+         *     step provides a lower bound, parl, for the zero of
+         *     the function. otherwise set this bound to zero.
+         */
+        let mut parl = 0.;
+        if nsing >= self.nfree {
+            self.newton_correction(dxnorm);
+            let mut jj = 0;
+            for j in 0..self.nfree {
+                let mut sum = 0.;
+                if j > 0 {
+                    let mut ij = jj;
+                    for i in 0..j {
+                        sum += self.fjac[ij] * self.wa3[i];
+                        ij += 1;
+                    }
+                }
+                self.wa3[j] = (self.wa3[j] - sum) / self.fjac[j + self.m * j];
+                jj += self.m;
+            }
+            let temp = self.wa3[0..self.nfree].enorm();
+            parl = ((fp / self.delta) / temp) / temp;
+        }
+        /*
+         *     calculate an upper bound, paru, for the zero of the function.
+         */
+        let mut jj = 0;
+        for j in 0..self.nfree {
+            let mut sum = 0.;
+            let mut ij = jj;
+            for i in 0..=j {
+                sum += self.fjac[ij] * self.qtf[i];
+                ij += 1;
+            }
+            let l = self.ipvt[j];
+            self.wa3[j] = sum / self.diag[self.ifree[l]];
+            jj += self.m;
+        }
+        let gnorm = self.wa3[0..self.nfree].enorm();
+        let mut paru = gnorm / self.delta;
+        if paru == 0. {
+            paru = f64::MIN_POSITIVE / self.delta.min(0.1);
+        }
+        /*
+         *     if the input par lies outside of the interval (parl,paru),
+         *     set par to the closer endpoint.
+         */
+        self.par = self.par.max(parl);
+        self.par = self.par.max(paru);
+        if self.par == 0. {
+            self.par = gnorm / dxnorm;
+        }
+        let mut iter = 0;
+        loop {
+            iter += 1;
+            if self.par == 0. {
+                self.par = f64::MIN_POSITIVE.max(0.001 * paru);
+            }
+            let temp = self.par.sqrt();
+            for j in 0..self.nfree {
+                self.wa3[j] = temp * self.diag[self.ifree[j]];
+            }
+            self.qrsolv();
+            for j in 0..self.nfree {
+                self.wa4[j] = self.diag[self.ifree[j]] * self.wa1[j];
+            }
+            dxnorm = self.wa4[0..self.nfree].enorm();
+            let temp = fp;
+            fp = dxnorm - self.delta;
+            /*
+             *	 if the function is small enough, accept the current value
+             *	 of par. also test for the exceptional cases where parl
+             *	 is zero or the number of iterations has reached 10.
+             */
+            if fp.abs() <= 0.1 * self.delta || (parl == 0. && fp <= temp && temp < 0.) || iter >= 10
+            {
+                return;
+            }
+            self.newton_correction(dxnorm);
+            jj = 0;
+            for j in 0..self.nfree {
+                self.wa3[j] /= self.wa2[j];
+                let temp = self.wa3[j];
+                let jp1 = j + 1;
+                if jp1 < self.nfree {
+                    let mut ij = jp1 + jj;
+                    for i in jp1..self.nfree {
+                        self.wa3[i] -= self.fjac[ij] * temp;
+                        ij += 1;
+                    }
+                }
+                jj += self.m;
+            }
+            let temp = self.wa3[0..self.nfree].enorm();
+            let parc = ((fp / self.delta) / temp) / temp;
+            /*
+             *	 depending on the sign of the function, update parl or paru.
+             */
+            if fp > 0.0 {
+                parl = parl.max(self.par);
+            }
+            if fp < 0.0 {
+                paru = paru.min(self.par);
+            }
+            /*
+             *	 compute an improved estimate for par.
+             */
+            self.par = parl.max(self.par + parc);
+        }
+    }
+
+    /// compute the newton correction.
+    fn newton_correction(&mut self, dxnorm: f64) {
+        for j in 0..self.nfree {
+            let l = self.ipvt[j];
+            self.wa3[j] = self.diag[self.ifree[l]] * (self.wa4[l] / dxnorm);
+        }
+    }
+
+    /// subroutine qrsolv
+    ///
+    /// given an m by n matrix a, an n by n diagonal matrix d, and an m-vector
+    /// b, the problem is to determine an x which solves the system
+    ///
+    /// a*x = b ,     d*x = 0 ,
+    ///
+    /// in the least squares sense.
+    ///
+    /// this subroutine completes the solution of the problem if it is provided
+    /// with the necessary information from the qr factorization, with column
+    /// pivoting, of a. that is, if a*p = q*r, where p is a permutation matrix,
+    /// q has orthogonal columns, and r is an upper triangular matrix with
+    /// diagonal elements of nonincreasing magnitude, then qrsolv expects the
+    /// full upper triangle of r, the permutation matrix p, and the first n
+    /// components of (q transpose)*b. the system a*x = b, d*x = 0, is then
+    /// equivalent to
+    ///
+    /// r*z = q *b ,  p *d*p*z = 0 ,
+    ///
+    /// where x = p*z. if this system does not have full rank, then a least
+    /// squares solution is obtained. on output qrsolv also provides an upper
+    /// triangular matrix s such that
+    ///
+    /// p *(a *a + d*d)*p = s *s .
+    ///
+    /// s is computed within qrsolv and may be of separate interest.
+    ///
+    /// the subroutine statement is
+    ///
+    /// subroutine qrsolv(n,r,ldr,ipvt,diag,qtb,x,sdiag,wa)
+    ///
+    /// where
+    ///
+    /// n is a positive integer input variable set to the order of r.
+    ///
+    /// r is an n by n array. on input the full upper triangle must contain the
+    /// full upper triangle of the matrix r. on output the full upper triangle
+    /// is unaltered, and the strict lower triangle contains the strict upper
+    /// triangle (transposed) of the upper triangular matrix s.
+    ///
+    /// ldr is a positive integer input variable not less than n which specifies
+    /// the leading dimension of the array r.
+    ///
+    /// ipvt is an integer input array of length n which defines the permutation
+    /// matrix p such that a*p = q*r. column j of p is column ipvt(j) of the
+    /// identity matrix.
+    ///
+    /// diag is an input array of length n which must contain the diagonal
+    /// elements of the matrix d.
+    ///
+    /// qtb is an input array of length n which must contain the first n
+    /// elements of the vector (q transpose)*b.
+    ///
+    /// x is an output array of length n which contains the least squares
+    /// solution of the system a*x = b, d*x = 0.
+    ///
+    /// sdiag is an output array of length n which contains the diagonal
+    /// elements of the upper triangular matrix s.
+    ///
+    /// wa is a work array of length n.
+    #[allow(clippy::explicit_counter_loop)]
+    fn qrsolv(&mut self) {
+        /*
+         *     copy r and (q transpose)*b to preserve input and initialize s.
+         *     in particular, save the diagonal elements of r in x.
+         */
+        let mut kk = 0;
+        for j in 0..self.nfree {
+            let mut ij = kk;
+            let mut ik = kk;
+            for _ in j..self.nfree {
+                self.fjac[ij] = self.fjac[ik];
+                ij += 1;
+                ik += self.m;
+            }
+            self.wa1[j] = self.fjac[kk];
+            self.wa4[j] = self.qtf[j];
+            kk += self.m + 1;
+        }
+        /*
+         *     eliminate the diagonal matrix d using a givens rotation.
+         */
+        for j in 0..self.nfree {
+            /*
+             *	 prepare the row of d to be eliminated, locating the
+             *	 diagonal element using p from the qr factorization.
+             */
+            let l = self.ipvt[j];
+            if self.wa3[l] != 0. {
+                for k in j..self.nfree {
+                    self.wa2[k] = 0.;
+                }
+                self.wa2[j] = self.wa3[l];
+                /*
+                 *	 the transformations to eliminate the row of d
+                 *	 modify only a single element of (q transpose)*b
+                 *	 beyond the first n, which is initially zero.
+                 */
+                let mut qtbpj = 0.;
+                for k in j..self.nfree {
+                    /*
+                     *	    determine a givens rotation which eliminates the
+                     *	    appropriate element in the current row of d.
+                     */
+                    if self.wa2[k] == 0. {
+                        continue;
+                    }
+                    let kk = k + self.m * k;
+                    let (sinx, cosx) = if self.fjac[kk].abs() < self.wa2[k].abs() {
+                        let cotan = self.fjac[kk] / self.wa2[k];
+                        let sinx = 0.5 / (0.25 + 0.25 * cotan * cotan).sqrt();
+                        let cosx = sinx * cotan;
+                        (sinx, cosx)
+                    } else {
+                        let tanx = self.wa2[k] / self.fjac[kk];
+                        let cosx = 0.5 / (0.25 + 0.25 * tanx * tanx).sqrt();
+                        let sinx = cosx * tanx;
+                        (sinx, cosx)
+                    };
+                    /*
+                     *	    compute the modified diagonal element of r and
+                     *	    the modified element of ((q transpose)*b,0).
+                     */
+                    self.fjac[kk] = cosx * self.fjac[kk] + sinx * self.wa2[k];
+                    let temp = cosx * self.wa4[k] + sinx * qtbpj;
+                    qtbpj = -sinx * self.wa4[k] + cosx * qtbpj;
+                    self.wa4[k] = temp;
+                    /*
+                     *	    accumulate the tranformation in the row of s.
+                     */
+                    let kp1 = k + 1;
+                    if self.nfree > kp1 {
+                        let mut ik = kk + 1;
+                        for i in kp1..self.nfree {
+                            let temp = cosx * self.fjac[ik] + sinx * self.wa2[i];
+                            self.wa2[i] = -sinx * self.fjac[ik] + cosx * self.wa2[i];
+                            self.fjac[ik] = temp;
+                            ik += 1;
+                        }
+                    }
+                }
+            }
+            /*
+             *	 store the diagonal element of s and restore
+             *	 the corresponding diagonal element of r.
+             */
+            let kk = j + self.m * j;
+            self.wa2[j] = self.fjac[kk];
+            self.fjac[kk] = self.wa1[j];
+        }
+        /*
+         *     solve the triangular system for z. if the system is
+         *     singular, then obtain a least squares solution.
+         */
+        let mut nsing = self.nfree;
+        for j in 0..self.nfree {
+            if self.wa2[j] == 0. && nsing == self.nfree {
+                nsing = j;
+            }
+            if nsing < self.nfree {
+                self.wa4[j] = 0.;
+            }
+        }
+        if nsing > 0 {
+            for k in 0..nsing {
+                let j = nsing - k - 1;
+                let mut sum = 0.;
+                let jp1 = j + 1;
+                if nsing > jp1 {
+                    let mut ij = jp1 + self.m * j;
+                    for i in jp1..nsing {
+                        sum += self.fjac[ij] * self.wa4[i];
+                        ij += 1;
+                    }
+                }
+                self.wa4[j] = (self.wa4[j] - sum) / self.wa2[j];
+            }
+        }
+        /*
+         *     permute the components of z back to components of x.
+         */
+        for j in 0..self.nfree {
+            self.wa1[self.ipvt[j]] = self.wa4[j];
+        }
+    }
+
+    fn iterate(&mut self, gnorm: f64) -> Result<MPDone, E> {
+        for j in 0..self.nfree {
+            self.wa1[j] = -self.wa1[j];
+        }
+        let mut alpha: f64 = 1.0;
+        if !self.qanylim {
+            /* No parameter limits, so just move to new position WA2 */
+            for j in 0..self.nfree {
+                self.wa2[j] = self.x[j] + self.wa1[j];
+            }
+        } else {
+            /* Respect the limits.  If a step were to go out of bounds, then
+             * we should take a step in the same direction but shorter distance.
+             * The step should take us right to the limit in that case.
+             */
+            for j in 0..self.nfree {
+                let lpegged = self.qllim[j] && self.x[j] <= self.llim[j];
+                let upegged = self.qulim[j] && self.x[j] >= self.ulim[j];
+                let dwa1 = self.wa1[j].abs() > f64::EPSILON;
+                if lpegged && self.wa1[j] < 0. {
+                    self.wa1[j] = 0.;
+                }
+                if upegged && self.wa1[j] > 0. {
+                    self.wa1[j] = 0.;
+                }
+                if dwa1 && self.qllim[j] && self.x[j] + self.wa1[j] < self.llim[j] {
+                    alpha = alpha.min((self.llim[j] - self.x[j]) / self.wa1[j]);
+                }
+                if dwa1 && self.qulim[j] && self.x[j] + self.wa1[j] > self.ulim[j] {
+                    alpha = alpha.min((self.ulim[j] - self.x[j]) / self.wa1[j]);
+                }
+            }
+            /* Scale the resulting vector, advance to the next position */
+            for j in 0..self.nfree {
+                self.wa1[j] *= alpha;
+                self.wa2[j] = self.x[j] + self.wa1[j];
+                /*
+                 * Adjust the output values.  If the step put us exactly
+                 * on a boundary, make sure it is exact.
+                 */
+                let sgnu = if self.ulim[j] >= 0. { 1. } else { -1. };
+                let sgnl = if self.llim[j] >= 0. { 1. } else { -1. };
+                let ulim1 = self.ulim[j] * (1. - sgnu * f64::EPSILON)
+                    - if self.ulim[j] == 0. { f64::EPSILON } else { 0. };
+                let llim1 = self.llim[j] * (1. + sgnl * f64::EPSILON)
+                    + if self.llim[j] == 0. { f64::EPSILON } else { 0. };
+                if self.qulim[j] && self.wa2[j] >= ulim1 {
+                    self.wa2[j] = self.ulim[j];
+                }
+                if self.qllim[j] && self.wa2[j] <= llim1 {
+                    self.wa2[j] = self.llim[j];
+                }
+            }
+        }
+        /* Shorten the steps of parameters with a maximum step */
+        let mut clamped = false;
+        for j in 0..self.nfree {
+            let max_step = self.max_step[self.ifree[j]];
+            if max_step > 0. && self.wa1[j].abs() > max_step {
+                self.wa1[j] = max_step.copysign(self.wa1[j]);
+                self.wa2[j] = self.x[j] + self.wa1[j];
+                clamped = true;
+            }
+        }
+        for j in 0..self.nfree {
+            self.wa3[j] = self.diag[self.ifree[j]] * self.wa1[j];
+        }
+        let pnorm = self.wa3[0..self.nfree].enorm();
+        /*
+         *	    on the first iteration, adjust the initial step bound.
+         */
+        if self.iter == 1 {
+            self.delta = self.delta.min(pnorm);
+        }
+        /*
+         *	    evaluate the function at x + p and calculate its norm.
+         */
+        for i in 0..self.nfree {
+            self.xnew[self.ifree[i]] = self.wa2[i];
+        }
+        self.f.eval(&self.xnew, &mut self.wa4)?;
+        self.nfev += 1;
+        self.fnorm1 = self.wa4[0..self.m].enorm_with(self.cfg.compensated_sum);
+        /*
+         *	    compute the scaled actual reduction.
+         */
+        let actred = if 0.1 * self.fnorm1 < self.fnorm {
+            let temp = self.fnorm1 / self.fnorm;
+            1.0 - temp * temp
+        } else {
+            -1.0
+        };
+        /*
+         *	    compute the scaled predicted reduction and
+         *	    the scaled directional derivative.
+         */
+        let mut jj = 0;
+        for j in 0..self.nfree {
+            self.wa3[j] = 0.;
+            let l = self.ipvt[j];
+            let temp = self.wa1[l];
+            for (i, fjac) in self.fjac[jj..=jj + j].iter().enumerate() {
+                self.wa3[i] += fjac * temp;
+            }
+            jj += self.m;
+        }
+        /*
+         * Remember, alpha is the fraction of the full LM step actually
+         * taken
+         */
+        let temp1 = self.wa3[0..self.nfree].enorm() * alpha / self.fnorm;
+        let temp2 = ((alpha * self.par).sqrt() * pnorm) / self.fnorm;
+        let temp11 = temp1 * temp1;
+        let temp22 = temp2 * temp2;
+        let mut prered = temp11 + temp22 / 0.5;
+        if clamped {
+            /* The shortened step is no LM step, use the linear model directly */
+            let mut sum = 0.;
+            for j in 0..self.nfree {
+                sum += self.qtf[j] * self.qtf[j] - (self.qtf[j] + self.wa3[j]).powi(2);
+            }
+            prered = sum / (self.fnorm * self.fnorm);
+        }
+        let dirder = -(temp11 + temp22);
+        /*
+         *	    compute the ratio of the actual to the predicted
+         *	    reduction.
+         */
+        let ratio = if prered != 0. { actred / prered } else { 0. };
+        /*
+         *	    update the step bound.
+         */
+        // Rejected steps always shrink the step bound, otherwise the same step
+        // would be retried forever for a large min_ratio
+        if ratio <= self.cfg.min_ratio.max(0.25) {
+            let mut temp = if actred >= 0. {
+                0.5
+            } else {
+                0.5 * dirder / (dirder + 0.5 * actred)
+            };
+            if 0.1 * self.fnorm1 >= self.fnorm || temp < 0.1 {
+                temp = 0.1;
+            }
+            self.delta = temp * self.delta.min(pnorm / 0.1);
+            self.par /= temp;
+        } else if self.par == 0. || ratio >= 0.75 {
+            self.delta = pnorm / 0.5;
+            self.par *= 0.5;
+        }
+        /*
+         *	    test for successful iteration.
+         */
+        if ratio >= self.cfg.min_ratio {
+            /*
+             *	    successful iteration. update x, fvec, and their norms.
+             */
+            for j in 0..self.nfree {
+                self.x[j] = self.wa2[j];
+                self.wa2[j] = self.diag[self.ifree[j]] * self.x[j];
+            }
+            for i in 0..self.m {
+                self.fvec[i] = self.wa4[i];
+            }
+            self.xnorm = self.wa2[0..self.nfree].enorm();
+            self.fnorm = self.fnorm1;
+            self.iter += 1;
+            self.rejects = 0;
+        } else {
+            self.rejects += 1;
+        }
+        /*
+         *	    tests for convergence.
+         */
+        if actred.abs() <= self.cfg.ftol && prered <= self.cfg.ftol && 0.5 * ratio <= 1.0 {
+            self.info = Success::Chi;
+        }
+        if self.delta <= self.cfg.xtol * self.xnorm {
+            self.info = Success::Par;
+        }
+        if ratio >= self.cfg.min_ratio
+            && self.cfg.xtol_abs > 0.
+            && self.wa1[0..self.nfree]
+                .iter()
+                .all(|step| step.abs() <= self.cfg.xtol_abs)
+        {
+            self.info = Success::Par;
+        }
+        if actred.abs() <= self.cfg.ftol
+            && prered <= self.cfg.ftol
+            && 0.5 * ratio <= 1.0
+            && self.info == Success::Par
+        {
+            self.info = Success::Both;
+        }
+        if self.cfg.require_all_criteria && matches!(self.info, Success::Chi | Success::Par) {
+            self.info = Success::NotDone;
+        }
+        if self.info != Success::NotDone {
+            return Ok(MPDone::Exit);
+        }
+        /*
+         *	    tests for termination and stringent tolerances.
+         */
+        if self.cfg.max_fev > 0 && self.nfev >= self.cfg.max_fev {
+            /* Too many function evaluations */
+            self.info = Success::MaxIter;
+        }
+        if self.iter >= self.cfg.max_iter {
+            /* Too many iterations */
+            self.info = Success::MaxIter;
+        }
+        if self.cfg.max_consecutive_rejects > 0 && self.rejects >= self.cfg.max_consecutive_rejects
+        {
+            self.info = Success::MaxRejects;
+        }
+        if actred.abs() <= f64::EPSILON && prered <= f64::EPSILON && 0.5 * ratio <= 1.0 {
+            self.info = Success::Ftol;
+        }
+        if self.delta <= f64::EPSILON * self.xnorm {
+            self.info = Success::Xtol;
+        }
+        if gnorm <= f64::EPSILON {
+            self.info = Success::Gtol;
+        }
+        if self.info != Success::NotDone {
+            return Ok(MPDone::Exit);
+        }
+        if ratio < self.cfg.min_ratio {
+            Ok(MPDone::Inner)
+        } else {
+            Ok(MPDone::Outer)
+        }
+    }
+
+    fn check_config(&self) -> Result<(), E> {
+        if self.cfg.ftol <= 0.
+            || self.cfg.xtol <= 0.
+            || self.cfg.gtol <= 0.
+            || self.cfg.xtol_abs < 0.
+            || !(0. ..1.).contains(&self.cfg.min_ratio)
+            || self.cfg.step_factor <= 0.
+            || self.cfg.limit_margin < 0.
+            || self.cfg.limit_margin.is_nan()
+            || self
+                .cfg
+                .relative
+                .is_some_and(|floor| floor <= 0. || floor.is_nan())
+        {
+            Err(Error::Input)
+        } else if self.n_unmasked() < self.nfree {
+            Err(Error::DoF)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Regularized upper incomplete gamma function Q(a, x), evaluated by its
+/// series representation for `x < a + 1` and its continued fraction otherwise.
+fn gamma_q(a: f64, x: f64) -> f64 {
+    if x <= 0. {
+        return 1.;
+    }
+    let prefactor = (a * x.ln() - x - ln_gamma(a)).exp();
+    if x < a + 1. {
+        let mut ap = a;
+        let mut del = 1. / a;
+        let mut sum = del;
+        for _ in 0..1000 {
+            ap += 1.;
+            del *= x / ap;
+            sum += del;
+            if del.abs() < sum.abs() * f64::EPSILON {
+                break;
+            }
+        }
+        1. - sum * prefactor
+    } else {
+        // modified Lentz's method
+        let tiny = f64::MIN_POSITIVE / f64::EPSILON;
+        let mut b = x + 1. - a;
+        let mut c = 1. / tiny;
+        let mut d = 1. / b;
+        let mut h = d;
+        for i in 1..1000 {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.;
+            d = an * d + b;
+            if d.abs() < tiny {
+                d = tiny;
+            }
+            c = b + an / c;
+            if c.abs() < tiny {
+                c = tiny;
+            }
+            d = 1. / d;
+            let del = d * c;
+            h *= del;
+            if (del - 1.).abs() < f64::EPSILON {
+                break;
+            }
+        }
+        prefactor * h
+    }
+}
+
+/// Natural logarithm of the gamma function (Lanczos approximation, g = 7)
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x < 0.5 {
+        // reflection formula
+        let pi = ::std::f64::consts::PI;
+        return (pi / (pi * x).sin()).ln() - ln_gamma(1. - x);
+    }
+    let x = x - 1.;
+    let mut sum = COEFFS[0];
+    for (i, c) in COEFFS.iter().enumerate().skip(1) {
+        sum += c / (x + i as f64);
+    }
+    let t = x + 7.5;
+    0.5 * (2. * ::std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
+
+/// Allocates a zero-filled vector, failing gracefully if out of memory.
+fn zeros<E>(len: usize) -> Result<Vec<f64>, E> {
+    filled(len, 0.)
+}
+
+/// Fallible allocation of a vector filled with `value`
+fn filled<T: Clone, E>(len: usize, value: T) -> Result<Vec<T>, E> {
+    let mut v = Vec::new();
+    v.try_reserve_exact(len).map_err(|_| Error::Memory)?;
+    v.resize(len, value);
+    Ok(v)
+}
+
+/// Lower bound of the peak size in bytes of the working arrays allocated by
+/// [`fit`] for `n_points` data points and `n_free` free parameters: the
+/// `n_points x n_free` Jacobian and the residual-sized work vectors of the
+/// plain finite differences. Depending on the configuration further buffers
+/// come on top, the `n_points x npar` Jacobian of [`Side::User`] or
+/// [`Problem::eval_with_jacobian`], the `n_points x n_free` residuals of
+/// [`Problem::eval_batch`], the complex residuals of [`Side::Complex`], a
+/// copy of the Jacobian for [`Config::freeze_jacobian_after`] or
+/// [`Config::return_jacobian`], and the weights of a robust [`Loss`] or
+/// [`Config::relative`]. Allocation failures are reported as
+/// [`Error::Memory`].
+pub fn working_set_size(n_points: usize, n_free: usize) -> usize {
+    n_points
+        .saturating_mul(n_free.saturating_add(3))
+        .saturating_add(n_free.saturating_mul(2))
+        .saturating_mul(::std::mem::size_of::<f64>())
+}
+
+enum MPDone {
+    Exit,
+    Inner,
+    Outer,
+}
+
+///    function enorm
+///
+///    given an n-vector x, this function calculates the
+///    euclidean norm of x.
+///
+///    the euclidean norm is computed by accumulating the sum of
+///    squares in three different sums. the sums of squares for the
+///    small and large components are scaled so that no overflows
+///    occur. non-destructive underflows are permitted. underflows
+///    and overflows do not occur in the computation of the unscaled
+///    sum of squares for the intermediate components.
+///    the definitions of small, intermediate and large components
+///    depend on two constants, rdwarf and rgiant. the main
+///    restrictions on these constants are that rdwarf**2 not
+///    underflow and rgiant**2 not overflow. the constants
+///    given here are suitable for every known computer.
+///    the function statement is
+///    double precision function enorm(n,x)
+///    where
+///
+///    n is a positive integer input variable.
+///
+///    x is an input array of length n.
+trait ENorm {
+    fn enorm(&self) -> f64 {
+        self.enorm_with(false)
+    }
+
+    /// `compensated` enables compensated summation of the intermediate
+    /// components.
+    fn enorm_with(&self, compensated: bool) -> f64;
+}
+
+impl ENorm for [f64] {
+    fn enorm_with(&self, compensated: bool) -> f64 {
+        let mut s1 = 0.;
+        let mut s2 = 0.;
+        let mut s3 = 0.;
+        let mut c2 = 0.;
+        let mut x1max = 0.;
+        let mut x3max = 0.;
+        let agiant = MP_RGIANT / self.len() as f64;
+        for val in self {
+            let xabs = val.abs();
+            if xabs > MP_RDWARF && xabs < agiant {
+                // sum for intermediate components.
+                let sq = xabs * xabs;
+                if compensated {
+                    let t = s2 + sq;
+                    c2 += if s2 >= sq {
+                        (s2 - t) + sq
+                    } else {
+                        (sq - t) + s2
+                    };
+                    s2 = t;
+                } else {
+                    s2 += sq;
+                }
+            } else if xabs > MP_RDWARF {
+                // sum for large components.
+                if xabs > x1max {
+                    let temp = x1max / xabs;
+                    s1 = 1.0 + s1 * temp * temp;
+                    x1max = xabs;
+                } else {
+                    let temp = xabs / x1max;
+                    s1 += temp * temp;
+                }
+            } else if xabs > x3max {
+                // sum for small components.
+                let temp = x3max / xabs;
+                s3 = 1.0 + s3 * temp * temp;
+                x3max = xabs;
+            } else if xabs != 0.0 {
+                let temp = xabs / x3max;
+                s3 += temp * temp;
+            }
+        }
+        s2 += c2;
+        // calculation of norm.
+        if s1 != 0.0 {
+            x1max * (s1 + (s2 / x1max) / x1max).sqrt()
+        } else if s2 != 0.0 {
+            if s2 >= x3max {
+                s2 * (1.0 + (x3max / s2) * (x3max * s3))
+            } else {
+                x3max * ((s2 / x3max) + (x3max * s3))
+            }
+            .sqrt()
+        } else {
+            x3max * s3.sqrt()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bootstrap, chi_square, clamp_to_bounds, clamp_variances, combine_covariance, curve_fit,
+        expect_success, fit, fit_fixed, fit_from, fit_linear, fit_sparse, fit_streaming,
+        fit_with_retry, gamma_q, invert, is_feasible, limit_step, multistart, profile, sensitivity,
+        validate, working_set_size, Bootstrap, Complex, Config, ConfigBuilder, Correlated, Curve,
+        ENorm, Error, Fit, FitReport, Information, Iteration, Limits, Loss, Masked, MultiStart,
+        ParamConfig, Parameters, Problem, Progress, ProgressBar, Reparameterized, Result, Side,
+        Status, Success, Weighted, ZeroColumn,
+    };
+    use assert_approx_eq::assert_approx_eq;
+    use std::f64::consts::{LN_2, PI};
+
+    struct Linear {
+        x: Vec<f64>,
+        y: Vec<f64>,
+        ye: Vec<f64>,
+    }
+
+    impl Problem<2> for Linear {
+        fn eval(&self, [a, b]: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+            for (((d, &x), y), ye) in deviates
+                .iter_mut()
+                .zip(self.x.iter())
+                .zip(self.y.iter())
+                .zip(self.ye.iter())
+            {
+                let f = a + b * x;
+                *d = (*y - f) / *ye;
+            }
+            Ok(())
+        }
+
+        fn number_of_points(&self) -> usize {
+            self.x.len()
+        }
+    }
+
+    fn linear_problem() -> Linear {
+        Linear {
+            x: vec![
+                -1.7237128E+00,
+                1.8712276E+00,
+                -9.6608055E-01,
+                -2.8394297E-01,
+                1.3416969E+00,
+                1.3757038E+00,
+                -1.3703436E+00,
+                4.2581975E-02,
+                -1.4970151E-01,
+                8.2065094E-01,
+            ],
+            y: vec![
+                1.9000429E-01,
+                6.5807428E+00,
+                1.4582725E+00,
+                2.7270851E+00,
+                5.5969253E+00,
+                5.6249280E+00,
+                0.787615,
+                3.2599759E+00,
+                2.9771762E+00,
+                4.5936475E+00,
+            ],
+            ye: vec![0.07; 10],
+        }
+    }
+
+    #[test]
+    fn linear() {
+        let l = linear_problem();
+        let mut init = [1., 1.];
+        let status = fit(&l, &mut init, Default::default(), Default::default())
+            .expect("Error in Linear fit");
+        assert_eq!(status.success, Success::Chi);
+        assert_eq!(status.n_iter, 3);
+        assert_eq!(status.n_fev, 7);
+        assert_approx_eq!(status.best_norm, 2.75628498);
+        assert_approx_eq!(init[0], 3.20996572);
+        assert_approx_eq!(init[1], 1.77095420);
+        assert_approx_eq!(status.xerror[0], 0.02221018);
+        assert_approx_eq!(status.xerror[1], 0.01893756);
+    }
+
+    #[test]
+    fn linear_covariance() {
+        // Closed form of the straight line: [S_xx, -S_x; -S_x, S] / D with
+        // the weighted sums S = sum 1/ye^2, S_x = sum x/ye^2, S_xx = sum x^2/ye^2
+        // and D = S S_xx - S_x^2
+        let l = linear_problem();
+        let status = fit_from(&l, &[1., 1.], Default::default(), Default::default())
+            .expect("Error in Linear fit");
+        let (mut s, mut sx, mut sxx) = (0., 0., 0.);
+        for (x, ye) in l.x.iter().zip(&l.ye) {
+            let w = 1. / (ye * ye);
+            s += w;
+            sx += w * x;
+            sxx += w * x * x;
+        }
+        let d = s * sxx - sx * sx;
+        let expected = [sxx / d, -sx / d, -sx / d, s / d];
+        for (actual, expected) in status.covar.iter().zip(expected) {
+            assert_approx_eq!(actual, expected, 1e-6 * expected.abs());
+        }
+    }
+
+    #[test]
+    fn xerror() {
+        let l = linear_problem();
+        let status = fit_from(&l, &[1., 1.], Default::default(), Default::default())
+            .expect("Error in Linear fit");
+        for i in 0..2 {
+            assert_eq!(status.xerror[i], status.covar[i * 2 + i].sqrt());
+        }
+
+        let pars = [
+            ParamConfig {
+                fixed: true,
+                ..Default::default()
+            },
+            ParamConfig::default(),
+        ];
+        let status = fit_from(&l, &[1., 1.], pars, Default::default()).expect("Error in fit");
+        assert_eq!(status.xerror[0], 0.);
+        assert_eq!(status.xerror[1], status.covar[3].sqrt());
+        assert!(status.xerror[1] > 0.);
+    }
+
+    #[test]
+    fn config_builder() {
+        assert_eq!(Config::builder().build(), Config::default());
+        let config = Config::builder()
+            .ftol(1e-12)
+            .max_iter(50)
+            .max_fev(100)
+            .freeze_jacobian_after(Some(2))
+            .build();
+        let expected = Config {
+            ftol: 1e-12,
+            max_iter: 50,
+            max_fev: 100,
+            freeze_jacobian_after: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(config, expected);
+    }
+
+    #[test]
+    fn param_config_constructors() {
+        let default = ParamConfig::default();
+        assert_eq!(
+            ParamConfig::fixed(),
+            ParamConfig {
+                fixed: true,
+                ..default
+            }
+        );
+        assert_eq!(ParamConfig::bounded(0., 1.).limits, Limits::Both(0., 1.));
+        assert_eq!(ParamConfig::lower_bound(0.).limits, Limits::Lower(0.));
+        assert_eq!(ParamConfig::upper_bound(1.).limits, Limits::Upper(1.));
+        assert_eq!(
+            ParamConfig::with_step(1e-3),
+            ParamConfig {
+                step_scale: 1e-3,
+                ..default
+            }
+        );
+
+        let pars = [ParamConfig::fixed(), ParamConfig::lower_bound(0.)];
+        let status =
+            fit_from(&linear_problem(), &[3., 1.], pars, Default::default()).expect("Error in fit");
+        assert_eq!(status.params[0], 3.);
+        assert_eq!(status.n_free, 1);
+    }
+
+    #[test]
+    fn curve_fit_linear() {
+        let l = linear_problem();
+        let mut params = [1., 1.];
+        let model = |[a, b]: &[f64; 2], x: f64| a + b * x;
+        let status = curve_fit(
+            model,
+            &l.x,
+            &l.y,
+            Some(&l.ye),
+            &mut params,
+            Default::default(),
+            Default::default(),
+        )
+        .expect("Error in curve fit");
+        let mut init = [1., 1.];
+        let expected = fit(&l, &mut init, Default::default(), Default::default())
+            .expect("Error in Linear fit");
+        assert_eq!(params, init);
+        assert_eq!(status, expected);
+
+        let res = curve_fit(
+            model,
+            &l.x,
+            &l.y[1..],
+            None,
+            &mut params,
+            Default::default(),
+            Default::default(),
+        );
+        assert_eq!(res, Err(Error::Input));
+    }
+
+    #[test]
+    fn masked() {
+        let mut l = linear_problem();
+        l.y[2] += 50.;
+        l.y[7] -= 30.;
+        let mut mask = [false; 10];
+        mask[2] = true;
+        mask[7] = true;
+        let masked = fit_from(
+            &Masked::new(&l, &mask),
+            &[1., 1.],
+            Default::default(),
+            Default::default(),
+        )
+        .expect("Error in masked fit");
+
+        let keep = |v: &[f64]| -> Vec<f64> {
+            (v.iter().zip(&mask))
+                .filter(|(_, &m)| !m)
+                .map(|(&v, _)| v)
+                .collect()
+        };
+        let removed = Linear {
+            x: keep(&l.x),
+            y: keep(&l.y),
+            ye: keep(&l.ye),
+        };
+        let expected = fit_from(&removed, &[1., 1.], Default::default(), Default::default())
+            .expect("Error in Linear fit");
+        assert_eq!(masked.n_func, 8);
+        assert_eq!(masked.dof(), expected.dof());
+        assert_eq!(masked.resid[2], 0.);
+        assert_eq!(masked.resid[7], 0.);
+        assert_approx_eq!(masked.best_norm, expected.best_norm, 1e-8);
+        for i in 0..2 {
+            assert_approx_eq!(masked.params[i], expected.params[i], 1e-8);
+            assert_approx_eq!(masked.xerror[i], expected.xerror[i], 1e-8);
+        }
+
+        let streamed = fit_streaming(
+            &Masked::new(&l, &mask),
+            &mut [1., 1.],
+            Default::default(),
+            Config {
+                chunk_size: 3,
+                ..Default::default()
+            },
+        )
+        .expect("Error in streaming fit");
+        assert_eq!(streamed.n_func, 8);
+        for i in 0..2 {
+            assert_approx_eq!(streamed.params[i], expected.params[i], 1e-6);
+        }
+
+        // A single unmasked point leaves no degrees of freedom for two
+        // parameters, also through the other adapters
+        let mut mask = [true; 10];
+        mask[4] = false;
+        let masked = Masked::new(&l, &mask);
+        let res = fit_from(&masked, &[1., 1.], Default::default(), Default::default());
+        assert_eq!(res, Err(Error::DoF));
+        let res = validate(&masked, &[1., 1.], Default::default(), Default::default());
+        assert_eq!(res, Err(Error::DoF));
+        let config = Config {
+            chunk_size: 3,
+            ..Default::default()
+        };
+        let res = fit_streaming(&masked, &mut [1., 1.], Default::default(), config);
+        assert_eq!(res, Err(Error::DoF));
+        let identity: Vec<f64> = (0..100)
+            .map(|i| if i % 11 == 0 { 1. } else { 0. })
+            .collect();
+        let correlated = Correlated::new(&masked, &identity);
+        let res = fit_from(
+            &correlated,
+            &[1., 1.],
+            Default::default(),
+            Default::default(),
+        );
+        assert_eq!(res, Err(Error::DoF));
+
+        // Extra mask entries do not mask anything and are rejected
+        let mut mask = [false; 20];
+        mask[10..].fill(true);
+        let masked = Masked::new(&l, &mask);
+        assert_eq!(Problem::<2>::number_of_masked_points(&masked), 0);
+        let res = fit_from(&masked, &[1., 1.], Default::default(), Default::default());
+        assert_eq!(res, Err(Error::Input));
+        let res = fit_streaming(&masked, &mut [1., 1.], Default::default(), config);
+        assert_eq!(res, Err(Error::Input));
+    }
+
+    #[test]
+    fn bootstrap_errors() {
+        let l = linear_problem();
+        let best = fit_from(&l, &[1., 1.], Default::default(), Default::default())
+            .expect("Error in Linear fit");
+        let boot = bootstrap(&l, &best, Default::default(), Default::default(), 500, 42)
+            .expect("Error in bootstrap");
+        assert_eq!(boot.samples.len(), 500);
+        let again = bootstrap(&l, &best, Default::default(), Default::default(), 500, 42)
+            .expect("Error in bootstrap");
+        assert_eq!(boot, again);
+
+        // The resampled residuals carry the actual scatter of the data
+        let scale = best.reduced_chi2().unwrap().sqrt();
+        for i in 0..2 {
+            let expected = best.xerror[i] * scale;
+            assert!((boot.std[i] - expected).abs() < 0.15 * expected);
+        }
+        let (lower, median, upper) = (
+            boot.percentile(16.).unwrap(),
+            boot.percentile(50.).unwrap(),
+            boot.percentile(84.).unwrap(),
+        );
+        for i in 0..2 {
+            assert!(lower[i] < median[i] && median[i] < upper[i]);
+            assert_approx_eq!(median[i], best.params[i], boot.std[i] / 2.);
+        }
+
+        let res = bootstrap(&l, &best, Default::default(), Default::default(), 1, 42);
+        assert_eq!(res, Err(Error::Input));
+
+        let empty = Bootstrap::<2> {
+            samples: Vec::new(),
+            std: [0.; 2],
+        };
+        assert_eq!(empty.percentile(50.), None);
+    }
+
+    #[test]
+    fn bootstrap_masked() {
+        let l = linear_problem();
+        let mut mask = vec![false; l.x.len()];
+        mask[2] = true;
+        mask[7] = true;
+        // Outliers at the masked points must not matter
+        let mut y = l.y.clone();
+        y[2] += 100.;
+        y[7] -= 100.;
+        let model = |[a, b]: &[f64; 2], x: f64| a + b * x;
+        let curve = Curve::new(model, &l.x, &y, Some(&l.ye));
+        let masked = Masked::new(&curve, &mask);
+        let best = fit_from(&masked, &[1., 1.], Default::default(), Default::default())
+            .expect("Error in masked fit");
+        assert_eq!(best.n_func, 8);
+        let boot = bootstrap(
+            &masked,
+            &best,
+            Default::default(),
+            Default::default(),
+            200,
+            7,
+        )
+        .expect("Error in bootstrap");
+
+        // Same draws as from the data without the masked points
+        let keep = |v: &[f64]| -> Vec<f64> {
+            v.iter()
+                .zip(&mask)
+                .filter(|(_, &m)| !m)
+                .map(|(v, _)| *v)
+                .collect()
+        };
+        let (x, y, ye) = (keep(&l.x), keep(&l.y), keep(&l.ye));
+        let reduced = Curve::new(model, &x, &y, Some(&ye));
+        let best = fit_from(&reduced, &[1., 1.], Default::default(), Default::default())
+            .expect("Error in reduced fit");
+        let expected = bootstrap(
+            &reduced,
+            &best,
+            Default::default(),
+            Default::default(),
+            200,
+            7,
+        )
+        .expect("Error in bootstrap");
+        for (sample, expected) in boot.samples.iter().zip(&expected.samples) {
+            for i in 0..2 {
+                assert_approx_eq!(sample[i], expected[i], 1e-8);
+            }
+        }
+
+        // The model is forwarded for relative residuals
+        let config = Config {
+            relative: Some(1e-3),
+            ..Default::default()
+        };
+        let best = fit_from(&masked, &[1., 1.], Default::default(), config)
+            .expect("Error in relative fit");
+        bootstrap(&masked, &best, Default::default(), config, 10, 7)
+            .expect("Error in relative bootstrap");
+
+        // An unmasked point fitted exactly is still drawn
+        let (x, y) = ([0., 1., 2., 3.], [1., 2., 3., 10.]);
+        let offset = |&[a]: &[f64; 1], _: f64| a;
+        let mask = [false, false, false, true];
+        let curve = Curve::new(offset, &x, &y, None);
+        let masked = Masked::new(&curve, &mask);
+        assert!(Problem::<1>::is_masked(&masked, 3) && !Problem::<1>::is_masked(&masked, 1));
+        let mut best = fit_from(&masked, &[0.], Default::default(), Default::default())
+            .expect("Error in masked fit");
+        assert_approx_eq!(best.params[0], 2., 1e-12);
+        best.params = [2.];
+        let boot = bootstrap(
+            &masked,
+            &best,
+            Default::default(),
+            Default::default(),
+            50,
+            3,
+        )
+        .expect("Error in bootstrap");
+        let reduced = Curve::new(offset, &x[..3], &y[..3], None);
+        let expected = bootstrap(
+            &reduced,
+            &best,
+            Default::default(),
+            Default::default(),
+            50,
+            3,
+        )
+        .expect("Error in bootstrap");
+        for (sample, expected) in boot.samples.iter().zip(&expected.samples) {
+            assert_approx_eq!(sample[0], expected[0], 1e-12);
+        }
+    }
+
+    #[test]
+    fn linear_bit_exact() {
+        let l = linear_problem();
+        let mut init = [1., 1.];
+        let status = fit(&l, &mut init, Default::default(), Default::default())
+            .expect("Error in Linear fit");
+        // Exact values, which have to be identical in debug and release builds
+        assert_eq!(init, [3.2099657168398106, 1.7709542025410758]);
+        assert_eq!(status.best_norm, 2.7562849828129674);
+        assert_eq!(status.xerror, [0.022210176366394878, 0.018937556624987077]);
+        assert_eq!(
+            *status.covar,
+            [
+                0.0004932919342263656,
+                -3.435971563685362e-5,
+                -3.435971563685362e-5,
+                0.0003586310509245919
+            ]
+        );
+    }
+
+    #[test]
+    fn xtol_abs() {
+        // Offset converging to zero
+        let mut l = linear_problem();
+        for y in l.y.iter_mut() {
+            *y -= 3.2099657168398106;
+        }
+        let config = Config {
+            ftol: 1e-300,
+            xtol: 1e-300,
+            ..Default::default()
+        };
+        let mut init = [1., 1.];
+        let status = fit(&l, &mut init, Default::default(), config).expect("Error in Linear fit");
+        assert!(status.success.is_warning());
+        let n_iter = status.n_iter;
+        let config = Config {
+            xtol_abs: 1e-6,
+            ..config
+        };
+        let mut init = [1., 1.];
+        let status = fit(&l, &mut init, Default::default(), config).expect("Error in Linear fit");
+        assert_eq!(status.success, Success::Par);
+        assert!(status.n_iter < n_iter);
+        assert!(init[0].abs() < 1e-6);
+        assert_approx_eq!(init[1], 1.77095420);
+
+        let config = Config {
+            xtol_abs: -1.,
+            ..config
+        };
+        let res = fit(&l, &mut init, Default::default(), config);
+        assert_eq!(res, Err(Error::Input));
+    }
+
+    #[test]
+    fn freeze_jacobian() {
+        struct NearlyLinear(Linear);
+        impl Problem<2> for NearlyLinear {
+            fn eval(&self, [a, b]: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                let l = &self.0;
+                for (((d, x), y), ye) in deviates.iter_mut().zip(&l.x).zip(&l.y).zip(&l.ye) {
+                    let f = a + b * x + 0.001 * b * b * x * x;
+                    *d = (y - f) / ye;
+                }
+                Ok(())
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.number_of_points()
+            }
+        }
+
+        let l = NearlyLinear(linear_problem());
+        let mut init = [1., 1.];
+        let full =
+            fit(&l, &mut init, Default::default(), Default::default()).expect("Error in fit");
+        let frozen = |after| {
+            let config = Config {
+                freeze_jacobian_after: Some(after),
+                ..Default::default()
+            };
+            fit_from(&l, &[1., 1.], Default::default(), config).expect("Error in fit")
+        };
+        // Only the Jacobian at the start point
+        let start = frozen(0);
+        assert!(2 * start.n_fev < full.n_fev);
+        assert!((start.best_norm - full.best_norm).abs() < 1e-6 * full.best_norm);
+
+        // Counted analytical Jacobians of the linear model
+        struct Counted(Linear, ::std::cell::Cell<usize>);
+        impl Problem<2> for Counted {
+            fn eval(&self, params: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                self.0.eval(params, deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.number_of_points()
+            }
+
+            fn jacobian(&self, _: &[f64; 2], jacobian: &mut [f64]) -> Result<()> {
+                self.1.set(self.1.get() + 1);
+                let (da, db) = jacobian.split_at_mut(self.0.x.len());
+                for (i, (x, ye)) in self.0.x.iter().zip(&self.0.ye).enumerate() {
+                    da[i] = -1. / ye;
+                    db[i] = -x / ye;
+                }
+                Ok(())
+            }
+        }
+
+        let user = ParamConfig {
+            side: Side::User,
+            ..Default::default()
+        };
+        let count = |after| {
+            let c = Counted(linear_problem(), Default::default());
+            let config = Config {
+                freeze_jacobian_after: after,
+                ..Default::default()
+            };
+            let status = fit_from(&c, &[1., 1.], [user; 2], config).expect("Error in fit");
+            assert_eq!(status.n_iter, 2);
+            c.1.get()
+        };
+        assert_eq!(count(None), 2);
+        assert_eq!(count(Some(0)), 1);
+        assert_eq!(count(Some(1)), 2);
+    }
+
+    #[test]
+    fn empty() {
+        // All data filtered out at runtime
+        struct Filtered(Linear);
+        impl Problem<2> for Filtered {
+            fn eval(&self, params: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                self.0.eval(params, deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.x.iter().filter(|x| x.is_nan()).count()
+            }
+        }
+
+        let f = Filtered(linear_problem());
+        let mut init = [1., 1.];
+        let res = fit(&f, &mut init, Default::default(), Default::default());
+        assert_eq!(res, Err(Error::Empty));
+        assert_eq!(init, [1., 1.]);
+        assert_eq!(chi_square(&f, &init), Err(Error::Empty));
+        assert_eq!(
+            Error::<::std::convert::Infallible>::Empty.to_string(),
+            "no user data points were supplied (zero points reported)"
+        );
+    }
+
+    #[test]
+    fn absolute_loss() {
+        // Constant with outliers on one side only
+        struct Constant(Vec<f64>);
+        impl Problem<1> for Constant {
+            fn eval(&self, [c]: &[f64; 1], deviates: &mut [f64]) -> Result<()> {
+                for (d, y) in deviates.iter_mut().zip(&self.0) {
+                    *d = y - c;
+                }
+                Ok(())
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.len()
+            }
+        }
+
+        let f = Constant(vec![0.9, 1.0, 1.05, 1.1, 1.2, 10., 12.]);
+        let mut init = [0.];
+        let squared =
+            fit(&f, &mut init, Default::default(), Default::default()).expect("Error in fit");
+        let config = Config {
+            loss: Loss::Absolute,
+            ..Default::default()
+        };
+        let mut init = [0.];
+        let absolute = fit(&f, &mut init, Default::default(), config).expect("Error in fit");
+        // Least squares gives the mean, least absolute deviations the median
+        assert_approx_eq!(squared.params[0], 3.89285714);
+        assert_approx_eq!(absolute.params[0], 1.1);
+        assert_approx_eq!(absolute.best_norm, 20.25);
+        assert_approx_eq!(absolute.resid[6], 10.9);
+    }
+
+    #[test]
+    fn expect_success_converged() {
+        let l = linear_problem();
+        let mut init = [1., 1.];
+        let status = expect_success(fit(&l, &mut init, Default::default(), Default::default()));
+        assert_eq!(status.success, Success::Chi);
+    }
+
+    #[test]
+    #[should_panic(expected = "fit failed: general input parameter error")]
+    fn expect_success_error() {
+        let l = linear_problem();
+        let config = Config {
+            ftol: -1.,
+            ..Default::default()
+        };
+        let _ = expect_success(fit_from(&l, &[1., 1.], Default::default(), config));
+    }
+
+    #[test]
+    #[should_panic(expected = "fit did not converge (maximum number of iterations reached)")]
+    fn expect_success_max_iter() {
+        let l = linear_problem();
+        let config = Config {
+            max_iter: 1,
+            ..Default::default()
+        };
+        let _ = expect_success(fit_from(&l, &[1., 1.], Default::default(), config));
+    }
+
+    #[test]
+    fn min_ratio() {
+        struct Counter {
+            inner: Gaussian,
+            evals: std::cell::Cell<usize>,
+            outer: std::cell::Cell<usize>,
+        }
+
+        impl Problem<4> for Counter {
+            fn eval(&self, params: &[f64; 4], deviates: &mut [f64]) -> Result<()> {
+                self.evals.set(self.evals.get() + 1);
+                self.inner.eval(params, deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.inner.number_of_points()
+            }
+
+            fn iteration(&self, _: &Iteration<4>) {
+                self.outer.set(self.outer.get() + 1);
+            }
+        }
+
+        let rejected = |min_ratio| {
+            let f = Counter {
+                inner: gaussian_problem(),
+                evals: Default::default(),
+                outer: Default::default(),
+            };
+            let config = Config {
+                min_ratio,
+                ..Default::default()
+            };
+            let status =
+                fit_from(&f, &[0., 1., 1., 1.], Default::default(), config).expect("Error in fit");
+            // One evaluation at the start, one per free parameter for the
+            // Jacobian and one per trial step
+            let trials = f.evals.get() - 1 - 4 * f.outer.get();
+            assert_approx_eq!(status.best_norm, 10.35003196);
+            trials - (status.n_iter - 1)
+        };
+        let default = rejected(1e-4);
+        let strict = rejected(0.5);
+        assert!(default < strict);
+        assert!(strict < rejected(0.9));
+
+        let config = Config {
+            min_ratio: 1.,
+            ..Default::default()
+        };
+        let res = fit_from(
+            &gaussian_problem(),
+            &[0., 1., 1., 1.],
+            Default::default(),
+            config,
+        );
+        assert_eq!(res, Err(Error::Input));
+    }
+
+    /// Linear in the parameters with column norms decreasing against the
+    /// parameter order, the second parameter is unused
+    struct Quadratic(Linear);
+    impl Problem<4> for Quadratic {
+        fn eval(&self, [a, _, b, c]: &[f64; 4], deviates: &mut [f64]) -> Result<()> {
+            let l = &self.0;
+            for (((d, x), y), ye) in deviates.iter_mut().zip(&l.x).zip(&l.y).zip(&l.ye) {
+                let f = 0.01 * a + b * x + 100. * c * x * x;
+                *d = (y - f) / ye;
+            }
+            Ok(())
+        }
+
+        fn number_of_points(&self) -> usize {
+            self.0.number_of_points()
+        }
+    }
+
+    #[test]
+    fn zero_column() {
+        let q = Quadratic(linear_problem());
+        let start = [1., 5., 1., 0.];
+        let warn =
+            fit_from(&q, &start, Default::default(), Default::default()).expect("Error in fit");
+        assert_eq!(warn.insensitive, [false, true, false, false]);
+        assert_eq!(warn.n_free, 4);
+        assert_eq!(warn.params[1], 5.);
+        assert_eq!(warn.xerror[1], 0.);
+
+        let config = Config {
+            zero_column: ZeroColumn::Error,
+            ..Default::default()
+        };
+        let result = fit_from(&q, &start, Default::default(), config);
+        assert_eq!(result.unwrap_err(), Error::Insensitive(1));
+
+        let config = Config {
+            zero_column: ZeroColumn::Fix,
+            ..Default::default()
+        };
+        let fixed = fit_from(&q, &start, Default::default(), config).expect("Error in fit");
+        assert_eq!(fixed.insensitive, warn.insensitive);
+        assert_eq!(fixed.n_free, 3);
+        assert_eq!(fixed.params[1], 5.);
+        for i in [0, 2, 3] {
+            assert_approx_eq!(fixed.params[i], warn.params[i], 1e-6);
+            assert_approx_eq!(fixed.xerror[i], warn.xerror[i], 1e-6);
+        }
+
+        // The frozen Jacobian is compacted as well, the model is linear so it
+        // stays exact
+        let config = Config {
+            zero_column: ZeroColumn::Fix,
+            freeze_jacobian_after: Some(0),
+            ..Default::default()
+        };
+        let frozen = fit_from(&q, &start, Default::default(), config).expect("Error in fit");
+        assert_eq!(frozen.n_free, 3);
+        for i in [0, 2, 3] {
+            let (p, e) = (fixed.params[i], fixed.xerror[i]);
+            assert_approx_eq!(frozen.params[i], p, 1e-6 * p.abs().max(1.));
+            assert_approx_eq!(frozen.xerror[i], e, 1e-6 * e.abs().max(1.));
+        }
+    }
+
+    #[test]
+    fn negative_variance() {
+        let mut covar = [-1e-20, 0.5, 0.5, 1.];
+        assert!(clamp_variances(&mut covar, 2));
+        assert_eq!(covar, [0., 0.5, 0.5, 1.]);
+        assert!(!clamp_variances(&mut covar, 2));
+
+        // Both parameters enter (almost) only as their sum
+        struct Degenerate(Linear);
+
+        impl Problem<2> for Degenerate {
+            fn eval(&self, [a, b]: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                self.0.eval(&[a + (1. + 1e-12) * b, 1.], deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.number_of_points()
+            }
+        }
+
+        let degenerate = Degenerate(linear_problem());
+        let status = fit_from(
+            &degenerate,
+            &[1., 1.],
+            Default::default(),
+            Default::default(),
+        )
+        .expect("Error in fit");
+        for i in 0..2 {
+            assert!(status.xerror[i].is_finite());
+            assert!(status.covar[i * 2 + i] >= 0.);
+        }
+
+        // The inverted normal equations lose the sign of the variances
+        let config = Config {
+            chunk_size: 5,
+            ..Default::default()
+        };
+        let streamed = fit_streaming(&degenerate, &mut [1., 1.], Default::default(), config)
+            .expect("Error in streaming fit");
+        assert!(streamed.negative_variance);
+        assert_eq!(streamed.xerror, [0., 0.]);
+        assert!(!streamed.is_reliable());
+
+        let regular = fit_streaming(&linear_problem(), &mut [1., 1.], Default::default(), config)
+            .expect("Error in streaming fit");
+        assert!(!regular.negative_variance);
+        assert!(regular.xerror.iter().all(|&e| e > 0.));
+    }
+
+    #[test]
+    fn zero_covariance() {
+        // The residuals depend on neither parameter
+        struct Constant(Linear);
+
+        impl Problem<2> for Constant {
+            fn eval(&self, _: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                self.0.eval(&[1., 1.], deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.number_of_points()
+            }
+        }
+
+        let status = fit_from(
+            &Constant(linear_problem()),
+            &[1., 1.],
+            Default::default(),
+            Default::default(),
+        )
+        .expect("Error in fit");
+        assert!(status.zero_covariance);
+        assert_eq!(status.rank, 0);
+        assert_eq!(*status.covar, [0.; 4]);
+        assert_eq!(status.xerror, [0.; 2]);
+        assert!(!status.is_reliable());
+
+        let status = fit_from(
+            &linear_problem(),
+            &[1., 1.],
+            Default::default(),
+            Default::default(),
+        )
+        .expect("Error in fit");
+        assert!(!status.zero_covariance);
+    }
+
+    /// Problem with `m` zero residuals, to drive the linear algebra of [`Fit`]
+    /// with a given Jacobian
+    struct Points(usize);
+
+    impl<const N: usize> Problem<N> for Points {
+        fn eval(&self, _: &[f64; N], deviates: &mut [f64]) -> Result<()> {
+            deviates.fill(0.);
+            Ok(())
+        }
+
+        fn number_of_points(&self) -> usize {
+            self.0
+        }
+    }
+
+    /// Fit with all parameters free, the column-major m x N Jacobian `a` and
+    /// the residuals `b`
+    fn dense_fit<'a, const N: usize>(
+        problem: &'a Points,
+        xall: &'a mut [f64; N],
+        a: &[f64],
+        b: &[f64],
+    ) -> Fit<'a, N, ::std::convert::Infallible, Points> {
+        let mut fit = Fit::new(
+            problem,
+            xall,
+            &[ParamConfig::default(); N],
+            Config::default(),
+        )
+        .expect("Error in setup");
+        fit.nfree = N;
+        fit.ifree = ::std::array::from_fn(|i| i);
+        fit.fjac = a.to_vec();
+        fit.fvec = b.to_vec();
+        fit
+    }
+
+    /// Column-major 5 x 3 matrix with increasing column norms
+    const DENSE: [f64; 15] = [
+        1., 2., 0., 1., -1., 3., -1., 4., 2., 0., 0.5, 1., -2., 6., 3.,
+    ];
+
+    #[test]
+    fn qrfac() {
+        let problem = Points(5);
+        let mut xall = [0.; 3];
+        let mut fit = dense_fit(&problem, &mut xall, &DENSE, &[0.; 5]);
+        fit.qrfac();
+        assert_eq!(fit.ipvt, [2, 1, 0]);
+        let m = 5;
+        for k in 0..3 {
+            // Column k of R, with its diagonal in rdiag (wa1)
+            let mut x = [0.; 5];
+            x[..k].copy_from_slice(&fit.fjac[m * k..][..k]);
+            x[k] = fit.wa1[k];
+            // Q = H_0 H_1 H_2 from the Householder vectors below the diagonal
+            for j in (0..3).rev() {
+                let v = &fit.fjac[j + m * j..m * (j + 1)];
+                let sum: f64 = v.iter().zip(&x[j..]).map(|(v, x)| v * x).sum();
+                let temp = sum / v[0];
+                for (x, v) in x[j..].iter_mut().zip(v) {
+                    *x -= temp * v;
+                }
+            }
+            let column = &DENSE[m * fit.ipvt[k]..][..m];
+            for i in 0..m {
+                assert_approx_eq!(x[i], column[i], 1e-12);
+            }
+        }
+        // The diagonal of R has non-increasing magnitude
+        assert!(fit.wa1[0].abs() >= fit.wa1[1].abs() && fit.wa1[1].abs() >= fit.wa1[2].abs());
+        // The column norms (acnorm) are kept in wa2
+        assert_approx_eq!(fit.wa2[0], 7f64.sqrt(), 1e-12);
+        assert_approx_eq!(fit.wa2[2], 50.25f64.sqrt(), 1e-12);
+    }
+
+    #[test]
+    fn lmpar() {
+        // J = I, so the Gauss-Newton step is b with norm 5 and the damped
+        // steps are b / (1 + par)
+        let problem = Points(3);
+        let mut xall = [0.; 2];
+        let a = [1., 0., 0., 0., 1., 0.];
+        let mut fit = dense_fit(&problem, &mut xall, &a, &[3., 4., 0.]);
+        fit.qrfac();
+        fit.transpose();
+        fit.diag = [1., 1.];
+
+        // Interior case: the Gauss-Newton step is inside the trust region
+        fit.delta = 10.;
+        fit.lmpar();
+        assert_eq!(fit.par, 0.);
+        assert_approx_eq!(fit.wa1[0], 3., 1e-12);
+        assert_approx_eq!(fit.wa1[1], 4., 1e-12);
+
+        // Boundary case: |b| / (1 + par) = delta for par = 1
+        fit.delta = 2.5;
+        fit.lmpar();
+        assert!((0.8..1.25).contains(&fit.par), "par = {}", fit.par);
+        let norm = fit.wa1[..2].enorm();
+        assert!((norm - fit.delta).abs() <= 0.1 * fit.delta);
+        assert_approx_eq!(fit.wa1[0], 3. / (1. + fit.par), 1e-12);
+        assert_approx_eq!(fit.wa1[1], 4. / (1. + fit.par), 1e-12);
+    }
+
+    #[test]
+    fn qrsolv() {
+        let problem = Points(5);
+        let mut xall = [0.; 3];
+        let b = [0.3, -1.2, 2.5, 0.7, -0.4];
+        let mut fit = dense_fit(&problem, &mut xall, &DENSE, &b);
+        fit.qrfac();
+        fit.transpose();
+        // Scaled diagonal sqrt(par) * D, indexed by parameter
+        let d = [0.5, 2., 1.5];
+        fit.wa3 = d;
+        let m = 5;
+        let upper = |fjac: &[f64]| -> Vec<f64> {
+            (0..3).flat_map(|j| fjac[m * j..][..=j].to_vec()).collect()
+        };
+        let r = upper(&fit.fjac);
+        fit.qrsolv();
+        assert_eq!(upper(&fit.fjac), r);
+
+        // Dense solve of (A^T A + D^2) x = A^T b
+        let mut a = [0.; 9];
+        let mut atb = [0.; 3];
+        for i in 0..3 {
+            let ci = &DENSE[m * i..][..m];
+            for j in 0..3 {
+                let cj = &DENSE[m * j..][..m];
+                a[i * 3 + j] = ci.iter().zip(cj).map(|(x, y)| x * y).sum();
+            }
+            a[i * 3 + i] += d[i] * d[i];
+            atb[i] = ci.iter().zip(&b).map(|(x, y)| x * y).sum();
+        }
+        assert!(invert(&mut a, 3));
+        for i in 0..3 {
+            let x: f64 = (0..3).map(|j| a[i * 3 + j] * atb[j]).sum();
+            assert_approx_eq!(fit.wa1[i], x, 1e-12);
+        }
+    }
+
+    #[test]
+    fn covar_pivoting() {
+        // Column norms decreasing against the parameter order, so the QR
+        // factorization pivots them, and a fixed parameter in between
+        let l = Quadratic(linear_problem());
+        let mut init = [1., 5., 1., 0.];
+        let pars = [
+            ParamConfig::default(),
+            ParamConfig {
+                fixed: true,
+                ..Default::default()
+            },
+            ParamConfig::default(),
+            ParamConfig::default(),
+        ];
+        let status = fit(&l, &mut init, pars, Default::default()).expect("Error in fit");
+        // Analytic covariance (J^T J)^-1 of the linear model
+        let basis = |x: f64| [0.01, x, 100. * x * x];
+        let mut a = [[0.; 3]; 3];
+        for (x, ye) in l.0.x.iter().zip(&l.0.ye) {
+            let b = basis(*x);
+            for i in 0..3 {
+                for j in 0..3 {
+                    a[i][j] += b[i] * b[j] / (ye * ye);
+                }
+            }
+        }
+        let cof = |i: usize, j: usize| {
+            let (i1, i2) = ((i + 1) % 3, (i + 2) % 3);
+            let (j1, j2) = ((j + 1) % 3, (j + 2) % 3);
+            a[i1][j1] * a[i2][j2] - a[i1][j2] * a[i2][j1]
+        };
+        let det = a[0][0] * cof(0, 0) + a[0][1] * cof(0, 1) + a[0][2] * cof(0, 2);
+        let free = [0, 2, 3];
+        for i in 0..3 {
+            for j in 0..3 {
+                let expected = cof(j, i) / det;
+                let actual = status.covar[free[i] * 4 + free[j]];
+                assert!(
+                    (actual - expected).abs() <= 1e-6 * expected.abs(),
+                    "covar[{i}][{j}]: {actual} != {expected}"
+                );
+            }
+        }
+        for k in 0..4 {
+            assert_eq!(status.covar[4 + k], 0.);
+            assert_eq!(status.covar[k * 4 + 1], 0.);
+        }
+        assert_eq!(status.xerror[1], 0.);
+    }
+
+    #[test]
+    fn no_pivoting() {
+        let l = Quadratic(linear_problem());
+        let pars = [
+            ParamConfig::default(),
+            ParamConfig {
+                fixed: true,
+                ..Default::default()
+            },
+            ParamConfig::default(),
+            ParamConfig::default(),
+        ];
+        let init = [1., 5., 1., 0.];
+        let pivoted = fit_from(&l, &init, pars, Default::default()).expect("Error in fit");
+        let config = Config {
+            pivot: false,
+            ..Default::default()
+        };
+        let natural = fit_from(&l, &init, pars, config).expect("Error in fit");
+        for (a, b) in natural.params.iter().zip(&pivoted.params) {
+            assert_approx_eq!(a, b);
+        }
+        // Natural order layout, with the fixed parameter row and column zero
+        for i in 0..4 {
+            for j in 0..4 {
+                let (a, b) = (natural.covar[i * 4 + j], pivoted.covar[i * 4 + j]);
+                assert!(
+                    (a - b).abs() <= 1e-5 * b.abs(),
+                    "covar[{i}][{j}]: {a} != {b}"
+                );
+            }
+        }
+        assert_eq!(natural.covar[4 + 2], 0.);
+        assert!(natural.covar[2 * 4 + 2] > 0.);
+    }
+
+    #[test]
+    fn fused_jacobian() {
+        struct Analytic {
+            inner: Linear,
+            evals: std::cell::Cell<usize>,
+        }
+
+        impl Problem<2> for Analytic {
+            fn eval(&self, params: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                self.evals.set(self.evals.get() + 1);
+                self.inner.eval(params, deviates)
+            }
+
+            fn eval_with_jacobian(
+                &self,
+                params: &[f64; 2],
+                deviates: &mut [f64],
+                jacobian: &mut [f64],
+            ) -> Result<bool> {
+                self.evals.set(self.evals.get() + 1);
+                self.inner.eval(params, deviates)?;
+                let (da, db) = jacobian.split_at_mut(deviates.len());
+                for (i, (&x, &ye)) in self.inner.x.iter().zip(&self.inner.ye).enumerate() {
+                    da[i] = -1. / ye;
+                    db[i] = -x / ye;
+                }
+                Ok(true)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.inner.number_of_points()
+            }
+        }
+
+        let numeric = fit_from(
+            &linear_problem(),
+            &[1., 1.],
+            Default::default(),
+            Default::default(),
+        )
+        .expect("Error in fit");
+        let l = Analytic {
+            inner: linear_problem(),
+            evals: Default::default(),
+        };
+        let fused =
+            fit_from(&l, &[1., 1.], Default::default(), Default::default()).expect("Error in fit");
+        assert_approx_eq!(fused.best_norm, numeric.best_norm, 1e-10);
+        for i in 0..2 {
+            assert_approx_eq!(fused.params[i], numeric.params[i], 1e-8);
+            assert_approx_eq!(fused.xerror[i], numeric.xerror[i], 1e-8);
+        }
+        assert_eq!(fused.n_fev, l.evals.get());
+        assert!(fused.n_fev < numeric.n_fev);
+    }
+
+    #[test]
+    fn auto_step() {
+        struct Stiff;
+
+        impl Problem<1> for Stiff {
+            fn eval(&self, [a]: &[f64; 1], deviates: &mut [f64]) -> Result<()> {
+                for (i, d) in deviates.iter_mut().enumerate() {
+                    *d = (200. * (i + 1) as f64 * a).exp();
+                }
+                Ok(())
+            }
+
+            fn number_of_points(&self) -> usize {
+                3
+            }
+        }
+
+        let max_error = |config: Config| {
+            let config = Config {
+                max_iter: 1,
+                return_jacobian: true,
+                ..config
+            };
+            let status = fit_from(&Stiff, &[1.], Default::default(), config).expect("Error in fit");
+            let jacobian = status.jacobian.expect("no jacobian");
+            (0..3)
+                .map(|i| {
+                    let c = 200. * (i + 1) as f64;
+                    let exact = c * c.exp();
+                    ((jacobian[i] - exact) / exact).abs()
+                })
+                .fold(0., f64::max)
+        };
+        let default = max_error(Default::default());
+        let tuned = max_error(Config {
+            auto_step: true,
+            ..Default::default()
+        });
+        assert!(tuned < default / 10., "tuned {tuned}, default {default}");
+    }
+
+    #[test]
+    fn relative_residuals() {
+        // Proportional model with +-30% multiplicative noise
+        struct Proportional(Vec<f64>);
+
+        impl Problem<1> for Proportional {
+            fn eval(&self, [a]: &[f64; 1], deviates: &mut [f64]) -> Result<()> {
+                for (i, (d, y)) in deviates.iter_mut().zip(&self.0).enumerate() {
+                    *d = y - a * (i + 1) as f64;
+                }
+                Ok(())
+            }
+
+            fn model(&self, [a]: &[f64; 1], values: &mut [f64]) -> Result<()> {
+                for (i, v) in values.iter_mut().enumerate() {
+                    *v = a * (i + 1) as f64;
+                }
+                Ok(())
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.len()
+            }
+        }
+
+        let y = (1..=10)
+            .map(|x| 2. * x as f64 * if x % 2 == 0 { 1.3 } else { 0.7 })
+            .collect();
+        let p = Proportional(y);
+        let absolute =
+            fit_from(&p, &[1.], Default::default(), Default::default()).expect("Error in fit");
+        assert!((absolute.params[0] - 2.).abs() > 0.05);
+        let config = Config {
+            relative: Some(1e-3),
+            ..Default::default()
+        };
+        let relative = fit_from(&p, &[1.], Default::default(), config).expect("Error in fit");
+        assert_approx_eq!(relative.params[0], 2., 1e-6);
+        // The residuals are returned without the normalization
+        assert_approx_eq!(relative.resid[0], 1.4 - relative.params[0], 1e-12);
+
+        let config = Config {
+            relative: Some(0.),
+            ..Default::default()
+        };
+        let result = fit_from(&p, &[1.], Default::default(), config);
+        assert_eq!(result.unwrap_err(), Error::Input);
+    }
+
+    #[test]
+    fn step_precedence() {
+        struct Exponential;
+
+        impl Problem<1> for Exponential {
+            fn eval(&self, [a]: &[f64; 1], deviates: &mut [f64]) -> Result<()> {
+                for (i, d) in deviates.iter_mut().enumerate() {
+                    *d = (a * i as f64).exp();
+                }
+                Ok(())
+            }
+
+            fn number_of_points(&self) -> usize {
+                3
+            }
+        }
+
+        let jacobian = |param: ParamConfig| {
+            let config = Config {
+                max_iter: 1,
+                return_jacobian: true,
+                ..Default::default()
+            };
+            let status = fit_from(&Exponential, &[1.], [param], config).expect("Error in fit");
+            status.jacobian.expect("no jacobian")
+        };
+        let absolute = jacobian(ParamConfig {
+            step_scale: 0.1,
+            ..Default::default()
+        });
+        let relative = jacobian(ParamConfig {
+            rel_step: 0.01,
+            ..Default::default()
+        });
+        assert_ne!(absolute, relative);
+        // The absolute step wins if both are given
+        let both = jacobian(ParamConfig {
+            rel_step: 0.01,
+            step_scale: 0.1,
+            ..Default::default()
+        });
+        assert_eq!(both, absolute);
+    }
+
+    #[test]
+    fn batched_jacobian() {
+        struct Batched {
+            inner: Gaussian,
+            batches: std::cell::Cell<usize>,
+        }
+
+        impl Problem<4> for Batched {
+            fn eval(&self, params: &[f64; 4], deviates: &mut [f64]) -> Result<()> {
+                self.inner.eval(params, deviates)
+            }
+
+            fn eval_batch(&self, params: &[[f64; 4]], deviates: &mut [f64]) -> Result<bool> {
+                self.batches.set(self.batches.get() + 1);
+                let n = self.number_of_points();
+                for (p, d) in params.iter().zip(deviates.chunks_exact_mut(n)) {
+                    self.inner.eval(p, d)?;
+                }
+                Ok(true)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.inner.number_of_points()
+            }
+        }
+
+        let config = Config {
+            return_jacobian: true,
+            ..Default::default()
+        };
+        let pars = [
+            ParamConfig {
+                fixed: true,
+                ..Default::default()
+            },
+            ParamConfig::default(),
+            ParamConfig::default(),
+            ParamConfig::default(),
+        ];
+        let start = [0., 1., 1., 1.];
+        let columns = fit_from(&gaussian_problem(), &start, pars, config).expect("Error in fit");
+        let g = Batched {
+            inner: gaussian_problem(),
+            batches: Default::default(),
+        };
+        let batched = fit_from(&g, &start, pars, config).expect("Error in fit");
+        assert_eq!(batched.jacobian, columns.jacobian);
+        assert_eq!(batched.params, columns.params);
+        assert_eq!(batched.n_fev, columns.n_fev);
+        // One batch per Jacobian, the iteration count starts at 1
+        assert_eq!(g.batches.get(), batched.n_iter - 1);
+    }
+
+    #[test]
+    fn streaming() {
+        // Residuals are generated on the fly, nothing of size n is stored
+        struct Synthetic {
+            n: usize,
+        }
+
+        impl Synthetic {
+            fn point(&self, i: usize) -> (f64, f64) {
+                let x = 10. * i as f64 / self.n as f64 - 5.;
+                let y = 3. + 2. * x + 0.5 * x * x + 0.1 * (1.7 * i as f64).sin();
+                (x, y)
+            }
+        }
+
+        impl Problem<3> for Synthetic {
+            fn eval(&self, params: &[f64; 3], deviates: &mut [f64]) -> Result<()> {
+                self.eval_chunk(params, 0, deviates)
+            }
+
+            fn eval_chunk(
+                &self,
+                [a, b, c]: &[f64; 3],
+                offset: usize,
+                deviates: &mut [f64],
+            ) -> Result<()> {
+                for (i, d) in (offset..).zip(deviates.iter_mut()) {
+                    let (x, y) = self.point(i);
+                    *d = (y - a - b * x - c * x * x) / 0.1;
+                }
+                Ok(())
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.n
+            }
+        }
+
+        let problem = Synthetic { n: 100_000 };
+        let start = [1., 1., 1.];
+        let memory = fit_from(&problem, &start, Default::default(), Default::default())
+            .expect("Error in fit");
+        let config = Config {
+            chunk_size: 4096,
+            ..Default::default()
+        };
+        let mut params = start;
+        let streamed = fit_streaming(&problem, &mut params, Default::default(), config)
+            .expect("Error in streaming fit");
+        assert!(matches!(
+            streamed.success,
+            Success::Chi | Success::Par | Success::Both
+        ));
+        assert_eq!(params, streamed.params);
+        assert_eq!((streamed.rank, memory.rank), (3, 3));
+        assert_approx_eq!(
+            streamed.best_norm,
+            memory.best_norm,
+            1e-6 * memory.best_norm
+        );
+        for i in 0..3 {
+            assert_approx_eq!(streamed.params[i], memory.params[i], 1e-8);
+            assert_approx_eq!(streamed.xerror[i], memory.xerror[i], 1e-8);
+        }
+        assert!(streamed.resid.is_empty());
+
+        let result = fit_streaming(
+            &problem,
+            &mut params,
+            Default::default(),
+            Default::default(),
+        );
+        assert_eq!(result.unwrap_err(), Error::Input);
+    }
+
+    #[test]
+    fn fixed_size() {
+        let l = linear_problem();
+        let pars = [
+            ParamConfig::default(),
+            ParamConfig {
+                limits: Limits::Upper(3.),
+                ..Default::default()
+            },
+        ];
+        let config = Config {
+            chunk_size: 3,
+            ..Default::default()
+        };
+        let mut streamed = [1., 1.];
+        let expected = fit_streaming(&l, &mut streamed, pars, config).expect("Error in fit");
+        let mut params = [1., 1.];
+        let status =
+            fit_fixed::<2, 10, _, _>(&l, &mut params, pars, config).expect("Error in fixed fit");
+        assert_eq!(params, status.params);
+        assert_eq!(status.success, expected.success);
+        assert_eq!(status.n_iter, expected.n_iter);
+        assert_eq!(status.n_fev, expected.n_fev);
+        assert_eq!(status.n_func, 10);
+        assert_approx_eq!(status.best_norm, expected.best_norm, 1e-8);
+        for i in 0..2 {
+            assert_approx_eq!(status.params[i], expected.params[i], 1e-8);
+            assert_approx_eq!(status.xerror[i], expected.xerror[i], 1e-8);
+            for k in 0..2 {
+                assert_approx_eq!(status.covar[i][k], expected.covar[i * 2 + k], 1e-8);
+            }
+        }
+
+        let res = fit_fixed::<2, 9, _, _>(&l, &mut params, pars, config);
+        assert_eq!(res, Err(Error::Input));
+    }
+
+    #[test]
+    fn truncated_best_norm() {
+        let g = gaussian_problem();
+        let start = [0., 1., 1., 1.];
+        // Truncated fits may end on a rejected trial step
+        for max_fev in 1..=40 {
+            let config = Config {
+                max_fev,
+                ..Default::default()
+            };
+            let status = fit_from(&g, &start, Default::default(), config).expect("Error in fit");
+            let chi2 = chi_square(&g, &status.params).expect("Error in chi^2");
+            assert_approx_eq!(status.best_norm, chi2, 1e-12 * chi2);
+            assert_eq!(
+                status.success.is_converged(),
+                status.success != Success::MaxIter
+            );
+        }
+    }
+
+    #[test]
+    fn max_fev() {
+        struct Counted {
+            inner: Gaussian,
+            evals: std::cell::Cell<usize>,
+        }
+
+        impl Problem<4> for Counted {
+            fn eval(&self, params: &[f64; 4], deviates: &mut [f64]) -> Result<()> {
+                self.evals.set(self.evals.get() + 1);
+                self.inner.eval(params, deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.inner.number_of_points()
+            }
+        }
+
+        // Far from the optimum, so the fit needs many evaluations
+        let g = Counted {
+            inner: gaussian_problem(),
+            evals: Default::default(),
+        };
+        let start = [0., 1., 1., 1.];
+        let unlimited =
+            fit_from(&g, &start, Default::default(), Default::default()).expect("Error in fit");
+        assert!(unlimited.n_fev > 40);
+        for max_fev in 1..=40 {
+            g.evals.set(0);
+            let config = Config {
+                max_fev,
+                ..Default::default()
+            };
+            let status = fit_from(&g, &start, Default::default(), config).expect("Error in fit");
+            assert_eq!(status.success, Success::MaxIter);
+            assert_eq!(status.n_fev, g.evals.get());
+            assert!(status.n_fev <= max_fev, "{} > {max_fev}", status.n_fev);
+        }
+
+        // The initial evaluation and one Jacobian
+        let config = Config {
+            max_fev: 5,
+            ..Default::default()
+        };
+        let status = fit_from(&g, &start, Default::default(), config).expect("Error in fit");
+        assert_eq!(status.n_fev, 5);
+        assert_eq!(status.params, start);
+    }
+
+    #[test]
+    fn max_step() {
+        struct Recorder {
+            inner: Linear,
+            steps: std::cell::RefCell<Vec<[f64; 2]>>,
+        }
+
+        impl Problem<2> for Recorder {
+            fn eval(&self, params: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                self.inner.eval(params, deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.inner.number_of_points()
+            }
+
+            fn iteration(&self, iteration: &Iteration<2>) {
+                self.steps.borrow_mut().push(iteration.params);
+            }
+        }
+
+        let run = |max_step| {
+            let l = Recorder {
+                inner: linear_problem(),
+                steps: Default::default(),
+            };
+            let pars = [
+                ParamConfig::default(),
+                ParamConfig {
+                    max_step,
+                    ..Default::default()
+                },
+            ];
+            let status = fit_from(&l, &[1., 1.], pars, Default::default()).expect("Error in fit");
+            (status, l.steps.into_inner())
+        };
+        let (free, free_steps) = run(0.);
+        let (slow, slow_steps) = run(0.1);
+        // The first step of the other parameter is unaffected
+        assert_eq!(free_steps[1][0], slow_steps[1][0]);
+        for step in slow_steps.windows(2) {
+            assert!((step[1][1] - step[0][1]).abs() <= 0.1 + 1e-12);
+        }
+        assert!(slow.n_iter > free.n_iter);
+        for i in 0..2 {
+            assert_approx_eq!(slow.params[i], free.params[i], 1e-6);
+        }
+    }
+
+    #[test]
+    fn multistart_bimodal() {
+        // Local minimum near -3 with chi^2 of about 2.3, global one at 2
+        struct Bimodal;
+
+        impl Problem<1> for Bimodal {
+            fn eval(&self, [a]: &[f64; 1], deviates: &mut [f64]) -> Result<()> {
+                deviates[0] = (a - 2.) * (a + 3.);
+                deviates[1] = 0.3 * (a - 2.);
+                Ok(())
+            }
+
+            fn number_of_points(&self) -> usize {
+                2
+            }
+        }
+
+        let pars = [ParamConfig {
+            limits: Limits::Both(-5., 5.),
+            ..Default::default()
+        }];
+        let local = fit_from(&Bimodal, &[-4.], pars, Default::default()).expect("Error in fit");
+        assert!(local.params[0] < 0.);
+        assert!(local.best_norm > 1.);
+
+        let mut params = [-4.];
+        let result = multistart(&Bimodal, &mut params, pars, Default::default(), 8, 42)
+            .expect("Error in fit");
+        assert_eq!(result.starts.len(), 8);
+        assert_eq!(result.results.len(), 8);
+        assert!(result.starts.iter().all(|[a]| (-5. ..=5.).contains(a)));
+        assert_approx_eq!(params[0], 2., 1e-8);
+        let best = result.best().expect("Best fit succeeded");
+        assert_eq!(best.params, params);
+        assert!(best.best_norm < 1e-12);
+        let mut changed = result.clone();
+        changed.best = 8;
+        assert_eq!(changed.best(), None);
+        changed.best = 0;
+        changed.results[0] = Err(Error::Eval);
+        assert_eq!(changed.best(), None);
+
+        let again = multistart(&Bimodal, &mut [-4.], pars, Default::default(), 8, 42)
+            .expect("Error in fit");
+        assert_eq!(again, result);
+        let other =
+            multistart(&Bimodal, &mut [-4.], pars, Default::default(), 8, 7).expect("Error in fit");
+        assert_ne!(other.starts, result.starts);
+    }
+
+    #[test]
+    fn sensitivity_matrix() {
+        let g = gaussian_problem();
+        let start = [0., 1., 1., 1.];
+        let pars = [
+            ParamConfig::default(),
+            ParamConfig {
+                fixed: true,
+                ..Default::default()
+            },
+            ParamConfig {
+                rel_step: 1e-4,
+                ..Default::default()
+            },
+            ParamConfig::default(),
+        ];
+        // A single iteration returns the Jacobian at the start point
+        let config = Config {
+            max_iter: 1,
+            return_jacobian: true,
+            ..Default::default()
+        };
+        let status = fit_from(&g, &start, pars, config).expect("Error in fit");
+        let jacobian = sensitivity(&g, &start, pars, Default::default()).expect("Error");
+        assert_eq!(Some(jacobian), status.jacobian);
+    }
+
+    #[test]
+    fn incremental_information() {
+        let all = linear_problem();
+        let batch = |range: ::std::ops::Range<usize>| Linear {
+            x: all.x[range.clone()].to_vec(),
+            y: all.y[range.clone()].to_vec(),
+            ye: all.ye[range].to_vec(),
+        };
+        let config = Config {
+            return_jacobian: true,
+            ..Default::default()
+        };
+        let mut info = Information::<2>::new();
+        let mut init = [1., 1.];
+        for range in [0..5, 5..10] {
+            // Each batch refines the parameters of the previous ones
+            let status =
+                fit(&batch(range), &mut init, Default::default(), config).expect("Error in fit");
+            info.add_jacobian(status.jacobian.as_deref().expect("no jacobian"));
+        }
+        let combined = fit_from(&all, &[1., 1.], Default::default(), Default::default())
+            .expect("Error in fit");
+        let covar = info.covariance().expect("singular information");
+        for (a, b) in covar.iter().zip(&combined.covar) {
+            assert_approx_eq!(a, b, 1e-10);
+        }
+        assert_eq!(
+            Information::<2>::default().covariance().as_deref(),
+            Some(&[0.; 4][..])
+        );
+    }
+
+    #[test]
+    fn validate_inputs() {
+        struct Untouchable(usize);
+
+        impl Problem<2> for Untouchable {
+            fn eval(&self, _: &[f64; 2], _: &mut [f64]) -> Result<()> {
+                panic!("validation evaluated the residuals");
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0
+            }
+        }
+
+        let fixed = |limits| ParamConfig {
+            fixed: true,
+            limits,
+            ..Default::default()
+        };
+        let ok = validate(
+            &Untouchable(2),
+            &[1., 1.],
+            Default::default(),
+            Default::default(),
+        );
+        assert_eq!(ok, Ok(()));
+        let empty = validate(
+            &Untouchable(0),
+            &[1., 1.],
+            Default::default(),
+            Default::default(),
+        );
+        assert_eq!(empty, Err(Error::Empty));
+        let config = Config {
+            xtol: 0.,
+            ..Default::default()
+        };
+        let input = validate(&Untouchable(2), &[1., 1.], Default::default(), config);
+        assert_eq!(input, Err(Error::Input));
+        let pars = [fixed(Limits::Lower(2.)), ParamConfig::default()];
+        let bounds = validate(&Untouchable(2), &[1., 1.], pars, Default::default());
+        assert_eq!(bounds, Err(Error::Bounds));
+        let pars = [
+            ParamConfig {
+                limits: Limits::Both(2., 1.),
+                ..Default::default()
+            },
+            ParamConfig::default(),
+        ];
+        let bounds = validate(&Untouchable(2), &[1.5, 1.], pars, Default::default());
+        assert_eq!(bounds, Err(Error::Bounds));
+        let dof = validate(
+            &Untouchable(1),
+            &[1., 1.],
+            Default::default(),
+            Default::default(),
+        );
+        assert_eq!(dof, Err(Error::DoF));
+        let pars = [fixed(Limits::None), ParamConfig::default()];
+        let ok = validate(&Untouchable(1), &[1., 1.], pars, Default::default());
+        assert_eq!(ok, Ok(()));
+    }
+
+    #[test]
+    fn log_scale() {
+        // Decay with a rate of 1e3, the times span 1e-6 to 1
+        struct Decay;
+
+        impl Problem<1> for Decay {
+            fn eval(&self, [k]: &[f64; 1], deviates: &mut [f64]) -> Result<()> {
+                for (i, d) in deviates.iter_mut().enumerate() {
+                    let t = 10f64.powf(-6. + 0.5 * i as f64);
+                    *d = (-1e3 * t).exp() - (-k * t).exp();
+                }
+                Ok(())
+            }
+
+            fn number_of_points(&self) -> usize {
+                13
+            }
+        }
+
+        let config = Config {
+            step_factor: 1.,
+            max_iter: 20,
+            ..Default::default()
+        };
+        let linear = [ParamConfig {
+            limits: Limits::Lower(0.),
+            ..Default::default()
+        }];
+        let log = [ParamConfig {
+            log_scale: true,
+            ..Default::default()
+        }];
+        let slow = fit_from(&Decay, &[1e-3], linear, config).expect("Error in fit");
+        assert_eq!(slow.success, Success::MaxIter);
+        let mut params = [1e-3];
+        let fast = fit(&Decay, &mut params, log, config).expect("Error in fit");
+        assert!(fast.success.is_converged());
+        assert_approx_eq!(params[0], 1e3, 1e-9);
+
+        // The uncertainty is transformed back to the rate
+        let config = Config {
+            step_factor: 1.,
+            ..Default::default()
+        };
+        let done = fit_from(&Decay, &[1e-3], linear, config).expect("Error in fit");
+        assert_approx_eq!(fast.xerror[0], done.xerror[0], 1e-6 * done.xerror[0]);
+        assert_eq!(fast.covar[0], fast.xerror[0].powi(2));
+
+        let result = fit_from(&Decay, &[0.], log, config);
+        assert_eq!(result.unwrap_err(), Error::InitBounds);
+        let mut params = [-1.];
+        assert!(fit(&Decay, &mut params, log, config).is_err());
+        assert_eq!(params, [-1.]);
+    }
+
+    #[test]
+    fn exactly_determined() {
+        let l = linear_problem();
+        let two = Linear {
+            x: l.x[..2].to_vec(),
+            y: l.y[..2].to_vec(),
+            ye: l.ye[..2].to_vec(),
+        };
+        let status = fit_from(&two, &[1., 1.], Default::default(), Default::default())
+            .expect("Error in fit");
+        assert!(status.zero_dof);
+        assert_eq!(status.dof(), Some(0));
+        assert_eq!(status.reduced_chi2(), None);
+        assert_eq!(status.chi2_pvalue(), None);
+        assert_eq!(status.report(None).reduced_chi2, None);
+
+        let status =
+            fit_from(&l, &[1., 1.], Default::default(), Default::default()).expect("Error in fit");
+        assert!(!status.zero_dof);
+        assert_eq!(status.reduced_chi2(), Some(status.best_norm / 8.));
+    }
+
+    #[test]
+    fn iteration_delta() {
+        struct Recorder {
+            inner: Gaussian,
+            history: std::cell::RefCell<Vec<(f64, f64)>>,
+        }
+
+        impl Problem<4> for Recorder {
+            fn eval(&self, params: &[f64; 4], deviates: &mut [f64]) -> Result<()> {
+                self.inner.eval(params, deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.inner.number_of_points()
+            }
+
+            fn iteration(&self, iteration: &Iteration<4>) {
+                self.history
+                    .borrow_mut()
+                    .push((iteration.delta, iteration.par));
+            }
+        }
+
+        let g = Recorder {
+            inner: gaussian_problem(),
+            history: Default::default(),
+        };
+        fit_from(
+            &g,
+            &[0., 1., 1., 1.],
+            Default::default(),
+            Default::default(),
+        )
+        .expect("Error in fit");
+        let history = g.history.into_inner();
+        let deltas: Vec<f64> = history.iter().map(|&(delta, _)| delta).collect();
+        assert!(deltas.iter().all(|&delta| delta > 0. && delta.is_finite()));
+        // The radius grows after good steps and shrinks towards convergence
+        assert!(deltas.windows(2).any(|d| d[1] > d[0]));
+        assert!(deltas.windows(2).any(|d| d[1] < d[0]));
+        assert!(deltas[deltas.len() - 1] < 1e-3 * deltas[0]);
+        assert!(history.iter().all(|&(_, par)| par >= 0.));
+        assert!(history.iter().any(|&(_, par)| par > 0.));
+    }
+
+    #[test]
+    fn progress() {
+        // Hidden bar which only records what would be drawn
+        #[derive(Default)]
+        struct Hidden {
+            length: std::cell::Cell<Option<u64>>,
+            positions: std::cell::RefCell<Vec<u64>>,
+            ticks: std::cell::Cell<usize>,
+            message: std::cell::RefCell<String>,
+        }
+
+        impl ProgressBar for Hidden {
+            fn set_length(&self, len: u64) {
+                self.length.set(Some(len));
+            }
+
+            fn set_position(&self, pos: u64) {
+                self.positions.borrow_mut().push(pos);
+            }
+
+            fn tick(&self) {
+                self.ticks.set(self.ticks.get() + 1);
+            }
+
+            fn set_message(&self, msg: String) {
+                *self.message.borrow_mut() = msg;
+            }
+        }
+
+        let g = gaussian_problem();
+        let bar = Hidden::default();
+        let config = Config::default();
+        let status = fit_from(
+            &Progress::new(&g, &bar, &config),
+            &[0., 1., 1., 1.],
+            Default::default(),
+            config,
+        )
+        .expect("Error in fit");
+        assert_eq!(bar.length.get(), Some(200));
+        // One advance per iteration, the fit stops after the step of the last
+        // one, which already counts the next iteration in n_iter
+        let positions = bar.positions.take();
+        assert_eq!(positions.len(), status.n_iter - 1);
+        assert!(positions.iter().copied().eq(1..status.n_iter as u64));
+        assert_eq!(bar.ticks.get(), 0);
+        assert!(bar.message.take().starts_with("chi^2 = "));
+
+        // Spinner without iterations
+        let bar = Hidden::default();
+        let config = Config {
+            max_iter: 0,
+            ..Default::default()
+        };
+        fit_from(
+            &Progress::new(&g, &bar, &config),
+            &[0., 1., 1., 1.],
+            Default::default(),
+            config,
+        )
+        .expect("Error in fit");
+        assert_eq!(bar.length.get(), None);
+        assert!(bar.positions.take().is_empty());
+        assert_eq!(bar.ticks.get(), 1);
+    }
+
+    #[test]
+    fn information_criteria() {
+        // Curved data, so the quadratic model fits genuinely better
+        let x: Vec<f64> = (0..20).map(|i| 0.25 * i as f64).collect();
+        let y: Vec<f64> = x
+            .iter()
+            .enumerate()
+            .map(|(i, x)| 1. + 0.5 * x + 0.3 * x * x + 0.05 * (7. * i as f64).sin())
+            .collect();
+        let line = Curve::new(|[a, b]: &[f64; 2], x: f64| a + b * x, &x, &y, None);
+        let parabola = Curve::new(
+            |[a, b, c]: &[f64; 3], x: f64| a + b * x + c * x * x,
+            &x,
+            &y,
+            None,
+        );
+        let linear = fit_from(&line, &[1., 1.], Default::default(), Default::default())
+            .expect("Error in fit");
+        let quadratic = fit_from(
+            &parabola,
+            &[1., 1., 1.],
+            Default::default(),
+            Default::default(),
+        )
+        .expect("Error in fit");
+        assert_eq!(quadratic.rank, 3);
+        assert!(quadratic.best_norm < linear.best_norm);
+        assert!(quadratic.aic() < linear.aic());
+        assert!(quadratic.bic() < linear.bic());
+
+        let n = 20f64;
+        let fit_term = |chi2: f64| n * (chi2 / n).ln();
+        assert_approx_eq!(linear.aic(), 2. * 2. + fit_term(linear.best_norm), 1e-12);
+        assert_approx_eq!(
+            quadratic.aic(),
+            2. * 3. + fit_term(quadratic.best_norm),
+            1e-12
+        );
+        assert_approx_eq!(
+            linear.bic(),
+            2. * n.ln() + fit_term(linear.best_norm),
+            1e-12
+        );
+        assert_approx_eq!(
+            quadratic.bic(),
+            3. * n.ln() + fit_term(quadratic.best_norm),
+            1e-12
+        );
+    }
+
+    #[test]
+    fn is_reliable() {
+        let l = linear_problem();
+        let mut init = [1., 1.];
+        let status = fit(&l, &mut init, Default::default(), Default::default())
+            .expect("Error in Linear fit");
+        assert_eq!(status.rank, 2);
+        assert!(status.is_reliable());
+
+        let config = Config {
+            max_iter: 1,
+            ..Default::default()
+        };
+        let mut init = [1., 1.];
+        let status = fit(&l, &mut init, Default::default(), config).expect("Error in Linear fit");
+        assert_eq!(status.success, Success::MaxIter);
+        assert!(!status.is_reliable());
+
+        let pars = [
+            ParamConfig::default(),
+            ParamConfig {
+                limits: Limits::Upper(1.5),
+                ..Default::default()
+            },
+        ];
+        let mut init = [1., 1.];
+        let status = fit(&l, &mut init, pars, Default::default()).expect("Error in Linear fit");
+        assert_eq!(status.n_pegged, 1);
+        assert!(!status.is_reliable());
+
+        let config = Config {
+            ftol: 1e-300,
+            xtol: 1e-300,
+            ..Default::default()
+        };
+        let mut init = [1., 1.];
+        let status = fit(&l, &mut init, Default::default(), config).expect("Error in Linear fit");
+        assert!(status.success.is_warning());
+        assert!(!status.is_reliable());
+
+        // only the sum of the slopes is determined
+        struct Degenerate(Linear);
+        impl Problem<3> for Degenerate {
+            fn eval(&self, [a, b, c]: &[f64; 3], deviates: &mut [f64]) -> Result<()> {
+                self.0.eval(&[*a, b + c], deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.number_of_points()
+            }
+        }
+        let mut init = [1., 1., 0.5];
+        let status = fit(
+            &Degenerate(linear_problem()),
+            &mut init,
+            Default::default(),
+            Default::default(),
+        )
+        .expect("Error in degenerate fit");
+        assert!(status.success.is_converged());
+        assert_eq!((status.n_free, status.rank), (3, 2));
+        assert!(!status.is_reliable());
+    }
+
+    #[test]
+    fn n_fev() {
+        struct Counted<P> {
+            inner: P,
+            evals: std::cell::Cell<usize>,
+        }
+
+        impl<const N: usize, P: Problem<N>> Problem<N> for Counted<P> {
+            fn eval(&self, params: &[f64; N], deviates: &mut [f64]) -> Result<()> {
+                self.evals.set(self.evals.get() + 1);
+                self.inner.eval(params, deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.inner.number_of_points()
+            }
+        }
+
+        let l = Counted {
+            inner: linear_problem(),
+            evals: Default::default(),
+        };
+        let status =
+            fit_from(&l, &[1., 1.], Default::default(), Default::default()).expect("Error in fit");
+        assert_eq!(status.n_fev, l.evals.get());
+
+        let g = Counted {
+            inner: gaussian_problem(),
+            evals: Default::default(),
+        };
+        let status = fit_from(
+            &g,
+            &[0., 1., 1., 1.],
+            Default::default(),
+            Default::default(),
+        )
+        .expect("Error in fit");
+        assert_eq!(status.n_fev, g.evals.get());
+
+        let config = Config {
+            max_fev: 20,
+            ..Default::default()
+        };
+        let g = Counted {
+            inner: gaussian_problem(),
+            evals: Default::default(),
+        };
+        let status =
+            fit_from(&g, &[0., 1., 1., 1.], Default::default(), config).expect("Error in fit");
+        assert_eq!(status.success, Success::MaxIter);
+        assert_eq!(status.n_fev, g.evals.get());
+    }
+
+    #[test]
+    fn max_consecutive_rejects() {
+        // Rough model, the linear predictions of the steps are useless
+        struct Rough(Linear);
+        impl Problem<2> for Rough {
+            fn eval(&self, params: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                self.0.eval(params, deviates)?;
+                for d in deviates.iter_mut() {
+                    *d += (1e4 * params[0]).sin();
+                }
+                Ok(())
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.number_of_points()
+            }
+        }
+
+        let f = Rough(linear_problem());
+        let status =
+            fit_from(&f, &[1., 1.], Default::default(), Default::default()).expect("Error in fit");
+        let n_fev = status.n_fev;
+        let config = Config {
+            max_consecutive_rejects: 2,
+            ..Default::default()
+        };
+        let status = fit_from(&f, &[1., 1.], Default::default(), config).expect("Error in fit");
+        assert_eq!(status.success, Success::MaxRejects);
+        assert!(status.n_fev < n_fev);
+    }
+
+    #[test]
+    fn linear_final_par() {
+        let l = linear_problem();
+        let mut init = [1., 1.];
+        let status = fit(&l, &mut init, Default::default(), Default::default())
+            .expect("Error in Linear fit");
+        assert!(status.final_par < 1e-6, "final_par = {}", status.final_par);
+    }
+
+    #[test]
+    fn linear_prediction() {
+        let l = linear_problem();
+        let status = fit_from(&l, &[1., 1.], Default::default(), Default::default())
+            .expect("Error in Linear fit");
+        let predict = |x: f64| status.predict_with_errors(|[a, b]| (a + b * x, [1., x]));
+        let centroid = l.x.iter().sum::<f64>() / l.x.len() as f64;
+        let (value, error) = predict(centroid);
+        assert_approx_eq!(value, status.params[0] + status.params[1] * centroid);
+        let c = &status.covar;
+        assert_approx_eq!(
+            error * error,
+            c[0] + 2. * centroid * c[1] + centroid * centroid * c[3]
+        );
+        // The band is narrowest at the centroid of the data
+        let mut last = error;
+        for dx in [0.5, 1., 2., 4.] {
+            let (_, lower) = predict(centroid - dx);
+            let (_, upper) = predict(centroid + dx);
+            assert!(lower > last && upper > last);
+            last = lower.min(upper);
+        }
+    }
+
+    #[test]
+    fn linear_chi_square() {
+        let l = linear_problem();
+        let start = [1., 1.];
+        let mut init = start;
+        let status = fit(&l, &mut init, Default::default(), Default::default())
+            .expect("Error in Linear fit");
+        let chi2 = chi_square(&l, &start).expect("Error in chi_square");
+        assert_eq!(chi2, status.orig_norm);
+        let chi2 = chi_square(&l, &init).expect("Error in chi_square");
+        assert_approx_eq!(chi2, status.best_norm);
+    }
+
+    #[test]
+    fn linear_write_back() {
+        let l = linear_problem();
+        let pars = [
+            ParamConfig::default(),
+            ParamConfig {
+                fixed: true,
+                ..Default::default()
+            },
+        ];
+        let mut init = [1., 1.77];
+        let status = fit(&l, &mut init, pars, Default::default()).expect("Error in Linear fit");
+        assert_eq!(init, status.params);
+        assert_eq!(init[1], 1.77);
+        assert_approx_eq!(init[0], 3.21, 1e-2);
+    }
+
+    #[test]
+    fn bounded_step_direction() {
+        struct Steps {
+            inner: Linear,
+            params: std::cell::RefCell<Vec<[f64; 2]>>,
+        }
+
+        impl Problem<2> for Steps {
+            fn eval(&self, params: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                self.inner.eval(params, deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.inner.number_of_points()
+            }
+
+            fn iteration(&self, iteration: &Iteration<2>) {
+                self.params.borrow_mut().push(iteration.params);
+            }
+        }
+
+        let f = Steps {
+            inner: linear_problem(),
+            params: Default::default(),
+        };
+        let pars = [
+            ParamConfig {
+                limits: Limits::Both(0., 2.),
+                ..Default::default()
+            },
+            ParamConfig {
+                limits: Limits::Both(0., 10.),
+                ..Default::default()
+            },
+        ];
+        let start = [1., 1.];
+        fit_from(&f, &start, pars, Default::default()).expect("Error in fit");
+        // The first step heads for the unconstrained minimum of the linear
+        // model and is shortened to end on the limit of the first parameter
+        let [a, b] = f.params.borrow()[1];
+        let (target_a, target_b) = (3.20996572, 1.77095420);
+        assert_eq!(a, 2.);
+        let cross = (a - start[0]) * (target_b - start[1]) - (b - start[1]) * (target_a - start[0]);
+        assert!(cross.abs() < 1e-6, "step direction changed: {cross}");
+        assert!(b > start[1] && b < target_b);
+    }
+
+    #[test]
+    fn lower_bound_dir() {
+        let l = linear_problem();
+        let pars = [
+            ParamConfig {
+                limits: Limits::Lower(4.),
+                ..Default::default()
+            },
+            ParamConfig::default(),
+        ];
+        let mut init = [5., 1.];
+        let config = Config {
+            ftol: 1e-300,
+            xtol: 1e-300,
+            gtol: 1e-6,
+            ..Default::default()
+        };
+        let status = fit(&l, &mut init, pars, config).expect("Error in bounded fit");
+        assert_eq!(status.success, Success::Dir);
+        assert_eq!(status.n_pegged, 1);
+        assert_eq!(init[0], 4.);
+        assert!(status.n_iter < config.max_iter);
+    }
+
+    #[test]
+    fn nonnegative_amplitude() {
+        // The unconstrained amplitude would be -2
+        let x: Vec<f64> = (0..20).map(|i| 0.25 * i as f64).collect();
+        let y: Vec<f64> = x.iter().map(|x| 1. - 2. * (-x).exp()).collect();
+        let pars = [
+            ParamConfig {
+                limits: Limits::Lower(0.),
+                ..Default::default()
+            },
+            ParamConfig::default(),
+        ];
+        let mut params = [1., 0.];
+        let status = curve_fit(
+            |[a, c]: &[f64; 2], x: f64| a * (-x).exp() + c,
+            &x,
+            &y,
+            None,
+            &mut params,
+            pars,
+            Default::default(),
+        )
+        .expect("Error in bounded fit");
+        assert_eq!(params[0], 0.);
+        assert_eq!(status.pegged, [true, false]);
+        assert_eq!(status.n_pegged, 1);
+        let mean = y.iter().sum::<f64>() / y.len() as f64;
+        assert_approx_eq!(params[1], mean, 1e-8);
+    }
+
+    #[test]
+    fn fixed_on_limit() {
+        let x: Vec<f64> = (0..10).map(|i| i as f64).collect();
+        let y: Vec<f64> = x.iter().map(|x| 1. + 2. * x).collect();
+        let curve = Curve::new(|[a, b]: &[f64; 2], x: f64| a + b * x, &x, &y, None);
+        let pars = [
+            ParamConfig {
+                fixed: true,
+                limits: Limits::Lower(1.),
+                ..Default::default()
+            },
+            ParamConfig::default(),
+        ];
+        let status = fit_from(&curve, &[1., 1.], pars, Default::default()).expect("Error in fit");
+        assert_eq!(status.pegged, [false, false]);
+        assert_eq!(status.n_pegged, 0);
+        assert!(status.is_reliable());
+        assert!(!status.report(None).params[0].pegged);
+
+        let config = Config {
+            chunk_size: 4,
+            ..Default::default()
+        };
+        let mut params = [1., 1.];
+        let status = fit_streaming(&curve, &mut params, pars, config).expect("Error in fit");
+        assert_eq!(status.pegged, [false, false]);
+        assert_eq!(status.n_pegged, 0);
+    }
+
+    #[test]
+    fn report() {
+        let pars = [
+            ParamConfig {
+                limits: Limits::Lower(4.),
+                ..Default::default()
+            },
+            ParamConfig::default(),
+        ];
+        let status =
+            fit_from(&linear_problem(), &[5., 1.], pars, Default::default()).expect("Error in fit");
+        let report = status.report(Some(&["offset"]));
+        assert_eq!(report.success, status.success);
+        assert_eq!(report.dof, 8);
+        assert_eq!(report.reduced_chi2, Some(status.best_norm / 8.));
+        assert_eq!(report.params.len(), 2);
+        for (i, row) in report.params.iter().enumerate() {
+            assert_eq!(row.value, status.params[i]);
+            assert_eq!(row.error, status.xerror[i]);
+            assert_eq!(row.pegged, status.pegged[i]);
+        }
+        assert_eq!(status.pegged, [true, false]);
+        assert_eq!(report.params[0].name.as_deref(), Some("offset"));
+        assert_eq!(report.params[1].name, None);
+        assert_eq!(status.report(None).params[0].name, None);
+    }
+
+    #[test]
+    fn linear_jacobian() {
+        let l = linear_problem();
+        let mut init = [1., 1.];
+        let status = fit(&l, &mut init, Default::default(), Default::default())
+            .expect("Error in Linear fit");
+        assert_eq!(status.jacobian, None);
+
+        let mut init = [1., 1.];
+        let config = Config {
+            return_jacobian: true,
+            ..Default::default()
+        };
+        let status = fit(&l, &mut init, Default::default(), config).expect("Error in Linear fit");
+        let jacobian = status.jacobian.expect("missing Jacobian");
+        assert_eq!(jacobian.len(), 2 * l.x.len());
+        let (da, db) = jacobian.split_at(l.x.len());
+        for (i, (x, ye)) in l.x.iter().zip(&l.ye).enumerate() {
+            assert_approx_eq!(da[i], -1. / ye, 1e-5);
+            assert_approx_eq!(db[i], -x / ye, 1e-5);
+        }
+    }
+
+    #[test]
+    fn linear_from() {
+        let l = linear_problem();
+        let init = [1., 1.];
+        let status = fit_from(&l, &init, Default::default(), Default::default())
+            .expect("Error in Linear fit");
+        assert_eq!(init, [1., 1.]);
+        assert_approx_eq!(status.params[0], 3.20996572);
+        assert_approx_eq!(status.params[1], 1.77095420);
+    }
+
+    #[test]
+    fn linear_pvalue() {
+        assert_approx_eq!(gamma_q(1., 2.), (-2f64).exp());
+        assert_approx_eq!(gamma_q(4., 0.5), 0.99824837);
+        assert_approx_eq!(gamma_q(4., 10.), 0.01033605);
+
+        let l = linear_problem();
+        let mut init = [1., 1.];
+        let status = fit(&l, &mut init, Default::default(), Default::default())
+            .expect("Error in Linear fit");
+        assert_approx_eq!(status.chi2_pvalue().unwrap(), 0.94870894, 1e-6);
+
+        struct Constant(Linear);
+        impl Problem<1> for Constant {
+            fn eval(&self, [c]: &[f64; 1], deviates: &mut [f64]) -> Result<()> {
+                self.0.eval(&[*c, 0.], deviates)
             }
-            if nsing < self.nfree {
-                self.wa3[j] = 0.;
+
+            fn number_of_points(&self) -> usize {
+                self.0.number_of_points()
             }
-            jj += self.m + 1;
         }
-        if nsing >= 1 {
-            for k in 0..nsing {
-                let j = nsing - k - 1;
-                let mut ij = self.m * j;
-                self.wa3[j] /= self.fjac[j + ij];
-                let temp = self.wa3[j];
-                if j > 0 {
-                    for i in 0..j {
-                        self.wa3[i] -= self.fjac[ij] * temp;
-                        ij += 1;
-                    }
+        let mut init = [1.];
+        let status = fit(
+            &Constant(l),
+            &mut init,
+            Default::default(),
+            Default::default(),
+        )
+        .expect("Error in Constant fit");
+        assert!(status.chi2_pvalue().unwrap() < 1e-10);
+    }
+
+    #[test]
+    fn linear_chunked() {
+        struct Chunked(Linear);
+        impl Problem<2> for Chunked {
+            fn eval(&self, params: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                self.0.eval(params, deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.number_of_points()
+            }
+
+            fn eval_chunk(
+                &self,
+                [a, b]: &[f64; 2],
+                offset: usize,
+                deviates: &mut [f64],
+            ) -> Result<()> {
+                let l = &self.0;
+                for (i, d) in (offset..).zip(deviates) {
+                    let f = a + b * l.x[i];
+                    *d = (l.y[i] - f) / l.ye[i];
                 }
+                Ok(())
             }
         }
-        for j in 0..self.nfree {
-            self.wa1[self.ipvt[j]] = self.wa3[j];
+
+        let l = Chunked(linear_problem());
+        let mut init = [1., 1.];
+        let whole = fit(&l, &mut init, Default::default(), Default::default())
+            .expect("Error in Linear fit");
+        for chunk_size in [1, 3, 10, 20] {
+            let config = Config {
+                chunk_size,
+                ..Default::default()
+            };
+            let mut init = [1., 1.];
+            let chunked = fit(&l, &mut init, Default::default(), config).expect("Error in fit");
+            assert_eq!(chunked, whole);
         }
-        /*
-         *     initialize the iteration counter.
-         *     evaluate the function at the origin, and test
-         *     for acceptance of the gauss-newton direction.
-         */
-        for j in 0..self.nfree {
-            self.wa4[j] = self.diag[self.ifree[j]] * self.wa1[j];
+
+        // default implementation
+        let l = linear_problem();
+        let config = Config {
+            chunk_size: 4,
+            ..Default::default()
+        };
+        let mut init = [1., 1.];
+        let chunked = fit(&l, &mut init, Default::default(), config).expect("Error in fit");
+        assert_eq!(chunked, whole);
+    }
+
+    #[test]
+    fn parallel_fits() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Status<2>>();
+        assert_send_sync::<Config>();
+        assert_send_sync::<ParamConfig>();
+        assert_send_sync::<Parameters<2>>();
+        assert_send_sync::<Error>();
+        assert_send_sync::<MultiStart<2>>();
+        assert_send_sync::<Bootstrap<2>>();
+        assert_send_sync::<Information<2>>();
+        assert_send_sync::<FitReport>();
+        assert_send_sync::<ConfigBuilder>();
+        // The adapters as long as the wrapped problem and closures are
+        type Map = fn(&[f64; 1]) -> [f64; 2];
+        type Model = fn(&[f64; 2], f64) -> f64;
+        assert_send_sync::<Correlated<Linear>>();
+        assert_send_sync::<Reparameterized<Linear, Map, 2>>();
+        assert_send_sync::<Weighted<Linear>>();
+        assert_send_sync::<Masked<Linear>>();
+        assert_send_sync::<Curve<Model>>();
+        assert_send_sync::<Progress<Linear, ()>>();
+
+        let problems: Vec<_> = (0..8)
+            .map(|i| {
+                let mut l = linear_problem();
+                for y in &mut l.y {
+                    *y += i as f64;
+                }
+                l
+            })
+            .collect();
+        let results: Vec<_> = std::thread::scope(|s| {
+            let handles: Vec<_> = problems
+                .iter()
+                .map(|l| {
+                    s.spawn(move || fit_from(l, &[1., 1.], Default::default(), Default::default()))
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        for (i, res) in results.into_iter().enumerate() {
+            let status = res.expect("Error in fit");
+            assert_approx_eq!(status.params[0], 3.20996572 + i as f64);
+            assert_approx_eq!(status.params[1], 1.77095420);
         }
-        let mut dxnorm = self.wa4[0..self.nfree].enorm();
-        let mut fp = dxnorm - self.delta;
-        if fp <= 0.1 * self.delta {
-            self.par = 0.;
-            return;
+    }
+
+    #[test]
+    fn reuse_config() {
+        let init = [1., 1.];
+        let pars = [ParamConfig::default(); 2];
+        let config = Config {
+            max_iter: 50,
+            ..Default::default()
+        };
+        let first = linear_problem();
+        let mut second = linear_problem();
+        for y in &mut second.y {
+            *y += 1.;
         }
-        /*
-         *     if the jacobian is not rank deficient, the newton
-         *     step provides a lower bound, parl, for the zero of
-         *     the function. otherwise set this bound to zero.
-         */
-        let mut parl = 0.;
-        if nsing >= self.nfree {
-            self.newton_correction(dxnorm);
-            let mut jj = 0;
-            for j in 0..self.nfree {
-                let mut sum = 0.;
-                if j > 0 {
-                    let mut ij = jj;
-                    for i in 0..j {
-                        sum += self.fjac[ij] * self.wa3[i];
-                        ij += 1;
-                    }
-                }
-                self.wa3[j] = (self.wa3[j] - sum) / self.fjac[j + self.m * j];
-                jj += self.m;
+        let a = fit_from(&first, &init, pars, config).expect("Error in first fit");
+        let b = fit_from(&second, &init, pars, config).expect("Error in second fit");
+        assert_approx_eq!(b.params[0] - a.params[0], 1.);
+        assert_approx_eq!(b.params[1], a.params[1]);
+        let c = fit_from(&first, &init, pars, config).expect("Error in third fit");
+        assert_eq!(a, c);
+    }
+
+    #[test]
+    fn param_config_template() {
+        let template = ParamConfig {
+            limits: Limits::Lower(0.),
+            ..Default::default()
+        };
+        let mut pars = vec![template; 3];
+        pars[1].fixed = true;
+        pars[2].limits = Limits::None;
+        assert_eq!(pars[0], template);
+        assert!(pars[1].fixed && !pars[0].fixed && !pars[2].fixed);
+        assert_eq!(pars[1].limits, Limits::Lower(0.));
+        assert_eq!(pars[2].limits, Limits::None);
+        let copied = pars.clone();
+        assert_eq!(copied, pars);
+        assert!(format!("{template:?}").starts_with("ParamConfig"));
+    }
+
+    #[test]
+    fn combined_covariance() {
+        // Two datasets with different offsets sharing the slope
+        let first = linear_problem();
+        let mut second = linear_problem();
+        for y in &mut second.y {
+            *y += 1.;
+        }
+        let a = fit_from(&first, &[1., 1.], Default::default(), Default::default())
+            .expect("Error in first fit");
+        let b = fit_from(&second, &[1., 1.], Default::default(), Default::default())
+            .expect("Error in second fit");
+        // Combined parameters: offset of the first, offset of the second, slope
+        let covar = combine_covariance::<3, 2, 2>(&a, [0, 2], &b, [1, 2])
+            .expect("Combined information is regular");
+        let slope_error = covar[2 * 3 + 2].sqrt();
+        assert!(slope_error < a.xerror[1]);
+        assert!(slope_error < b.xerror[1]);
+        // Same data errors, so the information doubles
+        assert_approx_eq!(slope_error, a.xerror[1] / 2f64.sqrt());
+        for i in 0..3 {
+            for j in 0..3 {
+                assert_approx_eq!(covar[i * 3 + j], covar[j * 3 + i]);
             }
-            let temp = self.wa3[0..self.nfree].enorm();
-            parl = ((fp / self.delta) / temp) / temp;
         }
-        /*
-         *     calculate an upper bound, paru, for the zero of the function.
-         */
-        let mut jj = 0;
-        for j in 0..self.nfree {
-            let mut sum = 0.;
-            let mut ij = jj;
-            for i in 0..=j {
-                sum += self.fjac[ij] * self.qtf[i];
-                ij += 1;
+
+        assert_eq!(combine_covariance::<3, 2, 2>(&a, [0, 3], &b, [1, 2]), None);
+        assert_eq!(combine_covariance::<4, 2, 2>(&a, [0, 2], &b, [1, 2]), None);
+    }
+
+    #[test]
+    fn toggle_parameters() {
+        let l = linear_problem();
+        let mut params = Parameters::new([1., 1.]);
+        params.fix(0).set_bounds(1, 0., 10.);
+        let status =
+            fit(&l, &mut params.values, params.config, Default::default()).expect("Error in fit");
+        assert_eq!(status.n_free, 1);
+        assert_eq!(params.values[0], 1.);
+        params.free(0);
+        let status =
+            fit(&l, &mut params.values, params.config, Default::default()).expect("Error in fit");
+        assert_eq!(status.n_free, 2);
+        assert_approx_eq!(params.values[0], 3.20996572);
+        assert_approx_eq!(params.values[1], 1.77095420);
+        assert_eq!(params.config[1].limits, Limits::Both(0., 10.));
+    }
+
+    #[test]
+    fn parameters_solve() {
+        let l = linear_problem();
+        let mut params = Parameters::new([1., 1.]);
+        params.set_bounds(1, 0., 10.);
+        let status = params.solve(&l, Default::default()).expect("Error in fit");
+        assert_approx_eq!(params.value(0).unwrap(), 3.20996572);
+        assert_approx_eq!(params.value(1).unwrap(), 1.77095420);
+        assert_eq!(params.value(2), None);
+        assert_eq!(params.values, status.params);
+        assert_approx_eq!(status.xerror[0], 0.02221018);
+        assert_approx_eq!(status.xerror[1], 0.01893756);
+        assert_eq!(
+            params.param_config(1).map(|p| p.limits),
+            Some(Limits::Both(0., 10.))
+        );
+        assert_eq!(params.param_config(2), None);
+    }
+
+    #[test]
+    fn mixed_scale_dir() {
+        struct Scaled(Linear);
+        impl Problem<2> for Scaled {
+            fn eval(&self, [a, b]: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                self.0.eval(&[a * 1e6, b * 1e-6], deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.number_of_points()
             }
-            let l = self.ipvt[j];
-            self.wa3[j] = sum / self.diag[self.ifree[l]];
-            jj += self.m;
         }
-        let gnorm = self.wa3[0..self.nfree].enorm();
-        let mut paru = gnorm / self.delta;
-        if paru == 0. {
-            paru = f64::MIN_POSITIVE / self.delta.min(0.1);
+
+        let config = Config {
+            ftol: 1e-300,
+            xtol: 1e-300,
+            gtol: 1e-6,
+            ..Default::default()
+        };
+        let mut init = [1e-6, 1e6];
+        let status = fit(
+            &Scaled(linear_problem()),
+            &mut init,
+            Default::default(),
+            config,
+        )
+        .expect("Error");
+        assert_eq!(status.success, Success::Dir);
+        assert_approx_eq!(init[0] * 1e6, 3.20996572, 1e-6);
+        assert_approx_eq!(init[1] * 1e-6, 1.77095420, 1e-6);
+    }
+
+    #[test]
+    fn step_scale() {
+        struct Recording {
+            inner: Linear,
+            evals: std::cell::RefCell<Vec<[f64; 2]>>,
         }
-        /*
-         *     if the input par lies outside of the interval (parl,paru),
-         *     set par to the closer endpoint.
-         */
-        self.par = self.par.max(parl);
-        self.par = self.par.max(paru);
-        if self.par == 0. {
-            self.par = gnorm / dxnorm;
+
+        impl Problem<2> for Recording {
+            fn eval(&self, params: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                self.evals.borrow_mut().push(*params);
+                self.inner.eval(params, deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.inner.number_of_points()
+            }
         }
-        let mut iter = 0;
-        loop {
-            iter += 1;
-            if self.par == 0. {
-                self.par = f64::MIN_POSITIVE.max(0.001 * paru);
+
+        let l = Recording {
+            inner: linear_problem(),
+            evals: Default::default(),
+        };
+        let pars = [
+            ParamConfig {
+                step_scale: 0.5,
+                rel_step: 0.1,
+                ..Default::default()
+            },
+            ParamConfig {
+                step_scale: 0.25,
+                ..Default::default()
+            },
+        ];
+        let mut init = [1., 1.];
+        fit(&l, &mut init, pars, Default::default()).expect("Error in Linear fit");
+        assert_eq!(l.evals.borrow()[..3], [[1., 1.], [1.5, 1.], [1., 1.25]]);
+        assert_approx_eq!(init[0], 3.20996572);
+        assert_approx_eq!(init[1], 1.77095420);
+    }
+
+    #[test]
+    fn linear_profile() {
+        let l = linear_problem();
+        let mut init = [1., 1.];
+        let status = fit(&l, &mut init, Default::default(), Default::default())
+            .expect("Error in Linear fit");
+        let bounds = profile(&l, &init, Default::default(), Default::default(), 1.0)
+            .expect("Error in Linear profile");
+        for i in 0..2 {
+            let (lower, upper) = bounds[i];
+            assert_approx_eq!(init[i] - lower, status.xerror[i], 1e-6);
+            assert_approx_eq!(upper - init[i], status.xerror[i], 1e-6);
+        }
+    }
+
+    #[test]
+    fn quad() {
+        struct Quad {
+            x: Vec<f64>,
+            y: Vec<f64>,
+            ye: Vec<f64>,
+        }
+
+        impl Problem<3> for Quad {
+            fn eval(&self, [a, b, c]: &[f64; 3], deviates: &mut [f64]) -> Result<()> {
+                for (((d, x), y), ye) in deviates
+                    .iter_mut()
+                    .zip(self.x.iter())
+                    .zip(self.y.iter())
+                    .zip(self.ye.iter())
+                {
+                    let x = *x;
+                    let f = a + b * x + c * x * x;
+                    *d = (*y - f) / *ye;
+                }
+                Ok(())
             }
-            let temp = self.par.sqrt();
-            for j in 0..self.nfree {
-                self.wa3[j] = temp * self.diag[self.ifree[j]];
+
+            fn number_of_points(&self) -> usize {
+                self.x.len()
             }
-            self.qrsolv();
-            for j in 0..self.nfree {
-                self.wa4[j] = self.diag[self.ifree[j]] * self.wa1[j];
+        }
+        let l = Quad {
+            x: vec![
+                -1.7237128E+00,
+                1.8712276E+00,
+                -9.6608055E-01,
+                -2.8394297E-01,
+                1.3416969E+00,
+                1.3757038E+00,
+                -1.3703436E+00,
+                4.2581975E-02,
+                -1.4970151E-01,
+                8.2065094E-01,
+            ],
+            y: vec![
+                2.3095947E+01,
+                2.6449392E+01,
+                1.0204468E+01,
+                5.40507,
+                1.5787588E+01,
+                1.6520903E+01,
+                1.5971818E+01,
+                4.7668524E+00,
+                4.9337711E+00,
+                8.7348375E+00,
+            ],
+            ye: vec![0.2; 10],
+        };
+        let mut init = [1., 1., 1.];
+        let status =
+            fit(&l, &mut init, Default::default(), Default::default()).expect("Error in Quad fit");
+        assert_eq!(status.success, Success::Chi);
+        assert_eq!(status.n_iter, 3);
+        assert_eq!(status.n_fev, 9);
+        assert_approx_eq!(status.best_norm, 5.67932273);
+        assert_approx_eq!(init[0], 4.70382909);
+        assert_approx_eq!(init[1], 0.06258629);
+        assert_approx_eq!(init[2], 6.16308723);
+        assert_approx_eq!(status.xerror[0], 0.09751164);
+        assert_approx_eq!(status.xerror[1], 0.05480195);
+        assert_approx_eq!(status.xerror[2], 0.05443275);
+
+        let pars = [
+            ParamConfig::default(),
+            ParamConfig {
+                fixed: true,
+                ..Default::default()
+            },
+            ParamConfig::default(),
+        ];
+        let mut init = [1., 0., 1.];
+        let status = fit(&l, &mut init, pars, Default::default()).expect("Error in Quad fixed fit");
+        assert_eq!(status.success, Success::Chi);
+        assert_eq!(status.n_iter, 3);
+        assert_eq!(status.n_fev, 7);
+        assert_approx_eq!(status.best_norm, 6.98358800);
+        assert_approx_eq!(init[0], 4.69625430);
+        assert_approx_eq!(init[1], 0.00000000);
+        assert_approx_eq!(init[2], 6.17295360);
+        assert_approx_eq!(status.xerror[0], 0.09728581);
+        assert_approx_eq!(status.xerror[1], 0.00000000);
+        assert_approx_eq!(status.xerror[2], 0.05374279);
+    }
+
+    #[test]
+    fn all_fixed() {
+        let l = linear_problem();
+        let pars = [ParamConfig {
+            fixed: true,
+            ..Default::default()
+        }; 2];
+        let mut init = [3.2, 1.8];
+        let status = fit(&l, &mut init, pars, Default::default()).expect("Error in fixed fit");
+        let chi2: f64 =
+            l.x.iter()
+                .zip(&l.y)
+                .zip(&l.ye)
+                .map(|((x, y), ye)| ((y - 3.2 - 1.8 * x) / ye).powi(2))
+                .sum();
+        assert_eq!(status.success, Success::MaxIter);
+        assert_eq!(status.n_free, 0);
+        assert_approx_eq!(status.best_norm, chi2);
+        assert_approx_eq!(status.orig_norm, chi2);
+        assert_eq!(init, [3.2, 1.8]);
+        assert_eq!(status.xerror, [0.; 2]);
+        assert!(status.covar.iter().all(|&c| c == 0.));
+    }
+
+    #[test]
+    fn working_set() {
+        assert_eq!(working_set_size(10, 2), (10 * 5 + 4) * 8);
+        assert_eq!(working_set_size(1_000_000, 5), 64_000_080);
+        assert_eq!(working_set_size(usize::MAX, 2), usize::MAX);
+
+        struct Huge;
+        impl Problem<1> for Huge {
+            fn eval(&self, _: &[f64; 1], _: &mut [f64]) -> Result<()> {
+                unreachable!()
             }
-            dxnorm = self.wa4[0..self.nfree].enorm();
-            let temp = fp;
-            fp = dxnorm - self.delta;
-            /*
-             *	 if the function is small enough, accept the current value
-             *	 of par. also test for the exceptional cases where parl
-             *	 is zero or the number of iterations has reached 10.
-             */
-            if fp.abs() <= 0.1 * self.delta || (parl == 0. && fp <= temp && temp < 0.) || iter >= 10
-            {
-                return;
+
+            fn number_of_points(&self) -> usize {
+                usize::MAX
             }
-            self.newton_correction(dxnorm);
-            jj = 0;
-            for j in 0..self.nfree {
-                self.wa3[j] /= self.wa2[j];
-                let temp = self.wa3[j];
-                let jp1 = j + 1;
-                if jp1 < self.nfree {
-                    let mut ij = jp1 + jj;
-                    for i in jp1..self.nfree {
-                        self.wa3[i] -= self.fjac[ij] * temp;
-                        ij += 1;
-                    }
+        }
+        let res = fit(&Huge, &mut [0.], Default::default(), Default::default());
+        assert_eq!(res, Err(Error::Memory));
+    }
+
+    #[test]
+    fn user_error() {
+        #[derive(Debug, PartialEq)]
+        struct Failed(usize);
+
+        struct Failing {
+            inner: Linear,
+            calls: std::cell::Cell<usize>,
+        }
+
+        impl Problem<2, Failed> for Failing {
+            fn eval(&self, params: &[f64; 2], deviates: &mut [f64]) -> Result<(), Failed> {
+                let calls = self.calls.get() + 1;
+                self.calls.set(calls);
+                if calls == 3 {
+                    return Err(Error::User(Failed(calls)));
                 }
-                jj += self.m;
-            }
-            let temp = self.wa3[0..self.nfree].enorm();
-            let parc = ((fp / self.delta) / temp) / temp;
-            /*
-             *	 depending on the sign of the function, update parl or paru.
-             */
-            if fp > 0.0 {
-                parl = parl.max(self.par);
+                self.inner.eval(params, deviates).map_err(|_| Error::Eval)
             }
-            if fp < 0.0 {
-                paru = paru.min(self.par);
+
+            fn number_of_points(&self) -> usize {
+                self.inner.number_of_points()
             }
-            /*
-             *	 compute an improved estimate for par.
-             */
-            self.par = parl.max(self.par + parc);
         }
+
+        let f = Failing {
+            inner: linear_problem(),
+            calls: Default::default(),
+        };
+        let res = fit(&f, &mut [1., 1.], Default::default(), Default::default());
+        assert_eq!(res, Err(Error::User(Failed(3))));
+        assert_eq!(f.calls.get(), 3);
     }
 
-    /// compute the newton correction.
-    fn newton_correction(&mut self, dxnorm: f64) {
-        for j in 0..self.nfree {
-            let l = self.ipvt[j];
-            self.wa3[j] = self.diag[self.ifree[l]] * (self.wa4[l] / dxnorm);
+    #[test]
+    fn compensated_norm() {
+        // one large value followed by a million small ones, whose squares are
+        // completely lost in a naive sum
+        let mut seed: u64 = 42;
+        let mut v = vec![1e8];
+        v.extend((0..1_000_000).map(|_| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            0.5 + (seed >> 11) as f64 / (1u64 << 53) as f64
+        }));
+        // reference sum of squares in double-double arithmetic
+        let (mut hi, mut lo) = (0f64, 0f64);
+        for x in &v {
+            let p = x * x;
+            let e = x.mul_add(*x, -p);
+            let t = hi + p;
+            lo += if hi.abs() >= p {
+                (hi - t) + p
+            } else {
+                (p - t) + hi
+            } + e;
+            hi = t;
         }
+        let reference = hi.sqrt() * (1. + 0.5 * lo / hi);
+
+        let plain = (v.enorm() - reference).abs() / reference;
+        let compensated = (v.enorm_with(true) - reference).abs() / reference;
+        assert!(compensated < 1e-15, "compensated error {compensated}");
+        assert!(compensated < plain, "{compensated} >= {plain}");
+
+        let l = linear_problem();
+        let mut init = [1., 1.];
+        let config = Config {
+            compensated_sum: true,
+            ..Default::default()
+        };
+        let status = fit(&l, &mut init, Default::default(), config).expect("Error in Linear fit");
+        assert_approx_eq!(status.best_norm, 2.75628498);
     }
 
-    /// subroutine qrsolv
-    ///
-    /// given an m by n matrix a, an n by n diagonal matrix d, and an m-vector
-    /// b, the problem is to determine an x which solves the system
-    ///
-    /// a*x = b ,     d*x = 0 ,
-    ///
-    /// in the least squares sense.
-    ///
-    /// this subroutine completes the solution of the problem if it is provided
-    /// with the necessary information from the qr factorization, with column
-    /// pivoting, of a. that is, if a*p = q*r, where p is a permutation matrix,
-    /// q has orthogonal columns, and r is an upper triangular matrix with
-    /// diagonal elements of nonincreasing magnitude, then qrsolv expects the
-    /// full upper triangle of r, the permutation matrix p, and the first n
-    /// components of (q transpose)*b. the system a*x = b, d*x = 0, is then
-    /// equivalent to
-    ///
-    /// r*z = q *b ,  p *d*p*z = 0 ,
-    ///
-    /// where x = p*z. if this system does not have full rank, then a least
-    /// squares solution is obtained. on output qrsolv also provides an upper
-    /// triangular matrix s such that
-    ///
-    /// p *(a *a + d*d)*p = s *s .
-    ///
-    /// s is computed within qrsolv and may be of separate interest.
-    ///
-    /// the subroutine statement is
-    ///
-    /// subroutine qrsolv(n,r,ldr,ipvt,diag,qtb,x,sdiag,wa)
-    ///
-    /// where
-    ///
-    /// n is a positive integer input variable set to the order of r.
-    ///
-    /// r is an n by n array. on input the full upper triangle must contain the
-    /// full upper triangle of the matrix r. on output the full upper triangle
-    /// is unaltered, and the strict lower triangle contains the strict upper
-    /// triangle (transposed) of the upper triangular matrix s.
-    ///
-    /// ldr is a positive integer input variable not less than n which specifies
-    /// the leading dimension of the array r.
-    ///
-    /// ipvt is an integer input array of length n which defines the permutation
-    /// matrix p such that a*p = q*r. column j of p is column ipvt(j) of the
-    /// identity matrix.
-    ///
-    /// diag is an input array of length n which must contain the diagonal
-    /// elements of the matrix d.
-    ///
-    /// qtb is an input array of length n which must contain the first n
-    /// elements of the vector (q transpose)*b.
-    ///
-    /// x is an output array of length n which contains the least squares
-    /// solution of the system a*x = b, d*x = 0.
-    ///
-    /// sdiag is an output array of length n which contains the diagonal
-    /// elements of the upper triangular matrix s.
-    ///
-    /// wa is a work array of length n.
-    fn qrsolv(&mut self) {
-        /*
-         *     copy r and (q transpose)*b to preserve input and initialize s.
-         *     in particular, save the diagonal elements of r in x.
-         */
-        let mut kk = 0;
-        for j in 0..self.nfree {
-            let mut ij = kk;
-            let mut ik = kk;
-            for _ in j..self.nfree {
-                self.fjac[ij] = self.fjac[ik];
-                ij += 1;
-                ik += self.m;
+    #[test]
+    fn regularization() {
+        // only the sum of both parameters is determined by the data
+        struct Sum;
+        impl Problem<2> for Sum {
+            fn eval(&self, [a, b]: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                deviates.fill(4. - a - b);
+                Ok(())
             }
-            self.wa1[j] = self.fjac[kk];
-            self.wa4[j] = self.qtf[j];
-            kk += self.m + 1;
-        }
-        /*
-         *     eliminate the diagonal matrix d using a givens rotation.
-         */
-        for j in 0..self.nfree {
-            /*
-             *	 prepare the row of d to be eliminated, locating the
-             *	 diagonal element using p from the qr factorization.
-             */
-            let l = self.ipvt[j];
-            if self.wa3[l] != 0. {
-                for k in j..self.nfree {
-                    self.wa2[k] = 0.;
-                }
-                self.wa2[j] = self.wa3[l];
-                /*
-                 *	 the transformations to eliminate the row of d
-                 *	 modify only a single element of (q transpose)*b
-                 *	 beyond the first n, which is initially zero.
-                 */
-                let mut qtbpj = 0.;
-                for k in j..self.nfree {
-                    /*
-                     *	    determine a givens rotation which eliminates the
-                     *	    appropriate element in the current row of d.
-                     */
-                    if self.wa2[k] == 0. {
-                        continue;
-                    }
-                    let kk = k + self.m * k;
-                    let (sinx, cosx) = if self.fjac[kk].abs() < self.wa2[k].abs() {
-                        let cotan = self.fjac[kk] / self.wa2[k];
-                        let sinx = 0.5 / (0.25 + 0.25 * cotan * cotan).sqrt();
-                        let cosx = sinx * cotan;
-                        (sinx, cosx)
-                    } else {
-                        let tanx = self.wa2[k] / self.fjac[kk];
-                        let cosx = 0.5 / (0.25 + 0.25 * tanx * tanx).sqrt();
-                        let sinx = cosx * tanx;
-                        (sinx, cosx)
-                    };
-                    /*
-                     *	    compute the modified diagonal element of r and
-                     *	    the modified element of ((q transpose)*b,0).
-                     */
-                    self.fjac[kk] = cosx * self.fjac[kk] + sinx * self.wa2[k];
-                    let temp = cosx * self.wa4[k] + sinx * qtbpj;
-                    qtbpj = -sinx * self.wa4[k] + cosx * qtbpj;
-                    self.wa4[k] = temp;
-                    /*
-                     *	    accumulate the tranformation in the row of s.
-                     */
-                    let kp1 = k + 1;
-                    if self.nfree > kp1 {
-                        let mut ik = kk + 1;
-                        for i in kp1..self.nfree {
-                            let temp = cosx * self.fjac[ik] + sinx * self.wa2[i];
-                            self.wa2[i] = -sinx * self.fjac[ik] + cosx * self.wa2[i];
-                            self.fjac[ik] = temp;
-                            ik += 1;
-                        }
-                    }
-                }
+
+            fn number_of_points(&self) -> usize {
+                3
             }
-            /*
-             *	 store the diagonal element of s and restore
-             *	 the corresponding diagonal element of r.
-             */
-            let kk = j + self.m * j;
-            self.wa2[j] = self.fjac[kk];
-            self.fjac[kk] = self.wa1[j];
         }
-        /*
-         *     solve the triangular system for z. if the system is
-         *     singular, then obtain a least squares solution.
-         */
-        let mut nsing = self.nfree;
-        for j in 0..self.nfree {
-            if self.wa2[j] == 0. && nsing == self.nfree {
-                nsing = j;
-            }
-            if nsing < self.nfree {
-                self.wa4[j] = 0.;
+
+        let mut init = [3., 0.5];
+        fit(&Sum, &mut init, Default::default(), Default::default()).expect("Error in Sum fit");
+        assert_approx_eq!(init[0] + init[1], 4.);
+        assert!(init[0] - init[1] > 1.);
+
+        let pars = [ParamConfig {
+            prior: Some(0.),
+            ..Default::default()
+        }; 2];
+        let config = Config {
+            regularization: 1e-6,
+            ..Default::default()
+        };
+        let mut init = [3., 0.5];
+        let status = fit(&Sum, &mut init, pars, config).expect("Error in regularized Sum fit");
+        assert_eq!(status.n_func, 3);
+        assert_eq!(status.resid.len(), 3);
+        assert_approx_eq!(init[0] + init[1], 4., 1e-5);
+        assert_approx_eq!(init[0], init[1], 1e-5);
+    }
+
+    struct Gaussian {
+        x: Vec<f64>,
+        y: Vec<f64>,
+        ye: Vec<f64>,
+    }
+
+    impl Problem<4> for Gaussian {
+        fn eval(&self, [off, a, x0, sig]: &[f64; 4], deviates: &mut [f64]) -> Result<()> {
+            let sig2 = sig * sig;
+            for (((d, x), y), ye) in deviates
+                .iter_mut()
+                .zip(self.x.iter())
+                .zip(self.y.iter())
+                .zip(self.ye.iter())
+            {
+                let xc = *x - x0;
+                let f = a * (-0.5 * xc * xc / sig2).exp() + off;
+                *d = (*y - f) / *ye;
             }
+            Ok(())
         }
-        if nsing > 0 {
-            for k in 0..nsing {
-                let j = nsing - k - 1;
-                let mut sum = 0.;
-                let jp1 = j + 1;
-                if nsing > jp1 {
-                    let mut ij = jp1 + self.m * j;
-                    for i in jp1..nsing {
-                        sum += self.fjac[ij] * self.wa4[i];
-                        ij += 1;
-                    }
-                }
-                self.wa4[j] = (self.wa4[j] - sum) / self.wa2[j];
-            }
+
+        fn number_of_points(&self) -> usize {
+            self.x.len()
         }
-        /*
-         *     permute the components of z back to components of x.
-         */
-        for j in 0..self.nfree {
-            self.wa1[self.ipvt[j]] = self.wa4[j];
+    }
+
+    fn gaussian_problem() -> Gaussian {
+        Gaussian {
+            x: vec![
+                -1.7237128E+00,
+                1.8712276E+00,
+                -9.6608055E-01,
+                -2.8394297E-01,
+                1.3416969E+00,
+                1.3757038E+00,
+                -1.3703436E+00,
+                4.2581975E-02,
+                -1.4970151E-01,
+                8.2065094E-01,
+            ],
+            y: vec![
+                -4.4494256E-02,
+                8.7324673E-01,
+                7.4443483E-01,
+                4.7631559E+00,
+                1.7187297E-01,
+                1.1639182E-01,
+                1.5646480E+00,
+                5.2322268E+00,
+                4.2543168E+00,
+                6.2792623E-01,
+            ],
+            ye: vec![0.5; 10],
         }
     }
 
-    fn iterate(&mut self, gnorm: f64) -> Result<MPDone> {
-        for j in 0..self.nfree {
-            self.wa1[j] = -self.wa1[j];
+    #[test]
+    fn iteration_covar() {
+        struct Recorder {
+            inner: Gaussian,
+            covars: std::cell::RefCell<Vec<Option<Box<[f64]>>>>,
         }
-        let mut alpha: f64 = 1.0;
-        if !self.qanylim {
-            /* No parameter limits, so just move to new position WA2 */
-            for j in 0..self.nfree {
-                self.wa2[j] = self.x[j] + self.wa1[j];
+
+        impl Problem<4> for Recorder {
+            fn eval(&self, params: &[f64; 4], deviates: &mut [f64]) -> Result<()> {
+                self.inner.eval(params, deviates)
             }
-        } else {
-            /* Respect the limits.  If a step were to go out of bounds, then
-             * we should take a step in the same direction but shorter distance.
-             * The step should take us right to the limit in that case.
-             */
-            for j in 0..self.nfree {
-                let lpegged = self.qllim[j] && self.x[j] <= self.llim[j];
-                let upegged = self.qulim[j] && self.x[j] >= self.ulim[j];
-                let dwa1 = self.wa1[j].abs() > f64::EPSILON;
-                if lpegged && self.wa1[j] < 0. {
-                    self.wa1[j] = 0.;
-                }
-                if upegged && self.wa1[j] > 0. {
-                    self.wa1[j] = 0.;
-                }
-                if dwa1 && self.qllim[j] && self.x[j] + self.wa1[j] < self.llim[j] {
-                    alpha = alpha.min((self.llim[j] - self.x[j]) / self.wa1[j]);
-                }
-                if dwa1 && self.qulim[j] && self.x[j] + self.wa1[j] > self.ulim[j] {
-                    alpha = alpha.min((self.ulim[j] - self.x[j]) / self.wa1[j]);
-                }
+
+            fn number_of_points(&self) -> usize {
+                self.inner.number_of_points()
             }
-            /* Scale the resulting vector, advance to the next position */
-            for j in 0..self.nfree {
-                self.wa1[j] *= alpha;
-                self.wa2[j] = self.x[j] + self.wa1[j];
-                /*
-                 * Adjust the output values.  If the step put us exactly
-                 * on a boundary, make sure it is exact.
-                 */
-                let sgnu = if self.ulim[j] >= 0. { 1. } else { -1. };
-                let sgnl = if self.llim[j] >= 0. { 1. } else { -1. };
-                let ulim1 = self.ulim[j] * (1. - sgnu * f64::EPSILON)
-                    - if self.ulim[j] == 0. { f64::EPSILON } else { 0. };
-                let llim1 = self.llim[j] * (1. + sgnl * f64::EPSILON)
-                    + if self.llim[j] == 0. { f64::EPSILON } else { 0. };
-                if self.qulim[j] && self.wa2[j] >= ulim1 {
-                    self.wa2[j] = self.ulim[j];
-                }
-                if self.qllim[j] && self.wa2[j] <= llim1 {
-                    self.wa2[j] = self.llim[j];
-                }
+
+            fn iteration(&self, iteration: &Iteration<4>) {
+                self.covars.borrow_mut().push(iteration.covar.clone());
             }
         }
-        for j in 0..self.nfree {
-            self.wa3[j] = self.diag[self.ifree[j]] * self.wa1[j];
-        }
-        let pnorm = self.wa3[0..self.nfree].enorm();
-        /*
-         *	    on the first iteration, adjust the initial step bound.
-         */
-        if self.iter == 1 {
-            self.delta = self.delta.min(pnorm);
+
+        let f = Recorder {
+            inner: gaussian_problem(),
+            covars: Default::default(),
+        };
+        let config = Config {
+            iteration_covar: true,
+            ..Default::default()
+        };
+        let mut init = [0., 1., 1., 1.];
+        let status = fit(&f, &mut init, Default::default(), config).expect("Error in fit");
+        let covars: Vec<_> = f.covars.into_inner().into_iter().flatten().collect();
+        // The iteration counter is already advanced by the final step
+        assert_eq!(covars.len(), status.n_iter - 1);
+        for covar in &covars {
+            assert_eq!(covar.len(), 16);
+            for i in 0..4 {
+                assert!(covar[i * 4 + i] >= 0.);
+                for j in 0..i {
+                    assert_eq!(covar[i * 4 + j], covar[j * 4 + i]);
+                }
+            }
         }
-        /*
-         *	    evaluate the function at x + p and calculate its norm.
-         */
-        for i in 0..self.nfree {
-            self.xnew[self.ifree[i]] = self.wa2[i];
+        let trace = |c: &[f64]| (0..4).map(|i| c[i * 4 + i]).sum::<f64>();
+        assert!(trace(&covars[covars.len() - 1]) < trace(&covars[0]));
+        for (a, b) in covars[covars.len() - 1].iter().zip(status.covar.iter()) {
+            assert_approx_eq!(a, b);
         }
-        self.f.eval(&self.xnew, &mut self.wa4)?;
-        self.nfev += 1;
-        self.fnorm1 = self.wa4[0..self.m].enorm();
-        /*
-         *	    compute the scaled actual reduction.
-         */
-        let actred = if 0.1 * self.fnorm1 < self.fnorm {
-            let temp = self.fnorm1 / self.fnorm;
-            1.0 - temp * temp
-        } else {
-            -1.0
+
+        // Not computed unless requested
+        let f = Recorder {
+            inner: gaussian_problem(),
+            covars: Default::default(),
         };
-        /*
-         *	    compute the scaled predicted reduction and
-         *	    the scaled directional derivative.
-         */
-        let mut jj = 0;
-        for j in 0..self.nfree {
-            self.wa3[j] = 0.;
-            let l = self.ipvt[j];
-            let temp = self.wa1[l];
-            let mut ij = jj;
-            for i in 0..=j {
-                self.wa3[i] += self.fjac[ij] * temp;
-                ij += 1;
+        let mut init = [0., 1., 1., 1.];
+        let status =
+            fit(&f, &mut init, Default::default(), Default::default()).expect("Error in fit");
+        let covars = f.covars.into_inner();
+        assert_eq!(covars.len(), status.n_iter - 1);
+        assert!(covars.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn retry() {
+        // chi^2 = sum (x^2 - 1)^2 has a saddle at x = 0, from where the steps
+        // grow only slowly
+        struct Saddle;
+
+        impl Problem<1> for Saddle {
+            fn eval(&self, [x]: &[f64; 1], deviates: &mut [f64]) -> Result<()> {
+                for (i, d) in deviates.iter_mut().enumerate() {
+                    *d = (i + 1) as f64 * (x * x - 1.);
+                }
+                Ok(())
             }
-            jj += self.m;
-        }
-        /*
-         * Remember, alpha is the fraction of the full LM step actually
-         * taken
-         */
-        let temp1 = self.wa3[0..self.nfree].enorm() * alpha / self.fnorm;
-        let temp2 = ((alpha * self.par).sqrt() * pnorm) / self.fnorm;
-        let temp11 = temp1 * temp1;
-        let temp22 = temp2 * temp2;
-        let prered = temp11 + temp22 / 0.5;
-        let dirder = -(temp11 + temp22);
-        /*
-         *	    compute the ratio of the actual to the predicted
-         *	    reduction.
-         */
-        let ratio = if prered != 0. { actred / prered } else { 0. };
-        /*
-         *	    update the step bound.
-         */
-        if ratio <= 0.25 {
-            let mut temp = if actred >= 0. {
-                0.5
-            } else {
-                0.5 * dirder / (dirder + 0.5 * actred)
-            };
-            if 0.1 * self.fnorm1 >= self.fnorm || temp < 0.1 {
-                temp = 0.1;
+
+            fn number_of_points(&self) -> usize {
+                3
             }
-            self.delta = temp * self.delta.min(pnorm / 0.1);
-            self.par /= temp;
-        } else if self.par == 0. || ratio >= 0.75 {
-            self.delta = pnorm / 0.5;
-            self.par *= 0.5;
         }
-        /*
-         *	    test for successful iteration.
-         */
-        if ratio >= 1e-4 {
-            /*
-             *	    successful iteration. update x, fvec, and their norms.
-             */
-            for j in 0..self.nfree {
-                self.x[j] = self.wa2[j];
-                self.wa2[j] = self.diag[self.ifree[j]] * self.x[j];
+
+        let config = Config {
+            gtol: 1e-300,
+            max_iter: 10,
+            ..Default::default()
+        };
+        let mut init = [1e-3];
+        let status = fit_from(&Saddle, &init, Default::default(), config).expect("Error in fit");
+        assert_eq!(status.success, Success::MaxIter);
+        let status = fit_with_retry(&Saddle, &mut init, Default::default(), config, 3, 0.5)
+            .expect("Error in fit");
+        assert_eq!(status.success, Success::Dir);
+        assert_approx_eq!(status.best_norm, 0.);
+        assert_approx_eq!(init[0].abs(), 1.);
+
+        // Clean fits are not retried
+        let l = linear_problem();
+        let mut init = [1., 1.];
+        let status = fit_with_retry(
+            &l,
+            &mut init,
+            Default::default(),
+            Default::default(),
+            3,
+            0.5,
+        )
+        .expect("Error in fit");
+        assert_eq!(status.n_fev, 7);
+
+        // Failing retries keep the first fit, its error is still returned
+        struct Sqrt {
+            broken: bool,
+            failures: std::cell::Cell<usize>,
+        }
+
+        impl Problem<1> for Sqrt {
+            fn eval(&self, [x]: &[f64; 1], deviates: &mut [f64]) -> Result<()> {
+                if self.broken || *x < 0. {
+                    self.failures.set(self.failures.get() + 1);
+                    return Err(Error::Eval);
+                }
+                for (i, d) in deviates.iter_mut().enumerate() {
+                    *d = x.sqrt() - i as f64;
+                }
+                Ok(())
             }
-            for i in 0..self.m {
-                self.fvec[i] = self.wa4[i];
+
+            fn number_of_points(&self) -> usize {
+                3
             }
-            self.xnorm = self.wa2[0..self.nfree].enorm();
-            self.fnorm = self.fnorm1;
-            self.iter += 1;
         }
-        /*
-         *	    tests for convergence.
-         */
-        if actred.abs() <= self.cfg.ftol && prered <= self.cfg.ftol && 0.5 * ratio <= 1.0 {
-            self.info = Success::Chi;
+
+        let sqrt = |broken| Sqrt {
+            broken,
+            failures: Default::default(),
+        };
+        let config = Config {
+            max_iter: 1,
+            ..Default::default()
+        };
+        let mut init = [0.5];
+        let first =
+            fit_from(&sqrt(false), &init, Default::default(), config).expect("Error in fit");
+        assert_eq!(first.success, Success::MaxIter);
+        let problem = sqrt(false);
+        let status = fit_with_retry(&problem, &mut init, Default::default(), config, 3, 10.)
+            .expect("Error in fit");
+        assert!(problem.failures.get() > 0);
+        assert!(status.best_norm <= first.best_norm);
+        assert_eq!(init, status.params);
+        let mut init = [0.5];
+        let res = fit_with_retry(&sqrt(true), &mut init, Default::default(), config, 3, 10.);
+        assert_eq!(res, Err(Error::Eval));
+        assert_eq!(init, [0.5]);
+    }
+
+    #[test]
+    fn complex_step() {
+        struct ComplexGaussian(Gaussian);
+        impl Problem<4> for ComplexGaussian {
+            fn eval(&self, params: &[f64; 4], deviates: &mut [f64]) -> Result<()> {
+                self.0.eval(params, deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.number_of_points()
+            }
+
+            fn eval_complex(
+                &self,
+                [off, a, x0, sig]: &[Complex; 4],
+                deviates: &mut [Complex],
+            ) -> Result<()> {
+                let sig2 = *sig * *sig;
+                let g = &self.0;
+                for (((d, x), y), ye) in deviates.iter_mut().zip(&g.x).zip(&g.y).zip(&g.ye) {
+                    let xc = *x - *x0;
+                    let f = *a * (-0.5 * xc * xc / sig2).exp() + *off;
+                    *d = (*y - f) / *ye;
+                }
+                Ok(())
+            }
         }
-        if self.delta <= self.cfg.xtol * self.xnorm {
-            self.info = Success::Par;
+
+        let f = ComplexGaussian(gaussian_problem());
+        let params = [0.5, 4.5, -0.1, 0.4];
+        let side = ParamConfig {
+            side: Side::Complex,
+            ..Default::default()
+        };
+        let config = Config {
+            max_iter: 0,
+            return_jacobian: true,
+            ..Default::default()
+        };
+        let status = fit_from(&f, &params, [side; 4], config).expect("Error in fit");
+        let jacobian = status.jacobian.expect("Jacobian requested");
+        let forward = fit_from(&f, &params, Default::default(), config)
+            .expect("Error in fit")
+            .jacobian
+            .expect("Jacobian requested");
+        let [_, a, x0, sig] = params;
+        let m = f.number_of_points();
+        let mut forward_error = 0f64;
+        for (i, (x, ye)) in f.0.x.iter().zip(&f.0.ye).enumerate() {
+            let xc = x - x0;
+            let e = (-0.5 * xc * xc / (sig * sig)).exp();
+            let analytic = [
+                -1. / ye,
+                -e / ye,
+                -a * e * xc / (sig * sig) / ye,
+                -a * e * xc * xc / (sig * sig * sig) / ye,
+            ];
+            for (j, d) in analytic.iter().enumerate() {
+                let scale = d.abs().max(1.);
+                assert!(
+                    (jacobian[j * m + i] - d).abs() <= 1e-15 * scale,
+                    "{} != {d}",
+                    jacobian[j * m + i]
+                );
+                forward_error = forward_error.max((forward[j * m + i] - d).abs() / scale);
+            }
         }
-        if actred.abs() <= self.cfg.ftol
-            && prered <= self.cfg.ftol
-            && 0.5 * ratio <= 1.0
-            && self.info == Success::Par
-        {
-            self.info = Success::Both;
+        assert!(forward_error > 1e-10);
+
+        // Not implemented by the problem
+        let l = linear_problem();
+        let res = fit_from(&l, &[1., 1.], [side; 2], Default::default());
+        assert_eq!(res, Err(Error::Input));
+    }
+
+    #[test]
+    fn central_differences() {
+        // Quadratic in the parameter a, so forward differences are off by h
+        struct Curved(Linear);
+        impl Problem<2> for Curved {
+            fn eval(&self, [a, b]: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                self.0.eval(&[a * a, *b], deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.number_of_points()
+            }
         }
-        if self.info != Success::NotDone {
-            return Ok(MPDone::Exit);
+
+        let f = Curved(linear_problem());
+        let params = [1.5, 2.];
+        let config = Config {
+            max_iter: 0,
+            return_jacobian: true,
+            ..Default::default()
+        };
+        let one_sided = ParamConfig {
+            rel_step: 1e-4,
+            ..Default::default()
+        };
+        let two_sided = ParamConfig {
+            side: Side::Both,
+            ..one_sided
+        };
+        let jacobian = |side| {
+            let status = fit_from(&f, &params, [side; 2], config).expect("Error in fit");
+            (status.n_fev, status.jacobian.expect("Jacobian requested"))
+        };
+        let (forward_fev, forward) = jacobian(one_sided);
+        let (central_fev, central) = jacobian(two_sided);
+        // Two evaluations per parameter instead of one
+        assert_eq!(central_fev, forward_fev + 2);
+        let (mut forward_error, mut central_error) = (0f64, 0f64);
+        for (i, ye) in f.0.ye.iter().enumerate() {
+            let analytic = -2. * params[0] / ye;
+            forward_error = forward_error.max((forward[i] - analytic).abs());
+            central_error = central_error.max((central[i] - analytic).abs());
         }
-        /*
-         *	    tests for termination and stringent tolerances.
-         */
-        if self.cfg.max_fev > 0 && self.nfev >= self.cfg.max_fev {
-            /* Too many function evaluations */
-            self.info = Success::MaxIter;
+        assert!(forward_error > 1e-4);
+        assert!(central_error < 1e-8);
+
+        // Falls back to one-sided differences at a limit
+        let limited = ParamConfig {
+            limits: Limits::Upper(1.5),
+            ..two_sided
+        };
+        let status = fit_from(&f, &params, [limited, two_sided], config).expect("Error in fit");
+        let jacobian = status.jacobian.expect("Jacobian requested");
+        assert_eq!(status.n_fev, forward_fev + 1);
+        for (i, ye) in f.0.ye.iter().enumerate() {
+            assert_approx_eq!(jacobian[i], -2. * params[0] / ye, 1e-2);
         }
-        if self.iter >= self.cfg.max_iter {
-            /* Too many iterations */
-            self.info = Success::MaxIter;
+    }
+
+    #[test]
+    fn one_sided_differences() {
+        // The model is not defined above a = 2
+        struct Bounded(Linear);
+        impl Problem<2> for Bounded {
+            fn eval(&self, params: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                if params[0] > 2. {
+                    return Err(Error::Eval);
+                }
+                self.0.eval(params, deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.number_of_points()
+            }
         }
-        if actred.abs() <= f64::EPSILON && prered <= f64::EPSILON && 0.5 * ratio <= 1.0 {
-            self.info = Success::Ftol;
+
+        let f = Bounded(linear_problem());
+        let params = [2., 1.];
+        let config = Config {
+            max_iter: 0,
+            return_jacobian: true,
+            ..Default::default()
+        };
+        let side = |side| ParamConfig {
+            side,
+            ..Default::default()
+        };
+        let res = fit_from(&f, &params, [side(Side::Right); 2], config);
+        assert_eq!(res, Err(Error::Eval));
+
+        let status = fit_from(&f, &params, [side(Side::Left), side(Side::Right)], config)
+            .expect("Error in fit");
+        let jacobian = status.jacobian.expect("Jacobian requested");
+        let m = f.number_of_points();
+        for (i, (x, ye)) in f.0.x.iter().zip(&f.0.ye).enumerate() {
+            assert_approx_eq!(jacobian[i], -1. / ye, 1e-6);
+            assert_approx_eq!(jacobian[m + i], -x / ye, 1e-6);
         }
-        if self.delta <= f64::EPSILON * self.xnorm {
-            self.info = Success::Xtol;
+
+        // Forward differences at a lower limit
+        let limited = ParamConfig {
+            limits: Limits::Lower(2.),
+            ..side(Side::Left)
+        };
+        let res = fit_from(&f, &params, [limited, side(Side::Right)], config);
+        assert_eq!(res, Err(Error::Eval));
+
+        // The model is not defined below a = 2, close to the upper limit
+        // forward steps are shortened instead of reversed
+        struct Above(Linear);
+        impl Problem<2> for Above {
+            fn eval(&self, params: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                if params[0] < 2. {
+                    return Err(Error::Eval);
+                }
+                self.0.eval(params, deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.number_of_points()
+            }
         }
-        if gnorm <= f64::EPSILON {
-            self.info = Success::Gtol;
+
+        let f = Above(linear_problem());
+        let near_limit = |side| ParamConfig {
+            limits: Limits::Upper(2.001),
+            step_scale: 0.01,
+            side,
+            ..Default::default()
+        };
+        let res = fit_from(
+            &f,
+            &params,
+            [near_limit(Side::Auto), side(Side::Auto)],
+            config,
+        );
+        assert_eq!(res, Err(Error::Eval));
+        let status = fit_from(
+            &f,
+            &params,
+            [near_limit(Side::Right), side(Side::Auto)],
+            config,
+        )
+        .expect("Error in fit");
+        let jacobian = status.jacobian.expect("Jacobian requested");
+        for (i, ye) in f.0.ye.iter().enumerate() {
+            assert_approx_eq!(jacobian[i], -1. / ye, 1e-6);
         }
-        if self.info != Success::NotDone {
-            return Ok(MPDone::Exit);
+
+        // Backward differences only on the upper limit itself
+        let on_limit = ParamConfig {
+            limits: Limits::Upper(2.),
+            ..side(Side::Right)
+        };
+        let res = fit_from(&f, &params, [on_limit, side(Side::Auto)], config);
+        assert_eq!(res, Err(Error::Eval));
+    }
+
+    #[test]
+    fn user_derivatives() {
+        struct Analytic(Linear);
+        impl Problem<2> for Analytic {
+            fn eval(&self, params: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                self.0.eval(params, deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.number_of_points()
+            }
+
+            fn jacobian(&self, _: &[f64; 2], jacobian: &mut [f64]) -> Result<()> {
+                // Only the derivatives of the offset are provided
+                for (d, ye) in jacobian.iter_mut().zip(&self.0.ye) {
+                    *d = -1. / ye;
+                }
+                Ok(())
+            }
         }
-        if ratio < 1e-4 {
-            Ok(MPDone::Inner)
-        } else {
-            Ok(MPDone::Outer)
+
+        let f = Analytic(linear_problem());
+        let user = ParamConfig {
+            side: Side::User,
+            ..Default::default()
+        };
+        let config = Config {
+            max_iter: 0,
+            return_jacobian: true,
+            ..Default::default()
+        };
+        let status =
+            fit_from(&f, &[1., 1.], [user, Default::default()], config).expect("Error in fit");
+        // The initial evaluation and the forward difference of the slope only
+        assert_eq!(status.n_fev, 2);
+        let jacobian = status.jacobian.expect("Jacobian requested");
+        let m = f.number_of_points();
+        for (i, (x, ye)) in f.0.x.iter().zip(&f.0.ye).enumerate() {
+            assert_eq!(jacobian[i], -1. / ye);
+            assert_approx_eq!(jacobian[m + i], -x / ye, 1e-6);
         }
-    }
 
-    fn check_config(&self) -> Result<()> {
-        if self.cfg.ftol <= 0.
-            || self.cfg.xtol <= 0.
-            || self.cfg.gtol <= 0.
-            || self.cfg.step_factor <= 0.
-        {
-            Err(Error::Input)
-        } else if self.m < self.nfree {
-            Err(Error::DoF)
-        } else {
-            Ok(())
+        let mixed = fit_from(
+            &f,
+            &[1., 1.],
+            [user, Default::default()],
+            Default::default(),
+        )
+        .expect("Error in fit");
+        let numeric =
+            fit_from(&f, &[1., 1.], Default::default(), Default::default()).expect("Error in fit");
+        assert_eq!(mixed.n_iter, numeric.n_iter);
+        // One evaluation less per Jacobian
+        assert!(mixed.n_fev < numeric.n_fev);
+        for i in 0..2 {
+            assert_approx_eq!(mixed.params[i], numeric.params[i], 1e-8);
+            assert_approx_eq!(mixed.xerror[i], numeric.xerror[i], 1e-8);
         }
+
+        // Not implemented by the problem
+        let l = linear_problem();
+        let res = fit_from(&l, &[1., 1.], [user; 2], Default::default());
+        assert_eq!(res, Err(Error::Input));
     }
-}
 
-enum MPDone {
-    Exit,
-    Inner,
-    Outer,
-}
+    #[test]
+    fn error_in_jacobian() {
+        // Fails for every perturbed slope
+        struct FailingProbe {
+            inner: Linear,
+            last: std::cell::Cell<[f64; 2]>,
+        }
 
-///    function enorm
-///
-///    given an n-vector x, this function calculates the
-///    euclidean norm of x.
-///
-///    the euclidean norm is computed by accumulating the sum of
-///    squares in three different sums. the sums of squares for the
-///    small and large components are scaled so that no overflows
-///    occur. non-destructive underflows are permitted. underflows
-///    and overflows do not occur in the computation of the unscaled
-///    sum of squares for the intermediate components.
-///    the definitions of small, intermediate and large components
-///    depend on two constants, rdwarf and rgiant. the main
-///    restrictions on these constants are that rdwarf**2 not
-///    underflow and rgiant**2 not overflow. the constants
-///    given here are suitable for every known computer.
-///    the function statement is
-///    double precision function enorm(n,x)
-///    where
-///
-///    n is a positive integer input variable.
-///
-///    x is an input array of length n.
-trait ENorm {
-    fn enorm(&self) -> f64;
-}
+        impl Problem<2> for FailingProbe {
+            fn eval(&self, params: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                self.last.set(*params);
+                if params[1] != 1. {
+                    return Err(Error::Eval);
+                }
+                self.inner.eval(params, deviates)
+            }
 
-impl ENorm for [f64] {
-    fn enorm(&self) -> f64 {
-        let mut s1 = 0.;
-        let mut s2 = 0.;
-        let mut s3 = 0.;
-        let mut x1max = 0.;
-        let mut x3max = 0.;
-        let agiant = MP_RGIANT / self.len() as f64;
-        for val in self {
-            let xabs = val.abs();
-            if xabs > MP_RDWARF && xabs < agiant {
-                // sum for intermediate components.
-                s2 += xabs * xabs;
-            } else if xabs > MP_RDWARF {
-                // sum for large components.
-                if xabs > x1max {
-                    let temp = x1max / xabs;
-                    s1 = 1.0 + s1 * temp * temp;
-                    x1max = xabs;
-                } else {
-                    let temp = xabs / x1max;
-                    s1 += temp * temp;
+            fn number_of_points(&self) -> usize {
+                self.inner.number_of_points()
+            }
+        }
+
+        let f = FailingProbe {
+            inner: linear_problem(),
+            last: Default::default(),
+        };
+        let mut init = [1., 1.];
+        let res = fit(&f, &mut init, Default::default(), Default::default());
+        assert_eq!(res, Err(Error::Eval));
+        assert_ne!(f.last.get()[1], 1.);
+
+        // The failed probe does not leak into the parameters of a following
+        // evaluation
+        let pars = [ParamConfig::default(); 2];
+        let mut xall = [1., 1.];
+        let mut fit = Fit::new(&f, &mut xall, &pars, Config::default()).expect("Error in setup");
+        fit.parse_params(pars).expect("Error in parameters");
+        fit.init_lm().expect("Error in first evaluation");
+        fit.fill_xnew();
+        assert_eq!(fit.fdjac2(), Err(Error::Eval));
+        assert_eq!(fit.xnew, [1., 1.]);
+        let mut deviates = vec![0.; f.number_of_points()];
+        f.eval(&fit.xnew, &mut deviates)
+            .expect("Error at unperturbed parameters");
+        assert_eq!(f.last.get(), [1., 1.]);
+    }
+
+    #[test]
+    fn correlated() {
+        struct Line {
+            x: [f64; 4],
+            y: [f64; 4],
+        }
+        impl Problem<2> for Line {
+            fn eval(&self, [a, b]: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                for ((d, x), y) in deviates.iter_mut().zip(&self.x).zip(&self.y) {
+                    *d = y - (a + b * x);
                 }
-            } else if xabs > x3max {
-                // sum for small components.
-                let temp = x3max / xabs;
-                s3 = 1.0 + s3 * temp * temp;
-                x3max = xabs;
-            } else if xabs != 0.0 {
-                let temp = xabs / x3max;
-                s3 += temp * temp;
+                Ok(())
+            }
+
+            fn number_of_points(&self) -> usize {
+                4
             }
         }
-        // calculation of norm.
-        if s1 != 0.0 {
-            x1max * (s1 + (s2 / x1max) / x1max).sqrt()
-        } else if s2 != 0.0 {
-            if s2 >= x3max {
-                s2 * (1.0 + (x3max / s2) * (x3max * s3))
-            } else {
-                x3max * ((s2 / x3max) + (x3max * s3))
+
+        let line = Line {
+            x: [0., 1., 2., 3.],
+            y: [1.1, 2.7, 5.2, 6.8],
+        };
+        #[rustfmt::skip]
+        let l = [
+            2.0, 0.0, 0.0, 0.0,
+            -0.8, 1.5, 0.0, 0.0,
+            0.3, -0.5, 1.8, 0.0,
+            0.1, 0.2, -0.6, 1.2,
+        ];
+        // generalized least squares via the normal equations of W = L L^T
+        let mut w = [0.; 16];
+        for i in 0..4 {
+            for j in 0..4 {
+                w[i * 4 + j] = (0..4).map(|k| l[i * 4 + k] * l[j * 4 + k]).sum();
+            }
+        }
+        let (mut xwx, mut xwy) = ([0.; 4], [0.; 2]);
+        for i in 0..4 {
+            for j in 0..4 {
+                let wij = w[i * 4 + j];
+                let (xi, xj) = ([1., line.x[i]], [1., line.x[j]]);
+                for a in 0..2 {
+                    xwy[a] += xi[a] * wij * line.y[j];
+                    for b in 0..2 {
+                        xwx[a * 2 + b] += xi[a] * wij * xj[b];
+                    }
+                }
             }
-            .sqrt()
-        } else {
-            x3max * s3.sqrt()
         }
+        let det = xwx[0] * xwx[3] - xwx[1] * xwx[2];
+        let cov = [xwx[3] / det, -xwx[1] / det, -xwx[2] / det, xwx[0] / det];
+        let gls = [
+            cov[0] * xwy[0] + cov[1] * xwy[1],
+            cov[2] * xwy[0] + cov[3] * xwy[1],
+        ];
+
+        let mut init = [1., 1.];
+        let problem = Correlated::new(&line, &l);
+        let status = fit(&problem, &mut init, Default::default(), Default::default())
+            .expect("Error in correlated fit");
+        assert_approx_eq!(init[0], gls[0], 1e-7);
+        assert_approx_eq!(init[1], gls[1], 1e-7);
+        assert_approx_eq!(status.xerror[0], cov[0].sqrt(), 1e-6);
+        assert_approx_eq!(status.xerror[1], cov[3].sqrt(), 1e-6);
+
+        let wrong = Correlated::new(&line, &l[..9]);
+        let res = fit(&wrong, &mut init, Default::default(), Default::default());
+        assert_eq!(res, Err(Error::Input));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::{fit, Limits, ParamConfig, Problem, Result, Success};
-    use assert_approx_eq::assert_approx_eq;
-    use std::f64::consts::{LN_2, PI};
+    #[test]
+    fn weighted() {
+        let reference = linear_problem();
+        let mut unweighted = linear_problem();
+        let sigma = ::std::mem::replace(&mut unweighted.ye, vec![1.; 10]);
+        let weights = sigma.iter().map(|s| 1. / (s * s)).collect::<Vec<_>>();
+
+        let mut expected = [1., 1.];
+        let status = fit(
+            &reference,
+            &mut expected,
+            Default::default(),
+            Default::default(),
+        )
+        .expect("Error in reference fit");
+
+        let from_sigma = Weighted::from_sigma(&unweighted, &sigma);
+        let from_weights = Weighted::from_weights(&unweighted, &weights);
+        let scale = from_sigma.scale().to_vec();
+        let borrowed = Weighted::new(&unweighted, &scale);
+        for problem in [&from_sigma, &from_weights, &borrowed] {
+            let mut init = [1., 1.];
+            let weighted = fit(problem, &mut init, Default::default(), Default::default())
+                .expect("Error in weighted fit");
+            assert_approx_eq!(init[0], expected[0], 1e-8);
+            assert_approx_eq!(init[1], expected[1], 1e-8);
+            assert_approx_eq!(weighted.best_norm, status.best_norm, 1e-8);
+            assert_approx_eq!(weighted.xerror[1], status.xerror[1], 1e-8);
+        }
+
+        let mut deviates = [0.; 10];
+        let res = Weighted::new(&unweighted, &scale[..9]).eval(&[1., 1.], &mut deviates);
+        assert_eq!(res, Err(Error::Input));
+    }
 
     #[test]
-    fn linear() {
-        struct Linear {
+    fn reparameterized() {
+        struct Parabola {
             x: Vec<f64>,
             y: Vec<f64>,
-            ye: Vec<f64>,
+            evals: ::std::cell::Cell<usize>,
         }
-
-        impl Problem<2> for Linear {
-            fn eval(&self, [a, b]: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
-                for (((d, &x), y), ye) in deviates
-                    .iter_mut()
-                    .zip(self.x.iter())
-                    .zip(self.y.iter())
-                    .zip(self.ye.iter())
-                {
-                    let f = a + b * x;
-                    *d = (*y - f) / *ye;
+        impl Problem<3> for Parabola {
+            fn eval(&self, [a, b, c]: &[f64; 3], deviates: &mut [f64]) -> Result<()> {
+                // the constraint holds for every evaluation, including the
+                // finite difference probes
+                assert_eq!(*c, a * b);
+                self.evals.set(self.evals.get() + 1);
+                for ((d, x), y) in deviates.iter_mut().zip(&self.x).zip(&self.y) {
+                    *d = y - (a + b * x + c * x * x);
                 }
                 Ok(())
             }
@@ -1840,197 +9745,351 @@ mod tests {
             }
         }
 
-        let l = Linear {
-            x: vec![
-                -1.7237128E+00,
-                1.8712276E+00,
-                -9.6608055E-01,
-                -2.8394297E-01,
-                1.3416969E+00,
-                1.3757038E+00,
-                -1.3703436E+00,
-                4.2581975E-02,
-                -1.4970151E-01,
-                8.2065094E-01,
-            ],
-            y: vec![
-                1.9000429E-01,
-                6.5807428E+00,
-                1.4582725E+00,
-                2.7270851E+00,
-                5.5969253E+00,
-                5.6249280E+00,
-                0.787615,
-                3.2599759E+00,
-                2.9771762E+00,
-                4.5936475E+00,
-            ],
-            ye: vec![0.07; 10],
+        let x = (0..20).map(|i| 0.25 * i as f64 - 2.).collect::<Vec<_>>();
+        let y = x.iter().map(|x| 1.5 + 0.8 * x + 1.2 * x * x).collect();
+        let parabola = Parabola {
+            x,
+            y,
+            evals: Default::default(),
         };
+        let problem = Reparameterized::new(&parabola, |&[a, b]: &[f64; 2]| [a, b, a * b]);
         let mut init = [1., 1.];
-        let status = fit(&l, &mut init, Default::default(), Default::default())
-            .expect("Error in Linear fit");
-        assert_eq!(status.success, Success::Chi);
-        assert_eq!(status.n_iter, 3);
-        assert_eq!(status.n_fev, 8);
-        assert_approx_eq!(status.best_norm, 2.75628498);
-        assert_approx_eq!(init[0], 3.20996572);
-        assert_approx_eq!(init[1], 1.77095420);
-        assert_approx_eq!(status.xerror[0], 0.02221018);
-        assert_approx_eq!(status.xerror[1], 0.01893756);
+        let status = fit(&problem, &mut init, Default::default(), Default::default())
+            .expect("Error in reparameterized fit");
+        assert!(status.success.is_converged());
+        assert_eq!(status.n_free, 2);
+        assert!(parabola.evals.get() >= status.n_fev);
+        assert_approx_eq!(init[0], 1.5, 1e-8);
+        assert_approx_eq!(init[1], 0.8, 1e-8);
+        let [_, _, c] = problem.full(&init);
+        assert_approx_eq!(c, 1.2, 1e-8);
+    }
+
+    #[test]
+    fn timing() {
+        struct Slow(Linear);
+        impl Problem<2> for Slow {
+            fn eval(&self, params: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                ::std::thread::sleep(::std::time::Duration::from_millis(1));
+                self.0.eval(params, deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.number_of_points()
+            }
+        }
+
+        let mut init = [1., 1.];
+        let status = fit(
+            &Slow(linear_problem()),
+            &mut init,
+            Default::default(),
+            Default::default(),
+        )
+        .expect("Error in slow fit");
+        let timing = status.timing;
+        // Only what the sleeps guarantee, the rest depends on the load
+        assert!(timing.eval >= ::std::time::Duration::from_millis(status.n_fev as u64));
+        assert!(!timing.jacobian.is_zero());
+        assert!(!timing.linear_algebra.is_zero());
+        assert!(timing.eval > timing.jacobian + timing.linear_algebra);
     }
 
     #[test]
-    fn quad() {
-        struct Quad {
-            x: Vec<f64>,
-            y: Vec<f64>,
-            ye: Vec<f64>,
+    fn sparse_jacobian() {
+        // independent line segments of 20 points each, plus a common offset
+        const K: usize = 8;
+        const N: usize = 2 * K + 1;
+        struct Piecewise {
+            evaluated: ::std::cell::Cell<usize>,
+        }
+        impl Piecewise {
+            fn residual(&self, params: &[f64; N], i: usize) -> f64 {
+                let (k, x) = (i / 20, (i % 20) as f64 / 4.);
+                let y = 0.5 * k as f64 + (1. + 0.1 * k as f64) * x + 0.05 * (1.3 * i as f64).sin();
+                y - (params[N - 1] + params[2 * k] + params[2 * k + 1] * x)
+            }
         }
+        impl Problem<N> for Piecewise {
+            fn eval(&self, params: &[f64; N], deviates: &mut [f64]) -> Result<()> {
+                self.eval_chunk(params, 0, deviates)
+            }
 
-        impl Problem<3> for Quad {
-            fn eval(&self, [a, b, c]: &[f64; 3], deviates: &mut [f64]) -> Result<()> {
-                for (((d, x), y), ye) in deviates
-                    .iter_mut()
-                    .zip(self.x.iter())
-                    .zip(self.y.iter())
-                    .zip(self.ye.iter())
-                {
-                    let x = *x;
-                    let f = a + b * x + c * x * x;
-                    *d = (*y - f) / *ye;
+            fn number_of_points(&self) -> usize {
+                20 * K
+            }
+
+            fn eval_chunk(
+                &self,
+                params: &[f64; N],
+                offset: usize,
+                deviates: &mut [f64],
+            ) -> Result<()> {
+                self.evaluated.set(self.evaluated.get() + deviates.len());
+                for (i, d) in deviates.iter_mut().enumerate() {
+                    *d = self.residual(params, offset + i);
                 }
                 Ok(())
             }
 
-            fn number_of_points(&self) -> usize {
-                self.x.len()
+            fn support(&self, param: usize) -> Option<::std::ops::Range<usize>> {
+                let k = param / 2;
+                (k < K).then(|| 20 * k..20 * (k + 1))
             }
         }
-        let l = Quad {
-            x: vec![
-                -1.7237128E+00,
-                1.8712276E+00,
-                -9.6608055E-01,
-                -2.8394297E-01,
-                1.3416969E+00,
-                1.3757038E+00,
-                -1.3703436E+00,
-                4.2581975E-02,
-                -1.4970151E-01,
-                8.2065094E-01,
-            ],
-            y: vec![
-                2.3095947E+01,
-                2.6449392E+01,
-                1.0204468E+01,
-                5.40507,
-                1.5787588E+01,
-                1.6520903E+01,
-                1.5971818E+01,
-                4.7668524E+00,
-                4.9337711E+00,
-                8.7348375E+00,
-            ],
-            ye: vec![0.2; 10],
+
+        let problem = Piecewise {
+            evaluated: Default::default(),
         };
-        let mut init = [1., 1., 1.];
-        let status =
-            fit(&l, &mut init, Default::default(), Default::default()).expect("Error in Quad fit");
-        assert_eq!(status.success, Success::Chi);
-        assert_eq!(status.n_iter, 3);
-        assert_eq!(status.n_fev, 10);
-        assert_approx_eq!(status.best_norm, 5.67932273);
-        assert_approx_eq!(init[0], 4.70382909);
-        assert_approx_eq!(init[1], 0.06258629);
-        assert_approx_eq!(init[2], 6.16308723);
-        assert_approx_eq!(status.xerror[0], 0.09751164);
-        assert_approx_eq!(status.xerror[1], 0.05480195);
-        assert_approx_eq!(status.xerror[2], 0.05443275);
+        let mut pars = [ParamConfig::default(); N];
+        // the offset is degenerate with the segment offsets
+        pars[N - 1].fixed = true;
+        let mut init = [1.; N];
+        init[N - 1] = 0.2;
+        let dense = fit_from(&problem, &init, pars, Default::default()).expect("Error in fit");
+
+        problem.evaluated.set(0);
+        let mut params = init;
+        let sparse =
+            fit_sparse(&problem, &mut params, pars, Default::default()).expect("Error in fit");
+        assert!(sparse.success.is_converged());
+        assert_eq!(params, sparse.params);
+        // every probe only evaluates its segment
+        assert!(problem.evaluated.get() < sparse.n_fev * 20 * K);
+        assert_approx_eq!(sparse.best_norm, dense.best_norm, 1e-10);
+        assert_eq!(sparse.resid.len(), 20 * K);
+        assert_eq!(sparse.rank, 2 * K);
+        for i in 0..N {
+            assert_approx_eq!(sparse.params[i], dense.params[i], 1e-6);
+            assert_approx_eq!(sparse.xerror[i], dense.xerror[i], 1e-6);
+        }
+        for (s, d) in sparse.covar.iter().zip(dense.covar.iter()) {
+            assert_approx_eq!(s, d, 1e-6);
+        }
+        // segments are independent
+        assert_eq!(sparse.covar[2], 0.);
+        assert!(sparse.covar[1] != 0.);
+
+        struct OutOfRange(Linear);
+        impl Problem<2> for OutOfRange {
+            fn eval(&self, params: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                self.0.eval(params, deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.number_of_points()
+            }
+
+            fn support(&self, _: usize) -> Option<::std::ops::Range<usize>> {
+                Some(0..11)
+            }
+        }
+        let wrong = fit_sparse(
+            &OutOfRange(linear_problem()),
+            &mut [1., 1.],
+            Default::default(),
+            Default::default(),
+        );
+        assert_eq!(wrong, Err(Error::Input));
+    }
+
+    #[test]
+    fn limit_margin() {
+        // at the upper limit, close to it and in a range narrower than the step
+        let h = 1e-8;
+        for (x, lower, upper) in [
+            (1.5, f64::NEG_INFINITY, 1.5),
+            (1.5 - 0.3 * h, f64::NEG_INFINITY, 1.5),
+            (0.1 + 0.2, 0.3, 0.3 + 0.5 * h),
+            (0.3 + 0.4 * h, 0.3, 0.3 + 0.5 * h),
+        ] {
+            for margin in [0., 1e-3] {
+                let step = limit_step(x, h, lower, upper, margin);
+                assert!(step != 0.);
+                assert!(x + step <= upper - margin * h);
+                assert!(x + step >= lower + margin * h);
+            }
+        }
+        assert_eq!(limit_step(1., h, 0., 2., 1e-3), h);
+
+        struct Bounded {
+            line: Linear,
+            max: ::std::cell::Cell<f64>,
+        }
+        impl Problem<2> for Bounded {
+            fn eval(&self, params: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                self.max.set(self.max.get().max(params[1]));
+                self.line.eval(params, deviates)
+            }
 
+            fn number_of_points(&self) -> usize {
+                self.line.number_of_points()
+            }
+        }
+        let problem = Bounded {
+            line: linear_problem(),
+            max: ::std::cell::Cell::new(f64::NEG_INFINITY),
+        };
         let pars = [
             ParamConfig::default(),
             ParamConfig {
-                fixed: true,
+                limits: Limits::Upper(1.5),
                 ..Default::default()
             },
-            ParamConfig::default(),
         ];
-        let mut init = [1., 0., 1.];
-        let status = fit(&l, &mut init, pars, Default::default()).expect("Error in Quad fixed fit");
-        assert_eq!(status.success, Success::Chi);
-        assert_eq!(status.n_iter, 3);
-        assert_eq!(status.n_fev, 8);
-        assert_approx_eq!(status.best_norm, 6.98358800);
-        assert_approx_eq!(init[0], 4.69625430);
-        assert_approx_eq!(init[1], 0.00000000);
-        assert_approx_eq!(init[2], 6.17295360);
-        assert_approx_eq!(status.xerror[0], 0.09728581);
-        assert_approx_eq!(status.xerror[1], 0.00000000);
-        assert_approx_eq!(status.xerror[2], 0.05374279);
+        for config in [
+            Config::default(),
+            Config {
+                limit_margin: 1e-3,
+                ..Default::default()
+            },
+        ] {
+            let status = fit_from(&problem, &[1., 1.5], pars, config).expect("Error in fit");
+            assert_eq!(status.params[1], 1.5);
+            assert!(problem.max.get() <= 1.5);
+        }
+
+        let config = Config {
+            limit_margin: -1.,
+            ..Default::default()
+        };
+        let res = fit_from(&problem, &[1., 1.], pars, config);
+        assert_eq!(res, Err(Error::Input));
     }
 
     #[test]
-    fn gaussian() {
-        struct Gaussian {
-            x: Vec<f64>,
-            y: Vec<f64>,
-            ye: Vec<f64>,
+    fn fit_linear_single_solve() {
+        let l = linear_problem();
+        let mut init = [1., 1.];
+        let iterative = fit(&l, &mut init, Default::default(), Default::default())
+            .expect("Error in Linear fit");
+        let mut params = [1., 1.];
+        let status = fit_linear(&l, &mut params, Default::default(), Default::default())
+            .expect("Error in linear fit");
+        assert_eq!(status.n_iter, 1);
+        // start, the forward differences of both parameters and the solution
+        assert_eq!(status.n_fev, 4);
+        assert_eq!(status.success, Success::Both);
+        assert_eq!(params, status.params);
+        for i in 0..2 {
+            assert_approx_eq!(params[i], init[i], 1e-7);
+            assert_approx_eq!(status.xerror[i], iterative.xerror[i], 1e-8);
         }
+        assert_approx_eq!(status.best_norm, iterative.best_norm, 1e-10);
 
-        impl Problem<4> for Gaussian {
-            fn eval(&self, [off, a, x0, sig]: &[f64; 4], deviates: &mut [f64]) -> Result<()> {
-                let sig2 = sig * sig;
-                for (((d, x), y), ye) in deviates
-                    .iter_mut()
-                    .zip(self.x.iter())
-                    .zip(self.y.iter())
-                    .zip(self.ye.iter())
-                {
-                    let xc = *x - x0;
-                    let f = a * (-0.5 * xc * xc / sig2).exp() + off;
-                    *d = (*y - f) / *ye;
-                }
-                Ok(())
-            }
+        let pars = [
+            ParamConfig {
+                limits: Limits::Lower(0.),
+                ..Default::default()
+            },
+            ParamConfig::default(),
+        ];
+        let res = fit_linear(&l, &mut [1., 1.], pars, Default::default());
+        assert_eq!(res, Err(Error::Input));
+    }
 
-            fn number_of_points(&self) -> usize {
-                self.x.len()
-            }
-        }
-        let l = Gaussian {
-            x: vec![
-                -1.7237128E+00,
-                1.8712276E+00,
-                -9.6608055E-01,
-                -2.8394297E-01,
-                1.3416969E+00,
-                1.3757038E+00,
-                -1.3703436E+00,
-                4.2581975E-02,
-                -1.4970151E-01,
-                8.2065094E-01,
-            ],
-            y: vec![
-                -4.4494256E-02,
-                8.7324673E-01,
-                7.4443483E-01,
-                4.7631559E+00,
-                1.7187297E-01,
-                1.1639182E-01,
-                1.5646480E+00,
-                5.2322268E+00,
-                4.2543168E+00,
-                6.2792623E-01,
-            ],
-            ye: vec![0.5; 10],
+    #[test]
+    fn feasibility() {
+        let pars = [
+            ParamConfig::default(),
+            ParamConfig {
+                limits: Limits::Lower(0.),
+                ..Default::default()
+            },
+            ParamConfig {
+                limits: Limits::Upper(1.),
+                ..Default::default()
+            },
+            ParamConfig {
+                limits: Limits::Both(-1., 1.),
+                ..Default::default()
+            },
+        ];
+        assert!(is_feasible(&[-1e9, 0., 1., -1.], &pars));
+        assert!(!is_feasible(&[0., -0.1, 0., 0.], &pars));
+        assert!(!is_feasible(&[0., 0., 0., 1.5], &pars));
+
+        let mut params = [5., -2., 3., -4.];
+        clamp_to_bounds(&mut params, &pars);
+        assert_eq!(params, [5., 0., 1., -1.]);
+        assert!(is_feasible(&params, &pars));
+    }
+
+    #[test]
+    fn init_bounds() {
+        let pars = [
+            ParamConfig {
+                limits: Limits::Both(0., 1.),
+                ..Default::default()
+            },
+            ParamConfig::default(),
+        ];
+        let mut params = [5., 1.];
+        let result = fit(&linear_problem(), &mut params, pars, Default::default());
+        assert_eq!(result.unwrap_err(), Error::InitBounds);
+        assert_eq!(params, [5., 1.]);
+        let result = validate(&linear_problem(), &params, pars, Default::default());
+        assert_eq!(result, Err(Error::InitBounds));
+    }
+
+    #[test]
+    fn bound_ordering() {
+        let l = linear_problem();
+        let chunked = Config {
+            chunk_size: 4,
+            ..Default::default()
+        };
+        let both = |lower, upper| ParamConfig {
+            limits: Limits::Both(lower, upper),
+            ..Default::default()
+        };
+        let swapped = [both(1., 0.), ParamConfig::default()];
+        let result = fit_from(&l, &[0.5, 1.], swapped, Default::default());
+        assert_eq!(result.unwrap_err(), Error::Bounds);
+        let mut params = [0.5, 1.];
+        let result = fit_streaming(&l, &mut params, swapped, chunked);
+        assert_eq!(result.unwrap_err(), Error::Bounds);
+
+        // A fixed parameter outside of its limits contradicts them
+        let fixed = ParamConfig {
+            fixed: true,
+            ..both(0., 1.)
         };
+        let pars = [fixed, ParamConfig::default()];
+        let result = fit_from(&l, &[2., 1.], pars, Default::default());
+        assert_eq!(result.unwrap_err(), Error::Bounds);
+        let mut params = [2., 1.];
+        let result = fit_streaming(&l, &mut params, pars, chunked);
+        assert_eq!(result.unwrap_err(), Error::Bounds);
+
+        // Equal limits leave no room for finite differences, the parameter
+        // has to be fixed instead
+        let pars = [both(3., 3.), ParamConfig::default()];
+        let result = fit_from(&l, &[3., 1.], pars, Default::default());
+        assert_eq!(result.unwrap_err(), Error::Bounds);
+        let mut params = [3., 1.];
+        let result = fit_streaming(&l, &mut params, pars, chunked);
+        assert_eq!(result.unwrap_err(), Error::Bounds);
+        let pars = [
+            ParamConfig {
+                fixed: true,
+                ..both(3., 3.)
+            },
+            ParamConfig::default(),
+        ];
+        let status = fit_from(&l, &[3., 1.], pars, Default::default()).expect("Error in fit");
+        assert_eq!(status.params[0], 3.);
+    }
+
+    #[test]
+    fn gaussian() {
+        let l = gaussian_problem();
         let mut init = [0., 1., 1., 1.];
         let status =
             fit(&l, &mut init, Default::default(), Default::default()).expect("Error in Quad fit");
         assert_eq!(status.success, Success::Chi);
         assert_eq!(status.n_iter, 27);
-        assert_eq!(status.n_fev, 134);
+        assert_eq!(status.n_fev, 133);
         assert_approx_eq!(status.best_norm, 10.35003196);
         assert_approx_eq!(init[0], 0.48044336);
         assert_approx_eq!(init[1], 4.55075247);
@@ -2057,7 +10116,7 @@ mod tests {
         let status = fit(&l, &mut init, pars, Default::default()).expect("Error in Quad fit");
         assert_eq!(status.success, Success::Chi);
         assert_eq!(status.n_iter, 12);
-        assert_eq!(status.n_fev, 35);
+        assert_eq!(status.n_fev, 34);
         assert_approx_eq!(status.best_norm, 15.51613428);
         assert_approx_eq!(init[0], 0.00000000);
         assert_approx_eq!(init[1], 5.05924391);
@@ -2069,6 +10128,200 @@ mod tests {
         assert_approx_eq!(status.xerror[3], 0.05380360);
     }
 
+    #[test]
+    fn require_all_criteria() {
+        let l = gaussian_problem();
+        let mut init = [0., 1., 1., 1.];
+        let first =
+            fit(&l, &mut init, Default::default(), Default::default()).expect("Error in fit");
+        let mut init = [0., 1., 1., 1.];
+        let config = Config {
+            require_all_criteria: true,
+            ..Default::default()
+        };
+        let all = fit(&l, &mut init, Default::default(), config).expect("Error in fit");
+        assert_eq!(first.success, Success::Chi);
+        assert!(
+            matches!(all.success, Success::Both | Success::Dir),
+            "{}",
+            all.success
+        );
+        assert!(all.n_iter > first.n_iter);
+        assert!(all.best_norm <= first.best_norm);
+    }
+
+    #[test]
+    fn error_interop() {
+        fn run(config: Config) -> ::std::result::Result<Status<2>, Box<dyn std::error::Error>> {
+            Ok(fit_from(
+                &linear_problem(),
+                &[1., 1.],
+                Default::default(),
+                config,
+            )?)
+        }
+        assert!(run(Default::default()).is_ok());
+        let config = Config {
+            ftol: -1.,
+            ..Default::default()
+        };
+        let err = run(config).unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::Input));
+    }
+
+    #[test]
+    fn info_codes() {
+        let success = [
+            (Success::NotDone, 0),
+            (Success::Chi, 1),
+            (Success::Par, 2),
+            (Success::Both, 3),
+            (Success::Dir, 4),
+            (Success::MaxIter, 5),
+            (Success::Ftol, 6),
+            (Success::Xtol, 7),
+            (Success::Gtol, 8),
+            (Success::MaxRejects, 5),
+        ];
+        for (success, info) in success {
+            assert_eq!(success.to_info(), info, "{success:?}");
+        }
+        let errors = [
+            (Error::Input, 0),
+            (Error::Nan, -16),
+            (Error::Eval, -17),
+            (Error::User(()), -17),
+            (Error::Empty, -18),
+            (Error::Memory, -20),
+            (Error::InitBounds, -21),
+            (Error::Bounds, -22),
+            (Error::DoF, -24),
+        ];
+        for (error, info) in errors {
+            assert_eq!(error.to_info(), info, "{error:?}");
+        }
+    }
+
+    #[test]
+    fn xtol_convergence() {
+        let g = gaussian_problem();
+        let start = [0., 1., 1., 1.];
+        let reference =
+            fit_from(&g, &start, Default::default(), Default::default()).expect("Error in fit");
+        // Chi-square criterion out of reach
+        let config = Config {
+            ftol: 1e-300,
+            xtol: 1e-6,
+            ..Default::default()
+        };
+        let status = fit_from(&g, &start, Default::default(), config).expect("Error in fit");
+        assert_eq!(status.success, Success::Par);
+        for i in 0..4 {
+            assert_approx_eq!(status.params[i], reference.params[i], 1e-5);
+        }
+
+        // Both criteria on the same iteration
+        let config = Config {
+            ftol: 1e-4,
+            xtol: 1e-4,
+            ..Default::default()
+        };
+        let l = linear_problem();
+        let status = fit_from(&l, &[1., 1.], Default::default(), config).expect("Error in fit");
+        assert_eq!(status.success, Success::Both);
+        assert_approx_eq!(status.params[0], 3.20996572, 1e-6);
+        assert_approx_eq!(status.params[1], 1.77095420, 1e-6);
+    }
+
+    #[test]
+    fn gtol_degenerate() {
+        // Both parameters enter (almost) only as their sum, so chi^2 is flat
+        // along their difference
+        struct Degenerate(Linear);
+        impl Problem<2> for Degenerate {
+            fn eval(&self, [a, b]: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+                self.0.eval(&[a + (1. + 1e-6) * b, 1.], deviates)
+            }
+
+            fn number_of_points(&self) -> usize {
+                self.0.number_of_points()
+            }
+        }
+
+        let config = Config {
+            xtol: 1e-300,
+            gtol: 1e-6,
+            ..Default::default()
+        };
+        let d = Degenerate(linear_problem());
+        let status = fit_from(&d, &[1., 1.], Default::default(), config).expect("Error in fit");
+        assert_eq!(status.success, Success::Dir);
+        assert_eq!(status.rank, 1);
+
+        // Same minimum as fitting the sum alone
+        let fixed = ParamConfig {
+            fixed: true,
+            ..Default::default()
+        };
+        let sum = fit_from(
+            &linear_problem(),
+            &[1., 1.],
+            [Default::default(), fixed],
+            Default::default(),
+        )
+        .expect("Error in fit");
+        let [a, b] = status.params;
+        assert_approx_eq!(a + (1. + 1e-6) * b, sum.params[0], 1e-6);
+        assert_approx_eq!(status.best_norm, sum.best_norm, 1e-8);
+    }
+
+    #[test]
+    fn tolerance_warnings() {
+        let tiny = Config {
+            ftol: 1e-300,
+            xtol: 1e-300,
+            gtol: 1e-300,
+            ..Default::default()
+        };
+
+        let mut init = [0., 1., 1., 1.];
+        let status = fit(&gaussian_problem(), &mut init, Default::default(), tiny).expect("Error");
+        assert_eq!(status.success, Success::Ftol);
+        assert!(status.success.is_warning());
+
+        let config = Config {
+            gtol: 1e-10,
+            ..tiny
+        };
+        let mut init = [1., 1.];
+        let status = fit(&linear_problem(), &mut init, Default::default(), config).expect("Error");
+        assert_eq!(status.success, Success::Xtol);
+        assert!(status.success.is_warning());
+
+        struct Constant;
+        impl Problem<1> for Constant {
+            fn eval(&self, [a]: &[f64; 1], deviates: &mut [f64]) -> Result<()> {
+                for (i, d) in deviates.iter_mut().enumerate() {
+                    *d = (i + 1) as f64 - a;
+                }
+                Ok(())
+            }
+
+            fn number_of_points(&self) -> usize {
+                3
+            }
+        }
+        let mut init = [1.];
+        let status = fit(&Constant, &mut init, Default::default(), tiny).expect("Error");
+        assert_eq!(status.success, Success::Gtol);
+        assert!(status.success.is_warning());
+        assert_approx_eq!(init[0], 2.);
+
+        for success in [Success::Chi, Success::Par, Success::Both, Success::Dir] {
+            assert!(!success.is_warning());
+        }
+    }
+
     fn gauss(x: f64, xc: f64, w: f64) -> f64 {
         (4. * LN_2).sqrt() / (PI.sqrt() * w) * (-4. * LN_2 / w.powi(2) * (x - xc).powi(2)).exp()
     }
@@ -2345,7 +10598,7 @@ mod tests {
 
         assert_eq!(status.success, Success::Chi);
         assert_eq!(status.n_iter, 12);
-        assert_eq!(status.n_fev, 69);
+        assert_eq!(status.n_fev, 68);
         assert_approx_eq!(status.best_norm, 37480.11190046);
         assert_approx_eq!(init[0], 45.99597613);
         assert_approx_eq!(init[1], 0.06848724);
@@ -0,0 +1,87 @@
+//! Allocation counting needs its own global allocator, which would affect
+//! every test in the unit test binary.
+
+use rmpfit::{fit_fixed, Problem, Result, Success, Weighted};
+
+/// Counts the allocations made by the current thread, so tests running in
+/// parallel do not interfere.
+struct CountingAlloc;
+
+::std::thread_local! {
+    static ALLOCATIONS: ::std::cell::Cell<usize> = const { ::std::cell::Cell::new(0) };
+}
+
+unsafe impl ::std::alloc::GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: ::std::alloc::Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        ::std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: ::std::alloc::Layout) {
+        ::std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|a| a.get())
+}
+
+struct Line {
+    x: Vec<f64>,
+    y: Vec<f64>,
+}
+
+impl Problem<2> for Line {
+    fn eval(&self, [a, b]: &[f64; 2], deviates: &mut [f64]) -> Result<()> {
+        for ((d, x), y) in deviates.iter_mut().zip(&self.x).zip(&self.y) {
+            *d = y - (a + b * x);
+        }
+        Ok(())
+    }
+
+    fn number_of_points(&self) -> usize {
+        self.x.len()
+    }
+}
+
+#[test]
+fn weighted_eval() {
+    let line = Line {
+        x: (0..10).map(f64::from).collect(),
+        y: (0..10).map(|i| 1. + 2. * f64::from(i)).collect(),
+    };
+    let sigma = vec![0.5; 10];
+    let weighted = Weighted::from_sigma(&line, &sigma);
+
+    // the weights are applied in place, evaluations do not allocate
+    let mut deviates = [0.; 10];
+    let before = allocations();
+    for i in 0..100 {
+        weighted
+            .eval(&[1., 0.01 * f64::from(i)], &mut deviates)
+            .expect("Error in weighted evaluation");
+    }
+    assert_eq!(allocations(), before);
+}
+
+#[test]
+fn fixed_fit() {
+    let line = Line {
+        x: (0..10).map(f64::from).collect(),
+        y: (0..10).map(|i| 1. + 2. * f64::from(i)).collect(),
+    };
+
+    // residuals, Jacobian and normal equations all live on the stack
+    let mut params = [0., 0.];
+    let before = allocations();
+    let status =
+        fit_fixed::<2, 10, _, _>(&line, &mut params, Default::default(), Default::default())
+            .expect("Error in fixed fit");
+    assert_eq!(allocations(), before);
+    assert_ne!(status.success, Success::MaxIter);
+    assert!((params[0] - 1.).abs() < 1e-6);
+    assert!((params[1] - 2.).abs() < 1e-6);
+}